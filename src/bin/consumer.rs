@@ -1,28 +1,136 @@
 use mqdish::shared::config::{AppConfig, BusParams};
 use mqdish::shared::executor::Executor;
 use mqdish::shared::msgbus::amqp::AmqpBus;
-use mqdish::shared::msgbus::bus::Closer;
+use mqdish::shared::msgbus::bus::{Closer, Consumer};
+#[cfg(feature = "kafka")]
+use mqdish::shared::msgbus::kafka::KafkaBus;
+#[cfg(feature = "mqtt")]
+use mqdish::shared::msgbus::mqtt::MqttBus;
+#[cfg(feature = "nats")]
+use mqdish::shared::msgbus::nats_jetstream::NatsBus;
+#[cfg(feature = "postgres")]
+use mqdish::shared::msgbus::postgres::PostgresBus;
+#[cfg(feature = "redis")]
+use mqdish::shared::msgbus::redis_streams::RedisBus;
+#[cfg(feature = "sqlite")]
+use mqdish::shared::msgbus::sqlite::SqliteBus;
 use openssl_probe::init_openssl_env_vars;
+use tokio::signal::unix::{signal, SignalKind};
 
 #[tokio::main]
 async fn main() {
-    // TODO: graceful shutdown
     unsafe {
         init_openssl_env_vars();
     }
     let config = AppConfig::load(None).expect("Failed to load config");
-    let mut bus = match config.bus_params {
+    match config.bus_params {
         BusParams::AMQP(_) => {
-            AmqpBus::new(config.connection, config.credentials, config.bus_params)
-                .await
-                .expect("AMQP driver init failed")
+            let mut bus = AmqpBus::new(
+                config.connection.clone(),
+                config.credentials.clone(),
+                config.bus_params.clone(),
+            )
+            .await
+            .expect("AMQP driver init failed")
+            .with_topics(config.topics.clone())
+            .with_labels(config.labels.clone());
+            consume(&mut bus, &config).await;
         }
-    };
+        #[cfg(feature = "redis")]
+        BusParams::Redis(_) => {
+            let mut bus = RedisBus::new(
+                config.connection.clone(),
+                config.credentials.clone(),
+                config.bus_params.clone(),
+            )
+            .await
+            .expect("Redis driver init failed")
+            .with_topics(config.topics.clone());
+            consume(&mut bus, &config).await;
+        }
+        #[cfg(feature = "nats")]
+        BusParams::Nats(_) => {
+            let mut bus = NatsBus::new(
+                config.connection.clone(),
+                config.credentials.clone(),
+                config.bus_params.clone(),
+            )
+            .await
+            .expect("NATS driver init failed")
+            .with_topics(config.topics.clone());
+            consume(&mut bus, &config).await;
+        }
+        #[cfg(feature = "sqlite")]
+        BusParams::Sqlite(_) => {
+            let mut bus = SqliteBus::new(
+                config.connection.clone(),
+                config.credentials.clone(),
+                config.bus_params.clone(),
+            )
+            .await
+            .expect("SQLite driver init failed")
+            .with_topics(config.topics.clone());
+            consume(&mut bus, &config).await;
+        }
+        #[cfg(feature = "postgres")]
+        BusParams::Postgres(_) => {
+            let mut bus = PostgresBus::new(
+                config.connection.clone(),
+                config.credentials.clone(),
+                config.bus_params.clone(),
+            )
+            .await
+            .expect("PostgreSQL driver init failed")
+            .with_topics(config.topics.clone());
+            consume(&mut bus, &config).await;
+        }
+        #[cfg(feature = "mqtt")]
+        BusParams::Mqtt(_) => {
+            let mut bus = MqttBus::new(
+                config.connection.clone(),
+                config.credentials.clone(),
+                config.bus_params.clone(),
+            )
+            .await
+            .expect("MQTT driver init failed")
+            .with_topics(config.topics.clone());
+            consume(&mut bus, &config).await;
+        }
+        #[cfg(feature = "kafka")]
+        BusParams::Kafka(_) => {
+            let mut bus = KafkaBus::new(
+                config.connection.clone(),
+                config.credentials.clone(),
+                config.bus_params.clone(),
+            )
+            .await
+            .expect("Kafka driver init failed")
+            .with_topics(config.topics.clone());
+            consume(&mut bus, &config).await;
+        }
+        BusParams::Memory(_) => {
+            panic!("Memory bus is not shared between processes, the producer executes tasks itself")
+        }
+    }
+}
 
-    Executor::new(&mut bus, config.concurrency, config.topic)
-        .run()
+// Executes tasks of the configured topic until the worker is stopped.
+async fn consume<B: Consumer + Closer>(bus: &mut B, config: &AppConfig) {
+    Executor::new(bus, config.concurrency, config.topic.clone())
+        .with_config(config)
+        .run_until(shutdown_signal())
         .await
         .expect("Executor failed");
 
     bus.close().await.expect("Failed to close bus");
 }
+
+// Completes on the first SIGTERM or SIGINT.
+async fn shutdown_signal() {
+    let mut terminate = signal(SignalKind::terminate()).expect("Failed to listen for SIGTERM");
+    let mut interrupt = signal(SignalKind::interrupt()).expect("Failed to listen for SIGINT");
+    tokio::select! {
+        _ = terminate.recv() => {}
+        _ = interrupt.recv() => {}
+    }
+}
@@ -1,21 +1,52 @@
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use mqdish::shared::config::{AppConfig, BusParams};
 use mqdish::shared::dispatcher::Dispatcher;
-use mqdish::shared::models::Task;
+use mqdish::shared::executor::Executor;
+use mqdish::shared::models::{RetryPolicy, Task, TaskResult};
 use mqdish::shared::msgbus::amqp::AmqpBus;
-use mqdish::shared::msgbus::bus::Closer;
+use mqdish::shared::msgbus::bus::{
+    Broadcaster, Closer, DeadLetters, Message, Publisher, ReplyConsumer,
+};
+#[cfg(feature = "kafka")]
+use mqdish::shared::msgbus::kafka::KafkaBus;
+use mqdish::shared::msgbus::memory::MemoryBus;
+#[cfg(feature = "mqtt")]
+use mqdish::shared::msgbus::mqtt::MqttBus;
+#[cfg(feature = "nats")]
+use mqdish::shared::msgbus::nats_jetstream::NatsBus;
+#[cfg(feature = "postgres")]
+use mqdish::shared::msgbus::postgres::PostgresBus;
+#[cfg(feature = "redis")]
+use mqdish::shared::msgbus::redis_streams::RedisBus;
+#[cfg(feature = "sqlite")]
+use mqdish::shared::msgbus::sqlite::SqliteBus;
+use mqdish::shared::tracker::{BroadcastTracker, Summary, Tracker};
+use mqdish::shared::workflow::{Coordinator, NodeStatus, Workflow};
 use openssl_probe::init_openssl_env_vars;
+use std::collections::HashMap;
+use std::error::Error;
 use std::io::{stdin, BufRead};
+use std::process::exit;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::oneshot;
+use tokio::time::Instant;
+use uuid::Uuid;
+
+// Seconds to wait for the results of broadcast commands by default.
+const DEFAULT_DEADLINE: u64 = 60;
 
 /// Distributes tasks as shell commands to be executed on multiple remote workers. It receives command to execute from stdin and publishes it to the chosen message broker.
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     // Topic name to publish task to.
     // Each topic should have consumers with same capabilities including resources.
     // So that tasks can be distributed among all workers within the same topic.
     // For workers with different capabilities (toolset or resources) you should use different topics.
-    #[arg(short, long)]
+    #[arg(short, long, global = true)]
     topic: Option<String>,
 
     // Shell to use for executing commands. Default is `sh`.
@@ -26,6 +57,118 @@ struct Args {
     // If `true` only one such command should be run on a single node, `concurrency_factor` is ignored.
     #[arg(short, long)]
     exclusive: Option<bool>,
+
+    // CPU slots of the worker's `concurrency` each command takes, 1 by default.
+    // Commands needing more than a worker has fail there.
+    #[arg(long, value_name = "SLOTS", value_parser = clap::value_parser!(u32).range(1..))]
+    cpu: Option<u32>,
+
+    // Megabytes of the worker's `memory_capacity` each command takes, none by default.
+    // Commands needing more than a worker has fail there.
+    #[arg(long, value_name = "MB", value_parser = clap::value_parser!(u32).range(1..))]
+    memory: Option<u32>,
+
+    // Comma separated labels a worker must have to get the commands, e.g. `ffmpeg,arch=arm64`.
+    // Only supported by the AMQP bus, which routes commands to the workers having the labels.
+    #[arg(long, value_name = "LABELS", value_delimiter = ',')]
+    require: Vec<String>,
+
+    // Seconds after which the command is killed and reported as timed out.
+    // Workers may apply a default and a maximum timeout of their own.
+    #[arg(long)]
+    timeout: Option<u64>,
+
+    // Total number of attempts to run the command, failed attempts are retried with exponential backoff.
+    // If not set, the retry policy of the topic configured on the worker applies.
+    #[arg(long)]
+    max_attempts: Option<u32>,
+
+    // Seconds before the first retry, doubled for every next one. Requires `--max-attempts`.
+    #[arg(long, requires = "max_attempts")]
+    retry_delay: Option<u64>,
+
+    // Comma separated exit codes to retry on, by default any failure is retried. Requires `--max-attempts`.
+    #[arg(long, value_delimiter = ',', requires = "max_attempts")]
+    retry_on: Vec<i32>,
+
+    // Priority of the commands, ones of higher priority are executed first.
+    // Requires `max_priority` set for the topic, values above it are treated as the maximum.
+    #[arg(short, long)]
+    priority: Option<u8>,
+
+    // Seconds to hold the commands back for before they may be started.
+    #[arg(long, conflicts_with = "at")]
+    delay: Option<u64>,
+
+    // Time before which the commands are not started, in RFC 3339 format and UTC,
+    // e.g. `2025-03-01T02:00:00Z`.
+    #[arg(long, value_parser = humantime::parse_rfc3339_weak)]
+    at: Option<SystemTime>,
+
+    // Environment variable of the commands as KEY=VALUE, may be repeated.
+    // Workers may allow only some variables with `allowed_env`.
+    #[arg(long, value_name = "KEY=VALUE", value_parser = parse_env_var)]
+    env: Vec<(String, String)>,
+
+    // Name of an environment variable of the producer to pass to the commands, may be repeated.
+    #[arg(long, value_name = "VAR")]
+    env_pass: Vec<String>,
+
+    // Working directory of the commands on the worker.
+    #[arg(long)]
+    cwd: Option<String>,
+
+    // Start the commands without the environment of the worker, only with `--env` variables.
+    #[arg(long)]
+    clear_env: bool,
+
+    // Queue to publish results (exit code, duration and output) of the tasks to.
+    // If not set, workers do not report results.
+    #[arg(short, long, conflicts_with = "wait")]
+    reply_to: Option<String>,
+
+    // Wait until every dispatched task is executed, printing its output and a progress summary.
+    // Exits with non-zero code if any of the tasks failed.
+    #[arg(short, long)]
+    wait: bool,
+
+    // Seconds to wait for the results with `--wait` or of a workflow, tasks which have not reported
+    // by then are listed as without result. Waits indefinitely if not set.
+    #[arg(long, global = true)]
+    wait_timeout: Option<u64>,
+
+    // Run every command once on each worker consuming the topic instead of once on any of them.
+    // Workers started afterwards do not run it. Only supported by AMQP.
+    #[arg(short, long, conflicts_with_all = ["require", "delay", "at"])]
+    broadcast: bool,
+
+    // Seconds to wait for the results of broadcast commands with `--wait`, workers which
+    // have not reported by then are listed. Default is 60.
+    #[arg(long, requires = "broadcast")]
+    deadline: Option<u64>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Inspects and replays tasks which failed for good in the topic (requires `dead_letter` enabled for the topic)
+    Dlq {
+        #[command(subcommand)]
+        action: DlqAction,
+    },
+    /// Runs tasks of a YAML or JSON workflow file, each once the tasks it depends on succeeded, and waits for them
+    Run { file: String },
+}
+
+#[derive(Subcommand, Debug)]
+enum DlqAction {
+    /// Lists dead-lettered tasks with their failure reasons
+    List,
+    /// Prints the dead-lettered task with the given ID
+    Show { id: String },
+    /// Removes all dead-lettered tasks
+    Purge,
+    /// Publishes dead-lettered tasks back to the topic, all of them if no IDs are given
+    Replay { ids: Vec<String> },
 }
 
 #[tokio::main]
@@ -36,26 +179,460 @@ async fn main() {
     let args = Args::parse();
 
     let config = AppConfig::load(None).expect("Failed to load config");
-    let mut bus = match config.bus_params {
+    let topic = match &args.topic {
+        Some(topic) => topic.clone(),
+        None => config.topic.clone(),
+    };
+
+    if !matches!(config.bus_params, BusParams::AMQP(_)) && prioritizes_tasks(&args) {
+        eprintln!("Priorities are only supported by the AMQP bus");
+        exit(2);
+    }
+    if args.priority.is_some() && config.topic_config(&topic).max_priority.is_none() {
+        eprintln!(
+            "Priority is ignored, `max_priority` is not set for topic {}",
+            topic
+        );
+    }
+
+    if args.broadcast && !matches!(config.bus_params, BusParams::AMQP(_)) {
+        eprintln!("Broadcasting is only supported by the AMQP bus");
+        exit(2);
+    }
+    // other buses would deliver the tasks to any worker, which fails them for good if it lacks the labels
+    if !matches!(config.bus_params, BusParams::AMQP(_)) && requires_labels(&args) {
+        eprintln!("Requiring labels is only supported by the AMQP bus");
+        exit(2);
+    }
+    #[cfg(feature = "mqtt")]
+    if matches!(config.bus_params, BusParams::Mqtt(_)) && delays_tasks(&args) {
+        eprintln!("Delaying tasks is not supported by the MQTT bus");
+        exit(2);
+    }
+
+    let success = match &config.bus_params {
         BusParams::AMQP(_) => {
-            AmqpBus::new(config.connection, config.credentials, config.bus_params)
+            let mut bus = AmqpBus::new(
+                config.connection.clone(),
+                config.credentials.clone(),
+                config.bus_params.clone(),
+            )
+            .await
+            .expect("AMQP driver init failed")
+            .with_topics(config.topics.clone());
+            if args.broadcast && args.command.is_none() {
+                broadcast(&mut bus, args, topic).await
+            } else {
+                produce(&mut bus, args, topic, false).await
+            }
+        }
+        #[cfg(feature = "redis")]
+        BusParams::Redis(_) => {
+            let mut bus = RedisBus::new(
+                config.connection.clone(),
+                config.credentials.clone(),
+                config.bus_params.clone(),
+            )
+            .await
+            .expect("Redis driver init failed")
+            .with_topics(config.topics.clone());
+            produce(&mut bus, args, topic, false).await
+        }
+        #[cfg(feature = "nats")]
+        BusParams::Nats(_) => {
+            let mut bus = NatsBus::new(
+                config.connection.clone(),
+                config.credentials.clone(),
+                config.bus_params.clone(),
+            )
+            .await
+            .expect("NATS driver init failed")
+            .with_topics(config.topics.clone());
+            produce(&mut bus, args, topic, false).await
+        }
+        #[cfg(feature = "sqlite")]
+        BusParams::Sqlite(_) => {
+            let mut bus = SqliteBus::new(
+                config.connection.clone(),
+                config.credentials.clone(),
+                config.bus_params.clone(),
+            )
+            .await
+            .expect("SQLite driver init failed")
+            .with_topics(config.topics.clone());
+            produce(&mut bus, args, topic, false).await
+        }
+        #[cfg(feature = "postgres")]
+        BusParams::Postgres(_) => {
+            let mut bus = PostgresBus::new(
+                config.connection.clone(),
+                config.credentials.clone(),
+                config.bus_params.clone(),
+            )
+            .await
+            .expect("PostgreSQL driver init failed")
+            .with_topics(config.topics.clone());
+            produce(&mut bus, args, topic, false).await
+        }
+        #[cfg(feature = "mqtt")]
+        BusParams::Mqtt(_) => {
+            let mut bus = MqttBus::new(
+                config.connection.clone(),
+                config.credentials.clone(),
+                config.bus_params.clone(),
+            )
+            .await
+            .expect("MQTT driver init failed")
+            .with_topics(config.topics.clone());
+            produce(&mut bus, args, topic, false).await
+        }
+        #[cfg(feature = "kafka")]
+        BusParams::Kafka(_) => {
+            let mut bus = KafkaBus::new(
+                config.connection.clone(),
+                config.credentials.clone(),
+                config.bus_params.clone(),
+            )
+            .await
+            .expect("Kafka driver init failed")
+            .with_topics(config.topics.clone());
+            produce(&mut bus, args, topic, false).await
+        }
+        BusParams::Memory(params) => {
+            // there are no remote workers, so tasks are executed by this process
+            let mut bus = MemoryBus::new(params.clone()).with_topics(config.topics.clone());
+            let mut worker_bus = bus.connect();
+            let mut executor = Executor::new(&mut worker_bus, config.concurrency, topic.clone())
+                .with_config(&config);
+            let (done_tx, done_rx) = oneshot::channel();
+            let (success, executed) = tokio::join!(
+                async {
+                    let success = produce(&mut bus, args, topic, true).await;
+                    let _ = done_tx.send(());
+                    success
+                },
+                executor.run_until(async {
+                    let _ = done_rx.await;
+                }),
+            );
+            executed.expect("Executor failed");
+            success
+        }
+    };
+    if !success {
+        exit(1);
+    }
+}
+
+// Dispatches tasks read from stdin or manages dead-lettered ones, returns false if any task failed.
+// Results are always awaited if `local` is set, since the tasks are executed by this process.
+async fn produce<B>(bus: &mut B, args: Args, topic: String, local: bool) -> bool
+where
+    B: Publisher + ReplyConsumer + DeadLetters + Closer,
+{
+    if let Some(Command::Dlq { action }) = args.command {
+        manage_dead_letters(bus, topic, action)
+            .await
+            .expect("Failed to manage dead-letter queue");
+        bus.close().await.expect("Failed to close bus");
+        return true;
+    }
+    if let Some(Command::Run { file }) = &args.command {
+        let deadline = wait_deadline(&args);
+        let success = run_workflow(bus, file, topic, local, deadline).await;
+        bus.close().await.expect("Failed to close bus");
+        return success;
+    }
+
+    let mut replies = if args.wait || local {
+        Some(
+            bus.consume_replies()
+                .await
+                .expect("Failed to declare reply queue"),
+        )
+    } else {
+        None
+    };
+    let reply_to = match &replies {
+        Some((queue, _)) => Some(queue.clone()),
+        None => args.reply_to.clone(),
+    };
+    let mut tracker = Tracker::new();
+    let template = task_template(&args, reply_to);
+    let mut dispatcher = Dispatcher::new(bus);
+    for command in read_commands() {
+        let task = Task {
+            command,
+            id: Some(Uuid::new_v4().to_string()),
+            ..template.clone()
+        };
+        let id = task.id.clone().unwrap_or_default();
+        dispatcher
+            .dispatch(topic.clone(), task)
+            .await
+            .expect("Failed to dispatch task");
+        tracker.expect(id);
+    }
+
+    let deadline = wait_deadline(&args);
+    let summary = match &mut replies {
+        // output of local tasks is already printed by the executor
+        Some((_, stream)) if local => Some(
+            tracker
+                .wait(stream, deadline, |result, progress| {
+                    if args.wait {
+                        print_status(result, progress)
+                    }
+                })
+                .await,
+        ),
+        Some((_, stream)) => Some(tracker.wait(stream, deadline, print_result).await),
+        None => None,
+    };
+
+    bus.close().await.expect("Failed to close bus");
+
+    match summary {
+        Some(summary) if args.wait => {
+            for id in tracker.pending() {
+                eprintln!("NO RESULT {}", id);
+            }
+            eprintln!(
+                "Finished: {} succeeded, {} failed, {} without result, {} total",
+                summary.succeeded, summary.failed, summary.missing, summary.total
+            );
+            summary.success()
+        }
+        _ => true,
+    }
+}
+
+// Broadcasts commands read from stdin to every worker of the topic. With `--wait`, prints the result
+// of every worker and lists the ones which have not reported by the deadline, returns false unless all succeeded.
+async fn broadcast<B>(bus: &mut B, args: Args, topic: String) -> bool
+where
+    B: Publisher + Broadcaster + ReplyConsumer + Closer,
+{
+    let mut replies = if args.wait {
+        Some(
+            bus.consume_replies()
                 .await
-                .expect("AMQP driver init failed")
+                .expect("Failed to declare reply queue"),
+        )
+    } else {
+        None
+    };
+    let reply_to = match &replies {
+        Some((queue, _)) => Some(queue.clone()),
+        None => args.reply_to.clone(),
+    };
+    let mut tracker = BroadcastTracker::new();
+    let template = task_template(&args, reply_to);
+    let mut dispatcher = Dispatcher::new(bus);
+    for command in read_commands() {
+        let task = Task {
+            command,
+            id: Some(Uuid::new_v4().to_string()),
+            ..template.clone()
+        };
+        let id = task.id.clone().unwrap_or_default();
+        let workers = dispatcher
+            .broadcast(topic.clone(), task)
+            .await
+            .expect("Failed to broadcast task");
+        if workers == 0 {
+            eprintln!("No workers consume {}, task {} is not run", topic, id);
         }
+        tracker.expect(id, workers as usize);
+    }
+
+    let deadline = Instant::now() + Duration::from_secs(args.deadline.unwrap_or(DEFAULT_DEADLINE));
+    let summary = match &mut replies {
+        Some((_, stream)) => Some(tracker.wait(stream, deadline, print_result).await),
+        None => None,
     };
 
-    let mut dispatcher = Dispatcher::new(&mut bus);
+    bus.close().await.expect("Failed to close bus");
 
-    let shell = args.shell.unwrap_or("sh".to_string());
-    let topic = match args.topic {
-        Some(topic) => topic,
-        None => config.topic,
+    let Some(summary) = summary else {
+        return true;
     };
+    for unanswered in tracker.unanswered() {
+        eprintln!(
+            "NO RESULT {} from {} workers{}",
+            unanswered.id,
+            unanswered.missing,
+            match unanswered.workers.is_empty() {
+                true => String::new(),
+                false => format!(": {}", unanswered.workers.join(", ")),
+            }
+        );
+    }
+    eprintln!(
+        "Finished: {} succeeded, {} failed, {} without result, {} total",
+        summary.succeeded, summary.failed, summary.missing, summary.total
+    );
+    summary.success()
+}
 
-    // TODO: maybe retry with backoff
+// Runs the workflow until every task has finished or is skipped, returns false unless all succeeded.
+async fn run_workflow<B>(
+    bus: &mut B,
+    file: &str,
+    topic: String,
+    local: bool,
+    deadline: Option<Instant>,
+) -> bool
+where
+    B: Publisher + ReplyConsumer,
+{
+    let workflow = Workflow::load(file).expect("Invalid workflow");
+    let (reply_to, mut replies) = bus
+        .consume_replies()
+        .await
+        .expect("Failed to declare reply queue");
+    let statuses = Coordinator::new(bus, topic)
+        .with_deadline(deadline)
+        .run(workflow, reply_to, &mut replies, |result, progress| {
+            // output of local tasks is already printed by the executor
+            if local {
+                print_status(result, progress)
+            } else {
+                print_result(result, progress)
+            }
+        })
+        .await
+        .expect("Failed to run workflow");
 
-    // Read input from stdin
-    let tasks = stdin()
+    let count = |expected: NodeStatus| {
+        statuses
+            .iter()
+            .filter(|(_, status)| *status == expected)
+            .count()
+    };
+    for (id, status) in &statuses {
+        let status = match status {
+            NodeStatus::Succeeded => "OK",
+            NodeStatus::Failed => "FAILED",
+            NodeStatus::Skipped => "SKIPPED",
+            _ => "NO RESULT",
+        };
+        eprintln!("{:<9} {}", status, id);
+    }
+    eprintln!(
+        "Finished: {} succeeded, {} failed, {} skipped, {} without result, {} total",
+        count(NodeStatus::Succeeded),
+        count(NodeStatus::Failed),
+        count(NodeStatus::Skipped),
+        count(NodeStatus::Missing),
+        statuses.len()
+    );
+    count(NodeStatus::Succeeded) == statuses.len()
+}
+
+// Time to stop waiting for results at, if `--wait-timeout` is set.
+fn wait_deadline(args: &Args) -> Option<Instant> {
+    args.wait_timeout
+        .map(|wait_timeout| Instant::now() + Duration::from_secs(wait_timeout))
+}
+
+// Whether the tasks to be published require labels of workers.
+fn requires_labels(args: &Args) -> bool {
+    if !args.require.is_empty() {
+        return true;
+    }
+    match &args.command {
+        Some(Command::Run { file }) => Workflow::load(file)
+            .expect("Invalid workflow")
+            .tasks
+            .iter()
+            .any(|node| !node.task.requires.is_empty()),
+        _ => false,
+    }
+}
+
+// Whether the tasks to be published have a priority.
+fn prioritizes_tasks(args: &Args) -> bool {
+    if args.priority.is_some() {
+        return true;
+    }
+    match &args.command {
+        Some(Command::Run { file }) => Workflow::load(file)
+            .expect("Invalid workflow")
+            .tasks
+            .iter()
+            .any(|node| node.task.priority.is_some()),
+        _ => false,
+    }
+}
+
+// Whether the tasks to be published are held back until a time.
+#[cfg(feature = "mqtt")]
+fn delays_tasks(args: &Args) -> bool {
+    if args.delay.is_some() || args.at.is_some() {
+        return true;
+    }
+    match &args.command {
+        Some(Command::Run { file }) => Workflow::load(file)
+            .expect("Invalid workflow")
+            .tasks
+            .iter()
+            .any(|node| node.task.not_before.is_some()),
+        _ => false,
+    }
+}
+
+// Task with the settings of the arguments, the command and ID are set for each line of stdin.
+fn task_template(args: &Args, reply_to: Option<String>) -> Task {
+    let retry = args.max_attempts.map(|max_attempts| {
+        let default = RetryPolicy::default();
+        RetryPolicy {
+            max_attempts,
+            delay: args.retry_delay.unwrap_or(default.delay),
+            exit_codes: args.retry_on.clone(),
+            ..default
+        }
+    });
+
+    let not_before = match args.delay {
+        Some(delay) => Some(SystemTime::now() + Duration::from_secs(delay)),
+        None => args.at,
+    }
+    .map(|at| at.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs());
+
+    let mut env: HashMap<String, String> = args.env.iter().cloned().collect();
+    for name in &args.env_pass {
+        match std::env::var(name) {
+            Ok(value) => {
+                env.insert(name.clone(), value);
+            }
+            Err(_) => eprintln!("Environment variable {} is not set, not passing it", name),
+        }
+    }
+
+    Task {
+        shell: args.shell.clone().unwrap_or("sh".to_string()),
+        command: String::new(),
+        exclusive: args.exclusive.unwrap_or_default(),
+        id: None,
+        reply_to,
+        timeout: args.timeout,
+        retry,
+        priority: args.priority,
+        not_before,
+        depends_on: vec![],
+        env,
+        cwd: args.cwd.clone(),
+        clear_env: args.clear_env,
+        cpu: args.cpu,
+        memory: args.memory,
+        requires: args.require.clone(),
+    }
+}
+
+// Commands to run, one per line of stdin.
+fn read_commands() -> impl Iterator<Item = String> {
+    stdin()
         .lock()
         .lines()
         .filter_map(|line_result| match line_result {
@@ -65,19 +642,101 @@ async fn main() {
                 None
             }
         })
-        .map(|line| async {
-            Task {
-                shell: shell.clone(),
-                command: line,
-                exclusive: args.exclusive.unwrap_or_default(),
+}
+
+fn parse_env_var(var: &str) -> Result<(String, String), String> {
+    match var.split_once('=') {
+        Some((name, value)) if !name.is_empty() => Ok((name.to_string(), value.to_string())),
+        _ => Err("expected KEY=VALUE".to_string()),
+    }
+}
+
+fn print_result(result: &TaskResult, progress: &Summary) {
+    print!("{}", result.stdout);
+    eprint!("{}", result.stderr);
+    if result.truncated {
+        eprintln!("(output truncated by the worker)");
+    }
+    print_status(result, progress);
+}
+
+fn print_status(result: &TaskResult, progress: &Summary) {
+    eprintln!(
+        "[{}/{}] {} {}{} (exit code: {}, {} ms){}",
+        progress.succeeded + progress.failed,
+        progress.total,
+        if result.success() { "OK" } else { "FAILED" },
+        result.id.as_deref().unwrap_or_default(),
+        result
+            .worker_id
+            .as_ref()
+            .map_or(String::new(), |worker| format!(" on {}", worker)),
+        result
+            .exit_code
+            .map_or("none".to_string(), |code| code.to_string()),
+        result.duration_ms,
+        result
+            .error
+            .as_ref()
+            .map_or(String::new(), |err| format!(": {}", err)),
+    );
+}
+
+async fn manage_dead_letters<B: DeadLetters + Publisher>(
+    bus: &mut B,
+    topic: String,
+    action: DlqAction,
+) -> Result<(), Box<dyn Error>> {
+    if let DlqAction::Purge = action {
+        let purged = bus.purge_dead_letters(topic.clone()).await?;
+        eprintln!("Removed {} dead-lettered tasks of {}", purged, topic);
+        return Ok(());
+    }
+
+    let messages = bus.dead_letters(topic.clone()).await?;
+    let mut replayed = 0;
+    for msg in messages {
+        let task = serde_json::from_str::<Task>(&msg.body()).ok();
+        let id = task.as_ref().and_then(|task| task.id.clone());
+        let matches = |ids: &[String]| id.as_ref().is_some_and(|id| ids.contains(id));
+        match &action {
+            DlqAction::List => print_dead_letter(msg.as_ref(), task.as_ref()),
+            DlqAction::Show { id } if matches(std::slice::from_ref(id)) => {
+                print_dead_letter(msg.as_ref(), task.as_ref());
+                println!("{}", msg.body());
             }
-        });
-    for task in tasks {
-        dispatcher
-            .dispatch(topic.clone(), task.await)
-            .await
-            .expect("Failed to dispatch task");
+            DlqAction::Replay { ids } if ids.is_empty() || matches(ids) => match task {
+                Some(mut task) => {
+                    // the producer which waited for the result is gone
+                    task.reply_to = None;
+                    Dispatcher::new(bus).dispatch(topic.clone(), task).await?;
+                    msg.ack().await?;
+                    replayed += 1;
+                    continue;
+                }
+                None => eprintln!("Skipping undecodable task: {}", msg.body()),
+            },
+            _ => {}
+        }
+        msg.requeue().await?;
     }
 
-    bus.close().await.expect("Failed to close bus");
+    if let DlqAction::Replay { .. } = action {
+        eprintln!("Replayed {} tasks to {}", replayed, topic);
+    }
+    Ok(())
+}
+
+fn print_dead_letter(msg: &(dyn Message + Send), task: Option<&Task>) {
+    let failure = msg.failure();
+    println!(
+        "{}\t{}\t{}\t{}",
+        task.and_then(|task| task.id.as_deref()).unwrap_or("-"),
+        failure
+            .as_ref()
+            .and_then(|failure| failure.exit_code)
+            .map_or("-".to_string(), |code| code.to_string()),
+        failure.map_or("-".to_string(), |failure| failure.reason),
+        task.map_or("-", |task| task.command.as_str()),
+    );
 }
@@ -0,0 +1,74 @@
+use clap::Parser;
+use mqdish::shared::config::{AppConfig, BusParams};
+use mqdish::shared::msgbus::amqp::AmqpBus;
+use mqdish::shared::msgbus::bus::{Closer, Elector, Publisher};
+use mqdish::shared::scheduler::Scheduler;
+use openssl_probe::init_openssl_env_vars;
+use tokio::signal::unix::{signal, SignalKind};
+
+/// Dispatches commands on the cron schedules from the config. Any number of instances can run,
+/// only the one leading the election over the message broker dispatches them.
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    // Name of the lock queue the leader is elected over.
+    // Instances with different schedules must use different locks.
+    #[arg(short, long, default_value = "mqdish.scheduler")]
+    lock: String,
+}
+
+#[tokio::main]
+async fn main() {
+    unsafe {
+        init_openssl_env_vars();
+    }
+    let args = Args::parse();
+    let config = AppConfig::load(None).expect("Failed to load config");
+    #[allow(unreachable_patterns)]
+    match config.bus_params {
+        BusParams::AMQP(_) => {
+            let mut bus = AmqpBus::new(
+                config.connection.clone(),
+                config.credentials.clone(),
+                config.bus_params.clone(),
+            )
+            .await
+            .expect("AMQP driver init failed")
+            .with_topics(config.topics.clone());
+            schedule(&mut bus, &config, args.lock).await;
+        }
+        BusParams::Memory(_) => {
+            panic!(
+                "Memory bus is not shared between processes, there are no workers to dispatch to"
+            )
+        }
+        _ => panic!("Leader election is supported by AMQP bus only"),
+    }
+}
+
+// Dispatches scheduled commands until the scheduler is stopped.
+async fn schedule<B: Publisher + Elector + Closer>(bus: &mut B, config: &AppConfig, lock: String) {
+    let mut leadership = bus
+        .elect(lock)
+        .await
+        .expect("Failed to join leader election");
+    Scheduler::new(bus, config.topic.clone(), &config.schedules)
+        .expect("Invalid schedules")
+        .run_until(&mut leadership, shutdown_signal())
+        .await
+        .expect("Scheduler failed");
+
+    // the next instance takes the leadership over
+    drop(leadership);
+    bus.close().await.expect("Failed to close bus");
+}
+
+// Completes on the first SIGTERM or SIGINT.
+async fn shutdown_signal() {
+    let mut terminate = signal(SignalKind::terminate()).expect("Failed to listen for SIGTERM");
+    let mut interrupt = signal(SignalKind::interrupt()).expect("Failed to listen for SIGINT");
+    tokio::select! {
+        _ = terminate.recv() => {}
+        _ = interrupt.recv() => {}
+    }
+}
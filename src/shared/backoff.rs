@@ -0,0 +1,40 @@
+use crate::shared::config::ReconnectParams;
+use rand::Rng;
+use std::time::Duration;
+
+/// Exponentially growing delays between attempts with half of each delay randomized,
+/// so that clients disconnected at once do not come back at once.
+pub struct Backoff {
+    params: ReconnectParams,
+    attempt: u32,
+}
+
+impl Backoff {
+    pub fn new(params: ReconnectParams) -> Self {
+        Backoff { params, attempt: 0 }
+    }
+
+    /// Returns the delay before the next attempt or None once attempts are exhausted.
+    pub fn next_delay(&mut self) -> Option<Duration> {
+        if let Some(max_attempts) = self.params.max_attempts {
+            if self.attempt >= max_attempts {
+                return None;
+            }
+        }
+        let delay = jittered_delay(&self.params, self.attempt);
+        self.attempt += 1;
+        Some(delay)
+    }
+}
+
+/// Returns the delay before the attempt following `attempt` failed ones, regardless of the limit.
+pub fn jittered_delay(params: &ReconnectParams, attempt: u32) -> Duration {
+    let factor = 2u64.saturating_pow(attempt);
+    let delay = params
+        .initial_delay
+        .saturating_mul(factor)
+        .min(params.max_delay);
+
+    let jitter = rand::thread_rng().gen_range(0..=delay / 2);
+    Duration::from_millis(delay - delay / 2 + jitter)
+}
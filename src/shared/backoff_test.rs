@@ -0,0 +1,20 @@
+use crate::shared::backoff::*;
+use crate::shared::config::ReconnectParams;
+use std::time::Duration;
+
+#[test]
+fn test_backoff() {
+    let mut backoff = Backoff::new(ReconnectParams {
+        initial_delay: 100,
+        max_delay: 300,
+        max_attempts: Some(4),
+    });
+
+    let bounds = [(50, 100), (100, 200), (150, 300), (150, 300)];
+    for (min, max) in bounds {
+        let delay = backoff.next_delay().unwrap();
+        assert!(delay >= Duration::from_millis(min), "{:?} < {}", delay, min);
+        assert!(delay <= Duration::from_millis(max), "{:?} > {}", delay, max);
+    }
+    assert_eq!(backoff.next_delay(), None);
+}
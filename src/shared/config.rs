@@ -1,27 +1,113 @@
+use crate::shared::models::RetryPolicy;
 use serde::Deserialize;
 use serde_yaml::{self};
+use std::collections::HashMap;
 use std::fs::File;
 use std::path::PathBuf;
 use std::thread::available_parallelism;
+use std::time::Duration;
 use thiserror::Error;
 
-#[derive(Debug, Deserialize)]
+// Bytes of stdout and stderr of a task reported in its result by default.
+pub const DEFAULT_OUTPUT_LIMIT: usize = 64 * 1024;
+// Time running tasks get to finish on shutdown before they are killed by default.
+pub const DEFAULT_GRACE_PERIOD: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Deserialize)]
 #[serde(default)]
 pub struct AppConfig {
     pub connection: Connection,
     pub credentials: Credentials,
     pub bus_params: BusParams,
     pub topic: String,
+    // CPU slots of the worker, every task takes one unless it declares its own weight.
     pub concurrency: usize,
+    // Megabytes of memory tasks declaring their weight may take together, unlimited if empty.
+    pub memory_capacity: Option<u32>,
+    pub output_limit: usize,
+    // Seconds running tasks are given to finish on shutdown before they are killed.
+    pub grace_period: u64,
+    // Seconds after which a task without its own timeout is killed, unlimited if empty.
+    pub task_timeout: Option<u64>,
+    // Upper bound for timeouts of all tasks, unlimited if empty.
+    pub max_task_timeout: Option<u64>,
+    // Settings of individual topics by topic name.
+    pub topics: HashMap<String, TopicConfig>,
+    // Commands dispatched by `mqdish-scheduler` on cron schedules.
+    pub schedules: Vec<ScheduleConfig>,
+    // Names of environment variables tasks may set, `PREFIX*` allows all starting with the prefix.
+    // Any variable is allowed if empty.
+    pub allowed_env: Option<Vec<String>>,
+    // Identifier of the worker passed to tasks as MQDISH_WORKER_ID, `<hostname>-<pid>` if empty.
+    pub worker_id: Option<String>,
+    // Capabilities of the worker, it only gets tasks requiring some of them or none.
+    pub labels: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct TopicConfig {
+    // Retry policy for tasks of the topic which do not specify their own.
+    pub retry: Option<RetryPolicy>,
+    // Whether tasks which failed for good are moved to the `<topic>.dlq` queue instead of being dropped.
+    pub dead_letter: bool,
+    // Highest priority of tasks of the topic, AMQP queues support priorities only if it is set.
+    pub max_priority: Option<u8>,
+}
+
+/// Command dispatched on a cron schedule.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ScheduleConfig {
+    // Unique name the runs of the schedule are tracked by.
+    pub name: String,
+    // Crontab expression in local time, optionally with seconds as the first field.
+    pub cron: String,
+    pub command: String,
+    // Topic to publish the command to, the default topic if empty.
+    pub topic: Option<String>,
+    pub shell: String,
+    pub exclusive: bool,
+    // Seconds after which the command is killed, the worker's default applies if empty.
+    pub timeout: Option<u64>,
 }
 
-#[derive(Debug, Deserialize)]
+impl Default for ScheduleConfig {
+    fn default() -> ScheduleConfig {
+        ScheduleConfig {
+            name: String::new(),
+            cron: String::new(),
+            command: String::new(),
+            topic: None,
+            shell: "sh".to_string(),
+            exclusive: false,
+            timeout: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
 #[serde(tag = "type", content = "params")]
 pub enum BusParams {
     AMQP(AMQPParams),
+    // Queues in the memory of the producer, which executes the tasks itself.
+    Memory(MemoryParams),
+    #[cfg(feature = "redis")]
+    Redis(RedisParams),
+    #[cfg(feature = "nats")]
+    Nats(NatsParams),
+    // Table of a local SQLite database, the connection is the path of the database file.
+    #[cfg(feature = "sqlite")]
+    Sqlite(SqliteParams),
+    #[cfg(feature = "postgres")]
+    Postgres(PostgresParams),
+    #[cfg(feature = "mqtt")]
+    Mqtt(MqttParams),
+    #[cfg(feature = "kafka")]
+    Kafka(KafkaParams),
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize)]
 #[serde(default)]
 pub struct AMQPParams {
     pub vhost: String,
@@ -29,6 +115,282 @@ pub struct AMQPParams {
     pub heartbeat: Option<u16>,
     pub consumer_timeout: Option<i32>,
     pub requeue: bool,
+    pub reconnect: ReconnectParams,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ReconnectParams {
+    // Milliseconds before the first reconnection attempt, doubled for every next one.
+    pub initial_delay: u64,
+    // Upper bound of the delay in milliseconds.
+    pub max_delay: u64,
+    // Number of attempts before giving up, unlimited if empty.
+    pub max_attempts: Option<u32>,
+}
+
+impl Default for ReconnectParams {
+    fn default() -> ReconnectParams {
+        ReconnectParams {
+            initial_delay: 500,
+            max_delay: 30_000,
+            max_attempts: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct MemoryParams {
+    pub prefetch: u16,
+    pub requeue: bool,
+}
+
+impl Default for MemoryParams {
+    fn default() -> MemoryParams {
+        MemoryParams {
+            prefetch: available_parallelism().unwrap().get() as u16,
+            requeue: false,
+        }
+    }
+}
+
+#[cfg(feature = "redis")]
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct RedisParams {
+    pub database: u32,
+    // Consumer group shared by the workers of a topic.
+    pub group: String,
+    pub prefetch: u16,
+    pub requeue: bool,
+    // Seconds after which tasks taken by a worker which stopped responding are taken over by others.
+    pub claim_timeout: u64,
+    pub reconnect: ReconnectParams,
+}
+
+#[cfg(feature = "redis")]
+impl Default for RedisParams {
+    fn default() -> RedisParams {
+        RedisParams {
+            database: 0,
+            group: "mqdish".to_string(),
+            prefetch: available_parallelism().unwrap().get() as u16,
+            requeue: false,
+            claim_timeout: 300,
+            reconnect: ReconnectParams::default(),
+        }
+    }
+}
+
+#[cfg(feature = "nats")]
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct NatsParams {
+    // Durable consumer shared by the workers of a topic.
+    pub durable: String,
+    // Number of tasks each worker pulls ahead of execution.
+    pub prefetch: u16,
+    // Maximum number of tasks delivered to all workers of a topic and not acknowledged yet.
+    pub max_ack_pending: i64,
+    pub requeue: bool,
+    // Seconds a delivered task may stay unacknowledged, extended while the task is running.
+    pub ack_wait: u64,
+    pub storage: async_nats::jetstream::stream::StorageType,
+    pub replicas: usize,
+    // Seconds after which tasks are removed from the stream, unlimited if zero.
+    pub max_age: u64,
+    pub reconnect: ReconnectParams,
+    // NATS specific authentication, in addition to `credentials` if both are set.
+    pub auth: Option<NatsAuth>,
+}
+
+#[cfg(feature = "nats")]
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum NatsAuth {
+    // User JWT along with the NKey seed to sign the server nonce with.
+    JWT(JWTAuth),
+    // `.creds` file holding both the user JWT and the NKey seed.
+    CredsFile(CredsFile),
+    NKey(NKeyAuth),
+}
+
+#[cfg(feature = "nats")]
+#[derive(Debug, Clone, Deserialize)]
+pub struct JWTAuth {
+    pub jwt: String,
+    pub nkey_seed: String,
+}
+
+#[cfg(feature = "nats")]
+#[derive(Debug, Clone, Deserialize)]
+pub struct CredsFile {
+    pub creds_file: String,
+}
+
+#[cfg(feature = "nats")]
+#[derive(Debug, Clone, Deserialize)]
+pub struct NKeyAuth {
+    pub nkey_seed: String,
+}
+
+#[cfg(feature = "nats")]
+impl Default for NatsParams {
+    fn default() -> NatsParams {
+        NatsParams {
+            durable: "mqdish".to_string(),
+            prefetch: available_parallelism().unwrap().get() as u16,
+            max_ack_pending: 1000,
+            requeue: false,
+            ack_wait: 30,
+            storage: async_nats::jetstream::stream::StorageType::File,
+            replicas: 1,
+            max_age: 0,
+            reconnect: ReconnectParams::default(),
+            auth: None,
+        }
+    }
+}
+
+#[cfg(feature = "sqlite")]
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct SqliteParams {
+    pub prefetch: u16,
+    pub requeue: bool,
+    // Seconds a taken task stays invisible to other workers, extended while the task is running.
+    pub visibility_timeout: u64,
+    // Milliseconds between checks of an empty queue.
+    pub poll_interval: u64,
+    // Milliseconds to wait for a lock held by another process.
+    pub busy_timeout: u64,
+    // Write-ahead logging lets workers read while another one writes,
+    // it must be disabled for databases on network filesystems.
+    pub wal: bool,
+}
+
+#[cfg(feature = "sqlite")]
+impl Default for SqliteParams {
+    fn default() -> SqliteParams {
+        SqliteParams {
+            prefetch: available_parallelism().unwrap().get() as u16,
+            requeue: false,
+            visibility_timeout: 300,
+            poll_interval: 500,
+            busy_timeout: 5000,
+            wal: true,
+        }
+    }
+}
+
+#[cfg(feature = "postgres")]
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct PostgresParams {
+    // Database to connect to unless the connection is a connection string.
+    pub dbname: String,
+    // Table holding the tasks, created if it does not exist.
+    pub table: String,
+    pub prefetch: u16,
+    pub requeue: bool,
+    // Seconds after which tasks claimed by a worker which stopped responding are claimed by others.
+    pub lease_timeout: u64,
+    // Milliseconds between checks for tasks in addition to notifications, delayed retries become
+    // visible without a notification.
+    pub poll_interval: u64,
+    // Seconds finished tasks are kept for along with their results.
+    pub retention: u64,
+    pub reconnect: ReconnectParams,
+}
+
+#[cfg(feature = "postgres")]
+impl Default for PostgresParams {
+    fn default() -> PostgresParams {
+        PostgresParams {
+            dbname: "mqdish".to_string(),
+            table: "mqdish_tasks".to_string(),
+            prefetch: available_parallelism().unwrap().get() as u16,
+            requeue: false,
+            lease_timeout: 300,
+            poll_interval: 1000,
+            retention: 86400,
+            reconnect: ReconnectParams::default(),
+        }
+    }
+}
+
+#[cfg(feature = "mqtt")]
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct MqttParams {
+    // Shared subscription group the workers of a topic are balanced in.
+    pub group: String,
+    // Receive maximum, the number of tasks delivered to the worker and not acknowledged yet.
+    pub prefetch: u16,
+    pub requeue: bool,
+    // Random unless set. A fixed one along with `session_expiry` makes the broker
+    // keep tasks for the worker while it is disconnected.
+    pub client_id: Option<String>,
+    // Seconds the broker keeps the session after disconnection, the session is not kept if zero.
+    pub session_expiry: u32,
+    // Seconds between keep-alive pings.
+    pub keep_alive: u64,
+    pub reconnect: ReconnectParams,
+}
+
+#[cfg(feature = "mqtt")]
+impl Default for MqttParams {
+    fn default() -> MqttParams {
+        MqttParams {
+            group: "mqdish".to_string(),
+            prefetch: available_parallelism().unwrap().get() as u16,
+            requeue: false,
+            client_id: None,
+            session_expiry: 0,
+            keep_alive: 30,
+            reconnect: ReconnectParams::default(),
+        }
+    }
+}
+
+#[cfg(feature = "kafka")]
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct KafkaParams {
+    // Consumer group of the workers of a topic.
+    pub group: String,
+    // Number of tasks delivered to the worker and not settled yet, fetching from the assigned
+    // partitions is paused while the limit is reached.
+    pub prefetch: u16,
+    pub requeue: bool,
+    // Whether the topics used by the bus are created if they do not exist.
+    pub create_topics: bool,
+    // Partitions and replication factor of created topics, broker defaults if -1.
+    pub partitions: i32,
+    pub replication: i32,
+    // SASL mechanism used with login and password credentials, e.g. PLAIN or SCRAM-SHA-512.
+    pub sasl_mechanism: String,
+    // Milliseconds after which a worker which stopped sending heartbeats is removed from the group.
+    pub session_timeout: u64,
+    pub reconnect: ReconnectParams,
+}
+
+#[cfg(feature = "kafka")]
+impl Default for KafkaParams {
+    fn default() -> KafkaParams {
+        KafkaParams {
+            group: "mqdish".to_string(),
+            prefetch: available_parallelism().unwrap().get() as u16,
+            requeue: false,
+            create_topics: true,
+            partitions: -1,
+            replication: -1,
+            sasl_mechanism: "PLAIN".to_string(),
+            session_timeout: 45_000,
+            reconnect: ReconnectParams::default(),
+        }
+    }
 }
 
 impl Default for BusParams {
@@ -45,25 +407,26 @@ impl Default for AMQPParams {
             prefetch: available_parallelism().unwrap().get() as u16,
             consumer_timeout: None,
             requeue: false,
+            reconnect: ReconnectParams::default(),
         }
     }
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize)]
 #[serde(untagged)]
 pub enum Connection {
     DSN(String),
     Params(ConnectionParams),
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize)]
 pub struct ConnectionParams {
     pub host: String,
     pub port: u16,
     pub ssl: bool,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize)]
 #[serde(untagged)]
 pub enum Credentials {
     LoginPassword(LoginPassword),
@@ -71,14 +434,14 @@ pub enum Credentials {
     None,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize)]
 pub struct TLSClientAuth {
     pub ca_file: String,
     pub cert_file: String,
     pub key_file: String,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize)]
 pub struct LoginPassword {
     pub login: String,
     pub password: String,
@@ -92,6 +455,16 @@ impl Default for AppConfig {
             credentials: Credentials::None,
             bus_params: BusParams::AMQP(AMQPParams::default()),
             concurrency: available_parallelism().unwrap().get(),
+            memory_capacity: None,
+            output_limit: DEFAULT_OUTPUT_LIMIT,
+            grace_period: DEFAULT_GRACE_PERIOD.as_secs(),
+            task_timeout: None,
+            max_task_timeout: None,
+            topics: HashMap::new(),
+            schedules: vec![],
+            allowed_env: None,
+            worker_id: None,
+            labels: vec![],
         }
     }
 }
@@ -103,6 +476,10 @@ pub enum ConfigError {
 }
 
 impl AppConfig {
+    pub fn topic_config(&self, topic: &str) -> TopicConfig {
+        self.topics.get(topic).cloned().unwrap_or_default()
+    }
+
     pub fn load(path: Option<String>) -> Result<AppConfig, ConfigError> {
         let paths = path.map_or_else(get_default_paths, |path| vec![path]);
 
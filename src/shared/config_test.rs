@@ -60,5 +60,60 @@ fn test_login_pass_amqp() {
 
     match config.bus_params {
         BusParams::AMQP(AMQPParams { .. }) => (),
+        _ => panic!("Incorrect bus params type"),
+    }
+}
+
+#[test]
+fn test_memory_bus() {
+    let file_contents = r#"
+            bus_params:
+                type: Memory
+                params:
+                  prefetch: 2
+                "#
+    .to_string();
+
+    let temp = TempConfigFile::new(file_contents);
+
+    let config = AppConfig::load(Some(temp.path.clone())).unwrap();
+
+    match config.bus_params {
+        BusParams::Memory(MemoryParams { prefetch, requeue }) => {
+            assert_eq!(prefetch, 2);
+            assert!(!requeue);
+        }
+        _ => panic!("Incorrect bus params type"),
+    }
+}
+
+#[cfg(feature = "nats")]
+#[test]
+fn test_nats_auth() {
+    let file_contents = r#"
+            connection: "nats://localhost:4222"
+            bus_params:
+                type: Nats
+                params:
+                  auth:
+                    jwt: "eyJ0eXAiOiJKV1Qi"
+                    nkey_seed: "SUA"
+                "#
+    .to_string();
+
+    let temp = TempConfigFile::new(file_contents);
+
+    let config = AppConfig::load(Some(temp.path.clone())).unwrap();
+
+    assert!(matches!(config.credentials, Credentials::None));
+    match config.bus_params {
+        BusParams::Nats(NatsParams {
+            auth: Some(NatsAuth::JWT(auth)),
+            ..
+        }) => {
+            assert_eq!(auth.jwt, "eyJ0eXAiOiJKV1Qi");
+            assert_eq!(auth.nkey_seed, "SUA");
+        }
+        _ => panic!("Incorrect bus params type"),
     }
 }
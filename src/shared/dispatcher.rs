@@ -1,6 +1,7 @@
 use crate::shared::models::Task;
-use crate::shared::msgbus::bus::Publisher;
+use crate::shared::msgbus::bus::{Broadcaster, MessageProps, Publisher};
 use std::error::Error;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 pub struct Dispatcher<'a, T: Publisher> {
     bus: &'a mut T,
@@ -12,7 +13,33 @@ impl<'a, T: Publisher> Dispatcher<'a, T> {
     }
 
     pub async fn dispatch(&mut self, topic: String, task: Task) -> Result<(), Box<dyn Error>> {
+        let props = props(&task);
         let msg = serde_json::to_string(&task)?;
-        self.bus.publish(topic, msg).await
+        let due = task
+            .not_before
+            .map(|not_before| UNIX_EPOCH + Duration::from_secs(not_before))
+            .filter(|due| *due > SystemTime::now());
+        match due {
+            Some(due) => self.bus.publish_at(topic, msg, props, due).await,
+            None => self.bus.publish(topic, msg, props).await,
+        }
+    }
+}
+
+impl<T: Publisher + Broadcaster> Dispatcher<'_, T> {
+    /// Publishes the task to every worker of the topic right away, returns their number.
+    pub async fn broadcast(&mut self, topic: String, task: Task) -> Result<u32, Box<dyn Error>> {
+        let props = props(&task);
+        let msg = serde_json::to_string(&task)?;
+        self.bus.broadcast(topic, msg, props).await
+    }
+}
+
+fn props(task: &Task) -> MessageProps {
+    MessageProps {
+        correlation_id: task.id.clone(),
+        reply_to: task.reply_to.clone(),
+        priority: task.priority,
+        requires: task.requires.clone(),
     }
 }
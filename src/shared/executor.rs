@@ -1,17 +1,93 @@
-use crate::shared::models::Task;
-use crate::shared::msgbus::bus::Consumer;
+use crate::shared::config::{AppConfig, DEFAULT_GRACE_PERIOD, DEFAULT_OUTPUT_LIMIT};
+use crate::shared::models::{RetryPolicy, Task, TaskResult, TaskStatus};
+use crate::shared::msgbus::bus::{Consumer, Failure, Message};
 use std::error::Error;
+use std::fmt::Display;
+use std::future::{pending, Future};
 use std::process::Stdio;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
-use tokio::process::Command;
-use tokio::spawn;
-use tokio::sync::{mpsc::channel, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::process::{Child, Command};
+use tokio::sync::{oneshot, watch, OwnedSemaphorePermit, Semaphore};
+use tokio::task::JoinSet;
+use tokio::time::{sleep, timeout};
+use tokio::{pin, select};
 use tokio_stream::StreamExt;
 
+// Time between SIGTERM and SIGKILL sent to the process group of a task being killed.
+const KILL_TIMEOUT: Duration = Duration::from_secs(10);
+// Time to wait for messages prefetched before the consumer was cancelled.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
 pub struct Executor<'a, T: Consumer> {
     bus: &'a mut T,
     topic: String,
     workers: usize,
+    output_limit: usize,
+    grace_period: Duration,
+    default_timeout: Option<Duration>,
+    max_timeout: Option<Duration>,
+    retry: Option<RetryPolicy>,
+    worker_id: String,
+    allowed_env: Option<Vec<String>>,
+    memory_capacity: Option<u32>,
+    labels: Vec<String>,
+    counters: Counters,
+}
+
+/// Messages the worker could not process normally since it was created.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Stats {
+    // Messages which are not tasks, rejected without being run.
+    pub undecodable: u64,
+    // Messages which could not be acknowledged, rejected or requeued.
+    pub unsettled: u64,
+}
+
+// Counts of `Stats` shared with the running tasks, along with whether the stream their messages
+// were delivered by has been lost.
+#[derive(Clone, Default)]
+struct Counters {
+    undecodable: Arc<AtomicU64>,
+    unsettled: Arc<AtomicU64>,
+    lost: Arc<AtomicBool>,
+}
+
+// Environment the processes of tasks get from the worker.
+struct Environment {
+    worker_id: String,
+    allowed: Option<Vec<String>>,
+}
+
+// Resources of the worker a task takes while it runs.
+struct Demand {
+    cpu: u32,
+    memory: u32,
+}
+
+// Resources of the worker, a task waits until the ones it demands are free.
+struct Slots {
+    cpu: Arc<Semaphore>,
+    memory: Arc<Semaphore>,
+}
+
+// Resources taken by a running task, freed when dropped.
+type Permits = (OwnedSemaphorePermit, OwnedSemaphorePermit);
+
+// Per task limits applied on execution.
+#[derive(Clone, Copy)]
+struct Limits {
+    output_limit: usize,
+    timeout: Option<Duration>,
+}
+
+// How the process of a task ended.
+enum Termination {
+    Exited,
+    Killed,
+    TimedOut,
 }
 
 impl<'a, T: Consumer> Executor<'a, T> {
@@ -20,71 +96,744 @@ impl<'a, T: Consumer> Executor<'a, T> {
             bus,
             topic,
             workers: cpus,
+            output_limit: DEFAULT_OUTPUT_LIMIT,
+            grace_period: DEFAULT_GRACE_PERIOD,
+            default_timeout: None,
+            max_timeout: None,
+            retry: None,
+            worker_id: default_worker_id(),
+            allowed_env: None,
+            memory_capacity: None,
+            labels: vec![],
+            counters: Counters::default(),
+        }
+    }
+
+    /// Sets the maximum number of bytes of stdout and stderr (each) reported in a task result.
+    pub fn with_output_limit(mut self, output_limit: usize) -> Self {
+        self.output_limit = output_limit;
+        self
+    }
+
+    /// Sets how long running tasks are allowed to finish after shutdown before they are killed.
+    pub fn with_grace_period(mut self, grace_period: Duration) -> Self {
+        self.grace_period = grace_period;
+        self
+    }
+
+    /// Sets the timeout for tasks which do not specify one and the upper bound for those which do.
+    pub fn with_timeouts(
+        mut self,
+        default_timeout: Option<Duration>,
+        max_timeout: Option<Duration>,
+    ) -> Self {
+        self.default_timeout = default_timeout;
+        self.max_timeout = max_timeout;
+        self
+    }
+
+    /// Sets the retry policy for tasks which do not specify their own.
+    pub fn with_retry(mut self, retry: Option<RetryPolicy>) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Sets the identifier of the worker passed to tasks as MQDISH_WORKER_ID.
+    pub fn with_worker_id(mut self, worker_id: String) -> Self {
+        self.worker_id = worker_id;
+        self
+    }
+
+    /// Sets the names of environment variables tasks may set, `PREFIX*` allows all starting
+    /// with the prefix. Tasks setting other ones fail. Any variable is allowed if None.
+    pub fn with_allowed_env(mut self, allowed_env: Option<Vec<String>>) -> Self {
+        self.allowed_env = allowed_env;
+        self
+    }
+
+    /// Sets the megabytes of memory tasks declaring their weight may take together, unlimited if None.
+    pub fn with_memory_capacity(mut self, memory_capacity: Option<u32>) -> Self {
+        self.memory_capacity = memory_capacity;
+        self
+    }
+
+    /// Sets the labels of the worker, tasks requiring other ones are rejected.
+    pub fn with_labels(mut self, labels: Vec<String>) -> Self {
+        self.labels = labels;
+        self
+    }
+
+    /// Applies the limits of the worker and the retry policy of its topic from the config.
+    pub fn with_config(self, config: &AppConfig) -> Self {
+        let retry = config.topic_config(&self.topic).retry;
+        let worker_id = config.worker_id.clone().unwrap_or(self.worker_id.clone());
+        self.with_output_limit(config.output_limit)
+            .with_grace_period(Duration::from_secs(config.grace_period))
+            .with_timeouts(
+                config.task_timeout.map(Duration::from_secs),
+                config.max_task_timeout.map(Duration::from_secs),
+            )
+            .with_retry(retry)
+            .with_worker_id(worker_id)
+            .with_allowed_env(config.allowed_env.clone())
+            .with_memory_capacity(config.memory_capacity)
+            .with_labels(config.labels.clone())
+    }
+
+    // Resources the task takes, an exclusive task takes all of them. Fails if the task demands
+    // more than the worker has or labels it does not have, as it would never be started.
+    fn demand(&self, task: &Task) -> Result<Demand, String> {
+        // such tasks only get here with buses which do not route them by their requirements
+        let missing: Vec<&str> = task
+            .requires
+            .iter()
+            .filter(|label| !self.labels.contains(label))
+            .map(String::as_str)
+            .collect();
+        if !missing.is_empty() {
+            return Err(format!(
+                "Worker does not have required labels: {}",
+                missing.join(", ")
+            ));
+        }
+        let cpu_capacity = self.workers as u32;
+        if task.exclusive {
+            return Ok(Demand {
+                cpu: cpu_capacity,
+                memory: self.memory_capacity.unwrap_or_default(),
+            });
+        }
+        // a task taking no slots would run regardless of the concurrency
+        if task.cpu == Some(0) || task.memory == Some(0) {
+            return Err("Task needs at least 1 CPU slot and 1 MB of memory if set".to_string());
+        }
+        let cpu = task.cpu.unwrap_or(1);
+        if cpu > cpu_capacity {
+            return Err(format!(
+                "Task needs {} CPU slots, the worker has {}",
+                cpu, cpu_capacity
+            ));
+        }
+        let memory = match (task.memory, self.memory_capacity) {
+            (Some(memory), Some(capacity)) if memory > capacity => {
+                return Err(format!(
+                    "Task needs {} MB of memory, the worker has {} MB",
+                    memory, capacity
+                ));
+            }
+            (Some(memory), Some(_)) => memory,
+            // memory is not accounted for if the worker does not limit it
+            _ => 0,
+        };
+        Ok(Demand { cpu, memory })
+    }
+
+    // Pauses delivery of new messages while an exclusive task is waiting or running.
+    async fn pause(&mut self, paused: bool) {
+        if let Err(err) = self.bus.pause(paused).await {
+            println!("Failed to pause consumer: {}", err);
+        }
+    }
+
+    fn task_timeout(&self, task: &Task) -> Option<Duration> {
+        let timeout = task
+            .timeout
+            .map(Duration::from_secs)
+            .or(self.default_timeout);
+        match (timeout, self.max_timeout) {
+            (Some(timeout), Some(max)) => Some(timeout.min(max)),
+            (timeout, max) => timeout.or(max),
+        }
+    }
+
+    pub fn stats(&self) -> Stats {
+        Stats {
+            undecodable: self.counters.undecodable.load(Ordering::Relaxed),
+            unsettled: self.counters.unsettled.load(Ordering::Relaxed),
         }
     }
 
     pub async fn run(&mut self) -> Result<(), Box<dyn Error>> {
-        let (semaphore_tx, semaphore_rx) = channel(self.workers);
-        // let semaphore_rx = Arc::new(Mutex::new(semaphore_rx));
-        let semaphore_rx = Arc::new(Mutex::new(semaphore_rx));
-        // semaphore_rx.
+        self.run_until(pending()).await
+    }
+
+    /// Consumes and executes tasks until the stream of messages ends or `shutdown` completes.
+    /// A stream ended by the bus, e.g. on a lost connection, is resubscribed if the bus supports it.
+    /// Tasks delivered by the ended stream keep running. Their messages may not be settled anymore
+    /// and are then redelivered by the bus, such failures are logged but not counted in `stats`.
+    /// On shutdown the consumer is cancelled, running tasks get the grace period to finish,
+    /// then their process groups are killed and their messages are requeued.
+    pub async fn run_until<S: Future<Output = ()>>(
+        &mut self,
+        shutdown: S,
+    ) -> Result<(), Box<dyn Error>> {
+        pin!(shutdown);
+        let slots = Slots {
+            cpu: Arc::new(Semaphore::new(self.workers)),
+            memory: Arc::new(Semaphore::new(
+                self.memory_capacity.unwrap_or_default() as usize
+            )),
+        };
+        let (kill_tx, kill_rx) = watch::channel(false);
+        let mut in_flight = JoinSet::new();
+        let environment = Arc::new(Environment {
+            worker_id: self.worker_id.clone(),
+            allowed: self.allowed_env.clone(),
+        });
+        let mut shutting_down = false;
+        let mut failure = None;
 
         let mut msg_stream = self.bus.consume(self.topic.clone()).await?;
-        while let Some(msg) = msg_stream.next().await {
-            let task = serde_json::from_str::<Task>(&msg.body())?;
-            if task.exclusive {
-                match exec(task.shell, task.command).await {
-                    Ok(_) => {
-                        msg.ack().await?;
+        loop {
+            let msg = select! {
+                _ = &mut shutdown => {
+                    shutting_down = true;
+                    break;
+                }
+                msg = msg_stream.next() => msg,
+            };
+            // finished tasks are dropped from the set, so it only holds running ones
+            while in_flight.try_join_next().is_some() {}
+            let Some(msg) = msg else {
+                // tasks already running keep going while the consumer is being restored
+                let resubscribed = select! {
+                    _ = &mut shutdown => {
+                        shutting_down = true;
+                        break;
+                    }
+                    resubscribed = self.bus.resubscribe() => {
+                        resubscribed.map_err(|err| err.to_string())
+                    }
+                };
+                match resubscribed {
+                    Ok(Some(stream)) => {
+                        println!("Resubscribed to {}", self.topic);
+                        if !in_flight.is_empty() {
+                            println!(
+                                "{} tasks delivered before the consumer was lost keep running",
+                                in_flight.len()
+                            );
+                        }
+                        self.counters = self.counters.resubscribed();
+                        msg_stream = stream;
+                        continue;
                     }
+                    Ok(None) => break,
                     Err(err) => {
-                        msg.nack().await?;
-                        println!("Failed to execute task: {}", err);
+                        failure = Some(err);
+                        break;
                     }
                 }
-            } else {
-                let t = task.clone();
-                let semaphore_rx = Arc::clone(&semaphore_rx);
-                let _ = semaphore_tx.send(()).await;
-                // TODO: properly handle errors inside future
-                spawn(async move {
-                    match exec(t.shell, t.command).await {
-                        Ok(_) => match msg.ack().await {
-                            Ok(_) => {}
-                            Err(err) => {
-                                println!("Failed to ack message: {}", err);
-                            }
-                        },
-                        Err(err) => {
-                            let _ = msg.nack().await;
-                            println!("Failed to execute task: {}", err);
-                        }
+            };
+            let task = match serde_json::from_str::<Task>(&msg.body()) {
+                Ok(task) => task,
+                Err(err) => {
+                    // requeueing it would only make the next worker fail the same way
+                    reject_undecodable(msg, err, &self.counters).await;
+                    continue;
+                }
+            };
+            let counters = self.counters.clone();
+            if let Some(due_in) = due_in(&task) {
+                in_flight.spawn(postpone(msg, due_in, counters));
+                continue;
+            }
+            let limits = Limits {
+                output_limit: self.output_limit,
+                timeout: self.task_timeout(&task),
+            };
+            let retry = task.retry.clone().or_else(|| self.retry.clone());
+            let environment = Arc::clone(&environment);
+            let demand = match self.demand(&task) {
+                Ok(demand) => demand,
+                Err(reason) => {
+                    in_flight.spawn(reject(msg, task, reason, environment, counters));
+                    continue;
+                }
+            };
+            let kill_rx = kill_rx.clone();
+            if task.exclusive {
+                // running tasks are drained first, meanwhile other workers get the queued messages
+                self.pause(true).await;
+            }
+            let permit = select! {
+                _ = &mut shutdown => {
+                    requeue(msg, &counters).await;
+                    shutting_down = true;
+                    break;
+                }
+                permit = slots.acquire(demand) => permit,
+            };
+            if task.exclusive {
+                let (done_tx, done_rx) = oneshot::channel();
+                in_flight.spawn(async move {
+                    handle(msg, task, limits, retry, environment, kill_rx, counters).await;
+                    drop(permit);
+                    let _ = done_tx.send(());
+                });
+                select! {
+                    _ = &mut shutdown => {
+                        shutting_down = true;
+                        break;
                     }
-
-                    semaphore_rx.lock().await.recv().await;
+                    _ = done_rx => {}
+                }
+                self.pause(false).await;
+            } else {
+                in_flight.spawn(async move {
+                    handle(msg, task, limits, retry, environment, kill_rx, counters).await;
+                    drop(permit);
                 });
             }
         }
-        Ok(())
+
+        while in_flight.try_join_next().is_some() {}
+        if shutting_down {
+            println!(
+                "Shutting down, waiting for {} running tasks",
+                in_flight.len()
+            );
+            if let Err(err) = self.bus.cancel().await {
+                println!("Failed to cancel consumer: {}", err);
+            }
+            // messages delivered before the cancellation are returned to the queue
+            let _ = timeout(DRAIN_TIMEOUT, async {
+                while let Some(msg) = msg_stream.next().await {
+                    requeue(msg, &self.counters).await;
+                }
+            })
+            .await;
+        }
+
+        let drained = timeout(self.grace_period, async {
+            while in_flight.join_next().await.is_some() {}
+        })
+        .await;
+        if drained.is_err() {
+            println!("Killing {} tasks still running", in_flight.len());
+            let _ = kill_tx.send(true);
+            while in_flight.join_next().await.is_some() {}
+        }
+
+        let stats = self.stats();
+        if stats != Stats::default() {
+            println!(
+                "Rejected {} undecodable messages, failed to settle {} messages",
+                stats.undecodable, stats.unsettled
+            );
+        }
+        match failure {
+            Some(err) => Err(format!("Failed to resubscribe: {}", err).into()),
+            None => Ok(()),
+        }
     }
 }
 
-async fn exec(shell: String, cmd: String) -> Result<(), Box<dyn Error + Send + Sync>> {
-    let mut process = Command::new(shell)
+impl Slots {
+    // Waits until the demanded resources are free and takes them. Only the consumer loop acquires,
+    // so taking the resources one after another cannot deadlock.
+    async fn acquire(&self, demand: Demand) -> Permits {
+        let cpu = Arc::clone(&self.cpu).acquire_many_owned(demand.cpu);
+        let cpu = cpu.await.expect("semaphore is never closed");
+        let memory = Arc::clone(&self.memory).acquire_many_owned(demand.memory);
+        let memory = memory.await.expect("semaphore is never closed");
+        (cpu, memory)
+    }
+}
+
+impl Counters {
+    // Counters of the messages of a new stream, the stream of these ones has been lost.
+    fn resubscribed(&self) -> Counters {
+        self.lost.store(true, Ordering::Relaxed);
+        Counters {
+            undecodable: Arc::clone(&self.undecodable),
+            unsettled: Arc::clone(&self.unsettled),
+            lost: Arc::default(),
+        }
+    }
+
+    // Logs and counts a message which could not be settled, it is redelivered by the bus.
+    fn settle_failed(&self, msg: &(dyn Message + Send), action: &str, err: &dyn Display) {
+        // expected for messages of a lost stream, so they are not counted
+        if self.lost.load(Ordering::Relaxed) {
+            println!(
+                "Failed to {} message delivered before the consumer was lost ({}), \
+                 it is redelivered by the bus: {}",
+                action,
+                msg.delivery_info(),
+                err
+            );
+            return;
+        }
+        self.unsettled.fetch_add(1, Ordering::Relaxed);
+        println!(
+            "Failed to {} message ({}): {}",
+            action,
+            msg.delivery_info(),
+            err
+        );
+    }
+}
+
+async fn requeue(msg: Box<dyn Message + Send>, counters: &Counters) {
+    if let Err(err) = msg.requeue().await {
+        counters.settle_failed(msg.as_ref(), "requeue", &err);
+    }
+}
+
+// Rejects a message which is not a task for good, so that it is dead-lettered or dropped.
+async fn reject_undecodable(
+    msg: Box<dyn Message + Send>,
+    err: serde_json::Error,
+    counters: &Counters,
+) {
+    counters.undecodable.fetch_add(1, Ordering::Relaxed);
+    println!(
+        "Rejecting undecodable message ({}): {}",
+        msg.delivery_info(),
+        err
+    );
+    let failure = Failure {
+        reason: format!("Undecodable task: {}", err),
+        exit_code: None,
+    };
+    if let Err(err) = msg.reject(failure).await {
+        counters.settle_failed(msg.as_ref(), "reject", &err);
+    }
+}
+
+// Time left until the task is due, None if it can be started right away.
+fn due_in(task: &Task) -> Option<Duration> {
+    let due = UNIX_EPOCH + Duration::from_secs(task.not_before?);
+    due.duration_since(SystemTime::now()).ok()
+}
+
+// Returns a task delivered before it is due to the queue to be delivered again once it is due.
+async fn postpone(msg: Box<dyn Message + Send>, due_in: Duration, counters: Counters) {
+    if let Err(err) = msg.postpone(due_in).await {
+        counters.settle_failed(msg.as_ref(), "postpone", &err);
+    }
+}
+
+// Executes the task, reports its result and acknowledges the message accordingly.
+async fn handle(
+    msg: Box<dyn Message + Send>,
+    task: Task,
+    limits: Limits,
+    retry: Option<RetryPolicy>,
+    environment: Arc<Environment>,
+    kill: watch::Receiver<bool>,
+    counters: Counters,
+) {
+    let result = exec(&task, msg.attempt(), limits, &environment, kill).await;
+    if result.status == TaskStatus::Interrupted {
+        // the task will be run again, so its result is not final
+        println!("Task interrupted, requeueing: {}", task.command);
+        requeue(msg, &counters).await;
+        return;
+    }
+    settle(msg, result, retry, &counters).await;
+}
+
+// Fails the task without running it, it is not retried or requeued as it would be rejected again.
+async fn reject(
+    msg: Box<dyn Message + Send>,
+    task: Task,
+    reason: String,
+    environment: Arc<Environment>,
+    counters: Counters,
+) {
+    println!("Rejecting task: {}", reason);
+    let mut result = failed(&task, &environment);
+    result.error = Some(reason.clone());
+    result.attempt = msg.attempt();
+    reply(msg.as_ref(), &result).await;
+    let failure = Failure {
+        reason,
+        exit_code: None,
+    };
+    if let Err(err) = msg.reject(failure).await {
+        counters.settle_failed(msg.as_ref(), "reject", &err);
+    }
+}
+
+// Publishes the result of the task to its reply queue, if any.
+async fn reply(msg: &(dyn Message + Send), result: &TaskResult) {
+    match serde_json::to_string(result) {
+        Ok(reply) => {
+            if let Err(err) = msg.reply(reply).await {
+                println!("Failed to publish task result: {}", err);
+            }
+        }
+        Err(err) => println!("Failed to serialize task result: {}", err),
+    }
+}
+
+// Reports the result of the task and acknowledges the message accordingly.
+async fn settle(
+    msg: Box<dyn Message + Send>,
+    mut result: TaskResult,
+    retry: Option<RetryPolicy>,
+    counters: &Counters,
+) {
+    if let Some(err) = &result.error {
+        println!("Failed to execute task: {}", err);
+    }
+
+    result.attempt = msg.attempt();
+    let retry_delay = match &retry {
+        Some(retry) if !result.success() && msg.retryable() => {
+            retry.next_delay(result.attempt, result.exit_code)
+        }
+        _ => None,
+    };
+    result.retrying = retry_delay.is_some();
+    reply(msg.as_ref(), &result).await;
+
+    let failure = Failure {
+        reason: result.error.clone().unwrap_or_default(),
+        exit_code: result.exit_code,
+    };
+    let acked = match retry_delay {
+        _ if result.success() => msg.ack().await,
+        Some(delay) => {
+            println!(
+                "Retrying task in {} s after attempt {}",
+                delay.as_secs(),
+                result.attempt
+            );
+            match msg.retry(delay).await.map_err(|err| err.to_string()) {
+                Ok(_) => Ok(()),
+                Err(err) => {
+                    println!("Failed to schedule retry: {}", err);
+                    msg.nack(failure).await
+                }
+            }
+        }
+        None => msg.nack(failure).await,
+    };
+    if let Err(err) = acked {
+        counters.settle_failed(msg.as_ref(), "settle", &err);
+    }
+}
+
+// Result of the task which has not run (yet) on this worker.
+fn failed(task: &Task, environment: &Environment) -> TaskResult {
+    TaskResult {
+        id: task.id.clone(),
+        status: TaskStatus::Failed,
+        exit_code: None,
+        duration_ms: 0,
+        stdout: String::new(),
+        stderr: String::new(),
+        truncated: false,
+        error: None,
+        attempt: 1,
+        retrying: false,
+        worker_id: Some(environment.worker_id.clone()),
+    }
+}
+
+// Runs the command of the task in its own process group,
+// so that the whole tree can be killed on timeout or when `kill` is set.
+async fn exec(
+    task: &Task,
+    attempt: u32,
+    limits: Limits,
+    environment: &Environment,
+    mut kill: watch::Receiver<bool>,
+) -> TaskResult {
+    let started = Instant::now();
+    let mut result = failed(task, environment);
+
+    if let Some(name) = environment.disallowed(task) {
+        result.error = Some(format!(
+            "Environment variable {} is not allowed by the worker",
+            name
+        ));
+        return result;
+    }
+
+    let mut command = Command::new(&task.shell);
+    if task.clear_env {
+        command.env_clear();
+    }
+    command
+        .envs(&task.env)
+        // set last, so that tasks cannot override them
+        .env("MQDISH_TASK_ID", task.id.as_deref().unwrap_or_default())
+        .env("MQDISH_ATTEMPT", attempt.to_string())
+        .env("MQDISH_WORKER_ID", &environment.worker_id);
+    if let Some(cwd) = &task.cwd {
+        command.current_dir(cwd);
+    }
+    let spawned = command
         .arg("-c")
-        .arg(cmd.clone())
-        .stdout(Stdio::inherit())
-        .stderr(Stdio::inherit())
-        .spawn()?;
+        .arg(&task.command)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .process_group(0)
+        .spawn();
+    let mut process = match spawned {
+        Ok(process) => process,
+        Err(err) => {
+            result.error = Some(match &task.cwd {
+                Some(cwd) => format!("Failed to spawn `{}` in {}: {}", task.shell, cwd, err),
+                None => format!("Failed to spawn `{}`: {}", task.shell, err),
+            });
+            return result;
+        }
+    };
+
+    // both pipes are requested above, so they are always present
+    let stdout = process.stdout.take().expect("stdout is piped");
+    let stderr = process.stderr.take().expect("stderr is piped");
+    let wait = async {
+        select! {
+            status = process.wait() => (status, Termination::Exited),
+            _ = expiration(limits.timeout) => {
+                (kill_tree(&mut process).await, Termination::TimedOut)
+            }
+            _ = async { let _ = kill.wait_for(|kill| *kill).await; } => {
+                (kill_tree(&mut process).await, Termination::Killed)
+            }
+        }
+    };
+    let (stdout, stderr, (status, termination)) = tokio::join!(
+        capture(stdout, tokio::io::stdout(), limits.output_limit),
+        capture(stderr, tokio::io::stderr(), limits.output_limit),
+        wait,
+    );
+    result.duration_ms = started.elapsed().as_millis() as u64;
+    result.stdout = String::from_utf8_lossy(&stdout.0).to_string();
+    result.stderr = String::from_utf8_lossy(&stderr.0).to_string();
+    result.truncated = stdout.1 || stderr.1;
+
+    if let Ok(status) = &status {
+        result.exit_code = status.code();
+    }
+    match (status, termination) {
+        (Ok(status), Termination::Exited) => {
+            if status.success() {
+                result.status = TaskStatus::Succeeded;
+            } else {
+                result.error = Some(format!(
+                    "Command exited with non-zero status: {}, command: {}",
+                    status, task.command
+                ));
+            }
+        }
+        (_, Termination::Killed) => {
+            result.status = TaskStatus::Interrupted;
+            result.error = Some(format!("Command was killed: {}", task.command));
+        }
+        (_, Termination::TimedOut) => {
+            result.status = TaskStatus::TimedOut;
+            result.error = Some(format!(
+                "Command timed out after {} s: {}",
+                limits.timeout.unwrap_or_default().as_secs(),
+                task.command
+            ));
+        }
+        (Err(err), Termination::Exited) => {
+            result.error = Some(format!("Failed to wait for command: {}", err));
+        }
+    }
+
+    result
+}
+
+impl Environment {
+    // Returns a variable set by the task which the worker does not allow.
+    fn disallowed<'t>(&self, task: &'t Task) -> Option<&'t str> {
+        let allowed = self.allowed.as_ref()?;
+        let is_allowed = |name: &str| {
+            allowed
+                .iter()
+                .any(|pattern| match pattern.strip_suffix('*') {
+                    Some(prefix) => name.starts_with(prefix),
+                    None => pattern == name,
+                })
+        };
+        task.env
+            .keys()
+            .map(String::as_str)
+            .filter(|name| !is_allowed(name))
+            .min()
+    }
+}
+
+// `<hostname>-<pid>`, unique among workers running at once.
+fn default_worker_id() -> String {
+    let mut hostname = [0u8; 256];
+    // SAFETY: the buffer is writable for its whole length, the name is cut if it does not fit
+    let failed = unsafe { libc::gethostname(hostname.as_mut_ptr().cast(), hostname.len()) } != 0;
+    let length = hostname.iter().position(|byte| *byte == 0).unwrap_or(0);
+    let hostname = if failed {
+        "localhost".into()
+    } else {
+        String::from_utf8_lossy(&hostname[..length])
+    };
+    format!("{}-{}", hostname, std::process::id())
+}
+
+// Completes once the timeout elapses, never if there is no timeout.
+async fn expiration(timeout: Option<Duration>) {
+    match timeout {
+        Some(timeout) => sleep(timeout).await,
+        None => pending().await,
+    }
+}
+
+// Sends SIGTERM to the process group of the child and SIGKILL if it is still alive after a timeout.
+async fn kill_tree(process: &mut Child) -> std::io::Result<std::process::ExitStatus> {
+    let Some(pid) = process.id() else {
+        // already reaped
+        return process.wait().await;
+    };
+    signal_group(pid, libc::SIGTERM);
+    match timeout(KILL_TIMEOUT, process.wait()).await {
+        Ok(status) => {
+            // the shell may exit before its children
+            signal_group(pid, libc::SIGKILL);
+            status
+        }
+        Err(_) => {
+            signal_group(pid, libc::SIGKILL);
+            process.wait().await
+        }
+    }
+}
 
-    let status = process.wait().await?;
-    if !status.success() {
-        return Err(format!(
-            "Command exited with non-zero status: {}, command: {}",
-            status, cmd
-        )
-        .into());
+fn signal_group(pgid: u32, signal: libc::c_int) {
+    // SAFETY: killpg has no memory safety preconditions, errors (e.g. no such group) are ignored
+    unsafe {
+        libc::killpg(pgid as libc::pid_t, signal);
     }
+}
 
-    Ok(())
+// Forwards everything from `reader` to `echo` and keeps up to `limit` bytes of it.
+// Returns the kept bytes and whether anything was cut.
+async fn capture<R, W>(mut reader: R, mut echo: W, limit: usize) -> (Vec<u8>, bool)
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut kept = Vec::new();
+    let mut truncated = false;
+    let mut buf = [0u8; 8192];
+    loop {
+        let n = match reader.read(&mut buf).await {
+            Ok(0) | Err(_) => break,
+            Ok(n) => n,
+        };
+        let _ = echo.write_all(&buf[..n]).await;
+        let room = limit.saturating_sub(kept.len());
+        if n > room {
+            truncated = true;
+        }
+        kept.extend_from_slice(&buf[..n.min(room)]);
+    }
+    let _ = echo.flush().await;
+    (kept, truncated)
 }
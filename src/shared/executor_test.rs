@@ -0,0 +1,655 @@
+use crate::shared::config::{MemoryParams, TopicConfig};
+use crate::shared::dispatcher::Dispatcher;
+use crate::shared::executor::*;
+use crate::shared::models::{RetryPolicy, Task, TaskResult, TaskStatus};
+use crate::shared::msgbus::bus::{
+    Consumer, DeadLetters, Failure, Message, MessageProps, MessageStream, Publisher, ReplyConsumer,
+};
+use crate::shared::msgbus::memory::MemoryBus;
+use crate::shared::tracker::Tracker;
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::{read_to_string, remove_file};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::oneshot;
+use tokio::time::sleep;
+use tokio_stream::StreamExt;
+use uuid::Uuid;
+
+const TOPIC: &str = "test";
+
+fn task(command: &str) -> Task {
+    Task {
+        shell: "sh".to_string(),
+        command: command.to_string(),
+        ..Task::default()
+    }
+}
+
+// Task running for a while, which prints the times it started and ended at.
+fn timed() -> Task {
+    task("date +%s%N; sleep 0.3; date +%s%N")
+}
+
+// Start and end of a `timed` task in nanoseconds since the epoch.
+fn span(result: &TaskResult) -> (u128, u128) {
+    let times: Vec<u128> = result
+        .stdout
+        .lines()
+        .map(|line| line.parse().unwrap())
+        .collect();
+    (times[0], times[1])
+}
+
+// Most tasks which were running at the same time.
+fn max_overlap(results: &[TaskResult]) -> usize {
+    let spans: Vec<(u128, u128)> = results.iter().map(span).collect();
+    spans
+        .iter()
+        .map(|(start, _)| {
+            spans
+                .iter()
+                .filter(|(other_start, other_end)| other_start <= start && start < other_end)
+                .count()
+        })
+        .max()
+        .unwrap_or_default()
+}
+
+// Dispatches the tasks and executes them until every one has reported its final result.
+async fn run(workers: usize, retry: Option<RetryPolicy>, tasks: Vec<Task>) -> Vec<TaskResult> {
+    run_with(workers, tasks, |executor| executor.with_retry(retry)).await
+}
+
+// Same as `run` with the executor set up by `setup`.
+async fn run_with<F>(workers: usize, tasks: Vec<Task>, setup: F) -> Vec<TaskResult>
+where
+    F: FnOnce(Executor<'_, MemoryBus>) -> Executor<'_, MemoryBus>,
+{
+    let mut bus = MemoryBus::new(MemoryParams {
+        prefetch: workers as u16,
+        requeue: false,
+    });
+    let mut worker_bus = bus.connect();
+    let (reply_to, mut replies) = bus.consume_replies().await.unwrap();
+    let mut tracker = Tracker::new();
+    for (i, mut task) in tasks.into_iter().enumerate() {
+        task.id = Some(i.to_string());
+        task.reply_to = Some(reply_to.clone());
+        tracker.expect(i.to_string());
+        Dispatcher::new(&mut bus)
+            .dispatch(TOPIC.to_string(), task)
+            .await
+            .unwrap();
+    }
+
+    let mut results = vec![];
+    let (done_tx, done_rx) = oneshot::channel();
+    let mut executor = setup(Executor::new(&mut worker_bus, workers, TOPIC.to_string()));
+    let (summary, executed) = tokio::join!(
+        async {
+            let summary = tracker
+                .wait(&mut replies, None, |result, _| results.push(result.clone()))
+                .await;
+            let _ = done_tx.send(());
+            summary
+        },
+        executor.run_until(async {
+            let _ = done_rx.await;
+        }),
+    );
+    executed.unwrap();
+    assert_eq!(summary.missing, 0);
+    results.sort_by_key(|result| result.id.clone());
+    results
+}
+
+#[tokio::test]
+async fn test_results() {
+    let results = run(
+        2,
+        None,
+        vec![task("echo ok"), task("echo failed >&2; exit 3")],
+    )
+    .await;
+
+    assert_eq!(results[0].status, TaskStatus::Succeeded);
+    assert_eq!(results[0].stdout, "ok\n");
+    assert_eq!(results[1].status, TaskStatus::Failed);
+    assert_eq!(results[1].exit_code, Some(3));
+    assert_eq!(results[1].stderr, "failed\n");
+}
+
+#[tokio::test]
+async fn test_concurrency() {
+    let results = run(2, None, vec![timed(); 4]).await;
+
+    assert!(results.iter().all(TaskResult::success));
+    assert_eq!(max_overlap(&results), 2);
+}
+
+#[tokio::test]
+async fn test_weighted_tasks() {
+    let weighted = |cpu: u32, memory: Option<u32>| Task {
+        cpu: Some(cpu),
+        memory,
+        ..timed()
+    };
+    let results = run_with(
+        4,
+        vec![
+            weighted(3, None),
+            // waits for CPU slots of the first one
+            weighted(2, None),
+            weighted(1, Some(600)),
+            // waits for memory of the previous one
+            weighted(1, Some(600)),
+        ],
+        |executor| executor.with_memory_capacity(Some(1000)),
+    )
+    .await;
+
+    assert!(results.iter().all(TaskResult::success));
+    let spans: Vec<(u128, u128)> = results.iter().map(span).collect();
+    assert!(spans[1].0 >= spans[0].1, "{:?}", spans);
+    // tasks start in order of delivery, but share the slots left
+    assert!(
+        spans[2].0 >= spans[0].1 && spans[2].0 < spans[1].1,
+        "{:?}",
+        spans
+    );
+    assert!(spans[3].0 >= spans[2].1, "{:?}", spans);
+}
+
+#[tokio::test]
+async fn test_task_exceeding_capacity_is_rejected() {
+    let results = run_with(
+        2,
+        vec![
+            Task {
+                cpu: Some(3),
+                ..task("true")
+            },
+            Task {
+                memory: Some(200),
+                ..task("true")
+            },
+            Task {
+                cpu: Some(2),
+                memory: Some(100),
+                ..task("true")
+            },
+            Task {
+                cpu: Some(0),
+                ..task("true")
+            },
+            Task {
+                memory: Some(0),
+                ..task("true")
+            },
+        ],
+        |executor| executor.with_memory_capacity(Some(100)),
+    )
+    .await;
+
+    assert_eq!(
+        results[0].error.as_deref(),
+        Some("Task needs 3 CPU slots, the worker has 2")
+    );
+    assert_eq!(
+        results[1].error.as_deref(),
+        Some("Task needs 200 MB of memory, the worker has 100 MB")
+    );
+    assert!(results[2].success());
+    for result in &results[3..] {
+        assert_eq!(
+            result.error.as_deref(),
+            Some("Task needs at least 1 CPU slot and 1 MB of memory if set")
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_task_requiring_missing_labels_is_rejected() {
+    let requiring = |labels: &[&str]| Task {
+        requires: labels.iter().map(|label| label.to_string()).collect(),
+        ..task("true")
+    };
+    let results = run_with(
+        1,
+        vec![requiring(&["ffmpeg"]), requiring(&["ffmpeg", "arch=arm64"])],
+        |executor| executor.with_labels(vec!["ffmpeg".to_string()]),
+    )
+    .await;
+
+    assert!(results[0].success());
+    assert_eq!(
+        results[1].error.as_deref(),
+        Some("Worker does not have required labels: arch=arm64")
+    );
+}
+
+#[tokio::test]
+async fn test_exclusive() {
+    let log = std::env::temp_dir().join(format!("mqdish_{}.log", Uuid::new_v4()));
+    let command = format!(
+        "echo start >> {0}; sleep 0.1; echo end >> {0}",
+        log.display()
+    );
+    let exclusive = Task {
+        exclusive: true,
+        ..task(&command)
+    };
+    let results = run(4, None, vec![exclusive; 3]).await;
+
+    assert!(results.iter().all(TaskResult::success));
+    let lines = read_to_string(&log).unwrap();
+    remove_file(&log).unwrap();
+    assert_eq!(lines, "start\nend\n".repeat(3));
+}
+
+#[tokio::test]
+async fn test_exclusive_waits_for_running_tasks() {
+    let log = std::env::temp_dir().join(format!("mqdish_{}.log", Uuid::new_v4()));
+    let slow = format!(
+        "echo start >> {0}; sleep 0.3; echo end >> {0}",
+        log.display()
+    );
+    let exclusive = Task {
+        exclusive: true,
+        ..task(&format!("echo exclusive >> {}", log.display()))
+    };
+    let after = task(&format!("echo after >> {}", log.display()));
+    let results = run(4, None, vec![task(&slow), exclusive, after]).await;
+
+    assert!(results.iter().all(TaskResult::success));
+    let lines = read_to_string(&log).unwrap();
+    remove_file(&log).unwrap();
+    assert_eq!(lines, "start\nend\nexclusive\nafter\n");
+}
+
+#[tokio::test]
+async fn test_retry() {
+    let retry = RetryPolicy {
+        max_attempts: 3,
+        delay: 0,
+        ..RetryPolicy::default()
+    };
+    let results = run(1, Some(retry), vec![task("exit 1")]).await;
+
+    assert_eq!(results.len(), 1);
+    assert_eq!(results[0].status, TaskStatus::Failed);
+    assert_eq!(results[0].attempt, 3);
+}
+
+#[tokio::test]
+async fn test_task_not_due_is_postponed() {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    let due = now.as_secs() + 2;
+    let delayed = Task {
+        not_before: Some(due),
+        ..task("date +%s%N")
+    };
+    // the worker takes a single task at a time
+    let results = run(1, None, vec![delayed, task("date +%s%N")]).await;
+
+    assert!(results.iter().all(TaskResult::success));
+    // a task returned to the queue before it is due is not counted as an attempt
+    assert_eq!(results[0].attempt, 1);
+    let started = |result: &TaskResult| result.stdout.trim().parse::<u128>().unwrap();
+    assert!(started(&results[0]) >= due as u128 * 1_000_000_000);
+    // and it does not hold back the tasks queued after it
+    assert!(started(&results[1]) < due as u128 * 1_000_000_000);
+}
+
+#[tokio::test]
+async fn test_environment() {
+    let dir = std::env::temp_dir().canonicalize().unwrap();
+    let configured = Task {
+        env: HashMap::from([("FOO".to_string(), "bar".to_string())]),
+        cwd: Some(dir.display().to_string()),
+        ..task("echo $FOO $(pwd) $MQDISH_TASK_ID $MQDISH_ATTEMPT $MQDISH_WORKER_ID")
+    };
+    let cleared = Task {
+        clear_env: true,
+        ..task("echo ${HOME:-none} $MQDISH_ATTEMPT")
+    };
+    let results = run_with(1, vec![configured, cleared], |executor| {
+        executor.with_worker_id("worker-1".to_string())
+    })
+    .await;
+
+    assert_eq!(
+        results[0].stdout,
+        format!("bar {} 0 1 worker-1\n", dir.display())
+    );
+    assert_eq!(results[1].stdout, "none 1\n");
+    assert_eq!(results[1].worker_id.as_deref(), Some("worker-1"));
+}
+
+#[tokio::test]
+async fn test_allowed_env() {
+    let env_task = |name: &str| Task {
+        env: HashMap::from([(name.to_string(), "1".to_string())]),
+        ..task("true")
+    };
+    let results = run_with(
+        2,
+        vec![env_task("APP_DEBUG"), env_task("LD_PRELOAD")],
+        |executor| executor.with_allowed_env(Some(vec!["APP_*".to_string()])),
+    )
+    .await;
+
+    assert!(results[0].success());
+    assert_eq!(results[1].status, TaskStatus::Failed);
+    assert_eq!(
+        results[1].error.as_deref(),
+        Some("Environment variable LD_PRELOAD is not allowed by the worker")
+    );
+}
+
+#[tokio::test]
+async fn test_shutdown_requeues_running_task() {
+    let mut bus = MemoryBus::new(MemoryParams {
+        prefetch: 1,
+        requeue: false,
+    });
+    let mut worker_bus = bus.connect();
+    Dispatcher::new(&mut bus)
+        .dispatch(TOPIC.to_string(), task("sleep 10"))
+        .await
+        .unwrap();
+
+    Executor::new(&mut worker_bus, 1, TOPIC.to_string())
+        .with_grace_period(Duration::ZERO)
+        .run_until(sleep(Duration::from_millis(200)))
+        .await
+        .unwrap();
+    assert_eq!(bus.queue_len(TOPIC), 1);
+}
+
+// Message of a lost stream, which can no longer be settled and is redelivered once dropped.
+struct LostMessage(Box<dyn Message + Send>);
+
+#[async_trait]
+impl Message for LostMessage {
+    async fn ack(&self) -> Result<(), Box<dyn Error>> {
+        Err("consumer is lost".into())
+    }
+
+    async fn nack(&self, _failure: Failure) -> Result<(), Box<dyn Error>> {
+        Err("consumer is lost".into())
+    }
+
+    async fn reject(&self, _failure: Failure) -> Result<(), Box<dyn Error>> {
+        Err("consumer is lost".into())
+    }
+
+    async fn requeue(&self) -> Result<(), Box<dyn Error>> {
+        Err("consumer is lost".into())
+    }
+
+    async fn retry(&self, _delay: Duration) -> Result<(), Box<dyn Error>> {
+        Err("consumer is lost".into())
+    }
+
+    async fn reply(&self, msg: String) -> Result<(), Box<dyn Error>> {
+        self.0.reply(msg).await
+    }
+
+    fn body(&self) -> String {
+        self.0.body()
+    }
+
+    fn attempt(&self) -> u32 {
+        self.0.attempt()
+    }
+
+    fn failure(&self) -> Option<Failure> {
+        self.0.failure()
+    }
+}
+
+// Consumer whose first stream ends a while after one message, as if the connection was lost
+// while the task was running. The message of the first stream can no longer be settled.
+struct LosingConsumer {
+    bus: MemoryBus,
+    lost: bool,
+}
+
+#[async_trait]
+impl Consumer for LosingConsumer {
+    async fn consume(&mut self, topic: String) -> Result<MessageStream, Box<dyn Error>> {
+        let stream = self.bus.consume(topic).await?;
+        if self.lost {
+            return Ok(stream);
+        }
+        self.lost = true;
+        let lost = tokio_stream::once(())
+            .then(|_| sleep(Duration::from_millis(300)))
+            .filter_map(|_| None);
+        let stream = stream
+            .take(1)
+            .map(|msg| Box::new(LostMessage(msg)) as Box<dyn Message + Send>);
+        Ok(Box::pin(stream.chain(lost)))
+    }
+
+    async fn cancel(&mut self) -> Result<(), Box<dyn Error>> {
+        self.bus.cancel().await
+    }
+
+    async fn resubscribe(&mut self) -> Result<Option<MessageStream>, Box<dyn Error>> {
+        self.bus.cancel().await?;
+        Ok(Some(self.consume(TOPIC.to_string()).await?))
+    }
+}
+
+#[tokio::test]
+async fn test_task_of_lost_consumer_keeps_running() {
+    let mut bus = MemoryBus::new(MemoryParams {
+        prefetch: 1,
+        requeue: false,
+    });
+    let mut consumer = LosingConsumer {
+        bus: bus.connect(),
+        lost: false,
+    };
+    let path = format!("/tmp/mqdish-test-{}", Uuid::new_v4());
+    Dispatcher::new(&mut bus)
+        .dispatch(
+            TOPIC.to_string(),
+            task(&format!(
+                "echo run >> {0}; sleep 0.5; echo done >> {0}",
+                path
+            )),
+        )
+        .await
+        .unwrap();
+
+    let mut executor = Executor::new(&mut consumer, 1, TOPIC.to_string());
+    executor
+        .run_until(sleep(Duration::from_millis(1500)))
+        .await
+        .unwrap();
+    // the first run finishes after the consumer is restored, then the redelivered message runs
+    let runs = read_to_string(&path).unwrap();
+    remove_file(&path).unwrap();
+    assert_eq!(runs, "run\ndone\nrun\ndone\n");
+    assert_eq!(bus.queue_len(TOPIC), 0);
+    // failing to settle the message of the lost stream is expected
+    assert_eq!(executor.stats(), Stats::default());
+}
+
+// Message which cannot be retried, as delivered from a broadcast queue.
+struct BroadcastMessage(Box<dyn Message + Send>);
+
+#[async_trait]
+impl Message for BroadcastMessage {
+    async fn ack(&self) -> Result<(), Box<dyn Error>> {
+        self.0.ack().await
+    }
+
+    async fn nack(&self, failure: Failure) -> Result<(), Box<dyn Error>> {
+        self.0.nack(failure).await
+    }
+
+    async fn reject(&self, failure: Failure) -> Result<(), Box<dyn Error>> {
+        self.0.reject(failure).await
+    }
+
+    async fn requeue(&self) -> Result<(), Box<dyn Error>> {
+        self.0.requeue().await
+    }
+
+    async fn retry(&self, _delay: Duration) -> Result<(), Box<dyn Error>> {
+        Err("broadcast messages cannot be retried".into())
+    }
+
+    async fn reply(&self, msg: String) -> Result<(), Box<dyn Error>> {
+        self.0.reply(msg).await
+    }
+
+    fn body(&self) -> String {
+        self.0.body()
+    }
+
+    fn attempt(&self) -> u32 {
+        self.0.attempt()
+    }
+
+    fn failure(&self) -> Option<Failure> {
+        self.0.failure()
+    }
+
+    fn retryable(&self) -> bool {
+        false
+    }
+}
+
+// Consumer delivering every message as a broadcast one.
+struct BroadcastConsumer {
+    bus: MemoryBus,
+}
+
+#[async_trait]
+impl Consumer for BroadcastConsumer {
+    async fn consume(&mut self, topic: String) -> Result<MessageStream, Box<dyn Error>> {
+        let stream = self.bus.consume(topic).await?;
+        Ok(Box::pin(stream.map(|msg| {
+            Box::new(BroadcastMessage(msg)) as Box<dyn Message + Send>
+        })))
+    }
+
+    async fn cancel(&mut self) -> Result<(), Box<dyn Error>> {
+        self.bus.cancel().await
+    }
+}
+
+#[tokio::test]
+async fn test_failed_broadcast_task_is_not_retried() {
+    let mut bus = MemoryBus::new(MemoryParams {
+        prefetch: 1,
+        requeue: false,
+    });
+    let mut consumer = BroadcastConsumer { bus: bus.connect() };
+    let (reply_to, mut replies) = bus.consume_replies().await.unwrap();
+    Dispatcher::new(&mut bus)
+        .dispatch(
+            TOPIC.to_string(),
+            Task {
+                id: Some("0".to_string()),
+                reply_to: Some(reply_to),
+                ..task("exit 1")
+            },
+        )
+        .await
+        .unwrap();
+    let retry = RetryPolicy {
+        max_attempts: 3,
+        delay: 0,
+        ..RetryPolicy::default()
+    };
+
+    let mut tracker = Tracker::new();
+    tracker.expect("0".to_string());
+    let mut results = vec![];
+    let (done_tx, done_rx) = oneshot::channel();
+    let mut executor = Executor::new(&mut consumer, 1, TOPIC.to_string()).with_retry(Some(retry));
+    // a result announcing a retry would keep the tracker waiting for the next attempt
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(5);
+    let (summary, executed) = tokio::join!(
+        async {
+            let summary = tracker
+                .wait(&mut replies, Some(deadline), |result, _| {
+                    results.push(result.clone())
+                })
+                .await;
+            let _ = done_tx.send(());
+            summary
+        },
+        executor.run_until(async {
+            let _ = done_rx.await;
+        }),
+    );
+    executed.unwrap();
+
+    assert_eq!(summary.failed, 1);
+    assert_eq!(results.len(), 1);
+    assert_eq!(results[0].attempt, 1);
+    assert!(!results[0].retrying);
+    assert_eq!(bus.queue_len(TOPIC), 0);
+}
+
+#[tokio::test]
+async fn test_undecodable_message_is_dead_lettered() {
+    let topics = HashMap::from([(
+        TOPIC.to_string(),
+        TopicConfig {
+            dead_letter: true,
+            ..TopicConfig::default()
+        },
+    )]);
+    // rejected for good even though failed messages are requeued otherwise
+    let mut bus = MemoryBus::new(MemoryParams {
+        prefetch: 1,
+        requeue: true,
+    })
+    .with_topics(topics);
+    let mut worker_bus = bus.connect();
+    bus.publish(
+        TOPIC.to_string(),
+        "not a task".to_string(),
+        MessageProps::default(),
+    )
+    .await
+    .unwrap();
+    Dispatcher::new(&mut bus)
+        .dispatch(TOPIC.to_string(), task("true"))
+        .await
+        .unwrap();
+
+    let mut executor = Executor::new(&mut worker_bus, 1, TOPIC.to_string());
+    executor
+        .run_until(sleep(Duration::from_millis(200)))
+        .await
+        .unwrap();
+    assert_eq!(
+        executor.stats(),
+        Stats {
+            undecodable: 1,
+            unsettled: 0
+        }
+    );
+    // the worker kept consuming after the undecodable message
+    assert_eq!(bus.queue_len(TOPIC), 0);
+    let dead_letters = bus.dead_letters(TOPIC.to_string()).await.unwrap();
+    assert_eq!(dead_letters.len(), 1);
+    assert_eq!(dead_letters[0].body(), "not a task");
+    let failure = dead_letters[0].failure().unwrap();
+    assert!(
+        failure.reason.starts_with("Undecodable task"),
+        "{}",
+        failure.reason
+    );
+}
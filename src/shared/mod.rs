@@ -1,8 +1,24 @@
+pub mod backoff;
 pub mod config;
 pub mod dispatcher;
 pub mod executor;
 pub mod models;
 pub mod msgbus;
+pub mod scheduler;
+pub mod tracker;
+pub mod workflow;
 
 #[cfg(test)]
-mod config_test;
\ No newline at end of file
+mod backoff_test;
+#[cfg(test)]
+mod config_test;
+#[cfg(test)]
+mod executor_test;
+#[cfg(test)]
+mod models_test;
+#[cfg(test)]
+mod scheduler_test;
+#[cfg(test)]
+mod tracker_test;
+#[cfg(test)]
+mod workflow_test;
@@ -1,8 +1,144 @@
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::Duration;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct Task {
+    #[serde(default = "default_shell")]
     pub shell: String,
     pub command: String,
+    #[serde(default)]
     pub exclusive: bool,
+    // Identifier of the task, sent back as correlation id of the result.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    // Queue to publish the result of the task to. No result is published if empty.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reply_to: Option<String>,
+    // Seconds after which the command is killed, the worker's default applies if empty.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<u64>,
+    // How failed attempts are retried, the policy of the worker's topic applies if empty.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry: Option<RetryPolicy>,
+    // Tasks of higher priority are executed first if the queue of the topic supports priorities.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub priority: Option<u8>,
+    // Unix time in seconds before which the task is not started.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub not_before: Option<u64>,
+    // IDs of the tasks of the workflow which have to succeed before this one is dispatched.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub depends_on: Vec<String>,
+    // Environment variables of the command, the worker may allow only some of them.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub env: HashMap<String, String>,
+    // Working directory of the command, the one of the worker if empty.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cwd: Option<String>,
+    // Whether the command starts without the environment inherited from the worker.
+    #[serde(default)]
+    pub clear_env: bool,
+    // CPU slots of the worker's `concurrency` the command takes while it runs, 1 if empty.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cpu: Option<u32>,
+    // Megabytes of the worker's `memory_capacity` the command takes while it runs, none if empty.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub memory: Option<u32>,
+    // Labels of the workers the task is routed to, e.g. `ffmpeg` or `arch=arm64`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub requires: Vec<String>,
+}
+
+fn default_shell() -> String {
+    "sh".to_string()
+}
+
+/// Retries failed tasks with exponentially growing delay.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RetryPolicy {
+    // Total number of attempts including the first one.
+    pub max_attempts: u32,
+    // Seconds before the second attempt, doubled for every next one.
+    pub delay: u64,
+    // Upper bound of the delay in seconds.
+    pub max_delay: u64,
+    // Exit codes to retry on, any failure is retried if empty.
+    pub exit_codes: Vec<i32>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 3,
+            delay: 10,
+            max_delay: 3600,
+            exit_codes: vec![],
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Returns the delay before the next attempt or None if the failed `attempt` is not retried.
+    pub fn next_delay(&self, attempt: u32, exit_code: Option<i32>) -> Option<Duration> {
+        if attempt >= self.max_attempts {
+            return None;
+        }
+        if !self.exit_codes.is_empty() {
+            match exit_code {
+                Some(code) if self.exit_codes.contains(&code) => {}
+                _ => return None,
+            }
+        }
+        let factor = 2u64.saturating_pow(attempt.saturating_sub(1));
+        let delay = self.delay.saturating_mul(factor).min(self.max_delay);
+        Some(Duration::from_secs(delay))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TaskStatus {
+    Succeeded,
+    Failed,
+    // Killed on worker shutdown, the task is returned to the queue.
+    Interrupted,
+    // Killed after exceeding its timeout.
+    TimedOut,
+}
+
+/// Outcome of a single task execution reported back by the worker.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TaskResult {
+    pub id: Option<String>,
+    pub status: TaskStatus,
+    // Exit code of the command, absent if it was terminated by a signal or not started at all.
+    pub exit_code: Option<i32>,
+    pub duration_ms: u64,
+    pub stdout: String,
+    pub stderr: String,
+    // Whether stdout or stderr was cut to the worker's output limit.
+    pub truncated: bool,
+    // Reason of the failure other than non-zero exit code, e.g. failure to spawn the shell.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(default = "first_attempt")]
+    pub attempt: u32,
+    // Whether the task is going to be attempted again, so this result is not final.
+    #[serde(default)]
+    pub retrying: bool,
+    // Worker which ran the task, tells the results of a broadcast task apart.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub worker_id: Option<String>,
+}
+
+fn first_attempt() -> u32 {
+    1
+}
+
+impl TaskResult {
+    pub fn success(&self) -> bool {
+        self.status == TaskStatus::Succeeded
+    }
 }
@@ -0,0 +1,47 @@
+use crate::shared::models::*;
+use std::time::Duration;
+
+#[test]
+fn test_retry_backoff() {
+    let policy = RetryPolicy {
+        max_attempts: 5,
+        delay: 10,
+        max_delay: 30,
+        exit_codes: vec![],
+    };
+
+    assert_eq!(policy.next_delay(1, Some(1)), Some(Duration::from_secs(10)));
+    assert_eq!(policy.next_delay(2, Some(1)), Some(Duration::from_secs(20)));
+    assert_eq!(policy.next_delay(3, None), Some(Duration::from_secs(30)));
+    assert_eq!(policy.next_delay(5, Some(1)), None);
+}
+
+#[test]
+fn test_retry_exit_codes() {
+    let policy = RetryPolicy {
+        exit_codes: vec![75],
+        ..RetryPolicy::default()
+    };
+
+    assert!(policy.next_delay(1, Some(75)).is_some());
+    assert_eq!(policy.next_delay(1, Some(1)), None);
+    assert_eq!(policy.next_delay(1, None), None);
+}
+
+#[test]
+fn test_task_priority_is_optional() {
+    let task: Task =
+        serde_json::from_str(r#"{"shell":"sh","command":"true","exclusive":false}"#).unwrap();
+    assert_eq!(task.priority, None);
+    assert!(!serde_json::to_string(&task).unwrap().contains("priority"));
+
+    let urgent = Task {
+        priority: Some(9),
+        ..task
+    };
+    let json = serde_json::to_string(&urgent).unwrap();
+    assert_eq!(
+        serde_json::from_str::<Task>(&json).unwrap().priority,
+        Some(9)
+    );
+}
@@ -1,28 +1,92 @@
+use crate::shared::backoff::Backoff;
 use crate::shared::config;
 use crate::shared::config::Credentials::{LoginPassword, TLSClientAuth};
-use crate::shared::config::{BusParams, Credentials};
-use crate::shared::msgbus::bus::{Closer, Consumer, Message, Publisher};
+use crate::shared::config::{BusParams, Credentials, ReconnectParams, TopicConfig};
+use crate::shared::msgbus::bus::{
+    dead_letter_queue, requirement_queue, Broadcaster, Closer, Consumer, DeadLetters, Elector,
+    Failure, Leadership, Message, MessageProps, MessageStream, Publisher, ReplyConsumer,
+};
 use async_trait::async_trait;
 use lapin::acker::Acker;
+use lapin::message::Delivery;
 use lapin::options::{
-    BasicAckOptions, BasicConsumeOptions, BasicNackOptions, BasicPublishOptions, BasicQosOptions,
-    ConfirmSelectOptions, QueueDeclareOptions, QueueDeleteOptions,
+    BasicAckOptions, BasicCancelOptions, BasicConsumeOptions, BasicGetOptions, BasicNackOptions,
+    BasicPublishOptions, BasicQosOptions, ConfirmSelectOptions, ExchangeDeclareOptions,
+    QueueBindOptions, QueueDeclareOptions, QueueDeleteOptions, QueuePurgeOptions,
 };
+use lapin::protocol::{AMQPErrorKind, AMQPSoftError};
+use lapin::tcp::{OwnedIdentity, OwnedTLSConfig};
 use lapin::types::AMQPValue;
-use lapin::{types::FieldTable, BasicProperties, Channel, Connection, ConnectionProperties};
+use lapin::uri::{AMQPScheme, AMQPUri, SASLMechanism};
+use lapin::{
+    types::FieldTable, BasicProperties, Channel, Connection, ConnectionProperties, ExchangeKind,
+};
+use openssl::error::ErrorStack;
+use openssl::pkcs12::Pkcs12;
+use openssl::pkey::PKey;
+use openssl::stack::Stack;
+use openssl::x509::X509;
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::error;
 use std::error::Error;
-use std::pin::Pin;
+use std::fs;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use thiserror::Error;
-use tokio_stream::{Stream, StreamExt};
+use tokio::select;
+use tokio::sync::watch;
+use tokio::time::sleep;
+use tokio_stream::{StreamExt, StreamMap};
+use uuid::Uuid;
+
+const CONSUMER_TAG: &str = "mqdish";
+const ELECTOR_CONSUMER_TAG: &str = "mqdish-elector";
+// How long the leader holds the election token before passing it on to itself,
+// unless its saved state changes earlier.
+const TOKEN_HOLD: Duration = Duration::from_secs(5);
+// Only protects the identity passed to the TLS connector in memory.
+const PKCS12_PASSWORD: &str = "mqdish";
+const ATTEMPT_HEADER: &str = "x-mqdish-attempt";
+const FAILURE_REASON_HEADER: &str = "x-mqdish-failure-reason";
+const EXIT_CODE_HEADER: &str = "x-mqdish-exit-code";
+// How long an idle retry queue is kept after its delay.
+const RETRY_QUEUE_EXPIRY_MS: i32 = 60 * 60 * 1000;
+// Delays an early task is postponed by, so postponing shares a few retry queues.
+const POSTPONE_DELAYS: [Duration; 6] = [
+    Duration::from_secs(1),
+    Duration::from_secs(10),
+    Duration::from_secs(60),
+    Duration::from_secs(10 * 60),
+    Duration::from_secs(60 * 60),
+    Duration::from_secs(24 * 60 * 60),
+];
+// Headers exchange routing tasks with required labels to the queues of the workers having them.
+const REQUIREMENTS_EXCHANGE: &str = "mqdish.requirements";
+const TOPIC_HEADER: &str = "x-mqdish-topic";
+const REQUIREMENT_COUNT_HEADER: &str = "x-mqdish-requirements";
+const REQUIREMENT_HEADER_PREFIX: &str = "x-mqdish-requires-";
+/// Most labels of a worker, it consumes a queue for every combination of them.
+pub const MAX_LABELS: usize = 8;
 
 pub struct AmqpBus {
-    #[allow(dead_code)] // we need to keep the connection alive
     connection: Connection,
     channel: Channel,
+    uri: AMQPUri,
+    tls_config: OwnedTLSConfig,
+    prefetch: u16,
+    reconnect: ReconnectParams,
     consumer_timeout: Option<i32>,
     consumption_queue: Option<String>,
+    // consumers of the topic queue, of the queues for combinations of the labels and of the broadcast queue
+    consumer_tags: Vec<String>,
+    // limit of unacknowledged messages of all consumers of the channel, unlimited if zero
+    channel_prefetch: u16,
     requeue: bool,
+    topics: HashMap<String, TopicConfig>,
+    labels: Vec<String>,
+    // delay queues declared by this bus, they are declared once since their expiry differs
+    delay_queues: HashSet<String>,
+    // queues for required labels declared and bound by this bus
+    requirement_queues: HashSet<String>,
 }
 
 #[derive(Error, Debug)]
@@ -33,45 +97,328 @@ pub enum AmqpError {
     InvalidArgument(String),
     #[error("Connection to AMQP server failed: {0}")]
     ConnectionFailure(String),
+    #[error("Invalid TLS client authentication config: {0}")]
+    InvalidTLSConfig(String),
+    #[error("Channel of delivery {0} is closed, the broker delivers the message again")]
+    DeliveryLost(u64),
+}
+
+// Builds TLS config trusting the CA bundle and authenticating with the client certificate.
+pub(crate) fn tls_config(auth: &config::TLSClientAuth) -> Result<OwnedTLSConfig, AmqpError> {
+    let read = |path: &str, what: &str| {
+        fs::read(path).map_err(|err| {
+            AmqpError::InvalidTLSConfig(format!("failed to read {} {}: {}", what, path, err))
+        })
+    };
+    let invalid = |path: &str, what: &str, err: ErrorStack| {
+        AmqpError::InvalidTLSConfig(format!("invalid {} {}: {}", what, path, err))
+    };
+
+    let ca_pem = read(&auth.ca_file, "CA file")?;
+    let ca_certs =
+        X509::stack_from_pem(&ca_pem).map_err(|err| invalid(&auth.ca_file, "CA file", err))?;
+    if ca_certs.is_empty() {
+        return Err(AmqpError::InvalidTLSConfig(format!(
+            "no certificates in CA file {}",
+            auth.ca_file
+        )));
+    }
+
+    // the certificate file may contain the intermediate certificates after the client one
+    let cert_pem = read(&auth.cert_file, "certificate file")?;
+    let mut certs = X509::stack_from_pem(&cert_pem)
+        .map_err(|err| invalid(&auth.cert_file, "certificate file", err))?
+        .into_iter();
+    let Some(cert) = certs.next() else {
+        return Err(AmqpError::InvalidTLSConfig(format!(
+            "no certificates in certificate file {}",
+            auth.cert_file
+        )));
+    };
+    let key_pem = read(&auth.key_file, "key file")?;
+    let key = PKey::private_key_from_pem(&key_pem)
+        .map_err(|err| invalid(&auth.key_file, "key file", err))?;
+    let matches = cert
+        .public_key()
+        .map(|public_key| public_key.public_eq(&key))
+        .map_err(|err| invalid(&auth.cert_file, "certificate file", err))?;
+    if !matches {
+        return Err(AmqpError::InvalidTLSConfig(format!(
+            "key {} does not match certificate {}",
+            auth.key_file, auth.cert_file
+        )));
+    }
+
+    let pkcs12 = (|| {
+        let mut chain = Stack::new()?;
+        for cert in certs {
+            chain.push(cert)?;
+        }
+        Pkcs12::builder()
+            .name("mqdish")
+            .pkey(&key)
+            .cert(&cert)
+            .ca(chain)
+            .build2(PKCS12_PASSWORD)?
+            .to_der()
+    })()
+    .map_err(|err| AmqpError::InvalidTLSConfig(format!("failed to build identity: {}", err)))?;
+
+    Ok(OwnedTLSConfig {
+        identity: Some(OwnedIdentity {
+            der: pkcs12,
+            password: PKCS12_PASSWORD.to_string(),
+        }),
+        cert_chain: Some(String::from_utf8_lossy(&ca_pem).to_string()),
+    })
+}
+
+// Settings shared by the messages consumed from the same queue.
+#[derive(Clone)]
+struct Subscription {
+    channel: Channel,
+    queue: String,
+    requeue: bool,
+    // queue to publish failed messages to when they are not requeued
+    dead_letter_queue: Option<String>,
+    // whether failed messages may be published to a retry queue of `queue`
+    retryable: bool,
 }
 
 struct AmqpMessage {
     body: String,
-    requeue: bool,
     delivery_tag: Acker,
+    tag: u64,
+    redelivered: bool,
+    properties: BasicProperties,
+    subscription: Subscription,
 }
 
 impl AmqpMessage {
-    fn new(body: String, delivery_tag: Acker, requeue: bool) -> Self {
+    fn new(delivery: Delivery, subscription: Subscription) -> Self {
         AmqpMessage {
-            body,
-            delivery_tag,
-            requeue,
+            body: String::from_utf8_lossy(delivery.data.as_slice()).to_string(),
+            delivery_tag: delivery.acker,
+            tag: delivery.delivery_tag,
+            redelivered: delivery.redelivered,
+            properties: delivery.properties,
+            subscription,
+        }
+    }
+
+    // A delivery can only be settled on the channel it was received on, once the channel is closed
+    // the broker requeues it on its own, so settling it on a new channel would drop another one.
+    fn check_channel(&self) -> Result<(), AmqpError> {
+        if !self.subscription.channel.status().connected() {
+            return Err(AmqpError::DeliveryLost(self.tag));
+        }
+        Ok(())
+    }
+
+    // Publishes the message as the given attempt to the retry queue of the delay, from which it is
+    // dead-lettered back to its queue once the delay expires, and acks the original.
+    async fn publish_delayed(&self, delay: Duration, attempt: u32) -> Result<(), Box<dyn Error>> {
+        self.check_channel()?;
+        if !self.subscription.retryable {
+            return Err(AmqpError::NotImplemented(format!(
+                "retry of messages from {}",
+                self.subscription.queue
+            ))
+            .into());
+        }
+        let channel = &self.subscription.channel;
+        let retry_queue = declare_retry_queue(channel, &self.subscription.queue, delay).await?;
+
+        let mut headers = self.properties.headers().clone().unwrap_or_default();
+        headers.insert(ATTEMPT_HEADER.into(), AMQPValue::LongUInt(attempt));
+        let properties = self.properties.clone().with_headers(headers);
+        publish_confirmed(
+            channel,
+            retry_queue.as_str(),
+            self.body.as_bytes(),
+            properties,
+        )
+        .await?;
+
+        self.delivery_tag.ack(BasicAckOptions::default()).await?;
+        Ok(())
+    }
+
+    fn header(&self, name: &str) -> Option<&AMQPValue> {
+        self.properties.headers().as_ref()?.inner().get(name)
+    }
+
+    fn string_header(&self, name: &str) -> Option<String> {
+        self.header(name).and_then(value_to_string)
+    }
+
+    // Publishes the message with failure details to the dead-letter queue and acks the original.
+    async fn dead_letter(&self, queue: &str, failure: &Failure) -> Result<(), Box<dyn Error>> {
+        let mut headers = self.properties.headers().clone().unwrap_or_default();
+        headers.insert(
+            FAILURE_REASON_HEADER.into(),
+            AMQPValue::LongString(failure.reason.as_str().into()),
+        );
+        if let Some(exit_code) = failure.exit_code {
+            headers.insert(EXIT_CODE_HEADER.into(), AMQPValue::LongInt(exit_code));
         }
+        let properties = self.properties.clone().with_headers(headers);
+        publish_confirmed(
+            &self.subscription.channel,
+            queue,
+            self.body.as_bytes(),
+            properties,
+        )
+        .await?;
+
+        self.delivery_tag.ack(BasicAckOptions::default()).await?;
+        Ok(())
     }
 }
 
 #[async_trait]
 impl Message for AmqpMessage {
     async fn ack(&self) -> Result<(), Box<dyn Error>> {
-        let _ = self.delivery_tag.ack(BasicAckOptions::default()).await;
+        self.check_channel()?;
+        self.delivery_tag.ack(BasicAckOptions::default()).await?;
         Ok(())
     }
 
-    async fn nack(&self) -> Result<(), Box<dyn Error>> {
-        let _ = self
-            .delivery_tag
+    async fn nack(&self, failure: Failure) -> Result<(), Box<dyn Error>> {
+        if self.subscription.requeue {
+            return self.requeue().await;
+        }
+        self.reject(failure).await
+    }
+
+    async fn reject(&self, failure: Failure) -> Result<(), Box<dyn Error>> {
+        self.check_channel()?;
+        if let Some(queue) = &self.subscription.dead_letter_queue {
+            match self.dead_letter(queue, &failure).await {
+                Ok(_) => return Ok(()),
+                // the broker still dead-letters it, though without the failure details
+                Err(err) => println!("Failed to publish message to {}: {}", queue, err),
+            }
+        }
+        self.delivery_tag
             .nack(BasicNackOptions {
-                requeue: self.requeue,
+                requeue: false,
                 ..BasicNackOptions::default()
             })
-            .await;
+            .await?;
         Ok(())
     }
 
+    async fn requeue(&self) -> Result<(), Box<dyn Error>> {
+        self.check_channel()?;
+        self.delivery_tag
+            .nack(BasicNackOptions {
+                requeue: true,
+                ..BasicNackOptions::default()
+            })
+            .await?;
+        Ok(())
+    }
+
+    async fn retry(&self, delay: Duration) -> Result<(), Box<dyn Error>> {
+        self.publish_delayed(delay, self.attempt() + 1).await
+    }
+
+    async fn postpone(&self, delay: Duration) -> Result<(), Box<dyn Error>> {
+        self.publish_delayed(postpone_delay(delay), self.attempt())
+            .await
+    }
+
+    async fn reply(&self, msg: String) -> Result<(), Box<dyn Error>> {
+        let Some(reply_to) = self.properties.reply_to() else {
+            return Ok(());
+        };
+        let props = MessageProps {
+            correlation_id: self
+                .properties
+                .correlation_id()
+                .as_ref()
+                .map(|id| id.to_string()),
+            reply_to: None,
+            priority: None,
+            requires: vec![],
+        };
+        publish_confirmed(
+            &self.subscription.channel,
+            reply_to.as_str(),
+            msg.as_bytes(),
+            basic_properties(&props),
+        )
+        .await
+    }
+
+    fn attempt(&self) -> u32 {
+        let attempt = match self.header(ATTEMPT_HEADER) {
+            Some(AMQPValue::LongUInt(attempt)) => Some(*attempt),
+            Some(value) => value
+                .as_long_long_int()
+                .or(value.as_long_int().map(i64::from))
+                .and_then(|attempt| u32::try_from(attempt).ok()),
+            None => None,
+        };
+        attempt.unwrap_or(1)
+    }
+
     fn body(&self) -> String {
         self.body.clone()
     }
+
+    fn retryable(&self) -> bool {
+        self.subscription.retryable
+    }
+
+    fn delivery_info(&self) -> String {
+        format!(
+            "queue {}, delivery tag {}, message id {}, attempt {}{}",
+            self.subscription.queue,
+            self.tag,
+            self.properties
+                .message_id()
+                .as_ref()
+                .map_or("none", |id| id.as_str()),
+            self.attempt(),
+            if self.redelivered {
+                ", redelivered"
+            } else {
+                ""
+            }
+        )
+    }
+
+    fn failure(&self) -> Option<Failure> {
+        if let Some(reason) = self.string_header(FAILURE_REASON_HEADER) {
+            let exit_code = self
+                .header(EXIT_CODE_HEADER)
+                .and_then(|code| code.as_long_int());
+            return Some(Failure { reason, exit_code });
+        }
+        // dead-lettered by the broker, the latest death is the first one
+        let death = self
+            .header("x-death")?
+            .as_array()?
+            .as_slice()
+            .first()?
+            .as_field_table()?
+            .inner();
+        let reason = death.get("reason").and_then(value_to_string)?;
+        Some(Failure {
+            reason: format!("dead-lettered by the broker: {}", reason),
+            exit_code: None,
+        })
+    }
+}
+
+fn value_to_string(value: &AMQPValue) -> Option<String> {
+    match value {
+        AMQPValue::LongString(value) => Some(value.to_string()),
+        AMQPValue::ShortString(value) => Some(value.to_string()),
+        _ => None,
+    }
 }
 
 impl AmqpBus {
@@ -80,15 +427,21 @@ impl AmqpBus {
         credentials: Credentials,
         bus_params: BusParams,
     ) -> Result<Self, AmqpError> {
-        let auth_part = match credentials {
-            LoginPassword(creds) => format!("{}:{}@", creds.login, creds.password),
-            TLSClientAuth(_) => {
-                return Err(AmqpError::NotImplemented("TLSClientAuth".to_string()));
-            }
-            Credentials::None => "".to_string(),
+        let (auth_part, tls_config) = match credentials {
+            LoginPassword(creds) => (
+                format!("{}:{}@", creds.login, creds.password),
+                OwnedTLSConfig::default(),
+            ),
+            TLSClientAuth(auth) => ("".to_string(), tls_config(&auth)?),
+            Credentials::None => ("".to_string(), OwnedTLSConfig::default()),
         };
+        let client_auth = tls_config.identity.is_some();
 
-        let BusParams::AMQP(amqp_params) = bus_params;
+        let BusParams::AMQP(amqp_params) = bus_params else {
+            return Err(AmqpError::InvalidArgument(
+                "bus params of another driver".to_string(),
+            ));
+        };
 
         let connection_url = match connection_cfg {
             config::Connection::DSN(dsn) => dsn,
@@ -100,7 +453,7 @@ impl AmqpBus {
                     }
                 };
                 format!(
-                    "{}:{}//{}:{}/{}?{}",
+                    "{}://{}{}:{}/{}?{}",
                     if params.ssl { "amqps" } else { "amqp" },
                     auth_part,
                     params.host,
@@ -110,149 +463,913 @@ impl AmqpBus {
                 )
             }
         };
-        //TODO: pass executor and reactor explicitly
-        let conn_result =
-            Connection::connect(&connection_url, ConnectionProperties::default()).await;
-
-        let connection = match conn_result {
-            Ok(connection) => connection,
-            Err(err) => {
-                return Err(AmqpError::ConnectionFailure(format!("err: {}", err)));
-            }
-        };
-
-        let channel = match connection.create_channel().await {
-            Ok(ch) => ch,
-            Err(err) => {
-                return Err(AmqpError::ConnectionFailure(err.to_string()));
-            }
-        };
-        match channel
-            .basic_qos(amqp_params.prefetch, BasicQosOptions::default())
-            .await
-        {
-            Ok(_) => {}
-            Err(err) => {
-                return Err(AmqpError::ConnectionFailure(err.to_string()));
+        let mut uri: AMQPUri = connection_url
+            .parse()
+            .map_err(|err| AmqpError::InvalidArgument(format!("connection URL: {}", err)))?;
+        if client_auth {
+            if uri.scheme != AMQPScheme::AMQPS {
+                return Err(AmqpError::InvalidArgument(
+                    "TLS client authentication requires amqps connection".to_string(),
+                ));
             }
+            // the broker takes the user name from the client certificate
+            uri.query.auth_mechanism = Some(SASLMechanism::External);
         }
 
-        match channel
-            .confirm_select(ConfirmSelectOptions::default())
-            .await
-        {
-            Ok(_) => {}
-            Err(err) => {
-                return Err(AmqpError::ConnectionFailure(err.to_string()));
-            }
-        }
+        let (connection, channel) = connect(&uri, &tls_config, amqp_params.prefetch).await?;
 
         Ok(AmqpBus {
             connection,
             channel,
+            uri,
+            tls_config,
+            prefetch: amqp_params.prefetch,
+            reconnect: amqp_params.reconnect,
             consumer_timeout: amqp_params.consumer_timeout,
             consumption_queue: None,
+            consumer_tags: vec![],
+            channel_prefetch: 0,
             requeue: amqp_params.requeue,
+            topics: HashMap::new(),
+            labels: vec![],
+            delay_queues: HashSet::new(),
+            requirement_queues: HashSet::new(),
         })
     }
 
-    async fn declare_queue(&mut self, topic: &str) -> Result<(), Box<dyn Error>> {
-        let args = match self.consumer_timeout {
-            None => FieldTable::default(),
-            Some(timeout) => {
-                let mut args: FieldTable = FieldTable::default();
-                args.insert("x-consumer-timeout".into(), AMQPValue::LongInt(timeout));
-                args
+    // Publishes the message, again once the connection is restored if it is lost meanwhile.
+    async fn publish_retrying(
+        &mut self,
+        topic: &str,
+        msg: &str,
+        props: &MessageProps,
+        due: Option<SystemTime>,
+    ) -> Result<(), Box<dyn error::Error>> {
+        loop {
+            self.ensure_connected().await?;
+            let published = self.publish_once(topic, msg, props, due).await;
+            let err = match published {
+                Err(err) if !self.connection.status().connected() => err,
+                published => return published.map_err(|err| err.into()),
+            };
+            println!("Connection lost while publishing: {}", err);
+        }
+    }
+
+    async fn publish_once(
+        &mut self,
+        topic: &str,
+        msg: &str,
+        props: &MessageProps,
+        due: Option<SystemTime>,
+    ) -> Result<(), String> {
+        // tasks with requirements wait in their queue until a worker having the labels binds it
+        let queue = match props.requires.is_empty() {
+            true => self
+                .declare_queue(topic, topic)
+                .await
+                .map(|_| topic.to_string()),
+            false => self.declare_requirement_queue(topic, &props.requires).await,
+        }
+        .map_err(|err| err.to_string())?;
+        let mut exchange = "";
+        let mut routing_key = queue.clone();
+        let mut properties = basic_properties(props);
+        if !props.requires.is_empty() {
+            exchange = REQUIREMENTS_EXCHANGE;
+            routing_key = String::new();
+            properties = properties.with_headers(requirement_headers(topic, &props.requires));
+        }
+        // the delay is counted anew if the message is published again after a reconnection
+        let delayed = due.and_then(|due| Some((due, due.duration_since(SystemTime::now()).ok()?)));
+        if let Some((due, delay)) = delayed {
+            exchange = "";
+            routing_key = self
+                .declare_delay_queue(&queue, due, delay)
+                .await
+                .map_err(|err| err.to_string())?;
+            properties = properties.with_expiration(delay.as_millis().to_string().into());
+        }
+        publish_confirmed_to(
+            &self.channel,
+            exchange,
+            &routing_key,
+            msg.as_bytes(),
+            properties,
+        )
+        .await
+        .map_err(|err| err.to_string())
+    }
+
+    // Restores the channel or the whole connection if it was lost.
+    async fn ensure_connected(&mut self) -> Result<(), AmqpError> {
+        if !self.connection.status().connected() {
+            return self.reconnect().await;
+        }
+        if !self.channel.status().connected() {
+            self.channel = open_channel(&self.connection, self.prefetch).await?;
+        }
+        Ok(())
+    }
+
+    async fn reconnect(&mut self) -> Result<(), AmqpError> {
+        let mut backoff = Backoff::new(self.reconnect.clone());
+        loop {
+            match connect(&self.uri, &self.tls_config, self.prefetch).await {
+                Ok((connection, channel)) => {
+                    println!("Reconnected to AMQP server");
+                    self.connection = connection;
+                    self.channel = channel;
+                    return Ok(());
+                }
+                Err(err) => match backoff.next_delay() {
+                    Some(delay) => {
+                        println!(
+                            "Reconnection failed, next attempt in {} ms: {}",
+                            delay.as_millis(),
+                            err
+                        );
+                        sleep(delay).await;
+                    }
+                    None => return Err(err),
+                },
             }
-        };
-        let _ = self
+        }
+    }
+
+    /// Sets the settings of topics applied when their queues are declared.
+    pub fn with_topics(mut self, topics: HashMap<String, TopicConfig>) -> Self {
+        self.topics = topics;
+        self
+    }
+
+    /// Sets the labels of the worker, it consumes the tasks of the topic requiring any
+    /// combination of them along with the ones requiring none.
+    pub fn with_labels(mut self, labels: Vec<String>) -> Self {
+        self.labels = labels;
+        self
+    }
+
+    fn dead_letter_queue(&self, topic: &str) -> Option<String> {
+        match self.topics.get(topic) {
+            Some(topic_config) if topic_config.dead_letter => Some(dead_letter_queue(topic)),
+            _ => None,
+        }
+    }
+
+    // Declares the queue holding tasks of the topic, with the arguments the settings of the topic require.
+    // Returns the number of its consumers.
+    async fn declare_queue(&mut self, topic: &str, queue: &str) -> Result<u32, Box<dyn Error>> {
+        if let Some(dead_letter_queue) = self.dead_letter_queue(topic) {
+            self.declare_durable_queue(&dead_letter_queue).await?;
+        }
+        let args = queue_arguments(topic, self.consumer_timeout, &self.topics);
+        let declared = self
+            .channel
+            .queue_declare(
+                queue,
+                QueueDeclareOptions {
+                    durable: true,
+                    ..QueueDeclareOptions::default()
+                },
+                args,
+            )
+            .await;
+        match declared {
+            Ok(queue) => Ok(queue.consumer_count()),
+            Err(err) => Err(match explain_queue_mismatch(queue, &err) {
+                Some(explanation) => explanation.into(),
+                None => err.into(),
+            }),
+        }
+    }
+
+    // Declares the fanout exchange delivering broadcast tasks of the topic to every worker, returns its name.
+    async fn declare_broadcast_exchange(&self, topic: &str) -> Result<String, Box<dyn Error>> {
+        let exchange = format!("{}.broadcast", topic);
+        self.channel
+            .exchange_declare(
+                exchange.as_str(),
+                ExchangeKind::Fanout,
+                ExchangeDeclareOptions {
+                    durable: true,
+                    ..ExchangeDeclareOptions::default()
+                },
+                FieldTable::default(),
+            )
+            .await?;
+        Ok(exchange)
+    }
+
+    // Declares a queue of this worker alone for the broadcast tasks of the topic, returns its name.
+    // It is removed along with the connection, so broadcasts only reach the workers alive.
+    async fn declare_broadcast_queue(&self, topic: &str) -> Result<String, Box<dyn Error>> {
+        let exchange = self.declare_broadcast_exchange(topic).await?;
+        // server generates a unique name for the queue
+        let queue = self
             .channel
             .queue_declare(
-                topic,
+                "",
+                QueueDeclareOptions {
+                    exclusive: true,
+                    auto_delete: true,
+                    ..QueueDeclareOptions::default()
+                },
+                FieldTable::default(),
+            )
+            .await?;
+        let queue = queue.name().to_string();
+        self.channel
+            .queue_bind(
+                queue.as_str(),
+                exchange.as_str(),
+                "",
+                QueueBindOptions::default(),
+                FieldTable::default(),
+            )
+            .await?;
+        Ok(queue)
+    }
+
+    // Publishes the message to the broadcast exchange of the topic, returns the number of workers
+    // consuming the topic, all of which have bound their broadcast queues before.
+    async fn broadcast_once(
+        &mut self,
+        topic: &str,
+        msg: &str,
+        props: &MessageProps,
+    ) -> Result<u32, String> {
+        let workers = self
+            .declare_queue(topic, topic)
+            .await
+            .map_err(|err| err.to_string())?;
+        let exchange = self
+            .declare_broadcast_exchange(topic)
+            .await
+            .map_err(|err| err.to_string())?;
+        publish_confirmed_to(
+            &self.channel,
+            &exchange,
+            "",
+            msg.as_bytes(),
+            basic_properties(props),
+        )
+        .await
+        .map_err(|err| err.to_string())?;
+        Ok(workers)
+    }
+
+    // Declares the queue holding tasks of the topic which require exactly the labels
+    // and binds it to the requirements exchange, returns its name.
+    async fn declare_requirement_queue(
+        &mut self,
+        topic: &str,
+        requires: &[String],
+    ) -> Result<String, Box<dyn Error>> {
+        let queue = requirement_queue(topic, requires);
+        if self.requirement_queues.contains(&queue) {
+            return Ok(queue);
+        }
+        self.channel
+            .exchange_declare(
+                REQUIREMENTS_EXCHANGE,
+                ExchangeKind::Headers,
+                ExchangeDeclareOptions {
+                    durable: true,
+                    ..ExchangeDeclareOptions::default()
+                },
+                FieldTable::default(),
+            )
+            .await?;
+        self.declare_queue(topic, &queue).await?;
+        let mut args = requirement_headers(topic, requires);
+        args.insert("x-match".into(), AMQPValue::LongString("all".into()));
+        self.channel
+            .queue_bind(
+                queue.as_str(),
+                REQUIREMENTS_EXCHANGE,
+                "",
+                QueueBindOptions::default(),
+                args,
+            )
+            .await?;
+        self.requirement_queues.insert(queue.clone());
+        Ok(queue)
+    }
+
+    // Declares the queue holding messages until `due`. Every message expires once the `delay`
+    // left until then passes and is dead-lettered to the `topic` queue. The broker only expires
+    // messages at the head of a queue, so messages due at other times are kept in other queues.
+    async fn declare_delay_queue(
+        &mut self,
+        topic: &str,
+        due: SystemTime,
+        delay: Duration,
+    ) -> Result<String, Box<dyn Error>> {
+        let due_secs = due.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let queue = format!("{}.delayed.{}", topic, due_secs);
+        if self.delay_queues.contains(&queue) {
+            return Ok(queue);
+        }
+
+        let mut args = FieldTable::default();
+        args.insert(
+            "x-dead-letter-exchange".into(),
+            AMQPValue::LongString("".into()),
+        );
+        args.insert(
+            "x-dead-letter-routing-key".into(),
+            AMQPValue::LongString(topic.into()),
+        );
+        // the queue is removed by the broker a while after the due time
+        let expiry = delay.as_millis() as i64 + RETRY_QUEUE_EXPIRY_MS as i64;
+        args.insert("x-expires".into(), AMQPValue::LongLongInt(expiry));
+        let declared = self
+            .channel
+            .queue_declare(
+                queue.as_str(),
                 QueueDeclareOptions {
                     durable: true,
                     ..QueueDeclareOptions::default()
                 },
                 args,
             )
+            .await;
+        match declared {
+            Ok(_) => {}
+            // declared by another producer with the expiry counted from an earlier time,
+            // it is kept until the due time anyway
+            Err(err) if precondition_failed(&err) => self.ensure_connected().await?,
+            Err(err) => return Err(err.into()),
+        }
+        self.delay_queues.insert(queue.clone());
+        Ok(queue)
+    }
+
+    async fn declare_durable_queue(&self, queue: &str) -> Result<(), Box<dyn Error>> {
+        self.channel
+            .queue_declare(
+                queue,
+                QueueDeclareOptions {
+                    durable: true,
+                    ..QueueDeclareOptions::default()
+                },
+                FieldTable::default(),
+            )
             .await?;
         Ok(())
     }
 }
 
+// Connects to the server and opens a channel.
+async fn connect(
+    uri: &AMQPUri,
+    tls_config: &OwnedTLSConfig,
+    prefetch: u16,
+) -> Result<(Connection, Channel), AmqpError> {
+    // the config is consumed by the connection, so it is copied for reconnections
+    let tls_config = copy_tls_config(tls_config);
+    //TODO: pass executor and reactor explicitly
+    let conn_result = Connection::connect_uri_with_config(
+        uri.clone(),
+        ConnectionProperties::default(),
+        tls_config,
+    )
+    .await;
+
+    let connection = match conn_result {
+        Ok(connection) => connection,
+        Err(err) => {
+            return Err(AmqpError::ConnectionFailure(format!("err: {}", err)));
+        }
+    };
+    let channel = open_channel(&connection, prefetch).await?;
+    Ok((connection, channel))
+}
+
+fn copy_tls_config(tls_config: &OwnedTLSConfig) -> OwnedTLSConfig {
+    OwnedTLSConfig {
+        identity: tls_config.identity.as_ref().map(|identity| OwnedIdentity {
+            der: identity.der.clone(),
+            password: identity.password.clone(),
+        }),
+        cert_chain: tls_config.cert_chain.clone(),
+    }
+}
+
+// Opens a channel with QoS and publisher confirms set up.
+async fn open_channel(connection: &Connection, prefetch: u16) -> Result<Channel, AmqpError> {
+    let channel = match connection.create_channel().await {
+        Ok(ch) => ch,
+        Err(err) => {
+            return Err(AmqpError::ConnectionFailure(err.to_string()));
+        }
+    };
+    match channel
+        .basic_qos(prefetch, BasicQosOptions::default())
+        .await
+    {
+        Ok(_) => {}
+        Err(err) => {
+            return Err(AmqpError::ConnectionFailure(err.to_string()));
+        }
+    }
+
+    match channel
+        .confirm_select(ConfirmSelectOptions::default())
+        .await
+    {
+        Ok(_) => {}
+        Err(err) => {
+            return Err(AmqpError::ConnectionFailure(err.to_string()));
+        }
+    }
+    Ok(channel)
+}
+
+/// Arguments the queue of the topic is declared with. The broker refuses to declare an existing queue
+/// with other arguments, so producers and workers have to derive them from the same settings.
+pub fn queue_arguments(
+    topic: &str,
+    consumer_timeout: Option<i32>,
+    topics: &HashMap<String, TopicConfig>,
+) -> FieldTable {
+    let mut args = FieldTable::default();
+    if let Some(timeout) = consumer_timeout {
+        args.insert("x-consumer-timeout".into(), AMQPValue::LongInt(timeout));
+    }
+    if topics
+        .get(topic)
+        .is_some_and(|topic_config| topic_config.dead_letter)
+    {
+        args.insert(
+            "x-dead-letter-exchange".into(),
+            AMQPValue::LongString("".into()),
+        );
+        args.insert(
+            "x-dead-letter-routing-key".into(),
+            AMQPValue::LongString(dead_letter_queue(topic).as_str().into()),
+        );
+    }
+    if let Some(max_priority) = topics
+        .get(topic)
+        .and_then(|topic_config| topic_config.max_priority)
+    {
+        args.insert(
+            "x-max-priority".into(),
+            AMQPValue::LongInt(max_priority.into()),
+        );
+    }
+    args
+}
+
 #[async_trait]
 impl Publisher for AmqpBus {
-    async fn publish(&mut self, topic: String, msg: String) -> Result<(), Box<dyn error::Error>> {
-        self.declare_queue(topic.as_str()).await?;
+    async fn publish(
+        &mut self,
+        topic: String,
+        msg: String,
+        props: MessageProps,
+    ) -> Result<(), Box<dyn error::Error>> {
+        self.publish_retrying(&topic, &msg, &props, None).await
+    }
 
-        let msg_vec = msg.into_bytes();
-        let publish = self
-            .channel
-            .basic_publish(
-                "",
-                topic.as_str(),
-                BasicPublishOptions::default(),
-                msg_vec.as_slice(),
-                BasicProperties::default()
-                    .with_content_type("application/json".into())
-                    .with_delivery_mode(2)
-                    .with_app_id("mqdish".into()),
-            )
-            .await;
-        match publish {
+    async fn publish_at(
+        &mut self,
+        topic: String,
+        msg: String,
+        props: MessageProps,
+        at: SystemTime,
+    ) -> Result<(), Box<dyn error::Error>> {
+        self.publish_retrying(&topic, &msg, &props, Some(at)).await
+    }
+}
+
+fn basic_properties(props: &MessageProps) -> BasicProperties {
+    let mut properties = BasicProperties::default()
+        .with_content_type("application/json".into())
+        .with_delivery_mode(2)
+        .with_app_id("mqdish".into());
+    if let Some(correlation_id) = &props.correlation_id {
+        properties = properties.with_correlation_id(correlation_id.as_str().into());
+    }
+    if let Some(reply_to) = &props.reply_to {
+        properties = properties.with_reply_to(reply_to.as_str().into());
+    }
+    if let Some(priority) = props.priority {
+        properties = properties.with_priority(priority);
+    }
+    properties
+}
+
+// Headers a task requiring the labels is routed by. They are also the arguments of the binding
+// of the queue for exactly these labels, the count keeps out tasks requiring more of them.
+fn requirement_headers(topic: &str, requires: &[String]) -> FieldTable {
+    let requires: BTreeSet<&str> = requires.iter().map(String::as_str).collect();
+    let mut headers = FieldTable::default();
+    headers.insert(TOPIC_HEADER.into(), AMQPValue::LongString(topic.into()));
+    headers.insert(
+        REQUIREMENT_COUNT_HEADER.into(),
+        AMQPValue::LongUInt(requires.len() as u32),
+    );
+    for label in requires {
+        headers.insert(
+            format!("{}{}", REQUIREMENT_HEADER_PREFIX, label).into(),
+            AMQPValue::Boolean(true),
+        );
+    }
+    headers
+}
+
+/// Every non-empty combination of the labels, the requirements of the tasks a worker with them can run.
+pub fn label_combinations(labels: &[String]) -> Result<Vec<Vec<String>>, AmqpError> {
+    let labels: Vec<&String> = labels.iter().collect::<BTreeSet<_>>().into_iter().collect();
+    if labels.len() > MAX_LABELS {
+        return Err(AmqpError::InvalidArgument(format!(
+            "at most {} labels are supported, got {}",
+            MAX_LABELS,
+            labels.len()
+        )));
+    }
+    if let Some(label) = labels
+        .iter()
+        .find(|label| label.is_empty() || label.contains(','))
+    {
+        return Err(AmqpError::InvalidArgument(format!(
+            "label `{}` is empty or contains a comma",
+            label
+        )));
+    }
+    Ok((1..1u32 << labels.len())
+        .map(|mask| {
+            labels
+                .iter()
+                .enumerate()
+                .filter(|(index, _)| mask & (1 << index) != 0)
+                .map(|(_, label)| label.to_string())
+                .collect()
+        })
+        .collect())
+}
+
+/// Explains why the queue of the topic could not be declared if it exists with other arguments
+/// than the settings of the topic require, e.g. without priorities. Arguments of an existing
+/// queue cannot be changed.
+pub fn explain_queue_mismatch(topic: &str, err: &lapin::Error) -> Option<String> {
+    if !precondition_failed(err) {
+        return None;
+    }
+    let lapin::Error::ProtocolError(err) = err else {
+        return None;
+    };
+    let message = err.get_message().as_str();
+    let setting = [
+        ("'x-max-priority'", "max_priority"),
+        ("'x-dead-letter-", "dead_letter"),
+        ("'x-consumer-timeout'", "consumer_timeout"),
+    ]
+    .into_iter()
+    .find(|(argument, _)| message.contains(argument))
+    .map(|(_, setting)| setting)?;
+    Some(format!(
+        "Queue {} exists with other arguments than `{}` in the config requires: {}. \
+         Arguments of a queue cannot be changed, delete the queue once it is drained \
+         (e.g. `rabbitmqctl delete_queue {}`) or use another topic",
+        topic, setting, message, topic
+    ))
+}
+
+// Whether the broker refused to redeclare a queue with other arguments, which closes the channel.
+fn precondition_failed(err: &lapin::Error) -> bool {
+    matches!(err, lapin::Error::ProtocolError(err)
+        if *err.kind() == AMQPErrorKind::Soft(AMQPSoftError::PRECONDITIONFAILED))
+}
+
+/// Delay an early task is postponed by, the longest of `POSTPONE_DELAYS` not past its due time.
+/// A task due later comes back early and is postponed again, until the remaining delay gets
+/// below a second.
+pub fn postpone_delay(delay: Duration) -> Duration {
+    POSTPONE_DELAYS
+        .into_iter()
+        .rev()
+        .find(|bucket| *bucket <= delay)
+        .unwrap_or(POSTPONE_DELAYS[0])
+}
+
+// Declares the queue holding messages to be retried after `delay`.
+// Once the delay expires, messages are dead-lettered back to the `topic` queue.
+async fn declare_retry_queue(
+    channel: &Channel,
+    topic: &str,
+    delay: Duration,
+) -> Result<String, Box<dyn Error>> {
+    // the message TTL is a signed 32-bit number of milliseconds
+    let delay_ms = i32::try_from(delay.as_millis()).map_err(|_| {
+        AmqpError::InvalidArgument(format!(
+            "retry delay of {} s is longer than the maximum of {} s",
+            delay.as_secs(),
+            i32::MAX / 1000
+        ))
+    })?;
+    let queue = format!("{}.retry.{}", topic, delay_ms);
+
+    let mut args = FieldTable::default();
+    args.insert("x-message-ttl".into(), AMQPValue::LongInt(delay_ms));
+    args.insert(
+        "x-dead-letter-exchange".into(),
+        AMQPValue::LongString("".into()),
+    );
+    args.insert(
+        "x-dead-letter-routing-key".into(),
+        AMQPValue::LongString(topic.into()),
+    );
+    // unused retry queues are removed by the broker
+    args.insert(
+        "x-expires".into(),
+        AMQPValue::LongInt(delay_ms.saturating_add(RETRY_QUEUE_EXPIRY_MS)),
+    );
+    channel
+        .queue_declare(
+            queue.as_str(),
+            QueueDeclareOptions {
+                durable: true,
+                ..QueueDeclareOptions::default()
+            },
+            args,
+        )
+        .await?;
+    Ok(queue)
+}
+
+async fn publish_confirmed(
+    channel: &Channel,
+    routing_key: &str,
+    body: &[u8],
+    properties: BasicProperties,
+) -> Result<(), Box<dyn Error>> {
+    publish_confirmed_to(channel, "", routing_key, body, properties).await
+}
+
+async fn publish_confirmed_to(
+    channel: &Channel,
+    exchange: &str,
+    routing_key: &str,
+    body: &[u8],
+    properties: BasicProperties,
+) -> Result<(), Box<dyn Error>> {
+    let publish = channel
+        .basic_publish(
+            exchange,
+            routing_key,
+            BasicPublishOptions::default(),
+            body,
+            properties,
+        )
+        .await;
+    match publish {
+        Err(err) => {
+            return Err(format!("Failed to publish message: {}", err).into());
+        }
+        // TODO: batch confirm
+        Ok(confirm) => match confirm.await {
+            Ok(_) => {}
             Err(err) => {
                 return Err(format!("Failed to publish message: {}", err).into());
             }
-            // TODO: batch confirm
-            Ok(confirm) => match confirm.await {
-                Ok(_) => {}
-                Err(err) => {
-                    return Err(format!("Failed to publish message: {}", err).into());
-                }
-            },
+        },
+    }
+    Ok(())
+}
+
+#[async_trait]
+impl Consumer for AmqpBus {
+    async fn consume(&mut self, topic: String) -> Result<MessageStream, Box<dyn Error>> {
+        self.consumption_queue = Some(topic.clone());
+        let combinations = label_combinations(&self.labels)?;
+
+        self.ensure_connected().await?;
+        self.declare_queue(&topic, &topic).await?;
+        let mut queues = vec![topic.clone()];
+        for labels in &combinations {
+            queues.push(self.declare_requirement_queue(&topic, labels).await?);
+        }
+        // bound before the topic queue is consumed, so producers counting its consumers
+        // get a result of every one of them
+        let broadcast_queue = self.declare_broadcast_queue(&topic).await?;
+        queues.push(broadcast_queue.clone());
+        // every consumer may take `prefetch` messages, so the channel is limited as a whole
+        self.channel_prefetch = self.prefetch;
+        self.channel
+            .basic_qos(self.prefetch, BasicQosOptions { global: true })
+            .await?;
+
+        let mut consumers = vec![];
+        self.consumer_tags.clear();
+        for (index, queue) in queues.into_iter().enumerate() {
+            // the broadcast queue is exclusive to this worker and named by the server, so its tasks
+            // are neither retried nor requeued, and replaying them from the dead-letter queue
+            // would run them on a single worker
+            let broadcast = queue == broadcast_queue;
+            let subscription = Subscription {
+                channel: self.channel.clone(),
+                queue: queue.clone(),
+                requeue: self.requeue && !broadcast,
+                dead_letter_queue: match broadcast {
+                    true => None,
+                    false => self.dead_letter_queue(&topic),
+                },
+                retryable: !broadcast,
+            };
+            let consumer_tag = match index {
+                0 => CONSUMER_TAG.to_string(),
+                index => format!("{}.{}", CONSUMER_TAG, index),
+            };
+            let consumer = self
+                .channel
+                .basic_consume(
+                    queue.as_str(),
+                    consumer_tag.as_str(),
+                    BasicConsumeOptions::default(),
+                    FieldTable::default(),
+                )
+                .await?;
+            self.consumer_tags.push(consumer_tag);
+            consumers.push((consumer, subscription));
+        }
+
+        let mut streams = StreamMap::new();
+        for (index, (consumer, subscription)) in consumers.into_iter().enumerate() {
+            streams.insert(index, into_message_stream(consumer, subscription));
+        }
+        Ok(Box::pin(streams.map(|(_, msg)| msg)))
+    }
+
+    async fn resubscribe(&mut self) -> Result<Option<MessageStream>, Box<dyn Error>> {
+        let Some(topic) = self.consumption_queue.clone() else {
+            return Ok(None);
+        };
+        // waits for the connection to be restored, the consumer is recreated on a new channel
+        self.ensure_connected().await?;
+        Ok(Some(self.consume(topic).await?))
+    }
+
+    async fn cancel(&mut self) -> Result<(), Box<dyn Error>> {
+        // the consumer is gone along with the channel
+        if self.consumption_queue.is_some() && self.channel.status().connected() {
+            for consumer_tag in &self.consumer_tags {
+                self.channel
+                    .basic_cancel(consumer_tag, BasicCancelOptions::default())
+                    .await?;
+            }
         }
         Ok(())
     }
+
+    async fn pause(&mut self, paused: bool) -> Result<(), Box<dyn Error>> {
+        // consumers cannot be paused by the client, but a channel-wide limit of one unacknowledged
+        // message holds delivery back while the message being processed is not settled
+        self.channel
+            .basic_qos(
+                if paused { 1 } else { self.channel_prefetch },
+                BasicQosOptions { global: true },
+            )
+            .await?;
+        Ok(())
+    }
 }
 
 #[async_trait]
-impl Consumer for AmqpBus {
-    async fn consume(
+impl Broadcaster for AmqpBus {
+    async fn broadcast(
         &mut self,
         topic: String,
-    ) -> Result<Pin<Box<dyn Stream<Item = Box<dyn Message + Send>>>>, Box<dyn Error>> {
-        self.consumption_queue = Some(topic.clone());
-
-        self.declare_queue(topic.as_str()).await?;
+        msg: String,
+        props: MessageProps,
+    ) -> Result<u32, Box<dyn Error>> {
+        // published again once the connection is restored if it is lost meanwhile
+        loop {
+            self.ensure_connected().await?;
+            let broadcast = self.broadcast_once(&topic, &msg, &props).await;
+            let err = match broadcast {
+                Err(err) if !self.connection.status().connected() => err,
+                broadcast => return broadcast.map_err(|err| err.into()),
+            };
+            println!("Connection lost while broadcasting: {}", err);
+        }
+    }
+}
 
-        let requeue = self.requeue;
+#[async_trait]
+impl ReplyConsumer for AmqpBus {
+    async fn consume_replies(&mut self) -> Result<(String, MessageStream), Box<dyn Error>> {
+        self.ensure_connected().await?;
+        // server generates a unique name for the queue
+        let queue = self
+            .channel
+            .queue_declare(
+                "",
+                QueueDeclareOptions {
+                    exclusive: true,
+                    auto_delete: true,
+                    ..QueueDeclareOptions::default()
+                },
+                FieldTable::default(),
+            )
+            .await?;
+        let queue_name = queue.name().to_string();
 
         let consumer = self
             .channel
             .basic_consume(
-                topic.as_str(),
-                "mqdish",
+                queue_name.as_str(),
+                "",
                 BasicConsumeOptions::default(),
                 FieldTable::default(),
             )
             .await?;
 
-        let msg_stream = consumer.filter_map(move |delivery| match delivery {
-            Ok(delivery) => {
-                let body = String::from_utf8_lossy(delivery.data.as_slice()).to_string();
-                Some(Box::new(AmqpMessage::new(body, delivery.acker, requeue))
-                    as Box<dyn Message + Send>)
+        let subscription = Subscription {
+            channel: self.channel.clone(),
+            queue: queue_name.clone(),
+            requeue: false,
+            dead_letter_queue: None,
+            retryable: false,
+        };
+        let stream = into_message_stream(consumer, subscription);
+        Ok((queue_name, stream))
+    }
+}
+
+fn into_message_stream(consumer: lapin::Consumer, subscription: Subscription) -> MessageStream {
+    // the consumer never yields anything after an error, so the stream ends there
+    let msg_stream =
+        consumer.map_while(move |delivery| match delivery {
+            Ok(delivery) => Some(Box::new(AmqpMessage::new(delivery, subscription.clone()))
+                as Box<dyn Message + Send>),
+            Err(err) => {
+                println!("Consumer of {} failed: {}", subscription.queue, err);
+                None
             }
-            _ => None,
         });
 
-        Ok(Box::pin(msg_stream))
+    Box::pin(msg_stream)
+}
+
+#[async_trait]
+impl DeadLetters for AmqpBus {
+    async fn dead_letters(
+        &mut self,
+        topic: String,
+    ) -> Result<Vec<Box<dyn Message + Send>>, Box<dyn Error>> {
+        let queue = dead_letter_queue(&topic);
+        self.ensure_connected().await?;
+        self.declare_durable_queue(&queue).await?;
+
+        let subscription = Subscription {
+            channel: self.channel.clone(),
+            queue: queue.clone(),
+            requeue: true,
+            dead_letter_queue: None,
+            retryable: false,
+        };
+        let mut messages: Vec<Box<dyn Message + Send>> = vec![];
+        // fetched messages stay unacked, so the next get returns the next message
+        while let Some(msg) = self
+            .channel
+            .basic_get(queue.as_str(), BasicGetOptions::default())
+            .await?
+        {
+            messages.push(Box::new(AmqpMessage::new(
+                msg.delivery,
+                subscription.clone(),
+            )));
+        }
+        Ok(messages)
+    }
+
+    async fn purge_dead_letters(&mut self, topic: String) -> Result<u32, Box<dyn Error>> {
+        let queue = dead_letter_queue(&topic);
+        self.ensure_connected().await?;
+        self.declare_durable_queue(&queue).await?;
+        let purged = self
+            .channel
+            .queue_purge(queue.as_str(), QueuePurgeOptions::default())
+            .await?;
+        Ok(purged)
     }
 }
 
 #[async_trait]
 impl Closer for AmqpBus {
     async fn close(&mut self) -> Result<(), Box<dyn Error>> {
+        // nothing is left to clean up on a lost connection
+        if !self.connection.status().connected() {
+            return Ok(());
+        }
+        if !self.channel.status().connected() {
+            self.connection.close(200, "closed by mqdish").await?;
+            return Ok(());
+        }
         if let Some(queue) = &self.consumption_queue {
             let delete_opts = QueueDeleteOptions {
                 if_empty: true,
@@ -263,6 +1380,186 @@ impl Closer for AmqpBus {
             self.channel.queue_delete(queue, delete_opts).await?;
         }
 
+        self.channel.close(200, "closed by mqdish").await?;
+        self.connection.close(200, "closed by mqdish").await?;
         Ok(())
     }
 }
+
+#[async_trait]
+impl Elector for AmqpBus {
+    async fn elect(&mut self, lock: String) -> Result<Leadership, Box<dyn Error>> {
+        let (leading_tx, leading_rx) = watch::channel(None);
+        let (state_tx, state_rx) = watch::channel(String::new());
+        let election = Election {
+            uri: self.uri.clone(),
+            tls_config: copy_tls_config(&self.tls_config),
+            reconnect: self.reconnect.clone(),
+            lock,
+        };
+        tokio::spawn(election.run(leading_tx, state_rx));
+        Ok(Leadership {
+            leading: leading_rx,
+            state: state_tx,
+        })
+    }
+}
+
+// Election held over a single active consumer queue, so that the broker delivers messages
+// to one of its consumers only. The queue holds a single token carrying the state of the leader,
+// the leader is the consumer it is delivered to. It keeps the token unacked and passes it
+// on to itself once in a while and whenever its state changes, so the token is redelivered
+// to the next consumer when it is gone.
+struct Election {
+    uri: AMQPUri,
+    tls_config: OwnedTLSConfig,
+    reconnect: ReconnectParams,
+    lock: String,
+}
+
+impl Election {
+    // Takes part in the election on its own connection until the leadership is dropped.
+    async fn run(self, leading: watch::Sender<Option<String>>, mut state: watch::Receiver<String>) {
+        let mut backoff = Backoff::new(self.reconnect.clone());
+        loop {
+            let held = self.hold(&leading, &mut state, &mut backoff).await;
+            if leading.send_replace(None).is_some() {
+                println!("Lost leadership of {}", self.lock);
+            }
+            let err = match held {
+                Ok(_) => return,
+                Err(err) => err,
+            };
+            match backoff.next_delay() {
+                Some(delay) => {
+                    println!(
+                        "Leader election failed, next attempt in {} ms: {}",
+                        delay.as_millis(),
+                        err
+                    );
+                    sleep(delay).await;
+                }
+                None => {
+                    println!("Leader election failed: {}", err);
+                    return;
+                }
+            }
+        }
+    }
+
+    async fn hold(
+        &self,
+        leading: &watch::Sender<Option<String>>,
+        state: &mut watch::Receiver<String>,
+        backoff: &mut Backoff,
+    ) -> Result<(), String> {
+        let (connection, channel) = connect(&self.uri, &self.tls_config, 1)
+            .await
+            .map_err(|err| err.to_string())?;
+        *backoff = Backoff::new(self.reconnect.clone());
+        let held = self
+            .pass_token(&channel, leading, state)
+            .await
+            .map_err(|err| err.to_string());
+        // the token held by this instance goes to the next consumer
+        let _ = connection.close(0, "").await;
+        held
+    }
+
+    async fn pass_token(
+        &self,
+        channel: &Channel,
+        leading: &watch::Sender<Option<String>>,
+        state: &mut watch::Receiver<String>,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut args = FieldTable::default();
+        args.insert("x-single-active-consumer".into(), AMQPValue::Boolean(true));
+        let queue = channel
+            .queue_declare(
+                self.lock.as_str(),
+                QueueDeclareOptions {
+                    durable: true,
+                    ..QueueDeclareOptions::default()
+                },
+                args,
+            )
+            .await?;
+        if queue.message_count() == 0 && queue.consumer_count() == 0 {
+            // tokens of instances started at once are dropped by the leader but the one it holds
+            self.publish_token(channel, "", &Uuid::new_v4().to_string())
+                .await?;
+        }
+
+        let mut consumer = channel
+            .basic_consume(
+                self.lock.as_str(),
+                ELECTOR_CONSUMER_TAG,
+                BasicConsumeOptions::default(),
+                FieldTable::default(),
+            )
+            .await?;
+        // the token this instance passed on last, None until it leads
+        let mut passed: Option<String> = None;
+        let mut current = String::new();
+        loop {
+            let delivery = select! {
+                delivery = consumer.next() => delivery,
+                _ = leading.closed() => return Ok(()),
+            };
+            let Some(delivery) = delivery else {
+                break;
+            };
+            let delivery = delivery?;
+            let id = delivery
+                .properties
+                .message_id()
+                .as_ref()
+                .map(|id| id.to_string());
+            match &passed {
+                None => {
+                    current = String::from_utf8_lossy(&delivery.data).to_string();
+                    state.mark_unchanged();
+                    leading.send_replace(Some(current.clone()));
+                    println!("Leading {}", self.lock);
+                }
+                Some(passed) if id.as_ref() == Some(passed) => {}
+                Some(_) => {
+                    delivery.acker.ack(BasicAckOptions::default()).await?;
+                    continue;
+                }
+            }
+            // the state is passed on as soon as it changes, so that the next leader knows
+            // of every run of this one, also when it is given up once the state is sent
+            let given_up = select! {
+                _ = sleep(TOKEN_HOLD) => false,
+                changed = state.changed() => match changed {
+                    Ok(_) => {
+                        current = state.borrow_and_update().clone();
+                        false
+                    }
+                    Err(_) => true,
+                },
+            };
+            let id = Uuid::new_v4().to_string();
+            self.publish_token(channel, &current, &id).await?;
+            delivery.acker.ack(BasicAckOptions::default()).await?;
+            if given_up {
+                return Ok(());
+            }
+            passed = Some(id);
+        }
+        Err(format!("Consumer of {} was cancelled", self.lock).into())
+    }
+
+    async fn publish_token(
+        &self,
+        channel: &Channel,
+        state: &str,
+        id: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        let properties = BasicProperties::default()
+            .with_delivery_mode(2)
+            .with_message_id(id.into());
+        publish_confirmed(channel, self.lock.as_str(), state.as_bytes(), properties).await
+    }
+}
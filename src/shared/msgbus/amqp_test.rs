@@ -0,0 +1,205 @@
+use crate::shared::config::{TLSClientAuth, TopicConfig};
+use crate::shared::msgbus::amqp::*;
+use crate::shared::msgbus::bus::requirement_queue;
+use lapin::protocol::{AMQPError, AMQPErrorKind, AMQPSoftError};
+use lapin::types::AMQPValue;
+use openssl::asn1::Asn1Time;
+use openssl::hash::MessageDigest;
+use openssl::pkcs12::Pkcs12;
+use openssl::pkey::{PKey, Private};
+use openssl::rsa::Rsa;
+use openssl::x509::{X509NameBuilder, X509};
+use rand::{distributions::Alphanumeric, Rng};
+use std::collections::HashMap;
+use std::fs::{remove_file, write};
+use std::time::Duration;
+
+struct TempFile {
+    path: String,
+}
+
+impl TempFile {
+    fn new(contents: &[u8]) -> Self {
+        let random_name: String = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(10)
+            .map(char::from)
+            .collect();
+        let path = format!("./tls_{}.pem", random_name);
+        write(&path, contents).unwrap();
+        TempFile { path }
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        remove_file(&self.path).expect("Failed to delete temp file");
+    }
+}
+
+fn self_signed() -> (PKey<Private>, X509) {
+    let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+    let mut name = X509NameBuilder::new().unwrap();
+    name.append_entry_by_text("CN", "mqdish").unwrap();
+    let name = name.build();
+
+    let mut cert = X509::builder().unwrap();
+    cert.set_version(2).unwrap();
+    cert.set_subject_name(&name).unwrap();
+    cert.set_issuer_name(&name).unwrap();
+    cert.set_pubkey(&key).unwrap();
+    cert.set_not_before(&Asn1Time::days_from_now(0).unwrap())
+        .unwrap();
+    cert.set_not_after(&Asn1Time::days_from_now(1).unwrap())
+        .unwrap();
+    cert.sign(&key, MessageDigest::sha256()).unwrap();
+    (key, cert.build())
+}
+
+#[test]
+fn test_tls_client_auth() {
+    let (key, cert) = self_signed();
+    let (other_key, _) = self_signed();
+    let cert_file = TempFile::new(&cert.to_pem().unwrap());
+    let key_file = TempFile::new(&key.private_key_to_pem_pkcs8().unwrap());
+    let other_key_file = TempFile::new(&other_key.private_key_to_pem_pkcs8().unwrap());
+
+    let auth = TLSClientAuth {
+        ca_file: cert_file.path.clone(),
+        cert_file: cert_file.path.clone(),
+        key_file: key_file.path.clone(),
+    };
+    let config = tls_config(&auth).unwrap();
+    assert!(config.cert_chain.is_some());
+    // the same way the TLS connector reads the identity
+    let identity = config.identity.unwrap();
+    let parsed = Pkcs12::from_der(&identity.der)
+        .unwrap()
+        .parse2(&identity.password)
+        .unwrap();
+    assert_eq!(
+        parsed.cert.unwrap().to_der().unwrap(),
+        cert.to_der().unwrap()
+    );
+
+    let mismatched = TLSClientAuth {
+        key_file: other_key_file.path.clone(),
+        ..auth
+    };
+    match tls_config(&mismatched) {
+        Err(AmqpError::InvalidTLSConfig(msg)) => assert!(msg.contains("does not match")),
+        _ => panic!("Mismatched key accepted"),
+    }
+
+    let missing = TLSClientAuth {
+        ca_file: "./missing.pem".to_string(),
+        cert_file: cert_file.path.clone(),
+        key_file: key_file.path.clone(),
+    };
+    match tls_config(&missing) {
+        Err(AmqpError::InvalidTLSConfig(msg)) => assert!(msg.contains("./missing.pem")),
+        _ => panic!("Missing CA file accepted"),
+    }
+}
+
+#[test]
+fn test_queue_arguments() {
+    let topics = HashMap::from([(
+        "transcode".to_string(),
+        TopicConfig {
+            dead_letter: true,
+            ..TopicConfig::default()
+        },
+    )]);
+    // the producer and the worker both declare the queue from the topics of the config
+    let args = queue_arguments("transcode", Some(60_000), &topics);
+    let args = args.inner();
+    assert_eq!(
+        args.get("x-dead-letter-routing-key"),
+        Some(&AMQPValue::LongString("transcode.dlq".into()))
+    );
+    assert_eq!(
+        args.get("x-dead-letter-exchange"),
+        Some(&AMQPValue::LongString("".into()))
+    );
+    assert_eq!(
+        args.get("x-consumer-timeout"),
+        Some(&AMQPValue::LongInt(60_000))
+    );
+
+    // a bus without the topics declares the queue with other arguments, which the broker refuses
+    assert_ne!(
+        queue_arguments("transcode", Some(60_000), &HashMap::new()).inner(),
+        args
+    );
+    assert!(queue_arguments("other", None, &topics).inner().is_empty());
+}
+
+#[test]
+fn test_explain_queue_mismatch() {
+    let refused = |message: &str| {
+        lapin::Error::ProtocolError(AMQPError::new(
+            AMQPErrorKind::Soft(AMQPSoftError::PRECONDITIONFAILED),
+            message.into(),
+        ))
+    };
+    let explanation = explain_queue_mismatch(
+        "tasks",
+        &refused("PRECONDITION_FAILED - inequivalent arg 'x-max-priority' for queue 'tasks' in vhost '/': received the value '10' of type 'signedint' but current is none"),
+    )
+    .unwrap();
+    assert!(explanation.contains("`max_priority`"));
+    assert!(explanation.contains("delete_queue tasks"));
+    assert!(explain_queue_mismatch(
+        "tasks",
+        &refused(
+            "PRECONDITION_FAILED - inequivalent arg 'x-dead-letter-exchange' for queue 'tasks'"
+        ),
+    )
+    .unwrap()
+    .contains("`dead_letter`"));
+    assert!(explain_queue_mismatch("tasks", &refused("PRECONDITION_FAILED - unknown")).is_none());
+    assert!(explain_queue_mismatch("tasks", &lapin::Error::InvalidChannel(1)).is_none());
+}
+
+#[test]
+fn test_label_combinations() {
+    let labels =
+        |labels: &[&str]| -> Vec<String> { labels.iter().map(|label| label.to_string()).collect() };
+    let combinations = label_combinations(&labels(&["ffmpeg", "arch=arm64", "ffmpeg"])).unwrap();
+    assert_eq!(
+        combinations,
+        vec![
+            labels(&["arch=arm64"]),
+            labels(&["ffmpeg"]),
+            labels(&["arch=arm64", "ffmpeg"]),
+        ]
+    );
+    // the queue of a combination is the one tasks with the same requirements are published to
+    assert_eq!(
+        requirement_queue("tasks", &labels(&["ffmpeg", "arch=arm64"])),
+        requirement_queue("tasks", &combinations[2])
+    );
+    assert_eq!(requirement_queue("tasks", &[]), "tasks");
+
+    assert!(label_combinations(&[]).unwrap().is_empty());
+    assert_eq!(
+        label_combinations(&labels(&["a", "b", "c"])).unwrap().len(),
+        7
+    );
+    let too_many: Vec<String> = (0..=MAX_LABELS).map(|label| label.to_string()).collect();
+    assert!(label_combinations(&too_many).is_err());
+    assert!(label_combinations(&labels(&["a,b"])).is_err());
+}
+
+#[test]
+fn test_postpone_delay() {
+    let secs = Duration::from_secs;
+    assert_eq!(postpone_delay(Duration::from_millis(200)), secs(1));
+    assert_eq!(postpone_delay(secs(1)), secs(1));
+    assert_eq!(postpone_delay(Duration::from_millis(9_999)), secs(1));
+    assert_eq!(postpone_delay(secs(90)), secs(60));
+    assert_eq!(postpone_delay(secs(2 * 60 * 60)), secs(60 * 60));
+    // delays beyond the longest one are postponed in several hops
+    assert_eq!(postpone_delay(secs(30 * 24 * 60 * 60)), secs(24 * 60 * 60));
+}
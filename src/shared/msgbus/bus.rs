@@ -1,26 +1,167 @@
 use async_trait::async_trait;
 use std::error::Error;
 use std::pin::Pin;
+use std::time::{Duration, SystemTime};
+use tokio::sync::watch;
 use tokio_stream::Stream;
 
+/// Transport level properties of a published message.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MessageProps {
+    pub correlation_id: Option<String>,
+    pub reply_to: Option<String>,
+    // Messages of higher priority are delivered first by queues supporting priorities.
+    pub priority: Option<u8>,
+    // Labels a worker must have to get the message, buses without routing by them ignore them.
+    pub requires: Vec<String>,
+}
+
+/// Why a message could not be processed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Failure {
+    pub reason: String,
+    pub exit_code: Option<i32>,
+}
+
+/// Name of the queue holding tasks of the topic which failed for good.
+pub fn dead_letter_queue(topic: &str) -> String {
+    format!("{}.dlq", topic)
+}
+
+/// Name of the queue holding tasks of the topic with the required labels, the topic queue if none.
+/// The labels are sorted, so that the same requirements are always kept in the same queue.
+pub fn requirement_queue(topic: &str, requires: &[String]) -> String {
+    let mut requires: Vec<&str> = requires.iter().map(String::as_str).collect();
+    if requires.is_empty() {
+        return topic.to_string();
+    }
+    requires.sort_unstable();
+    requires.dedup();
+    format!("{}.requires.{}", topic, requires.join(","))
+}
+
+pub type MessageStream = Pin<Box<dyn Stream<Item = Box<dyn Message + Send>>>>;
+
 #[async_trait]
 pub trait Message: Sync {
     async fn ack(&self) -> Result<(), Box<dyn Error>>;
-    async fn nack(&self) -> Result<(), Box<dyn Error>>;
+    // Rejects the message, it is either requeued or dead-lettered along with the failure details.
+    async fn nack(&self, failure: Failure) -> Result<(), Box<dyn Error>>;
+    // Rejects the message for good regardless of the requeue setting, it is dead-lettered along
+    // with the failure details if the topic has a dead-letter queue and dropped otherwise.
+    async fn reject(&self, failure: Failure) -> Result<(), Box<dyn Error>>;
+    // Returns the message to the queue regardless of the requeue setting.
+    async fn requeue(&self) -> Result<(), Box<dyn Error>>;
+    // Returns the message to the queue after `delay` as the next attempt.
+    // Only called if `retryable` returns true.
+    async fn retry(&self, delay: Duration) -> Result<(), Box<dyn Error>>;
+    // Returns the message to the queue after `delay` without counting an attempt, e.g. a task
+    // delivered before it is due. Messages which cannot be returned that way are retried.
+    async fn postpone(&self, delay: Duration) -> Result<(), Box<dyn Error>> {
+        self.retry(delay).await
+    }
+    // Publishes `msg` to the reply queue of this message, does nothing if the message has none.
+    async fn reply(&self, msg: String) -> Result<(), Box<dyn Error>>;
     fn body(&self) -> String;
+    // Number of the delivery attempt of the message starting from 1.
+    fn attempt(&self) -> u32;
+    // Details of the last failure of a dead-lettered message.
+    fn failure(&self) -> Option<Failure>;
+    // Whether a failed message may be retried, e.g. broadcast ones are run once by every worker.
+    fn retryable(&self) -> bool {
+        true
+    }
+    // Where the message was delivered from, for logs.
+    fn delivery_info(&self) -> String {
+        format!("attempt {}", self.attempt())
+    }
 }
 
 #[async_trait]
-pub trait Publisher {
-    async fn publish(&mut self, topic: String, msg: String) -> Result<(), Box<dyn Error>>;
+pub trait Publisher: Send {
+    async fn publish(
+        &mut self,
+        topic: String,
+        msg: String,
+        props: MessageProps,
+    ) -> Result<(), Box<dyn Error>>;
+    // Publishes `msg` to be delivered at `at`. Buses without delayed delivery publish it
+    // right away, workers postpone tasks which are not due yet.
+    async fn publish_at(
+        &mut self,
+        topic: String,
+        msg: String,
+        props: MessageProps,
+        at: SystemTime,
+    ) -> Result<(), Box<dyn Error>> {
+        let _ = at;
+        self.publish(topic, msg, props).await
+    }
 }
 
 #[async_trait]
-pub trait Consumer {
-    async fn consume(
+pub trait Consumer: Send {
+    async fn consume(&mut self, topic: String) -> Result<MessageStream, Box<dyn Error>>;
+    // Stops delivery of new messages to the stream returned by `consume`.
+    async fn cancel(&mut self) -> Result<(), Box<dyn Error>>;
+    // Called when the stream returned by `consume` ends unexpectedly.
+    // Returns a new stream of the same topic if the consumption can be resumed. Messages delivered
+    // to the ended stream may fail to be settled, the bus delivers them again in that case.
+    async fn resubscribe(&mut self) -> Result<Option<MessageStream>, Box<dyn Error>> {
+        Ok(None)
+    }
+    // Holds back delivery of new messages to the stream while `paused`, so that other consumers
+    // get them meanwhile. Messages already delivered are not affected.
+    async fn pause(&mut self, _paused: bool) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+}
+
+#[async_trait]
+pub trait Broadcaster {
+    // Publishes `msg` to every worker consuming the topic at the moment, returns their number.
+    // Workers which start consuming afterwards do not get it.
+    async fn broadcast(
+        &mut self,
+        topic: String,
+        msg: String,
+        props: MessageProps,
+    ) -> Result<u32, Box<dyn Error>>;
+}
+
+#[async_trait]
+pub trait ReplyConsumer {
+    // Declares a temporary queue which lives as long as the connection and consumes it.
+    // Returns the name of the queue to be used as `reply_to` and the stream of replies.
+    async fn consume_replies(&mut self) -> Result<(String, MessageStream), Box<dyn Error>>;
+}
+
+#[async_trait]
+pub trait DeadLetters {
+    // Fetches all messages dead-lettered from the topic. They are kept by the bus until
+    // acked or requeued, the ones left are returned to the dead-letter queue once the bus is closed.
+    async fn dead_letters(
         &mut self,
         topic: String,
-    ) -> Result<Pin<Box<dyn Stream<Item = Box<dyn Message + Send>>>>, Box<dyn Error>>;
+    ) -> Result<Vec<Box<dyn Message + Send>>, Box<dyn Error>>;
+    // Removes all messages dead-lettered from the topic, returns their number.
+    async fn purge_dead_letters(&mut self, topic: String) -> Result<u32, Box<dyn Error>>;
+}
+
+/// Part of an instance in the election of a leader, kept up to date by the bus.
+pub struct Leadership {
+    // While this instance leads, the state handed over by the previous leader (empty if none).
+    // None while another instance leads. The sender is dropped once the election is given up.
+    pub leading: watch::Receiver<Option<String>>,
+    // State to hand over to the next leader, only sent by the leader.
+    pub state: watch::Sender<String>,
+}
+
+#[async_trait]
+pub trait Elector {
+    // Joins the election of a single leader among the instances using the same `lock`.
+    // The election is held in the background until the returned leadership is dropped.
+    async fn elect(&mut self, lock: String) -> Result<Leadership, Box<dyn Error>>;
 }
 
 #[async_trait]
@@ -0,0 +1,993 @@
+use crate::shared::backoff::Backoff;
+use crate::shared::config;
+use crate::shared::config::{BusParams, Credentials, KafkaParams, TopicConfig};
+use crate::shared::msgbus::bus::{
+    dead_letter_queue, Closer, Consumer, DeadLetters, Failure, Message, MessageProps,
+    MessageStream, Publisher, ReplyConsumer,
+};
+use async_trait::async_trait;
+use rdkafka::admin::{AdminClient, AdminOptions, NewTopic, TopicReplication};
+use rdkafka::client::DefaultClientContext;
+use rdkafka::config::ClientConfig;
+use rdkafka::consumer::{
+    BaseConsumer, CommitMode, Consumer as _, ConsumerContext, Rebalance, StreamConsumer,
+};
+use rdkafka::error::{KafkaResult, RDKafkaErrorCode};
+use rdkafka::message::{Header, Headers, OwnedHeaders, OwnedMessage};
+use rdkafka::producer::{FutureProducer, FutureRecord, Producer as _};
+use rdkafka::util::Timeout;
+use rdkafka::{ClientContext, Message as _, Offset, TopicPartitionList};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::error::Error;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+use tokio::select;
+use tokio::sync::{mpsc, watch, OwnedSemaphorePermit, Semaphore};
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, timeout};
+use tokio_stream::wrappers::ReceiverStream;
+use uuid::Uuid;
+
+pub(crate) const CORRELATION_ID_HEADER: &str = "mqdish-correlation-id";
+pub(crate) const REPLY_TO_HEADER: &str = "mqdish-reply-to";
+pub(crate) const ATTEMPT_HEADER: &str = "mqdish-attempt";
+pub(crate) const FAILURE_REASON_HEADER: &str = "mqdish-failure-reason";
+pub(crate) const EXIT_CODE_HEADER: &str = "mqdish-exit-code";
+// Milliseconds since the epoch before which a retried or scheduled task is not executed.
+pub(crate) const NOT_BEFORE_HEADER: &str = "mqdish-not-before";
+// Bounds requests for metadata and offsets, and waiting for messages to be delivered on close.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+// Longest time a retried or scheduled task waits in memory, holding back commits of its partition.
+// Tasks due later are published to the retry topic again once it passes.
+const WAIT_HORIZON: Duration = Duration::from_secs(60);
+// Maximum number of tasks waiting in memory, consumption stops while it is reached.
+const MAX_WAITING: usize = 1024;
+
+/// Message bus on Kafka, the workers of a topic form a consumer group sharing its partitions.
+///
+/// Offsets are committed once tasks are settled. Tasks of a partition may finish out of order,
+/// so only the offset following the tasks settled without gaps is committed. Kafka cannot return
+/// a single message to its partition, requeued and retried tasks are published to the
+/// `<topic>.retry` topic which the workers consume along with the topic.
+pub struct KafkaBus {
+    config: ClientConfig,
+    producer: FutureProducer,
+    admin: AdminClient<DefaultClientContext>,
+    params: KafkaParams,
+    topics: HashMap<String, TopicConfig>,
+    // Topics known to exist.
+    created: HashSet<String>,
+    // Stops the subscription created by `consume`.
+    subscription: Option<(watch::Sender<bool>, JoinHandle<()>)>,
+    // Reply topics with their subscriptions, deleted on close.
+    reply_topics: Vec<(String, watch::Sender<bool>, JoinHandle<()>)>,
+}
+
+#[derive(Error, Debug)]
+pub enum KafkaBusError {
+    #[error("Not implemented for Kafka driver: {0}")]
+    NotImplemented(String),
+    #[error("Invalid argument: {0}")]
+    InvalidArgument(String),
+    #[error("Connection to Kafka cluster failed: {0}")]
+    ConnectionFailure(String),
+}
+
+/// Bootstrap servers and whether TLS is used,
+/// the connection string is `kafka[+ssl]://host:port[,host:port...]` or just the list of servers.
+pub fn bootstrap_servers(connection: config::Connection) -> Result<(String, bool), KafkaBusError> {
+    let dsn = match connection {
+        config::Connection::DSN(dsn) => dsn,
+        config::Connection::Params(params) => {
+            return Ok((format!("{}:{}", params.host, params.port), params.ssl))
+        }
+    };
+    let invalid = || KafkaBusError::InvalidArgument(format!("connection string {}", dsn));
+    let (ssl, servers) = match dsn.split_once("://") {
+        Some(("kafka", servers)) => (false, servers),
+        Some(("kafka+ssl", servers)) => (true, servers),
+        Some(_) => return Err(invalid()),
+        None => (false, dsn.as_str()),
+    };
+    let servers = servers.trim_end_matches('/');
+    if servers.is_empty() {
+        return Err(invalid());
+    }
+    Ok((servers.to_string(), ssl))
+}
+
+/// Topic requeued and retried tasks of the topic are published to.
+pub fn retry_topic(topic: &str) -> String {
+    format!("{}.retry", topic)
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+/// Task and its metadata, which are carried in headers of the record.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct Envelope {
+    pub body: String,
+    pub props: MessageProps,
+    pub attempt: u32,
+    pub failure: Option<Failure>,
+    pub not_before: Option<u64>,
+}
+
+impl Envelope {
+    pub(crate) fn from_message<M: rdkafka::Message>(message: &M) -> Envelope {
+        let mut headers = HashMap::new();
+        if let Some(message_headers) = message.headers() {
+            for header in message_headers.iter() {
+                if let Some(value) = header.value {
+                    let value = String::from_utf8_lossy(value).to_string();
+                    headers.insert(header.key.to_string(), value);
+                }
+            }
+        }
+        let header = |name: &str| headers.get(name).cloned();
+        Envelope {
+            body: String::from_utf8_lossy(message.payload().unwrap_or_default()).to_string(),
+            props: MessageProps {
+                correlation_id: header(CORRELATION_ID_HEADER),
+                reply_to: header(REPLY_TO_HEADER),
+                priority: None,
+                requires: vec![],
+            },
+            attempt: header(ATTEMPT_HEADER)
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(1),
+            failure: header(FAILURE_REASON_HEADER).map(|reason| Failure {
+                reason,
+                exit_code: header(EXIT_CODE_HEADER).and_then(|value| value.parse().ok()),
+            }),
+            not_before: header(NOT_BEFORE_HEADER).and_then(|value| value.parse().ok()),
+        }
+    }
+
+    pub(crate) fn headers(&self) -> OwnedHeaders {
+        let mut headers = vec![(ATTEMPT_HEADER, self.attempt.to_string())];
+        if let Some(correlation_id) = &self.props.correlation_id {
+            headers.push((CORRELATION_ID_HEADER, correlation_id.clone()));
+        }
+        if let Some(reply_to) = &self.props.reply_to {
+            headers.push((REPLY_TO_HEADER, reply_to.clone()));
+        }
+        if let Some(failure) = &self.failure {
+            headers.push((FAILURE_REASON_HEADER, failure.reason.clone()));
+            if let Some(exit_code) = failure.exit_code {
+                headers.push((EXIT_CODE_HEADER, exit_code.to_string()));
+            }
+        }
+        if let Some(not_before) = self.not_before {
+            headers.push((NOT_BEFORE_HEADER, not_before.to_string()));
+        }
+        headers
+            .iter()
+            .fold(OwnedHeaders::new(), |owned, (key, value)| {
+                owned.insert(Header {
+                    key,
+                    value: Some(value.as_str()),
+                })
+            })
+    }
+}
+
+/// Offsets of the messages delivered from each partition along with whether they are settled.
+#[derive(Debug, Default)]
+pub(crate) struct Offsets {
+    partitions: HashMap<(String, i32), BTreeMap<i64, bool>>,
+}
+
+impl Offsets {
+    pub(crate) fn deliver(&mut self, topic: &str, partition: i32, offset: i64) {
+        self.partitions
+            .entry((topic.to_string(), partition))
+            .or_default()
+            .insert(offset, false);
+    }
+
+    /// Marks the message settled, returns the offset to be committed for its partition
+    /// if the messages delivered before it are settled as well.
+    pub(crate) fn settle(&mut self, topic: &str, partition: i32, offset: i64) -> Option<i64> {
+        let offsets = self.partitions.get_mut(&(topic.to_string(), partition))?;
+        *offsets.get_mut(&offset)? = true;
+        let mut committed = None;
+        while let Some(first) = offsets.first_entry() {
+            if !*first.get() {
+                break;
+            }
+            committed = Some(first.key() + 1);
+            first.remove();
+        }
+        committed
+    }
+
+    /// Forgets messages of a partition which was taken away from the consumer,
+    /// they are delivered again to its new owner.
+    pub(crate) fn revoke(&mut self, topic: &str, partition: i32) {
+        self.partitions.remove(&(topic.to_string(), partition));
+    }
+}
+
+// Keeps the offsets of a consumer in step with the partitions assigned to it.
+struct OffsetsContext {
+    offsets: Arc<Mutex<Offsets>>,
+}
+
+impl ClientContext for OffsetsContext {}
+
+impl ConsumerContext for OffsetsContext {
+    fn post_rebalance(&self, _consumer: &BaseConsumer<Self>, rebalance: &Rebalance<'_>) {
+        if let Rebalance::Revoke(partitions) = rebalance {
+            let mut offsets = self.offsets.lock().unwrap();
+            for partition in partitions.elements() {
+                offsets.revoke(partition.topic(), partition.partition());
+            }
+        }
+    }
+
+    fn commit_callback(&self, result: KafkaResult<()>, _offsets: &TopicPartitionList) {
+        if let Err(err) = result {
+            println!("Failed to commit offsets: {}", err);
+        }
+    }
+}
+
+async fn produce(
+    producer: &FutureProducer,
+    topic: &str,
+    envelope: &Envelope,
+) -> Result<(), Box<dyn Error>> {
+    let record = FutureRecord::<(), str>::to(topic)
+        .payload(&envelope.body)
+        .headers(envelope.headers());
+    // the producer retries on its own until the message times out
+    producer
+        .send(record, Timeout::Never)
+        .await
+        .map_err(|(err, _)| err)?;
+    Ok(())
+}
+
+impl KafkaBus {
+    pub async fn new(
+        connection_cfg: config::Connection,
+        credentials: Credentials,
+        bus_params: BusParams,
+    ) -> Result<Self, KafkaBusError> {
+        let BusParams::Kafka(params) = bus_params else {
+            return Err(KafkaBusError::InvalidArgument(
+                "bus params of another driver".to_string(),
+            ));
+        };
+        let (servers, ssl) = bootstrap_servers(connection_cfg)?;
+        let mut config = ClientConfig::new();
+        config.set("bootstrap.servers", servers);
+        match credentials {
+            Credentials::LoginPassword(creds) => {
+                config
+                    .set(
+                        "security.protocol",
+                        if ssl { "SASL_SSL" } else { "SASL_PLAINTEXT" },
+                    )
+                    .set("sasl.mechanisms", &params.sasl_mechanism)
+                    .set("sasl.username", creds.login)
+                    .set("sasl.password", creds.password);
+            }
+            Credentials::TLSClientAuth(auth) => {
+                config
+                    .set("security.protocol", "SSL")
+                    .set("ssl.ca.location", auth.ca_file)
+                    .set("ssl.certificate.location", auth.cert_file)
+                    .set("ssl.key.location", auth.key_file);
+            }
+            Credentials::None => {
+                if ssl {
+                    config.set("security.protocol", "SSL");
+                }
+            }
+        }
+
+        let producer: FutureProducer = config
+            .create()
+            .map_err(|err| KafkaBusError::InvalidArgument(err.to_string()))?;
+        // the client connects lazily, so the cluster is asked for metadata to fail early
+        producer
+            .client()
+            .fetch_metadata(None, REQUEST_TIMEOUT)
+            .map_err(|err| KafkaBusError::ConnectionFailure(err.to_string()))?;
+        let admin = config
+            .create()
+            .map_err(|err| KafkaBusError::InvalidArgument(err.to_string()))?;
+
+        Ok(KafkaBus {
+            config,
+            producer,
+            admin,
+            params,
+            topics: HashMap::new(),
+            created: HashSet::new(),
+            subscription: None,
+            reply_topics: vec![],
+        })
+    }
+
+    /// Sets the settings of topics, tasks of topics with `dead_letter` enabled
+    /// are published to the dead-letter topic when they fail for good.
+    pub fn with_topics(mut self, topics: HashMap<String, TopicConfig>) -> Self {
+        self.topics = topics;
+        self
+    }
+
+    fn dead_letter_topic(&self, topic: &str) -> Option<String> {
+        match self.topics.get(topic) {
+            Some(topic_config) if topic_config.dead_letter => Some(dead_letter_queue(topic)),
+            _ => None,
+        }
+    }
+
+    // Creates the topics which do not exist yet unless it is disabled.
+    async fn create_topics(
+        &mut self,
+        topics: &[String],
+        partitions: i32,
+    ) -> Result<(), Box<dyn Error>> {
+        let missing: Vec<&String> = topics
+            .iter()
+            .filter(|topic| !self.created.contains(*topic))
+            .collect();
+        if missing.is_empty() || !self.params.create_topics {
+            return Ok(());
+        }
+        let new_topics: Vec<NewTopic> = missing
+            .iter()
+            .map(|topic| {
+                NewTopic::new(
+                    topic,
+                    partitions,
+                    TopicReplication::Fixed(self.params.replication),
+                )
+            })
+            .collect();
+        let options = AdminOptions::new().operation_timeout(Some(REQUEST_TIMEOUT));
+        for created in self.admin.create_topics(&new_topics, &options).await? {
+            match created {
+                Ok(_) | Err((_, RDKafkaErrorCode::TopicAlreadyExists)) => {}
+                Err((topic, code)) => {
+                    return Err(format!("Failed to create topic {}: {}", topic, code).into())
+                }
+            }
+        }
+        self.created.extend(missing.into_iter().cloned());
+        Ok(())
+    }
+
+    fn subscription(
+        &self,
+        group: &str,
+        commit_mode: Option<CommitMode>,
+        retry_topic: String,
+        requeue: bool,
+        dead_letter_topic: Option<String>,
+    ) -> KafkaResult<Subscription> {
+        let offsets = Arc::new(Mutex::new(Offsets::default()));
+        let context = OffsetsContext {
+            offsets: Arc::clone(&offsets),
+        };
+        let consumer = self
+            .config
+            .clone()
+            .set("group.id", group)
+            .set("enable.auto.commit", "false")
+            .set("auto.offset.reset", "earliest")
+            .set("partition.assignment.strategy", "cooperative-sticky")
+            .set(
+                "session.timeout.ms",
+                self.params.session_timeout.to_string(),
+            )
+            .create_with_context(context)?;
+        Ok(Subscription {
+            consumer,
+            producer: self.producer.clone(),
+            offsets,
+            commit_mode,
+            retry_topic,
+            requeue,
+            dead_letter_topic,
+        })
+    }
+
+    // Delivers messages of the subscription to the returned stream until the sender is set
+    // or the messages are dropped. No more than `prefetch` messages are delivered without being
+    // settled, unlimited if zero.
+    fn deliver(
+        &self,
+        subscription: Subscription,
+    ) -> (MessageStream, watch::Sender<bool>, JoinHandle<()>) {
+        let (cancel_tx, cancel_rx) = watch::channel(false);
+        let (msg_tx, msg_rx) = mpsc::channel::<Box<dyn Message + Send>>(1);
+        let prefetch = match self.params.prefetch {
+            0 => Semaphore::MAX_PERMITS,
+            prefetch => prefetch as usize,
+        };
+        let feeder = tokio::spawn(deliver(
+            Arc::new(subscription),
+            Arc::new(Semaphore::new(prefetch)),
+            Arc::new(Semaphore::new(MAX_WAITING)),
+            self.params.reconnect.clone(),
+            cancel_rx,
+            msg_tx,
+        ));
+        (Box::pin(ReceiverStream::new(msg_rx)), cancel_tx, feeder)
+    }
+
+    // Partitions of the dead-letter topic with the offsets of their first unsettled and
+    // next published message, the consumer group of the dead-letter topic commits the settled ones.
+    fn dead_letter_ranges(
+        &self,
+        subscription: &Subscription,
+        topic: &str,
+    ) -> KafkaResult<Vec<(i32, i64, i64)>> {
+        let consumer = &subscription.consumer;
+        let metadata = consumer.fetch_metadata(Some(topic), REQUEST_TIMEOUT)?;
+        let mut partitions = TopicPartitionList::new();
+        for partition in metadata
+            .topics()
+            .iter()
+            .filter(|metadata| metadata.error().is_none())
+            .flat_map(|metadata| metadata.partitions())
+        {
+            partitions.add_partition(topic, partition.id());
+        }
+        if partitions.count() == 0 {
+            return Ok(vec![]);
+        }
+        let committed = consumer.committed_offsets(partitions, REQUEST_TIMEOUT)?;
+        let mut ranges = vec![];
+        for partition in committed.elements() {
+            let (low, high) =
+                consumer.fetch_watermarks(topic, partition.partition(), REQUEST_TIMEOUT)?;
+            let start = match partition.offset() {
+                Offset::Offset(offset) => offset.max(low),
+                _ => low,
+            };
+            if start < high {
+                ranges.push((partition.partition(), start, high));
+            }
+        }
+        Ok(ranges)
+    }
+
+    fn dead_letter_subscription(&self, topic: &str) -> KafkaResult<Subscription> {
+        // dead-lettered tasks which are not replayed are published to the end of the topic again
+        self.subscription(
+            &format!("{}.dlq", self.params.group),
+            Some(CommitMode::Sync),
+            topic.to_string(),
+            true,
+            None,
+        )
+    }
+}
+
+// Consumer of a subscription and settings shared by the messages consumed with it.
+struct Subscription {
+    consumer: StreamConsumer<OffsetsContext>,
+    producer: FutureProducer,
+    offsets: Arc<Mutex<Offsets>>,
+    // How settled offsets are committed, not at all for replies.
+    commit_mode: Option<CommitMode>,
+    // Topic requeued and retried messages are published to.
+    retry_topic: String,
+    requeue: bool,
+    dead_letter_topic: Option<String>,
+}
+
+impl Subscription {
+    fn commit(&self, topic: &str, partition: i32, offset: i64) -> KafkaResult<()> {
+        let settled = self
+            .offsets
+            .lock()
+            .unwrap()
+            .settle(topic, partition, offset);
+        let (Some(next), Some(mode)) = (settled, self.commit_mode) else {
+            return Ok(());
+        };
+        let mut offsets = TopicPartitionList::new();
+        offsets.add_partition_offset(topic, partition, Offset::Offset(next))?;
+        self.consumer.commit(&offsets, mode)
+    }
+
+    // Pausing the assigned partitions stops fetching, while polling keeps the worker in the group.
+    fn pause(&self, paused: bool) {
+        let result = self.consumer.assignment().and_then(|assignment| {
+            if paused {
+                self.consumer.pause(&assignment)
+            } else {
+                self.consumer.resume(&assignment)
+            }
+        });
+        if let Err(err) = result {
+            println!("Failed to pause or resume consumption: {}", err);
+        }
+    }
+}
+
+async fn deliver(
+    subscription: Arc<Subscription>,
+    prefetch: Arc<Semaphore>,
+    waiting: Arc<Semaphore>,
+    reconnect: config::ReconnectParams,
+    mut cancel_rx: watch::Receiver<bool>,
+    msg_tx: mpsc::Sender<Box<dyn Message + Send>>,
+) {
+    let mut backoff = Backoff::new(reconnect.clone());
+    // received while paused, e.g. from partitions assigned meanwhile
+    let mut received = VecDeque::new();
+    loop {
+        let permit = match Arc::clone(&prefetch).try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => {
+                subscription.pause(true);
+                let permit = loop {
+                    select! {
+                        permit = Arc::clone(&prefetch).acquire_owned() => {
+                            break permit.expect("semaphore is never closed");
+                        }
+                        message = subscription.consumer.recv() => {
+                            if let Ok(message) = message {
+                                received.push_back(message.detach());
+                            }
+                        }
+                        _ = async { let _ = cancel_rx.wait_for(|cancelled| *cancelled).await; } => return,
+                        _ = msg_tx.closed() => return,
+                    }
+                };
+                subscription.pause(false);
+                permit
+            }
+        };
+        let message = match received.pop_front() {
+            Some(message) => message,
+            None => {
+                let message = select! {
+                    message = subscription.consumer.recv() => message.map(|message| message.detach()),
+                    _ = async { let _ = cancel_rx.wait_for(|cancelled| *cancelled).await; } => return,
+                    _ = msg_tx.closed() => return,
+                };
+                match message {
+                    Ok(message) => {
+                        backoff = Backoff::new(reconnect.clone());
+                        message
+                    }
+                    Err(err) => match backoff.next_delay() {
+                        Some(delay) => {
+                            println!(
+                                "Failed to consume, next attempt in {} ms: {}",
+                                delay.as_millis(),
+                                err
+                            );
+                            sleep(delay).await;
+                            continue;
+                        }
+                        None => {
+                            println!("Giving up consuming: {}", err);
+                            return;
+                        }
+                    },
+                }
+            }
+        };
+
+        subscription.offsets.lock().unwrap().deliver(
+            message.topic(),
+            message.partition(),
+            message.offset(),
+        );
+        let msg = KafkaMessage::new(&subscription, &message);
+        if msg.due_in().is_zero() {
+            msg.permit.lock().unwrap().replace(permit);
+            // an undelivered message is not committed, so it is consumed again
+            if msg_tx.send(Box::new(msg)).await.is_err() {
+                return;
+            }
+            continue;
+        }
+        // waiting tasks take no prefetch slots, but hold back commits of their partition
+        drop(permit);
+        let waiting = select! {
+            permit = Arc::clone(&waiting).acquire_owned() => permit.expect("semaphore is never closed"),
+            _ = async { let _ = cancel_rx.wait_for(|cancelled| *cancelled).await; } => return,
+            _ = msg_tx.closed() => return,
+        };
+        tokio::spawn(wait(msg, waiting, Arc::clone(&prefetch), msg_tx.clone()));
+    }
+}
+
+// Delivers a task once it is due. A task due after `WAIT_HORIZON` is published to the retry topic
+// again with the same due time instead, so that its offset can be committed.
+async fn wait(
+    msg: KafkaMessage,
+    _waiting: OwnedSemaphorePermit,
+    prefetch: Arc<Semaphore>,
+    msg_tx: mpsc::Sender<Box<dyn Message + Send>>,
+) {
+    loop {
+        let due_in = msg.due_in();
+        if due_in <= WAIT_HORIZON {
+            sleep(due_in).await;
+            break;
+        }
+        select! {
+            _ = sleep(WAIT_HORIZON) => {}
+            _ = msg_tx.closed() => return,
+        }
+        let subscription = &msg.subscription;
+        let envelope = &msg.envelope;
+        if let Err(err) = produce(&subscription.producer, &subscription.retry_topic, envelope).await
+        {
+            println!("Failed to publish waiting task again: {}", err);
+            continue;
+        }
+        if let Err(err) = msg.ack().await {
+            println!("Failed to commit waiting task: {}", err);
+        }
+        return;
+    }
+    let permit = select! {
+        permit = prefetch.acquire_owned() => permit.expect("semaphore is never closed"),
+        _ = msg_tx.closed() => return,
+    };
+    msg.permit.lock().unwrap().replace(permit);
+    let _ = msg_tx.send(Box::new(msg)).await;
+}
+
+struct KafkaMessage {
+    subscription: Arc<Subscription>,
+    // Topic the message was consumed from, the retry topic for requeued and retried tasks.
+    topic: String,
+    partition: i32,
+    offset: i64,
+    envelope: Envelope,
+    // Frees a prefetch slot of the subscription once the message is settled.
+    permit: Mutex<Option<OwnedSemaphorePermit>>,
+    settled: AtomicBool,
+}
+
+impl KafkaMessage {
+    fn new(subscription: &Arc<Subscription>, message: &OwnedMessage) -> KafkaMessage {
+        KafkaMessage {
+            subscription: Arc::clone(subscription),
+            topic: message.topic().to_string(),
+            partition: message.partition(),
+            offset: message.offset(),
+            envelope: Envelope::from_message(message),
+            permit: Mutex::new(None),
+            settled: AtomicBool::new(false),
+        }
+    }
+
+    // Commits the message once `publish`ed messages are delivered.
+    async fn settle(&self, publish: Option<(&str, &Envelope)>) -> Result<(), Box<dyn Error>> {
+        if self.settled.swap(true, Ordering::SeqCst) {
+            return Err("Message is already settled".into());
+        }
+        self.permit.lock().unwrap().take();
+        if let Some((topic, envelope)) = publish {
+            produce(&self.subscription.producer, topic, envelope).await?;
+        }
+        self.subscription
+            .commit(&self.topic, self.partition, self.offset)?;
+        Ok(())
+    }
+
+    // Time left until the task is due, zero if it can be executed right away.
+    fn due_in(&self) -> Duration {
+        let not_before = self.envelope.not_before.unwrap_or_default();
+        Duration::from_millis(not_before.saturating_sub(now_ms()))
+    }
+
+    fn requeued(&self) -> Envelope {
+        Envelope {
+            not_before: None,
+            ..self.envelope.clone()
+        }
+    }
+}
+
+impl Drop for KafkaMessage {
+    // like unacknowledged messages of a closed AMQP channel, unsettled messages are redelivered
+    fn drop(&mut self) {
+        if self.settled.load(Ordering::SeqCst) {
+            return;
+        }
+        let subscription = Arc::clone(&self.subscription);
+        let (topic, partition, offset) = (self.topic.clone(), self.partition, self.offset);
+        // a task which is not due yet keeps waiting in the retry topic
+        let envelope = if self.due_in().is_zero() {
+            self.requeued()
+        } else {
+            self.envelope.clone()
+        };
+        if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            runtime.spawn(async move {
+                if produce(&subscription.producer, &subscription.retry_topic, &envelope)
+                    .await
+                    .is_ok()
+                {
+                    let _ = subscription.commit(&topic, partition, offset);
+                }
+            });
+        }
+    }
+}
+
+#[async_trait]
+impl Message for KafkaMessage {
+    async fn ack(&self) -> Result<(), Box<dyn Error>> {
+        self.settle(None).await
+    }
+
+    async fn nack(&self, failure: Failure) -> Result<(), Box<dyn Error>> {
+        if self.subscription.requeue {
+            return self.requeue().await;
+        }
+        self.reject(failure).await
+    }
+
+    async fn reject(&self, failure: Failure) -> Result<(), Box<dyn Error>> {
+        match &self.subscription.dead_letter_topic {
+            Some(topic) => {
+                let envelope = Envelope {
+                    failure: Some(failure),
+                    ..self.requeued()
+                };
+                self.settle(Some((topic, &envelope))).await
+            }
+            None => self.settle(None).await,
+        }
+    }
+
+    async fn requeue(&self) -> Result<(), Box<dyn Error>> {
+        let envelope = self.requeued();
+        self.settle(Some((&self.subscription.retry_topic, &envelope)))
+            .await
+    }
+
+    async fn retry(&self, delay: Duration) -> Result<(), Box<dyn Error>> {
+        let envelope = Envelope {
+            attempt: self.envelope.attempt + 1,
+            not_before: Some(now_ms() + delay.as_millis() as u64),
+            ..self.envelope.clone()
+        };
+        self.settle(Some((&self.subscription.retry_topic, &envelope)))
+            .await
+    }
+
+    async fn postpone(&self, delay: Duration) -> Result<(), Box<dyn Error>> {
+        let envelope = Envelope {
+            not_before: Some(now_ms() + delay.as_millis() as u64),
+            ..self.envelope.clone()
+        };
+        self.settle(Some((&self.subscription.retry_topic, &envelope)))
+            .await
+    }
+
+    async fn reply(&self, msg: String) -> Result<(), Box<dyn Error>> {
+        let Some(reply_to) = &self.envelope.props.reply_to else {
+            return Ok(());
+        };
+        let envelope = Envelope {
+            body: msg,
+            props: MessageProps {
+                correlation_id: self.envelope.props.correlation_id.clone(),
+                reply_to: None,
+                priority: None,
+                requires: vec![],
+            },
+            attempt: 1,
+            failure: None,
+            not_before: None,
+        };
+        produce(&self.subscription.producer, reply_to, &envelope).await
+    }
+
+    fn body(&self) -> String {
+        self.envelope.body.clone()
+    }
+
+    fn delivery_info(&self) -> String {
+        format!(
+            "topic {}, partition {}, offset {}, attempt {}",
+            self.topic, self.partition, self.offset, self.envelope.attempt
+        )
+    }
+
+    fn attempt(&self) -> u32 {
+        self.envelope.attempt
+    }
+
+    fn failure(&self) -> Option<Failure> {
+        self.envelope.failure.clone()
+    }
+}
+
+#[async_trait]
+impl Publisher for KafkaBus {
+    async fn publish(
+        &mut self,
+        topic: String,
+        msg: String,
+        props: MessageProps,
+    ) -> Result<(), Box<dyn Error>> {
+        self.create_topics(std::slice::from_ref(&topic), self.params.partitions)
+            .await?;
+        let envelope = Envelope {
+            body: msg,
+            props,
+            attempt: 1,
+            failure: None,
+            not_before: None,
+        };
+        produce(&self.producer, &topic, &envelope).await
+    }
+
+    // The message waits in the retry topic like retried ones, so that it does not hold back
+    // commits of the topic until it is due.
+    async fn publish_at(
+        &mut self,
+        topic: String,
+        msg: String,
+        props: MessageProps,
+        at: SystemTime,
+    ) -> Result<(), Box<dyn Error>> {
+        let retry_topic = retry_topic(&topic);
+        self.create_topics(&[topic, retry_topic.clone()], self.params.partitions)
+            .await?;
+        let envelope = Envelope {
+            body: msg,
+            props,
+            attempt: 1,
+            failure: None,
+            not_before: Some(at.duration_since(UNIX_EPOCH)?.as_millis() as u64),
+        };
+        produce(&self.producer, &retry_topic, &envelope).await
+    }
+}
+
+#[async_trait]
+impl Consumer for KafkaBus {
+    async fn consume(&mut self, topic: String) -> Result<MessageStream, Box<dyn Error>> {
+        let retry_topic = retry_topic(&topic);
+        let dead_letter_topic = self.dead_letter_topic(&topic);
+        let mut topics = vec![topic.clone(), retry_topic.clone()];
+        topics.extend(dead_letter_topic.clone());
+        self.create_topics(&topics, self.params.partitions).await?;
+
+        let subscription = self.subscription(
+            &self.params.group,
+            Some(CommitMode::Async),
+            retry_topic.clone(),
+            self.params.requeue,
+            dead_letter_topic,
+        )?;
+        subscription.consumer.subscribe(&[&topic, &retry_topic])?;
+        let (stream, cancel, feeder) = self.deliver(subscription);
+        self.subscription = Some((cancel, feeder));
+        Ok(stream)
+    }
+
+    async fn cancel(&mut self) -> Result<(), Box<dyn Error>> {
+        if let Some((cancel, _)) = &self.subscription {
+            let _ = cancel.send(true);
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl ReplyConsumer for KafkaBus {
+    async fn consume_replies(&mut self) -> Result<(String, MessageStream), Box<dyn Error>> {
+        let topic = format!("mqdish.reply.{}", Uuid::new_v4());
+        self.create_topics(std::slice::from_ref(&topic), 1).await?;
+        // the only partition is read by this consumer alone, so no group offsets are committed
+        let subscription = self.subscription(&topic, None, topic.clone(), false, None)?;
+        let mut assignment = TopicPartitionList::new();
+        assignment.add_partition_offset(&topic, 0, Offset::Beginning)?;
+        subscription.consumer.assign(&assignment)?;
+        let (stream, cancel, feeder) = self.deliver(subscription);
+        self.reply_topics.push((topic.clone(), cancel, feeder));
+        Ok((topic, stream))
+    }
+}
+
+#[async_trait]
+impl DeadLetters for KafkaBus {
+    async fn dead_letters(
+        &mut self,
+        topic: String,
+    ) -> Result<Vec<Box<dyn Message + Send>>, Box<dyn Error>> {
+        let topic = dead_letter_queue(&topic);
+        let subscription = Arc::new(self.dead_letter_subscription(&topic)?);
+        let ranges = self.dead_letter_ranges(&subscription, &topic)?;
+        let mut assignment = TopicPartitionList::new();
+        let mut ends = HashMap::new();
+        for (partition, start, end) in ranges {
+            assignment.add_partition_offset(&topic, partition, Offset::Offset(start))?;
+            ends.insert(partition, end);
+        }
+        subscription.consumer.assign(&assignment)?;
+
+        let mut messages: Vec<Box<dyn Message + Send>> = vec![];
+        while !ends.is_empty() {
+            let Ok(message) = timeout(REQUEST_TIMEOUT, subscription.consumer.recv()).await else {
+                println!("Timed out reading {}, some tasks are not listed", topic);
+                break;
+            };
+            let message = message?.detach();
+            if ends
+                .get(&message.partition())
+                .is_some_and(|end| message.offset() + 1 >= *end)
+            {
+                ends.remove(&message.partition());
+            }
+            subscription.offsets.lock().unwrap().deliver(
+                &topic,
+                message.partition(),
+                message.offset(),
+            );
+            messages.push(Box::new(KafkaMessage::new(&subscription, &message)));
+        }
+        Ok(messages)
+    }
+
+    async fn purge_dead_letters(&mut self, topic: String) -> Result<u32, Box<dyn Error>> {
+        let topic = dead_letter_queue(&topic);
+        let subscription = self.dead_letter_subscription(&topic)?;
+        let ranges = self.dead_letter_ranges(&subscription, &topic)?;
+        if ranges.is_empty() {
+            return Ok(0);
+        }
+        // the records stay in the topic until its retention, they are skipped by committing
+        let mut offsets = TopicPartitionList::new();
+        let mut purged = 0;
+        for (partition, start, end) in ranges {
+            offsets.add_partition_offset(&topic, partition, Offset::Offset(end))?;
+            purged += end - start;
+        }
+        subscription.consumer.commit(&offsets, CommitMode::Sync)?;
+        Ok(purged as u32)
+    }
+}
+
+#[async_trait]
+impl Closer for KafkaBus {
+    async fn close(&mut self) -> Result<(), Box<dyn Error>> {
+        self.cancel().await?;
+        // the consumer leaves the group once the subscription is dropped by the feeder
+        if let Some((_, feeder)) = self.subscription.take() {
+            let _ = feeder.await;
+        }
+        let mut reply_topics = vec![];
+        for (topic, cancel, feeder) in self.reply_topics.drain(..) {
+            let _ = cancel.send(true);
+            let _ = feeder.await;
+            reply_topics.push(topic);
+        }
+        if !reply_topics.is_empty() {
+            let reply_topics: Vec<&str> = reply_topics.iter().map(String::as_str).collect();
+            self.admin
+                .delete_topics(&reply_topics, &AdminOptions::new())
+                .await?;
+        }
+        self.producer.flush(REQUEST_TIMEOUT)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,352 @@
+use crate::shared::config::{BusParams, Connection, ConnectionParams, Credentials, KafkaParams};
+use crate::shared::msgbus::bus::*;
+use crate::shared::msgbus::kafka::*;
+use rdkafka::admin::{AdminClient, AdminOptions, NewTopic, TopicReplication};
+use rdkafka::client::DefaultClientContext;
+use rdkafka::config::ClientConfig;
+use rdkafka::consumer::{BaseConsumer, Consumer as _};
+use rdkafka::message::OwnedMessage;
+use rdkafka::producer::{FutureProducer, FutureRecord};
+use rdkafka::util::Timeout;
+use rdkafka::{Offset, Timestamp, TopicPartitionList};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::time::{sleep, timeout};
+use tokio_stream::StreamExt;
+use uuid::Uuid;
+
+// Bootstrap servers of the Kafka cluster the integration tests run against, e.g. `localhost:9092`.
+// They are skipped if it is not set, each one uses topics and a consumer group of its own.
+const SERVER_VAR: &str = "MQDISH_TEST_KAFKA";
+// Joining the consumer group takes a while before the first message is delivered.
+const FIRST_DELIVERY: Duration = Duration::from_secs(20);
+// Long enough for a message to be delivered if it is available.
+const NO_DELIVERY: Duration = Duration::from_millis(1000);
+const COMMIT_TIMEOUT: Duration = Duration::from_secs(10);
+
+struct TestServer {
+    servers: String,
+    topic: String,
+    group: String,
+}
+
+impl TestServer {
+    fn new() -> Option<Self> {
+        let Ok(servers) = std::env::var(SERVER_VAR) else {
+            println!("{} is not set, skipping", SERVER_VAR);
+            return None;
+        };
+        Some(TestServer {
+            servers,
+            topic: format!("mqdish-test-{}", Uuid::new_v4()),
+            group: format!("mqdish-test-{}", Uuid::new_v4()),
+        })
+    }
+
+    async fn bus(&self, prefetch: u16, partitions: i32) -> KafkaBus {
+        let params = KafkaParams {
+            group: self.group.clone(),
+            prefetch,
+            partitions,
+            session_timeout: 10_000,
+            ..KafkaParams::default()
+        };
+        KafkaBus::new(
+            Connection::DSN(self.servers.clone()),
+            Credentials::None,
+            BusParams::Kafka(params),
+        )
+        .await
+        .unwrap()
+    }
+
+    fn config(&self) -> ClientConfig {
+        let mut config = ClientConfig::new();
+        config.set("bootstrap.servers", &self.servers);
+        config
+    }
+
+    // Publishes a task without headers to the given partition of the topic.
+    async fn publish_to(&self, partition: i32, body: &str) {
+        let producer: FutureProducer = self.config().create().unwrap();
+        let record = FutureRecord::<(), str>::to(&self.topic)
+            .partition(partition)
+            .payload(body);
+        producer.send(record, Timeout::Never).await.unwrap();
+    }
+
+    // Offsets of the partitions of the topic committed by the consumer group.
+    fn committed(&self, partitions: i32) -> Vec<Option<i64>> {
+        let consumer: BaseConsumer = self.config().set("group.id", &self.group).create().unwrap();
+        let mut list = TopicPartitionList::new();
+        for partition in 0..partitions {
+            list.add_partition(&self.topic, partition);
+        }
+        let committed = consumer
+            .committed_offsets(list, Duration::from_secs(10))
+            .unwrap();
+        committed
+            .elements()
+            .iter()
+            .map(|element| match element.offset() {
+                Offset::Offset(offset) => Some(offset),
+                _ => None,
+            })
+            .collect()
+    }
+
+    // Waits for the offsets to be committed, they are committed asynchronously.
+    async fn wait_committed(&self, expected: &[Option<i64>]) {
+        let started = Instant::now();
+        while self.committed(expected.len() as i32) != expected
+            && started.elapsed() < COMMIT_TIMEOUT
+        {
+            sleep(Duration::from_millis(200)).await;
+        }
+        assert_eq!(self.committed(expected.len() as i32), expected);
+    }
+
+    async fn create_topic(&self, topic: &str, partitions: i32) {
+        let admin: AdminClient<DefaultClientContext> = self.config().create().unwrap();
+        let topic = NewTopic::new(topic, partitions, TopicReplication::Fixed(-1));
+        for created in admin
+            .create_topics(&[topic], &AdminOptions::new())
+            .await
+            .unwrap()
+        {
+            created.unwrap();
+        }
+    }
+
+    async fn clean_up(&self) {
+        let admin: AdminClient<DefaultClientContext> = self.config().create().unwrap();
+        let topics = [
+            self.topic.clone(),
+            retry_topic(&self.topic),
+            dead_letter_queue(&self.topic),
+        ];
+        let topics: Vec<&str> = topics.iter().map(String::as_str).collect();
+        let _ = admin.delete_topics(&topics, &AdminOptions::new()).await;
+    }
+}
+
+async fn next(stream: &mut MessageStream, wait: Duration) -> Option<Box<dyn Message + Send>> {
+    timeout(wait, stream.next()).await.ok().flatten()
+}
+
+#[test]
+fn test_bootstrap_servers() {
+    let servers = |dsn: &str| bootstrap_servers(Connection::DSN(dsn.to_string())).ok();
+    assert_eq!(
+        servers("kafka://k1:9092,k2:9092/"),
+        Some(("k1:9092,k2:9092".to_string(), false))
+    );
+    assert_eq!(
+        servers("kafka+ssl://k1:9093"),
+        Some(("k1:9093".to_string(), true))
+    );
+    assert_eq!(servers("k1:9092"), Some(("k1:9092".to_string(), false)));
+    assert_eq!(servers("amqp://k1"), None);
+    assert_eq!(servers("kafka://"), None);
+    let params = Connection::Params(ConnectionParams {
+        host: "k1".to_string(),
+        port: 9093,
+        ssl: true,
+    });
+    assert_eq!(
+        bootstrap_servers(params).ok(),
+        Some(("k1:9093".to_string(), true))
+    );
+}
+
+#[test]
+fn test_envelope_headers() {
+    let envelope = Envelope {
+        body: "task".to_string(),
+        props: MessageProps {
+            correlation_id: Some("id".to_string()),
+            reply_to: Some("mqdish.reply.1".to_string()),
+            priority: None,
+            requires: vec![],
+        },
+        attempt: 3,
+        failure: Some(Failure {
+            reason: "failed".to_string(),
+            exit_code: Some(2),
+        }),
+        not_before: Some(1_700_000_000_000),
+    };
+    let message = OwnedMessage::new(
+        Some(envelope.body.clone().into_bytes()),
+        None,
+        "tasks".to_string(),
+        Timestamp::NotAvailable,
+        0,
+        0,
+        Some(envelope.headers()),
+    );
+    assert_eq!(Envelope::from_message(&message), envelope);
+
+    let bare = OwnedMessage::new(
+        Some(b"task".to_vec()),
+        None,
+        "tasks".to_string(),
+        Timestamp::NotAvailable,
+        0,
+        0,
+        None,
+    );
+    let bare = Envelope::from_message(&bare);
+    assert_eq!(bare.attempt, 1);
+    assert_eq!(bare.props, MessageProps::default());
+    assert!(bare.failure.is_none() && bare.not_before.is_none());
+}
+
+#[test]
+fn test_offsets_committed_without_gaps() {
+    let mut offsets = Offsets::default();
+    for offset in 5..8 {
+        offsets.deliver("tasks", 0, offset);
+    }
+    offsets.deliver("tasks", 1, 0);
+
+    // the first message is still running, so nothing can be committed
+    assert_eq!(offsets.settle("tasks", 0, 6), None);
+    assert_eq!(offsets.settle("tasks", 1, 0), Some(1));
+    assert_eq!(offsets.settle("tasks", 0, 5), Some(7));
+    assert_eq!(offsets.settle("tasks", 0, 5), None);
+
+    offsets.revoke("tasks", 0);
+    assert_eq!(offsets.settle("tasks", 0, 7), None);
+}
+
+#[tokio::test]
+async fn test_offset_committed_after_ack() {
+    let Some(server) = TestServer::new() else {
+        return;
+    };
+    let mut bus = server.bus(2, 1).await;
+    let mut stream = bus.consume(server.topic.clone()).await.unwrap();
+    server.publish_to(0, "first").await;
+    server.publish_to(0, "second").await;
+    let first = next(&mut stream, FIRST_DELIVERY).await.unwrap();
+    let second = next(&mut stream, NO_DELIVERY).await.unwrap();
+    assert_eq!(first.body(), "first");
+    assert_eq!(server.committed(1), vec![None]);
+
+    // the first task is still running, so the offset of the second one is not committed yet
+    second.ack().await.unwrap();
+    sleep(NO_DELIVERY).await;
+    assert_eq!(server.committed(1), vec![None]);
+    first.ack().await.unwrap();
+    server.wait_committed(&[Some(2)]).await;
+    bus.close().await.unwrap();
+    server.clean_up().await;
+}
+
+#[tokio::test]
+async fn test_retry_waits_in_retry_topic() {
+    let Some(server) = TestServer::new() else {
+        return;
+    };
+    let mut bus = server.bus(1, 1).await;
+    let mut stream = bus.consume(server.topic.clone()).await.unwrap();
+    server.publish_to(0, "failing").await;
+    let failing = next(&mut stream, FIRST_DELIVERY).await.unwrap();
+    let retried_at = Instant::now();
+    failing.retry(Duration::from_secs(3)).await.unwrap();
+    // the task is committed once it is published to the retry topic
+    server.wait_committed(&[Some(1)]).await;
+
+    // the waiting retry takes no prefetch slot
+    server.publish_to(0, "other").await;
+    let other = next(&mut stream, NO_DELIVERY * 2).await.unwrap();
+    assert_eq!(other.body(), "other");
+    other.ack().await.unwrap();
+
+    let retried = next(&mut stream, FIRST_DELIVERY).await.unwrap();
+    assert!(retried_at.elapsed() >= Duration::from_secs(3));
+    assert_eq!((retried.body().as_str(), retried.attempt()), ("failing", 2));
+    retried.ack().await.unwrap();
+    bus.close().await.unwrap();
+    server.clean_up().await;
+}
+
+#[tokio::test]
+async fn test_delayed_message_waits_in_retry_topic() {
+    let Some(server) = TestServer::new() else {
+        return;
+    };
+    let mut bus = server.bus(1, 1).await;
+    let at = SystemTime::now() + Duration::from_secs(3);
+    bus.publish_at(
+        server.topic.clone(),
+        "delayed".to_string(),
+        MessageProps::default(),
+        at,
+    )
+    .await
+    .unwrap();
+    let mut stream = bus.consume(server.topic.clone()).await.unwrap();
+
+    // the waiting message takes no prefetch slot
+    server.publish_to(0, "other").await;
+    let other = next(&mut stream, FIRST_DELIVERY).await.unwrap();
+    assert_eq!(other.body(), "other");
+    other.ack().await.unwrap();
+
+    let delayed = next(&mut stream, FIRST_DELIVERY).await.unwrap();
+    assert!(SystemTime::now() >= at);
+    assert_eq!((delayed.body().as_str(), delayed.attempt()), ("delayed", 1));
+    // postponing it is not counted as an attempt
+    delayed.postpone(Duration::from_millis(100)).await.unwrap();
+    let postponed = next(&mut stream, FIRST_DELIVERY).await.unwrap();
+    assert_eq!(postponed.attempt(), 1);
+    postponed.ack().await.unwrap();
+    bus.close().await.unwrap();
+    server.clean_up().await;
+}
+
+#[tokio::test]
+async fn test_revoked_partitions_are_redelivered() {
+    let Some(server) = TestServer::new() else {
+        return;
+    };
+    // the retry topic has a single partition, so the joining worker takes over
+    // at least one partition of the topic
+    server.create_topic(&retry_topic(&server.topic), 1).await;
+    let mut first = server.bus(0, 4).await;
+    let mut first_stream = first.consume(server.topic.clone()).await.unwrap();
+    for partition in 0..4 {
+        server.publish_to(partition, &partition.to_string()).await;
+    }
+    let mut running = vec![];
+    for _ in 0..4 {
+        running.push(next(&mut first_stream, FIRST_DELIVERY).await.unwrap());
+    }
+
+    // unsettled tasks of the revoked partitions are delivered to their new owner
+    let mut second = server.bus(0, 4).await;
+    let mut second_stream = second.consume(server.topic.clone()).await.unwrap();
+    let mut taken_over = vec![next(&mut second_stream, FIRST_DELIVERY).await.unwrap()];
+    while let Some(msg) = next(&mut second_stream, NO_DELIVERY).await {
+        taken_over.push(msg);
+    }
+    assert!(taken_over.len() < 4);
+
+    // settling them on the first worker commits nothing for the revoked partitions
+    for msg in &running {
+        msg.ack().await.unwrap();
+    }
+    let mut expected = vec![Some(1); 4];
+    for msg in &taken_over {
+        expected[msg.body().parse::<usize>().unwrap()] = None;
+    }
+    server.wait_committed(&expected).await;
+    for msg in &taken_over {
+        msg.ack().await.unwrap();
+    }
+    server.wait_committed(&[Some(1); 4]).await;
+    first.close().await.unwrap();
+    second.close().await.unwrap();
+    server.clean_up().await;
+}
@@ -0,0 +1,420 @@
+use crate::shared::config::{MemoryParams, TopicConfig};
+use crate::shared::msgbus::bus::{
+    dead_letter_queue, Closer, Consumer, DeadLetters, Failure, Message, MessageProps,
+    MessageStream, Publisher, ReplyConsumer,
+};
+use async_trait::async_trait;
+use std::collections::{HashMap, VecDeque};
+use std::error::Error;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::select;
+use tokio::sync::{mpsc, watch, Notify, OwnedSemaphorePermit, Semaphore};
+use tokio::time::sleep;
+use tokio_stream::wrappers::ReceiverStream;
+use uuid::Uuid;
+
+/// Message bus living in the memory of the process, for tests and single machine setups.
+/// Every bus connected to the same broker with `connect` shares its queues.
+pub struct MemoryBus {
+    broker: Arc<Broker>,
+    prefetch: u16,
+    requeue: bool,
+    topics: HashMap<String, TopicConfig>,
+    // Stops the subscription created by `consume`.
+    consumer: Option<watch::Sender<bool>>,
+    // Holds back delivery of the subscriptions while set.
+    paused: watch::Sender<bool>,
+    // Reply queues with their subscriptions, removed on close.
+    reply_queues: Vec<(String, watch::Sender<bool>)>,
+}
+
+#[derive(Default)]
+struct Broker {
+    queues: Mutex<HashMap<String, Queue>>,
+}
+
+#[derive(Default)]
+struct Queue {
+    messages: VecDeque<Envelope>,
+    published: Arc<Notify>,
+}
+
+#[derive(Clone)]
+struct Envelope {
+    body: String,
+    props: MessageProps,
+    attempt: u32,
+    failure: Option<Failure>,
+}
+
+impl Broker {
+    // Appends the message to the queue, the queue is created if it does not exist.
+    fn push(&self, queue: &str, envelope: Envelope) {
+        let mut queues = self.queues.lock().unwrap();
+        let queue = queues.entry(queue.to_string()).or_default();
+        queue.messages.push_back(envelope);
+        queue.published.notify_waiters();
+    }
+
+    fn pop(&self, queue: &str) -> Option<Envelope> {
+        let mut queues = self.queues.lock().unwrap();
+        queues.get_mut(queue)?.messages.pop_front()
+    }
+
+    fn published(&self, queue: &str) -> Arc<Notify> {
+        let mut queues = self.queues.lock().unwrap();
+        let queue = queues.entry(queue.to_string()).or_default();
+        Arc::clone(&queue.published)
+    }
+
+    // Waits for the next message of the queue and takes it.
+    async fn next(&self, queue: &str) -> Envelope {
+        let published = self.published(queue);
+        loop {
+            // registered before checking the queue, so a message published in between is not missed
+            let notified = published.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+            if let Some(envelope) = self.pop(queue) {
+                return envelope;
+            }
+            notified.await;
+        }
+    }
+
+    fn take_all(&self, queue: &str) -> Vec<Envelope> {
+        let mut queues = self.queues.lock().unwrap();
+        match queues.get_mut(queue) {
+            Some(queue) => queue.messages.drain(..).collect(),
+            None => vec![],
+        }
+    }
+
+    fn remove(&self, queue: &str) {
+        self.queues.lock().unwrap().remove(queue);
+    }
+}
+
+impl MemoryBus {
+    pub fn new(params: MemoryParams) -> Self {
+        MemoryBus {
+            broker: Arc::new(Broker::default()),
+            prefetch: params.prefetch,
+            requeue: params.requeue,
+            topics: HashMap::new(),
+            consumer: None,
+            paused: watch::channel(false).0,
+            reply_queues: vec![],
+        }
+    }
+
+    /// Returns another bus with the same settings sharing the queues of this one.
+    pub fn connect(&self) -> Self {
+        MemoryBus {
+            broker: Arc::clone(&self.broker),
+            prefetch: self.prefetch,
+            requeue: self.requeue,
+            topics: self.topics.clone(),
+            consumer: None,
+            paused: watch::channel(false).0,
+            reply_queues: vec![],
+        }
+    }
+
+    /// Sets the settings of topics, tasks of topics with `dead_letter` enabled
+    /// are moved to the dead-letter queue when they fail for good.
+    pub fn with_topics(mut self, topics: HashMap<String, TopicConfig>) -> Self {
+        self.topics = topics;
+        self
+    }
+
+    /// Number of messages waiting in the queue, not counting delivered ones.
+    pub fn queue_len(&self, queue: &str) -> usize {
+        let queues = self.broker.queues.lock().unwrap();
+        queues.get(queue).map_or(0, |queue| queue.messages.len())
+    }
+
+    fn dead_letter_queue(&self, topic: &str) -> Option<String> {
+        match self.topics.get(topic) {
+            Some(topic_config) if topic_config.dead_letter => Some(dead_letter_queue(topic)),
+            _ => None,
+        }
+    }
+
+    // Delivers messages of the queue to the returned stream until the sender is set or dropped.
+    // No more than `prefetch` messages are delivered without being settled, unlimited if zero,
+    // and none while `paused` is set.
+    fn subscribe(
+        &self,
+        queue: String,
+        requeue: bool,
+        mut paused: watch::Receiver<bool>,
+    ) -> (MessageStream, watch::Sender<bool>) {
+        let (cancel_tx, mut cancel_rx) = watch::channel(false);
+        let (msg_tx, msg_rx) = mpsc::channel::<Box<dyn Message + Send>>(1);
+        let prefetch = match self.prefetch {
+            0 => Semaphore::MAX_PERMITS,
+            prefetch => prefetch as usize,
+        };
+        let prefetch = Arc::new(Semaphore::new(prefetch));
+        let broker = Arc::clone(&self.broker);
+        let dead_letter_queue = self.dead_letter_queue(&queue);
+
+        tokio::spawn(async move {
+            let cancelled = async move {
+                let _ = cancel_rx.wait_for(|cancelled| *cancelled).await;
+            };
+            tokio::pin!(cancelled);
+            loop {
+                select! {
+                    _ = paused.wait_for(|paused| !*paused) => {}
+                    _ = &mut cancelled => break,
+                    _ = msg_tx.closed() => break,
+                }
+                let permit = select! {
+                    permit = Arc::clone(&prefetch).acquire_owned() => {
+                        permit.expect("semaphore is never closed")
+                    }
+                    _ = &mut cancelled => break,
+                    _ = msg_tx.closed() => break,
+                };
+                let envelope = select! {
+                    envelope = broker.next(&queue) => envelope,
+                    _ = &mut cancelled => break,
+                    _ = msg_tx.closed() => break,
+                };
+                let msg = MemoryMessage {
+                    broker: Arc::clone(&broker),
+                    queue: queue.clone(),
+                    envelope,
+                    requeue,
+                    dead_letter_queue: dead_letter_queue.clone(),
+                    permit: Mutex::new(Some(permit)),
+                    settled: AtomicBool::new(false),
+                };
+                // a message which could not be delivered is returned to the queue when dropped
+                if msg_tx.send(Box::new(msg)).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        (Box::pin(ReceiverStream::new(msg_rx)), cancel_tx)
+    }
+}
+
+struct MemoryMessage {
+    broker: Arc<Broker>,
+    queue: String,
+    envelope: Envelope,
+    requeue: bool,
+    dead_letter_queue: Option<String>,
+    // Frees a prefetch slot of the subscription once the message is settled.
+    permit: Mutex<Option<OwnedSemaphorePermit>>,
+    settled: AtomicBool,
+}
+
+impl MemoryMessage {
+    fn settle(&self) -> Result<(), Box<dyn Error>> {
+        if self.settled.swap(true, Ordering::SeqCst) {
+            return Err("Message is already settled".into());
+        }
+        self.permit.lock().unwrap().take();
+        Ok(())
+    }
+
+    fn push_after(&self, delay: Duration, envelope: Envelope) -> Result<(), Box<dyn Error>> {
+        self.settle()?;
+        let broker = Arc::clone(&self.broker);
+        let queue = self.queue.clone();
+        tokio::spawn(async move {
+            sleep(delay).await;
+            broker.push(&queue, envelope);
+        });
+        Ok(())
+    }
+}
+
+impl Drop for MemoryMessage {
+    // like unacknowledged messages of a closed channel, unsettled messages are redelivered
+    fn drop(&mut self) {
+        if !self.settled.load(Ordering::SeqCst) {
+            self.broker.push(&self.queue, self.envelope.clone());
+        }
+    }
+}
+
+#[async_trait]
+impl Message for MemoryMessage {
+    async fn ack(&self) -> Result<(), Box<dyn Error>> {
+        self.settle()
+    }
+
+    async fn nack(&self, failure: Failure) -> Result<(), Box<dyn Error>> {
+        if self.requeue {
+            return self.requeue().await;
+        }
+        self.reject(failure).await
+    }
+
+    async fn reject(&self, failure: Failure) -> Result<(), Box<dyn Error>> {
+        self.settle()?;
+        if let Some(queue) = &self.dead_letter_queue {
+            let envelope = Envelope {
+                failure: Some(failure),
+                ..self.envelope.clone()
+            };
+            self.broker.push(queue, envelope);
+        }
+        Ok(())
+    }
+
+    async fn requeue(&self) -> Result<(), Box<dyn Error>> {
+        self.settle()?;
+        self.broker.push(&self.queue, self.envelope.clone());
+        Ok(())
+    }
+
+    async fn retry(&self, delay: Duration) -> Result<(), Box<dyn Error>> {
+        let envelope = Envelope {
+            attempt: self.envelope.attempt + 1,
+            ..self.envelope.clone()
+        };
+        self.push_after(delay, envelope)
+    }
+
+    async fn postpone(&self, delay: Duration) -> Result<(), Box<dyn Error>> {
+        self.push_after(delay, self.envelope.clone())
+    }
+
+    async fn reply(&self, msg: String) -> Result<(), Box<dyn Error>> {
+        let Some(reply_to) = &self.envelope.props.reply_to else {
+            return Ok(());
+        };
+        let envelope = Envelope {
+            body: msg,
+            props: MessageProps {
+                correlation_id: self.envelope.props.correlation_id.clone(),
+                reply_to: None,
+                priority: None,
+                requires: vec![],
+            },
+            attempt: 1,
+            failure: None,
+        };
+        self.broker.push(reply_to, envelope);
+        Ok(())
+    }
+
+    fn body(&self) -> String {
+        self.envelope.body.clone()
+    }
+
+    fn delivery_info(&self) -> String {
+        format!("queue {}, attempt {}", self.queue, self.envelope.attempt)
+    }
+
+    fn attempt(&self) -> u32 {
+        self.envelope.attempt
+    }
+
+    fn failure(&self) -> Option<Failure> {
+        self.envelope.failure.clone()
+    }
+}
+
+#[async_trait]
+impl Publisher for MemoryBus {
+    async fn publish(
+        &mut self,
+        topic: String,
+        msg: String,
+        props: MessageProps,
+    ) -> Result<(), Box<dyn Error>> {
+        let envelope = Envelope {
+            body: msg,
+            props,
+            attempt: 1,
+            failure: None,
+        };
+        self.broker.push(&topic, envelope);
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Consumer for MemoryBus {
+    async fn consume(&mut self, topic: String) -> Result<MessageStream, Box<dyn Error>> {
+        let (stream, cancel) = self.subscribe(topic, self.requeue, self.paused.subscribe());
+        self.consumer = Some(cancel);
+        Ok(stream)
+    }
+
+    async fn cancel(&mut self) -> Result<(), Box<dyn Error>> {
+        if let Some(cancel) = &self.consumer {
+            let _ = cancel.send(true);
+        }
+        Ok(())
+    }
+
+    async fn pause(&mut self, paused: bool) -> Result<(), Box<dyn Error>> {
+        self.paused.send_replace(paused);
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl ReplyConsumer for MemoryBus {
+    async fn consume_replies(&mut self) -> Result<(String, MessageStream), Box<dyn Error>> {
+        let queue = format!("mqdish.reply.{}", Uuid::new_v4());
+        let (stream, cancel) = self.subscribe(queue.clone(), false, watch::channel(false).1);
+        self.reply_queues.push((queue.clone(), cancel));
+        Ok((queue, stream))
+    }
+}
+
+#[async_trait]
+impl DeadLetters for MemoryBus {
+    async fn dead_letters(
+        &mut self,
+        topic: String,
+    ) -> Result<Vec<Box<dyn Message + Send>>, Box<dyn Error>> {
+        let queue = dead_letter_queue(&topic);
+        let messages = self
+            .broker
+            .take_all(&queue)
+            .into_iter()
+            .map(|envelope| {
+                Box::new(MemoryMessage {
+                    broker: Arc::clone(&self.broker),
+                    queue: queue.clone(),
+                    envelope,
+                    requeue: true,
+                    dead_letter_queue: None,
+                    permit: Mutex::new(None),
+                    settled: AtomicBool::new(false),
+                }) as Box<dyn Message + Send>
+            })
+            .collect();
+        Ok(messages)
+    }
+
+    async fn purge_dead_letters(&mut self, topic: String) -> Result<u32, Box<dyn Error>> {
+        let purged = self.broker.take_all(&dead_letter_queue(&topic));
+        Ok(purged.len() as u32)
+    }
+}
+
+#[async_trait]
+impl Closer for MemoryBus {
+    async fn close(&mut self) -> Result<(), Box<dyn Error>> {
+        self.cancel().await?;
+        for (queue, cancel) in self.reply_queues.drain(..) {
+            let _ = cancel.send(true);
+            self.broker.remove(&queue);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,130 @@
+use crate::shared::config::{MemoryParams, TopicConfig};
+use crate::shared::msgbus::bus::*;
+use crate::shared::msgbus::memory::*;
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::time::timeout;
+use tokio_stream::StreamExt;
+
+const TOPIC: &str = "test";
+// Long enough for a message to be delivered if it is available.
+const NO_DELIVERY: Duration = Duration::from_millis(100);
+
+fn bus(prefetch: u16, requeue: bool) -> MemoryBus {
+    MemoryBus::new(MemoryParams { prefetch, requeue })
+}
+
+async fn publish(bus: &mut MemoryBus, topic: &str, body: &str) {
+    bus.publish(topic.to_string(), body.to_string(), MessageProps::default())
+        .await
+        .unwrap();
+}
+
+async fn next(stream: &mut MessageStream) -> Option<Box<dyn Message + Send>> {
+    timeout(NO_DELIVERY, stream.next()).await.ok().flatten()
+}
+
+#[tokio::test]
+async fn test_prefetch_limit() {
+    let mut bus = bus(1, false);
+    publish(&mut bus, TOPIC, "first").await;
+    publish(&mut bus, TOPIC, "second").await;
+    let mut stream = bus.consume(TOPIC.to_string()).await.unwrap();
+
+    let first = next(&mut stream).await.unwrap();
+    assert_eq!(first.body(), "first");
+    assert!(next(&mut stream).await.is_none());
+
+    first.ack().await.unwrap();
+    assert_eq!(next(&mut stream).await.unwrap().body(), "second");
+}
+
+#[tokio::test]
+async fn test_requeue() {
+    let mut bus = bus(0, true);
+    publish(&mut bus, TOPIC, "task").await;
+    let mut stream = bus.consume(TOPIC.to_string()).await.unwrap();
+
+    let msg = next(&mut stream).await.unwrap();
+    msg.nack(Failure {
+        reason: "failed".to_string(),
+        exit_code: Some(1),
+    })
+    .await
+    .unwrap();
+    assert!(msg.ack().await.is_err());
+
+    let msg = next(&mut stream).await.unwrap();
+    assert_eq!(msg.body(), "task");
+    assert_eq!(msg.attempt(), 1);
+    // unsettled messages are returned to the queue
+    drop(msg);
+    assert_eq!(next(&mut stream).await.unwrap().body(), "task");
+}
+
+#[tokio::test]
+async fn test_dead_letter() {
+    let topics = HashMap::from([(
+        TOPIC.to_string(),
+        TopicConfig {
+            dead_letter: true,
+            ..TopicConfig::default()
+        },
+    )]);
+    let mut bus = bus(0, false).with_topics(topics);
+    publish(&mut bus, TOPIC, "task").await;
+    let mut stream = bus.consume(TOPIC.to_string()).await.unwrap();
+
+    let failure = Failure {
+        reason: "failed".to_string(),
+        exit_code: Some(2),
+    };
+    next(&mut stream)
+        .await
+        .unwrap()
+        .nack(failure.clone())
+        .await
+        .unwrap();
+    assert!(next(&mut stream).await.is_none());
+
+    let dead_letters = bus.dead_letters(TOPIC.to_string()).await.unwrap();
+    assert_eq!(dead_letters.len(), 1);
+    assert_eq!(dead_letters[0].failure(), Some(failure));
+    drop(dead_letters);
+    assert_eq!(bus.purge_dead_letters(TOPIC.to_string()).await.unwrap(), 1);
+}
+
+#[tokio::test]
+async fn test_retry_and_reply() {
+    let mut bus = bus(0, false);
+    let mut producer = bus.connect();
+    let (reply_to, mut replies) = producer.consume_replies().await.unwrap();
+    let props = MessageProps {
+        correlation_id: Some("id".to_string()),
+        reply_to: Some(reply_to),
+        priority: None,
+        requires: vec![],
+    };
+    producer
+        .publish(TOPIC.to_string(), "task".to_string(), props)
+        .await
+        .unwrap();
+    let mut stream = bus.consume(TOPIC.to_string()).await.unwrap();
+
+    let msg = next(&mut stream).await.unwrap();
+    msg.reply("result".to_string()).await.unwrap();
+    msg.retry(Duration::from_millis(10)).await.unwrap();
+    assert_eq!(next(&mut replies).await.unwrap().body(), "result");
+    assert_eq!(next(&mut stream).await.unwrap().attempt(), 2);
+}
+
+#[tokio::test]
+async fn test_cancel() {
+    let mut bus = bus(0, false);
+    let mut stream = bus.consume(TOPIC.to_string()).await.unwrap();
+    bus.cancel().await.unwrap();
+    assert!(timeout(NO_DELIVERY, stream.next()).await.unwrap().is_none());
+
+    publish(&mut bus, TOPIC, "task").await;
+    assert_eq!(bus.queue_len(TOPIC), 1);
+}
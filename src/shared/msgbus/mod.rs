@@ -1,2 +1,32 @@
 pub mod amqp;
 pub mod bus;
+#[cfg(feature = "kafka")]
+pub mod kafka;
+pub mod memory;
+#[cfg(feature = "mqtt")]
+pub mod mqtt;
+#[cfg(feature = "nats")]
+pub mod nats_jetstream;
+#[cfg(feature = "postgres")]
+pub mod postgres;
+#[cfg(feature = "redis")]
+pub mod redis_streams;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+
+#[cfg(test)]
+mod amqp_test;
+#[cfg(all(test, feature = "kafka"))]
+mod kafka_test;
+#[cfg(test)]
+mod memory_test;
+#[cfg(all(test, feature = "mqtt"))]
+mod mqtt_test;
+#[cfg(all(test, feature = "nats"))]
+mod nats_jetstream_test;
+#[cfg(all(test, feature = "postgres"))]
+mod postgres_test;
+#[cfg(all(test, feature = "redis"))]
+mod redis_streams_test;
+#[cfg(all(test, feature = "sqlite"))]
+mod sqlite_test;
@@ -0,0 +1,719 @@
+use crate::shared::backoff::Backoff;
+use crate::shared::config;
+use crate::shared::config::{BusParams, Credentials, MqttParams, ReconnectParams, TopicConfig};
+use crate::shared::msgbus::amqp::tls_config;
+use crate::shared::msgbus::bus::{
+    dead_letter_queue, Closer, Consumer, DeadLetters, Failure, Message, MessageProps,
+    MessageStream, Publisher, ReplyConsumer,
+};
+use async_trait::async_trait;
+use rumqttc::v5::mqttbytes::v5::{Packet, Publish, PublishProperties};
+use rumqttc::v5::mqttbytes::QoS;
+use rumqttc::v5::{AsyncClient, ClientError, ConnectionError, Event, EventLoop, MqttOptions};
+use rumqttc::Outgoing;
+use rumqttc::{TlsConfiguration, Transport};
+use std::collections::{HashMap, VecDeque};
+use std::error::Error;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use thiserror::Error;
+use tokio::select;
+use tokio::sync::{mpsc, oneshot, watch};
+use tokio::time::{sleep, timeout};
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use tokio_stream::StreamExt;
+use uuid::Uuid;
+
+pub(crate) const ATTEMPT_PROPERTY: &str = "mqdish-attempt";
+pub(crate) const FAILURE_REASON_PROPERTY: &str = "mqdish-failure-reason";
+pub(crate) const EXIT_CODE_PROPERTY: &str = "mqdish-exit-code";
+// How long closing waits for the broker to acknowledge published messages.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Message bus on an MQTT 5 broker. Workers of a topic are balanced with a shared
+/// subscription and acknowledge tasks manually once they are finished.
+///
+/// MQTT has no negative acknowledgement, so a message which is requeued, retried or
+/// dead-lettered is published again (to the same topic or to the `<topic>.dlq` topic)
+/// and only then acknowledged. Retry delays are not supported, retries are published right away. Tasks of a worker which
+/// was disconnected before acknowledging them are redelivered by the broker only if it
+/// keeps the session of the worker, see `client_id` and `session_expiry`.
+pub struct MqttBus {
+    outbox: Outbox,
+    params: MqttParams,
+    topics: HashMap<String, TopicConfig>,
+    // Streams of the subscribed topics, fed by the event loop.
+    routes: Routes,
+    // Topic filters subscribed to again when the broker has not kept the session.
+    subscriptions: Arc<Mutex<Vec<String>>>,
+    // Topic consumed with `consume`.
+    consumed: Option<String>,
+    reply_topics: Vec<String>,
+    // Stops the event loop from reconnecting once the bus is being closed.
+    closing: watch::Sender<bool>,
+}
+
+// Messages are numbered in the order they are received on the connection.
+type Routes = Arc<Mutex<HashMap<String, mpsc::UnboundedSender<(u64, Publish)>>>>;
+
+#[derive(Error, Debug)]
+pub enum MqttBusError {
+    #[error("Not implemented for MQTT driver: {0}")]
+    NotImplemented(String),
+    #[error("Invalid argument: {0}")]
+    InvalidArgument(String),
+    #[error("Connection to MQTT broker failed: {0}")]
+    ConnectionFailure(String),
+}
+
+/// Host, port and whether TLS is used, the connection string is `mqtt[s]://host[:port]`.
+pub fn broker_address(connection: config::Connection) -> Result<(String, u16, bool), MqttBusError> {
+    let dsn = match connection {
+        config::Connection::DSN(dsn) => dsn,
+        config::Connection::Params(params) => return Ok((params.host, params.port, params.ssl)),
+    };
+    let invalid = || MqttBusError::InvalidArgument(format!("connection string {}", dsn));
+    let (scheme, address) = dsn.split_once("://").ok_or_else(invalid)?;
+    let (ssl, default_port) = match scheme {
+        "mqtt" | "tcp" => (false, 1883),
+        "mqtts" | "ssl" => (true, 8883),
+        _ => return Err(invalid()),
+    };
+    let address = address.trim_end_matches('/');
+    match address.rsplit_once(':') {
+        Some((host, port)) => {
+            let port = port.parse().map_err(|_| invalid())?;
+            Ok((host.to_string(), port, ssl))
+        }
+        None => Ok((address.to_string(), default_port, ssl)),
+    }
+}
+
+/// Task and its metadata, which are carried in properties of the published message.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct Envelope {
+    pub body: String,
+    pub props: MessageProps,
+    pub attempt: u32,
+    pub failure: Option<Failure>,
+}
+
+impl Envelope {
+    pub(crate) fn from_publish(publish: &Publish) -> Envelope {
+        let mut envelope = Envelope {
+            body: String::from_utf8_lossy(&publish.payload).to_string(),
+            attempt: 1,
+            ..Envelope::default()
+        };
+        let Some(properties) = &publish.properties else {
+            return envelope;
+        };
+        envelope.props = MessageProps {
+            correlation_id: properties
+                .correlation_data
+                .as_ref()
+                .map(|data| String::from_utf8_lossy(data).to_string()),
+            reply_to: properties.response_topic.clone(),
+            priority: None,
+            requires: vec![],
+        };
+        let property = |name: &str| {
+            properties
+                .user_properties
+                .iter()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.clone())
+        };
+        if let Some(attempt) = property(ATTEMPT_PROPERTY).and_then(|value| value.parse().ok()) {
+            envelope.attempt = attempt;
+        }
+        envelope.failure = property(FAILURE_REASON_PROPERTY).map(|reason| Failure {
+            reason,
+            exit_code: property(EXIT_CODE_PROPERTY).and_then(|value| value.parse().ok()),
+        });
+        envelope
+    }
+
+    pub(crate) fn properties(&self) -> PublishProperties {
+        let mut user_properties = vec![(ATTEMPT_PROPERTY.to_string(), self.attempt.to_string())];
+        if let Some(failure) = &self.failure {
+            user_properties.push((FAILURE_REASON_PROPERTY.to_string(), failure.reason.clone()));
+            if let Some(exit_code) = failure.exit_code {
+                user_properties.push((EXIT_CODE_PROPERTY.to_string(), exit_code.to_string()));
+            }
+        }
+        PublishProperties {
+            response_topic: self.props.reply_to.clone(),
+            correlation_data: self
+                .props
+                .correlation_id
+                .as_ref()
+                .map(|id| id.clone().into_bytes().into()),
+            user_properties,
+            ..PublishProperties::default()
+        }
+    }
+}
+
+// MQTT requires received messages to be acknowledged in the order they were delivered and
+// brokers close the connection otherwise, so acknowledgements of tasks finished early wait
+// for the tasks received before them.
+enum Acknowledgement {
+    Received(u64, Box<Publish>),
+    Settled(u64),
+    // messages received before are either forgotten or redelivered by the broker
+    Reconnected,
+    // answered once the preceding acknowledgements are handed to the client
+    Flush(oneshot::Sender<()>),
+}
+
+async fn acknowledge_in_order(
+    client: AsyncClient,
+    mut acknowledgements: mpsc::UnboundedReceiver<Acknowledgement>,
+) {
+    let mut received: VecDeque<(u64, Box<Publish>, bool)> = VecDeque::new();
+    while let Some(acknowledgement) = acknowledgements.recv().await {
+        match acknowledgement {
+            Acknowledgement::Received(id, publish) => received.push_back((id, publish, false)),
+            Acknowledgement::Settled(id) => {
+                if let Some(message) = received.iter_mut().find(|message| message.0 == id) {
+                    message.2 = true;
+                }
+                while received.front().is_some_and(|message| message.2) {
+                    let (_, publish, _) = received.pop_front().unwrap();
+                    // fails only once the event loop has stopped
+                    let _ = client.ack(&publish).await;
+                }
+            }
+            Acknowledgement::Reconnected => received.clear(),
+            Acknowledgement::Flush(done) => {
+                let _ = done.send(());
+            }
+        }
+    }
+}
+
+// Publishes messages with QoS 1 keeping count of the ones not acknowledged by the broker.
+#[derive(Clone)]
+struct Outbox {
+    client: AsyncClient,
+    unconfirmed: Arc<watch::Sender<usize>>,
+    acknowledgements: mpsc::UnboundedSender<Acknowledgement>,
+}
+
+impl Outbox {
+    async fn publish(&self, topic: &str, envelope: &Envelope) -> Result<(), ClientError> {
+        self.unconfirmed
+            .send_modify(|unconfirmed| *unconfirmed += 1);
+        let published = self
+            .client
+            .publish_with_properties(
+                topic,
+                QoS::AtLeastOnce,
+                false,
+                envelope.body.clone(),
+                envelope.properties(),
+            )
+            .await;
+        if published.is_err() {
+            self.unconfirmed
+                .send_modify(|unconfirmed| *unconfirmed -= 1);
+        }
+        published
+    }
+
+    fn acknowledge(&self, acknowledgement: Acknowledgement) {
+        // fails only once the bus and all its messages are dropped
+        let _ = self.acknowledgements.send(acknowledgement);
+    }
+
+    // Emulates a negative acknowledgement, the original message is acknowledged
+    // only once its replacement is published.
+    async fn replace(
+        &self,
+        original: u64,
+        topic: &str,
+        envelope: &Envelope,
+    ) -> Result<(), ClientError> {
+        self.publish(topic, envelope).await?;
+        self.acknowledge(Acknowledgement::Settled(original));
+        Ok(())
+    }
+}
+
+// Polls the connection until the bus is closed, reconnecting with backoff when it is lost.
+async fn run_event_loop(
+    mut eventloop: EventLoop,
+    outbox: Outbox,
+    routes: Routes,
+    subscriptions: Arc<Mutex<Vec<String>>>,
+    mut closing: watch::Receiver<bool>,
+    reconnect: ReconnectParams,
+) {
+    let mut backoff = Backoff::new(reconnect.clone());
+    let mut received = 0;
+    loop {
+        match eventloop.poll().await {
+            Ok(Event::Incoming(Packet::ConnAck(connack))) => {
+                backoff = Backoff::new(reconnect.clone());
+                outbox.acknowledge(Acknowledgement::Reconnected);
+                if !connack.session_present {
+                    // subscribing waits for the event loop, so it is not awaited here
+                    for filter in subscriptions.lock().unwrap().iter() {
+                        let _ = outbox.client.try_subscribe(filter, QoS::AtLeastOnce);
+                    }
+                }
+            }
+            Ok(Event::Incoming(Packet::PubAck(_))) => {
+                outbox
+                    .unconfirmed
+                    .send_modify(|unconfirmed| *unconfirmed = unconfirmed.saturating_sub(1));
+            }
+            Ok(Event::Incoming(Packet::Publish(publish))) => {
+                received += 1;
+                outbox.acknowledge(Acknowledgement::Received(
+                    received,
+                    Box::new(publish.clone()),
+                ));
+                let topic = String::from_utf8_lossy(&publish.topic).to_string();
+                let route = routes.lock().unwrap().get(&topic).cloned();
+                let undelivered = match route {
+                    Some(route) => route.send((received, publish)).err().map(|err| err.0),
+                    None => Some((received, publish)),
+                };
+                // delivered after the subscription was cancelled, so it is left to other workers
+                if let Some((id, publish)) = undelivered {
+                    let outbox = outbox.clone();
+                    tokio::spawn(async move {
+                        let envelope = Envelope::from_publish(&publish);
+                        let _ = outbox.replace(id, &topic, &envelope).await;
+                    });
+                }
+            }
+            Ok(Event::Outgoing(Outgoing::Disconnect)) => break,
+            Ok(_) => {}
+            Err(ConnectionError::RequestsDone) => break,
+            Err(_) if *closing.borrow() => break,
+            Err(err) => match backoff.next_delay() {
+                Some(delay) => {
+                    println!(
+                        "Connection to MQTT broker lost, next attempt in {} ms: {}",
+                        delay.as_millis(),
+                        err
+                    );
+                    select! {
+                        _ = sleep(delay) => {}
+                        _ = closing.wait_for(|closing| *closing) => break,
+                    }
+                }
+                None => {
+                    println!("Connection to MQTT broker lost: {}", err);
+                    break;
+                }
+            },
+        }
+    }
+    // ends the streams of the subscriptions
+    routes.lock().unwrap().clear();
+}
+
+impl MqttBus {
+    pub async fn new(
+        connection_cfg: config::Connection,
+        credentials: Credentials,
+        bus_params: BusParams,
+    ) -> Result<Self, MqttBusError> {
+        let BusParams::Mqtt(params) = bus_params else {
+            return Err(MqttBusError::InvalidArgument(
+                "bus params of another driver".to_string(),
+            ));
+        };
+        let (host, port, ssl) = broker_address(connection_cfg)?;
+        let client_id = params
+            .client_id
+            .clone()
+            .unwrap_or_else(|| format!("mqdish-{}", Uuid::new_v4()));
+        let mut options = MqttOptions::new(client_id, host, port);
+        options
+            .set_keep_alive(Duration::from_secs(params.keep_alive))
+            .set_manual_acks(true)
+            .set_clean_start(params.session_expiry == 0)
+            .set_receive_maximum(match params.prefetch {
+                0 => None,
+                prefetch => Some(prefetch),
+            });
+        if params.session_expiry > 0 {
+            let mut properties = options.connect_properties().unwrap_or_default();
+            properties.session_expiry_interval = Some(params.session_expiry);
+            options.set_connect_properties(properties);
+        }
+        match credentials {
+            Credentials::LoginPassword(creds) => {
+                options.set_credentials(creds.login, creds.password);
+                if ssl {
+                    options.set_transport(Transport::tls_with_config(TlsConfiguration::Native));
+                }
+            }
+            Credentials::TLSClientAuth(auth) => {
+                let tls = tls_config(&auth)
+                    .map_err(|err| MqttBusError::InvalidArgument(err.to_string()))?;
+                options.set_transport(Transport::tls_with_config(TlsConfiguration::SimpleNative {
+                    ca: tls.cert_chain.unwrap_or_default().into_bytes(),
+                    client_auth: tls
+                        .identity
+                        .map(|identity| (identity.der, identity.password)),
+                }));
+            }
+            Credentials::None => {
+                if ssl {
+                    options.set_transport(Transport::tls_with_config(TlsConfiguration::Native));
+                }
+            }
+        }
+
+        let (client, mut eventloop) = AsyncClient::new(options, 64);
+        loop {
+            match eventloop.poll().await {
+                Ok(Event::Incoming(Packet::ConnAck(_))) => break,
+                Ok(_) => {}
+                Err(err) => return Err(MqttBusError::ConnectionFailure(err.to_string())),
+            }
+        }
+
+        let (acknowledgements, acknowledgements_rx) = mpsc::unbounded_channel();
+        tokio::spawn(acknowledge_in_order(client.clone(), acknowledgements_rx));
+        let outbox = Outbox {
+            client,
+            unconfirmed: Arc::new(watch::Sender::new(0)),
+            acknowledgements,
+        };
+        let routes = Routes::default();
+        let subscriptions = Arc::new(Mutex::new(vec![]));
+        let (closing, closing_rx) = watch::channel(false);
+        tokio::spawn(run_event_loop(
+            eventloop,
+            outbox.clone(),
+            Arc::clone(&routes),
+            Arc::clone(&subscriptions),
+            closing_rx,
+            params.reconnect.clone(),
+        ));
+
+        Ok(MqttBus {
+            outbox,
+            params,
+            topics: HashMap::new(),
+            routes,
+            subscriptions,
+            consumed: None,
+            reply_topics: vec![],
+            closing,
+        })
+    }
+
+    /// Sets the settings of topics, tasks of topics with `dead_letter` enabled
+    /// are published to the dead-letter topic when they fail for good.
+    pub fn with_topics(mut self, topics: HashMap<String, TopicConfig>) -> Self {
+        self.topics = topics;
+        self
+    }
+
+    fn dead_letter_topic(&self, topic: &str) -> Option<String> {
+        match self.topics.get(topic) {
+            Some(topic_config) if topic_config.dead_letter => Some(dead_letter_queue(topic)),
+            _ => None,
+        }
+    }
+
+    // Subscribes to the topic filter delivering messages of the topic to the returned stream.
+    async fn subscribe(
+        &mut self,
+        topic: String,
+        filter: String,
+        requeue: bool,
+    ) -> Result<MessageStream, Box<dyn Error>> {
+        let (publish_tx, publish_rx) = mpsc::unbounded_channel();
+        self.routes
+            .lock()
+            .unwrap()
+            .insert(topic.clone(), publish_tx);
+        self.subscriptions.lock().unwrap().push(filter.clone());
+        self.outbox
+            .client
+            .subscribe(filter, QoS::AtLeastOnce)
+            .await?;
+
+        let outbox = self.outbox.clone();
+        let dead_letter_topic = self.dead_letter_topic(&topic);
+        let stream = UnboundedReceiverStream::new(publish_rx).map(move |(id, publish)| {
+            Box::new(MqttMessage {
+                outbox: outbox.clone(),
+                envelope: Envelope::from_publish(&publish),
+                id,
+                topic: topic.clone(),
+                requeue,
+                dead_letter_topic: dead_letter_topic.clone(),
+                settled: AtomicBool::new(false),
+            }) as Box<dyn Message + Send>
+        });
+        Ok(Box::pin(stream))
+    }
+
+    async fn unsubscribe(&mut self, topic: &str, filter: &str) -> Result<(), Box<dyn Error>> {
+        self.subscriptions
+            .lock()
+            .unwrap()
+            .retain(|subscribed| subscribed != filter);
+        self.routes.lock().unwrap().remove(topic);
+        self.outbox.client.unsubscribe(filter).await?;
+        Ok(())
+    }
+
+    fn shared_filter(&self, topic: &str) -> String {
+        format!("$share/{}/{}", self.params.group, topic)
+    }
+}
+
+struct MqttMessage {
+    outbox: Outbox,
+    // number of the message on the connection it was received on
+    id: u64,
+    envelope: Envelope,
+    topic: String,
+    requeue: bool,
+    dead_letter_topic: Option<String>,
+    settled: AtomicBool,
+}
+
+impl MqttMessage {
+    fn settle(&self) -> Result<(), Box<dyn Error>> {
+        if self.settled.swap(true, Ordering::SeqCst) {
+            return Err("Message is already settled".into());
+        }
+        Ok(())
+    }
+
+    async fn republish(&self, envelope: &Envelope) -> Result<(), Box<dyn Error>> {
+        self.settle()?;
+        self.outbox.replace(self.id, &self.topic, envelope).await?;
+        Ok(())
+    }
+}
+
+impl Drop for MqttMessage {
+    // like unacknowledged messages of a closed AMQP channel, unsettled messages are redelivered
+    fn drop(&mut self) {
+        if self.settled.load(Ordering::SeqCst) {
+            return;
+        }
+        let outbox = self.outbox.clone();
+        let id = self.id;
+        let topic = self.topic.clone();
+        let envelope = self.envelope.clone();
+        if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            runtime.spawn(async move {
+                let _ = outbox.replace(id, &topic, &envelope).await;
+            });
+        }
+    }
+}
+
+#[async_trait]
+impl Message for MqttMessage {
+    async fn ack(&self) -> Result<(), Box<dyn Error>> {
+        self.settle()?;
+        self.outbox.acknowledge(Acknowledgement::Settled(self.id));
+        Ok(())
+    }
+
+    async fn nack(&self, failure: Failure) -> Result<(), Box<dyn Error>> {
+        if self.requeue {
+            return self.republish(&self.envelope).await;
+        }
+        self.reject(failure).await
+    }
+
+    async fn reject(&self, failure: Failure) -> Result<(), Box<dyn Error>> {
+        self.settle()?;
+        match &self.dead_letter_topic {
+            Some(topic) => {
+                let envelope = Envelope {
+                    failure: Some(failure),
+                    ..self.envelope.clone()
+                };
+                self.outbox.replace(self.id, topic, &envelope).await?;
+            }
+            None => self.outbox.acknowledge(Acknowledgement::Settled(self.id)),
+        }
+        Ok(())
+    }
+
+    async fn requeue(&self) -> Result<(), Box<dyn Error>> {
+        self.republish(&self.envelope).await
+    }
+
+    // Holding the message until the delay passes would hold back acknowledgements of the tasks
+    // received after it, so it is published again right away.
+    async fn retry(&self, _delay: Duration) -> Result<(), Box<dyn Error>> {
+        let envelope = Envelope {
+            attempt: self.envelope.attempt + 1,
+            ..self.envelope.clone()
+        };
+        self.republish(&envelope).await
+    }
+
+    async fn postpone(&self, _delay: Duration) -> Result<(), Box<dyn Error>> {
+        self.republish(&self.envelope).await
+    }
+
+    async fn reply(&self, msg: String) -> Result<(), Box<dyn Error>> {
+        let Some(reply_to) = &self.envelope.props.reply_to else {
+            return Ok(());
+        };
+        let envelope = Envelope {
+            body: msg,
+            props: MessageProps {
+                correlation_id: self.envelope.props.correlation_id.clone(),
+                reply_to: None,
+                priority: None,
+                requires: vec![],
+            },
+            attempt: 1,
+            failure: None,
+        };
+        self.outbox.publish(reply_to, &envelope).await?;
+        Ok(())
+    }
+
+    fn body(&self) -> String {
+        self.envelope.body.clone()
+    }
+
+    fn delivery_info(&self) -> String {
+        format!(
+            "topic {}, message {}, attempt {}",
+            self.topic, self.id, self.envelope.attempt
+        )
+    }
+
+    fn attempt(&self) -> u32 {
+        self.envelope.attempt
+    }
+
+    fn failure(&self) -> Option<Failure> {
+        self.envelope.failure.clone()
+    }
+}
+
+#[async_trait]
+impl Publisher for MqttBus {
+    async fn publish(
+        &mut self,
+        topic: String,
+        msg: String,
+        props: MessageProps,
+    ) -> Result<(), Box<dyn Error>> {
+        let envelope = Envelope {
+            body: msg,
+            props,
+            attempt: 1,
+            failure: None,
+        };
+        self.outbox.publish(&topic, &envelope).await?;
+        Ok(())
+    }
+
+    // A task held by a worker until it is due would hold back acknowledgements of the tasks
+    // received after it, which have to be sent in order.
+    async fn publish_at(
+        &mut self,
+        _topic: String,
+        _msg: String,
+        _props: MessageProps,
+        _at: SystemTime,
+    ) -> Result<(), Box<dyn Error>> {
+        Err(MqttBusError::NotImplemented("delayed delivery".to_string()).into())
+    }
+}
+
+#[async_trait]
+impl Consumer for MqttBus {
+    async fn consume(&mut self, topic: String) -> Result<MessageStream, Box<dyn Error>> {
+        let filter = self.shared_filter(&topic);
+        let stream = self
+            .subscribe(topic.clone(), filter, self.params.requeue)
+            .await?;
+        self.consumed = Some(topic);
+        Ok(stream)
+    }
+
+    async fn cancel(&mut self) -> Result<(), Box<dyn Error>> {
+        if let Some(topic) = self.consumed.take() {
+            let filter = self.shared_filter(&topic);
+            self.unsubscribe(&topic, &filter).await?;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl ReplyConsumer for MqttBus {
+    async fn consume_replies(&mut self) -> Result<(String, MessageStream), Box<dyn Error>> {
+        let topic = format!("mqdish.reply.{}", Uuid::new_v4());
+        let stream = self.subscribe(topic.clone(), topic.clone(), false).await?;
+        self.reply_topics.push(topic.clone());
+        Ok((topic, stream))
+    }
+}
+
+// The broker keeps no messages without a subscriber, dead-lettered tasks are only published
+// to the dead-letter topic for whichever client subscribes to it.
+#[async_trait]
+impl DeadLetters for MqttBus {
+    async fn dead_letters(
+        &mut self,
+        topic: String,
+    ) -> Result<Vec<Box<dyn Message + Send>>, Box<dyn Error>> {
+        Err(MqttBusError::NotImplemented(format!(
+            "dead-letter queues, tasks are published to {}",
+            dead_letter_queue(&topic)
+        ))
+        .into())
+    }
+
+    async fn purge_dead_letters(&mut self, topic: String) -> Result<u32, Box<dyn Error>> {
+        Err(MqttBusError::NotImplemented(format!(
+            "dead-letter queues, tasks are published to {}",
+            dead_letter_queue(&topic)
+        ))
+        .into())
+    }
+}
+
+#[async_trait]
+impl Closer for MqttBus {
+    async fn close(&mut self) -> Result<(), Box<dyn Error>> {
+        self.cancel().await?;
+        for topic in std::mem::take(&mut self.reply_topics) {
+            self.unsubscribe(&topic, &topic).await?;
+        }
+        let mut unconfirmed = self.outbox.unconfirmed.subscribe();
+        if timeout(
+            CLOSE_TIMEOUT,
+            unconfirmed.wait_for(|unconfirmed| *unconfirmed == 0),
+        )
+        .await
+        .is_err()
+        {
+            println!("Some published messages were not acknowledged by the MQTT broker");
+        }
+        let (flushed, flushed_rx) = oneshot::channel();
+        self.outbox.acknowledge(Acknowledgement::Flush(flushed));
+        let _ = timeout(CLOSE_TIMEOUT, flushed_rx).await;
+        let _ = self.closing.send(true);
+        // fails if the event loop has already stopped after losing the connection
+        let _ = self.outbox.client.disconnect().await;
+        Ok(())
+    }
+}
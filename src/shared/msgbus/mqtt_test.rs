@@ -0,0 +1,271 @@
+use crate::shared::config::{
+    BusParams, Connection, ConnectionParams, Credentials, MqttParams, TopicConfig,
+};
+use crate::shared::msgbus::bus::*;
+use crate::shared::msgbus::mqtt::*;
+use rumqttc::v5::mqttbytes::v5::Publish;
+use rumqttc::v5::mqttbytes::QoS;
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::select;
+use tokio::time::timeout;
+use tokio_stream::StreamExt;
+use uuid::Uuid;
+
+// MQTT 5 broker the integration tests run against, e.g. `mqtt://localhost:1883`.
+// They are skipped if it is not set, each one uses a topic and a shared subscription group
+// of its own. The broker has to honour the receive maximum of the client.
+const SERVER_VAR: &str = "MQDISH_TEST_MQTT";
+// Long enough for a message to be delivered if it is available.
+const NO_DELIVERY: Duration = Duration::from_millis(500);
+
+struct TestServer {
+    url: String,
+    topic: String,
+    group: String,
+}
+
+impl TestServer {
+    fn new() -> Option<Self> {
+        let Ok(url) = std::env::var(SERVER_VAR) else {
+            println!("{} is not set, skipping", SERVER_VAR);
+            return None;
+        };
+        Some(TestServer {
+            url,
+            topic: format!("mqdish-test-{}", Uuid::new_v4()),
+            group: format!("mqdish-test-{}", Uuid::new_v4()),
+        })
+    }
+
+    async fn bus(&self, prefetch: u16, requeue: bool) -> MqttBus {
+        self.bus_in_group(&self.group, prefetch, requeue).await
+    }
+
+    async fn bus_in_group(&self, group: &str, prefetch: u16, requeue: bool) -> MqttBus {
+        let params = MqttParams {
+            group: group.to_string(),
+            prefetch,
+            requeue,
+            ..MqttParams::default()
+        };
+        MqttBus::new(
+            Connection::DSN(self.url.clone()),
+            Credentials::None,
+            BusParams::Mqtt(params),
+        )
+        .await
+        .unwrap()
+    }
+
+    async fn publish(&self, bus: &mut MqttBus, body: &str) {
+        bus.publish(
+            self.topic.clone(),
+            body.to_string(),
+            MessageProps::default(),
+        )
+        .await
+        .unwrap();
+    }
+}
+
+async fn next(stream: &mut MessageStream) -> Option<Box<dyn Message + Send>> {
+    timeout(NO_DELIVERY, stream.next()).await.ok().flatten()
+}
+
+fn publish(envelope: &Envelope) -> Publish {
+    Publish::new(
+        "test",
+        QoS::AtLeastOnce,
+        envelope.body.clone(),
+        Some(envelope.properties()),
+    )
+}
+
+#[test]
+fn test_envelope_properties() {
+    let envelope = Envelope {
+        body: "{}".to_string(),
+        props: MessageProps {
+            correlation_id: Some("id".to_string()),
+            reply_to: Some("mqdish.reply.1".to_string()),
+            priority: None,
+            requires: vec![],
+        },
+        attempt: 2,
+        failure: Some(Failure {
+            reason: "failed".to_string(),
+            exit_code: Some(3),
+        }),
+    };
+    assert_eq!(Envelope::from_publish(&publish(&envelope)), envelope);
+
+    // messages published by other clients have no properties
+    let foreign = Publish::new("test", QoS::AtLeastOnce, "{}", None);
+    let expected = Envelope {
+        body: "{}".to_string(),
+        attempt: 1,
+        ..Envelope::default()
+    };
+    assert_eq!(Envelope::from_publish(&foreign), expected);
+}
+
+#[test]
+fn test_broker_address() {
+    let address = |dsn: &str| broker_address(Connection::DSN(dsn.to_string())).unwrap();
+    assert_eq!(
+        address("mqtt://broker"),
+        ("broker".to_string(), 1883, false)
+    );
+    assert_eq!(
+        address("mqtts://broker:8884/"),
+        ("broker".to_string(), 8884, true)
+    );
+    assert!(broker_address(Connection::DSN("amqp://broker".to_string())).is_err());
+
+    let params = Connection::Params(ConnectionParams {
+        host: "broker".to_string(),
+        port: 1883,
+        ssl: false,
+    });
+    assert_eq!(
+        broker_address(params).unwrap(),
+        ("broker".to_string(), 1883, false)
+    );
+}
+
+#[tokio::test]
+async fn test_shared_subscription_balances_workers() {
+    let Some(server) = TestServer::new() else {
+        return;
+    };
+    let mut first = server.bus(1, false).await;
+    let mut second = server.bus(1, false).await;
+    let mut first_stream = first.consume(server.topic.clone()).await.unwrap();
+    let mut second_stream = second.consume(server.topic.clone()).await.unwrap();
+    let mut producer = server.bus(1, false).await;
+    for body in ["1", "2", "3", "4"] {
+        server.publish(&mut producer, body).await;
+    }
+
+    let mut first_bodies = vec![];
+    let mut second_bodies = vec![];
+    loop {
+        select! {
+            Some(msg) = next(&mut first_stream) => {
+                first_bodies.push(msg.body());
+                msg.ack().await.unwrap();
+            }
+            Some(msg) = next(&mut second_stream) => {
+                second_bodies.push(msg.body());
+                msg.ack().await.unwrap();
+            }
+            else => break,
+        }
+    }
+    // each task is delivered to one of the workers only
+    assert!(!first_bodies.is_empty());
+    assert!(!second_bodies.is_empty());
+    let mut bodies = [first_bodies, second_bodies].concat();
+    bodies.sort();
+    assert_eq!(bodies, vec!["1", "2", "3", "4"]);
+    for mut bus in [first, second, producer] {
+        bus.close().await.unwrap();
+    }
+}
+
+#[tokio::test]
+async fn test_acks_are_sent_in_order() {
+    let Some(server) = TestServer::new() else {
+        return;
+    };
+    let mut bus = server.bus(2, false).await;
+    let mut stream = bus.consume(server.topic.clone()).await.unwrap();
+    let mut producer = server.bus(1, false).await;
+    server.publish(&mut producer, "first").await;
+    server.publish(&mut producer, "second").await;
+    let first = next(&mut stream).await.unwrap();
+    let second = next(&mut stream).await.unwrap();
+    assert_eq!(first.body(), "first");
+
+    // the acknowledgement of the second task waits for the first one,
+    // so the broker does not deliver more than the receive maximum
+    second.ack().await.unwrap();
+    server.publish(&mut producer, "third").await;
+    assert!(next(&mut stream).await.is_none());
+    first.ack().await.unwrap();
+    let third = next(&mut stream).await.unwrap();
+    assert_eq!(third.body(), "third");
+    third.ack().await.unwrap();
+    bus.close().await.unwrap();
+    producer.close().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_nack_is_republished() {
+    let Some(server) = TestServer::new() else {
+        return;
+    };
+    let mut bus = server.bus(1, true).await;
+    let mut stream = bus.consume(server.topic.clone()).await.unwrap();
+    server.publish(&mut bus, "task").await;
+    let failure = Failure {
+        reason: "failed".to_string(),
+        exit_code: Some(2),
+    };
+
+    // the republished task is delivered only once the original one is acknowledged
+    let msg = next(&mut stream).await.unwrap();
+    msg.nack(failure).await.unwrap();
+    let requeued = next(&mut stream).await.unwrap();
+    assert_eq!((requeued.body().as_str(), requeued.attempt()), ("task", 1));
+    // retries are published right away, the delay would hold back later acknowledgements
+    requeued.retry(Duration::from_secs(60)).await.unwrap();
+    let retried = next(&mut stream).await.unwrap();
+    assert_eq!((retried.body().as_str(), retried.attempt()), ("task", 2));
+    retried.ack().await.unwrap();
+    assert!(next(&mut stream).await.is_none());
+    bus.close().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_rejected_task_is_dead_lettered() {
+    let Some(server) = TestServer::new() else {
+        return;
+    };
+    let topics = HashMap::from([(
+        server.topic.clone(),
+        TopicConfig {
+            dead_letter: true,
+            ..TopicConfig::default()
+        },
+    )]);
+    let mut bus = server.bus(1, true).await.with_topics(topics);
+    let mut stream = bus.consume(server.topic.clone()).await.unwrap();
+    let mut dead_letters = server
+        .bus_in_group(&format!("{}-dlq", server.group), 1, false)
+        .await;
+    let mut dead_letter_stream = dead_letters
+        .consume(dead_letter_queue(&server.topic))
+        .await
+        .unwrap();
+    server.publish(&mut bus, "task").await;
+    let failure = Failure {
+        reason: "failed".to_string(),
+        exit_code: Some(2),
+    };
+
+    next(&mut stream)
+        .await
+        .unwrap()
+        .reject(failure.clone())
+        .await
+        .unwrap();
+    let dead_letter = next(&mut dead_letter_stream).await.unwrap();
+    assert_eq!(dead_letter.body(), "task");
+    assert_eq!(dead_letter.failure(), Some(failure));
+    dead_letter.ack().await.unwrap();
+    assert!(next(&mut stream).await.is_none());
+    bus.close().await.unwrap();
+    dead_letters.close().await.unwrap();
+}
@@ -0,0 +1,684 @@
+use crate::shared::backoff::{jittered_delay, Backoff};
+use crate::shared::config;
+use crate::shared::config::{BusParams, Credentials, NatsAuth, NatsParams, TopicConfig};
+use crate::shared::msgbus::bus::{
+    dead_letter_queue, Closer, Consumer, DeadLetters, Failure, Message, MessageProps,
+    MessageStream, Publisher, ReplyConsumer,
+};
+use async_nats::jetstream::consumer::{pull, AckPolicy};
+use async_nats::jetstream::message::AckKind;
+use async_nats::jetstream::stream::{self, RetentionPolicy};
+use async_nats::jetstream::{self, Context};
+use async_nats::{Client, ConnectOptions, HeaderMap};
+use async_trait::async_trait;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+use tokio::select;
+use tokio::sync::{mpsc, oneshot, watch};
+use tokio::time::sleep;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::StreamExt;
+
+pub(crate) const CORRELATION_ID_HEADER: &str = "Mqdish-Correlation-Id";
+pub(crate) const REPLY_TO_HEADER: &str = "Mqdish-Reply-To";
+pub(crate) const FAILURE_REASON_HEADER: &str = "Mqdish-Failure-Reason";
+pub(crate) const EXIT_CODE_HEADER: &str = "Mqdish-Exit-Code";
+// Number of the attempt, counted by retries only unlike the deliveries of JetStream.
+pub(crate) const ATTEMPT_HEADER: &str = "Mqdish-Attempt";
+// Milliseconds since the epoch before which a scheduled task is not delivered to workers.
+pub(crate) const NOT_BEFORE_HEADER: &str = "Mqdish-Not-Before";
+
+/// Message bus on NATS JetStream, each topic is a work queue stream consumed by a durable
+/// pull consumer shared by the workers.
+pub struct NatsBus {
+    client: Client,
+    context: Context,
+    params: NatsParams,
+    topics: HashMap<String, TopicConfig>,
+    // Streams known to exist, so that they are not looked up on every publish.
+    streams: HashSet<String>,
+    // Stops the subscription created by `consume`.
+    subscription: Option<watch::Sender<bool>>,
+}
+
+#[derive(Error, Debug)]
+pub enum NatsBusError {
+    #[error("Not implemented for NATS driver: {0}")]
+    NotImplemented(String),
+    #[error("Invalid argument: {0}")]
+    InvalidArgument(String),
+    #[error("Connection to NATS server failed: {0}")]
+    ConnectionFailure(String),
+}
+
+// Stream names may not contain dots and wildcards the subjects consist of.
+pub(crate) fn stream_name(subject: &str) -> String {
+    let name: String = subject
+        .chars()
+        .map(|c| match c {
+            '.' | '*' | '>' | '/' | '\\' => '_',
+            c if c.is_whitespace() => '_',
+            c => c,
+        })
+        .collect();
+    format!("mqdish-{}", name)
+}
+
+pub(crate) fn header(headers: Option<&HeaderMap>, name: &str) -> Option<String> {
+    headers?.get(name).map(|value| value.as_str().to_string())
+}
+
+pub(crate) fn props_headers(props: &MessageProps) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    if let Some(correlation_id) = &props.correlation_id {
+        headers.insert(CORRELATION_ID_HEADER, correlation_id.as_str());
+    }
+    if let Some(reply_to) = &props.reply_to {
+        headers.insert(REPLY_TO_HEADER, reply_to.as_str());
+    }
+    headers
+}
+
+// Time left until the message is due, None if it can be delivered right away.
+pub(crate) fn due_in(headers: Option<&HeaderMap>) -> Option<Duration> {
+    let not_before: u64 = header(headers, NOT_BEFORE_HEADER)?.parse().ok()?;
+    let due = UNIX_EPOCH + Duration::from_millis(not_before);
+    due.duration_since(SystemTime::now()).ok()
+}
+
+pub(crate) fn attempt(headers: Option<&HeaderMap>) -> u32 {
+    header(headers, ATTEMPT_HEADER)
+        .and_then(|attempt| attempt.parse().ok())
+        .unwrap_or(1)
+}
+
+pub(crate) fn failure(headers: Option<&HeaderMap>) -> Option<Failure> {
+    header(headers, FAILURE_REASON_HEADER).map(|reason| Failure {
+        reason,
+        exit_code: header(headers, EXIT_CODE_HEADER).and_then(|code| code.parse().ok()),
+    })
+}
+
+impl NatsBus {
+    pub async fn new(
+        connection_cfg: config::Connection,
+        credentials: Credentials,
+        bus_params: BusParams,
+    ) -> Result<Self, NatsBusError> {
+        let BusParams::Nats(params) = bus_params else {
+            return Err(NatsBusError::InvalidArgument(
+                "bus params of another driver".to_string(),
+            ));
+        };
+        let reconnect = params.reconnect.clone();
+        let mut options = ConnectOptions::new()
+            .name("mqdish")
+            .max_reconnects(reconnect.max_attempts.map(|attempts| attempts as usize))
+            .reconnect_delay_callback(move |attempts| {
+                jittered_delay(&reconnect, attempts.saturating_sub(1) as u32)
+            });
+        options = match credentials {
+            Credentials::LoginPassword(creds) => {
+                options.user_and_password(creds.login, creds.password)
+            }
+            Credentials::TLSClientAuth(auth) => options
+                .require_tls(true)
+                .add_root_certificates(PathBuf::from(auth.ca_file))
+                .add_client_certificate(
+                    PathBuf::from(auth.cert_file),
+                    PathBuf::from(auth.key_file),
+                ),
+            Credentials::None => options,
+        };
+        options = match params.auth.clone() {
+            Some(NatsAuth::JWT(auth)) => options
+                .credentials(&format!(
+                    "-----BEGIN NATS USER JWT-----\n{}\n------END NATS USER JWT------\n\n\
+                     -----BEGIN USER NKEY SEED-----\n{}\n------END USER NKEY SEED------\n",
+                    auth.jwt, auth.nkey_seed
+                ))
+                .map_err(|err| NatsBusError::InvalidArgument(format!("JWT: {}", err)))?,
+            Some(NatsAuth::CredsFile(creds)) => options
+                .credentials_file(&creds.creds_file)
+                .await
+                .map_err(|err| {
+                NatsBusError::InvalidArgument(format!("creds file {}: {}", creds.creds_file, err))
+            })?,
+            Some(NatsAuth::NKey(auth)) => options.nkey(auth.nkey_seed),
+            None => options,
+        };
+
+        let servers = match connection_cfg {
+            config::Connection::DSN(dsn) => dsn,
+            config::Connection::Params(params) => format!(
+                "{}://{}:{}",
+                if params.ssl { "tls" } else { "nats" },
+                params.host,
+                params.port
+            ),
+        };
+        let client = options
+            .connect(servers)
+            .await
+            .map_err(|err| NatsBusError::ConnectionFailure(err.to_string()))?;
+
+        Ok(NatsBus {
+            context: jetstream::new(client.clone()),
+            client,
+            params,
+            topics: HashMap::new(),
+            streams: HashSet::new(),
+            subscription: None,
+        })
+    }
+
+    /// Sets the settings of topics, tasks of topics with `dead_letter` enabled
+    /// are moved to the dead-letter stream when they fail for good.
+    pub fn with_topics(mut self, topics: HashMap<String, TopicConfig>) -> Self {
+        self.topics = topics;
+        self
+    }
+
+    fn dead_letter_subject(&self, topic: &str) -> Option<String> {
+        match self.topics.get(topic) {
+            Some(topic_config) if topic_config.dead_letter => Some(dead_letter_queue(topic)),
+            _ => None,
+        }
+    }
+
+    // Creates the stream holding messages of the subject unless it exists.
+    async fn declare_stream(
+        &mut self,
+        subject: &str,
+        retention: RetentionPolicy,
+    ) -> Result<stream::Stream, Box<dyn Error>> {
+        let config = stream::Config {
+            name: stream_name(subject),
+            subjects: vec![subject.to_string()],
+            retention,
+            storage: self.params.storage,
+            num_replicas: self.params.replicas,
+            max_age: Duration::from_secs(self.params.max_age),
+            ..stream::Config::default()
+        };
+        let stream = self.context.get_or_create_stream(config).await?;
+        self.streams.insert(subject.to_string());
+        Ok(stream)
+    }
+
+    async fn declare_topic(&mut self, topic: &str) -> Result<stream::Stream, Box<dyn Error>> {
+        if let Some(subject) = self.dead_letter_subject(topic) {
+            self.declare_stream(&subject, RetentionPolicy::Limits)
+                .await?;
+        }
+        self.declare_stream(topic, RetentionPolicy::WorkQueue).await
+    }
+
+    // Publishes the message to the stream of the topic, retrying until the server confirms it.
+    async fn publish_with_headers(
+        &mut self,
+        topic: String,
+        msg: String,
+        headers: HeaderMap,
+    ) -> Result<(), Box<dyn Error>> {
+        if !self.streams.contains(&topic) {
+            self.declare_topic(&topic).await?;
+        }
+        let mut backoff = Backoff::new(self.params.reconnect.clone());
+        loop {
+            // the client reconnects by itself, the message is published again until confirmed
+            let published =
+                publish_confirmed(&self.context, topic.clone(), headers.clone(), msg.clone())
+                    .await
+                    .map_err(|err| err.to_string());
+            let err = match published {
+                Ok(_) => return Ok(()),
+                Err(err) => err,
+            };
+            match backoff.next_delay() {
+                Some(delay) => {
+                    println!(
+                        "Failed to publish, next attempt in {} ms: {}",
+                        delay.as_millis(),
+                        err
+                    );
+                    sleep(delay).await;
+                }
+                None => return Err(err.into()),
+            }
+        }
+    }
+}
+
+// Publishes the message and waits for the stream to store it.
+async fn publish_confirmed(
+    context: &Context,
+    subject: String,
+    headers: HeaderMap,
+    body: String,
+) -> Result<(), Box<dyn Error>> {
+    context
+        .publish_with_headers(subject, headers, body.into())
+        .await?
+        .await?;
+    Ok(())
+}
+
+// Settings shared by the messages consumed from the same topic.
+#[derive(Clone)]
+struct Subscription {
+    client: Client,
+    context: Context,
+    requeue: bool,
+    dead_letter_subject: Option<String>,
+    ack_wait: Duration,
+}
+
+struct NatsMessage {
+    message: jetstream::Message,
+    subscription: Subscription,
+    // Stops extending the ack deadline once the message is settled or dropped.
+    in_progress: Mutex<Option<oneshot::Sender<()>>>,
+}
+
+impl NatsMessage {
+    fn new(message: jetstream::Message, subscription: Subscription) -> Self {
+        let (in_progress_tx, mut in_progress_rx) = oneshot::channel();
+        // long running tasks would be redelivered to other workers once the ack wait passes
+        let progress = message.clone();
+        let period = subscription.ack_wait / 2;
+        tokio::spawn(async move {
+            loop {
+                select! {
+                    _ = sleep(period) => {}
+                    _ = &mut in_progress_rx => return,
+                }
+                if let Err(err) = progress.ack_with(AckKind::Progress).await {
+                    println!("Failed to extend ack deadline: {}", err);
+                }
+            }
+        });
+        NatsMessage {
+            message,
+            subscription,
+            in_progress: Mutex::new(Some(in_progress_tx)),
+        }
+    }
+
+    async fn settle(&self, kind: AckKind) -> Result<(), Box<dyn Error>> {
+        if self.in_progress.lock().unwrap().take().is_none() {
+            return Err("Message is already settled".into());
+        }
+        self.message
+            .ack_with(kind)
+            .await
+            .map_err(|err| err.to_string())?;
+        Ok(())
+    }
+}
+
+impl Drop for NatsMessage {
+    // like unacknowledged messages of a closed AMQP channel, unsettled messages are redelivered
+    fn drop(&mut self) {
+        if self.in_progress.lock().unwrap().take().is_none() {
+            return;
+        }
+        let message = self.message.clone();
+        if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            runtime.spawn(async move {
+                let _ = message.ack_with(AckKind::Nak(None)).await;
+            });
+        }
+    }
+}
+
+#[async_trait]
+impl Message for NatsMessage {
+    async fn ack(&self) -> Result<(), Box<dyn Error>> {
+        self.settle(AckKind::Ack).await
+    }
+
+    async fn nack(&self, failure: Failure) -> Result<(), Box<dyn Error>> {
+        if self.subscription.requeue {
+            return self.settle(AckKind::Nak(None)).await;
+        }
+        self.reject(failure).await
+    }
+
+    async fn reject(&self, failure: Failure) -> Result<(), Box<dyn Error>> {
+        let Some(subject) = &self.subscription.dead_letter_subject else {
+            // stops redelivery, the work queue stream drops the message
+            return self.settle(AckKind::Term).await;
+        };
+        let mut headers = self.message.headers.clone().unwrap_or_default();
+        headers.insert(FAILURE_REASON_HEADER, failure.reason.as_str());
+        if let Some(exit_code) = failure.exit_code {
+            headers.insert(EXIT_CODE_HEADER, exit_code.to_string().as_str());
+        }
+        publish_confirmed(
+            &self.subscription.context,
+            subject.clone(),
+            headers,
+            self.body(),
+        )
+        .await?;
+        self.settle(AckKind::Ack).await
+    }
+
+    async fn requeue(&self) -> Result<(), Box<dyn Error>> {
+        self.settle(AckKind::Nak(None)).await
+    }
+
+    // JetStream counts every delivery, so the retry is published again with the next attempt
+    // and handed back by consumers until its delay passes.
+    async fn retry(&self, delay: Duration) -> Result<(), Box<dyn Error>> {
+        let mut headers = self.message.headers.clone().unwrap_or_default();
+        let not_before = (SystemTime::now() + delay).duration_since(UNIX_EPOCH)?;
+        headers.insert(ATTEMPT_HEADER, (self.attempt() + 1).to_string().as_str());
+        headers.insert(
+            NOT_BEFORE_HEADER,
+            not_before.as_millis().to_string().as_str(),
+        );
+        publish_confirmed(
+            &self.subscription.context,
+            self.message.subject.to_string(),
+            headers,
+            self.body(),
+        )
+        .await?;
+        self.settle(AckKind::Ack).await
+    }
+
+    async fn postpone(&self, delay: Duration) -> Result<(), Box<dyn Error>> {
+        self.settle(AckKind::Nak(Some(delay))).await
+    }
+
+    async fn reply(&self, msg: String) -> Result<(), Box<dyn Error>> {
+        let headers = self.message.headers.as_ref();
+        let Some(reply_to) = header(headers, REPLY_TO_HEADER) else {
+            return Ok(());
+        };
+        let props = MessageProps {
+            correlation_id: header(headers, CORRELATION_ID_HEADER),
+            reply_to: None,
+            priority: None,
+            requires: vec![],
+        };
+        self.subscription
+            .client
+            .publish_with_headers(reply_to, props_headers(&props), msg.into())
+            .await?;
+        Ok(())
+    }
+
+    fn body(&self) -> String {
+        String::from_utf8_lossy(&self.message.payload).to_string()
+    }
+
+    fn delivery_info(&self) -> String {
+        match self.message.info() {
+            Ok(info) => format!(
+                "stream {}, sequence {}, delivered {} times",
+                info.stream, info.stream_sequence, info.delivered
+            ),
+            Err(_) => format!("subject {}", self.message.subject),
+        }
+    }
+
+    fn attempt(&self) -> u32 {
+        attempt(self.message.headers.as_ref())
+    }
+
+    fn failure(&self) -> Option<Failure> {
+        failure(self.message.headers.as_ref())
+    }
+}
+
+// Result published to the core NATS inbox of the producer, there is nothing to acknowledge.
+struct Reply {
+    message: async_nats::Message,
+}
+
+#[async_trait]
+impl Message for Reply {
+    async fn ack(&self) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    async fn nack(&self, _failure: Failure) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    async fn reject(&self, _failure: Failure) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    async fn requeue(&self) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    async fn retry(&self, _delay: Duration) -> Result<(), Box<dyn Error>> {
+        Err(NatsBusError::NotImplemented("retry of replies".to_string()).into())
+    }
+
+    async fn reply(&self, _msg: String) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    fn body(&self) -> String {
+        String::from_utf8_lossy(&self.message.payload).to_string()
+    }
+
+    fn attempt(&self) -> u32 {
+        1
+    }
+
+    fn failure(&self) -> Option<Failure> {
+        None
+    }
+}
+
+// Message of a dead-letter stream, which is read by sequence without a consumer.
+struct DeadLetter {
+    stream: stream::Stream,
+    message: jetstream::message::StreamMessage,
+}
+
+#[async_trait]
+impl Message for DeadLetter {
+    async fn ack(&self) -> Result<(), Box<dyn Error>> {
+        self.stream.delete_message(self.message.sequence).await?;
+        Ok(())
+    }
+
+    // messages which are not acknowledged just stay in the stream
+    async fn nack(&self, _failure: Failure) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    async fn reject(&self, _failure: Failure) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    async fn requeue(&self) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    async fn retry(&self, _delay: Duration) -> Result<(), Box<dyn Error>> {
+        Err(NatsBusError::NotImplemented("retry of dead-lettered messages".to_string()).into())
+    }
+
+    async fn reply(&self, _msg: String) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    fn body(&self) -> String {
+        String::from_utf8_lossy(&self.message.payload).to_string()
+    }
+
+    fn attempt(&self) -> u32 {
+        attempt(Some(&self.message.headers))
+    }
+
+    fn failure(&self) -> Option<Failure> {
+        failure(Some(&self.message.headers))
+    }
+}
+
+#[async_trait]
+impl Publisher for NatsBus {
+    async fn publish(
+        &mut self,
+        topic: String,
+        msg: String,
+        props: MessageProps,
+    ) -> Result<(), Box<dyn Error>> {
+        self.publish_with_headers(topic, msg, props_headers(&props))
+            .await
+    }
+
+    // Consumers of the workers hand the message back with a delayed Nak until it is due.
+    async fn publish_at(
+        &mut self,
+        topic: String,
+        msg: String,
+        props: MessageProps,
+        at: SystemTime,
+    ) -> Result<(), Box<dyn Error>> {
+        let not_before = at.duration_since(UNIX_EPOCH)?.as_millis();
+        let mut headers = props_headers(&props);
+        headers.insert(NOT_BEFORE_HEADER, not_before.to_string().as_str());
+        self.publish_with_headers(topic, msg, headers).await
+    }
+}
+
+#[async_trait]
+impl Consumer for NatsBus {
+    async fn consume(&mut self, topic: String) -> Result<MessageStream, Box<dyn Error>> {
+        let stream = self.declare_topic(&topic).await?;
+        let config = pull::Config {
+            durable_name: Some(self.params.durable.clone()),
+            ack_policy: AckPolicy::Explicit,
+            ack_wait: Duration::from_secs(self.params.ack_wait),
+            max_ack_pending: self.params.max_ack_pending,
+            ..pull::Config::default()
+        };
+        let consumer = stream
+            .get_or_create_consumer(&self.params.durable, config)
+            .await?;
+        let mut messages = consumer
+            .stream()
+            .max_messages_per_batch(self.params.prefetch.max(1) as usize)
+            .messages()
+            .await?;
+
+        let subscription = Subscription {
+            client: self.client.clone(),
+            context: self.context.clone(),
+            requeue: self.params.requeue,
+            dead_letter_subject: self.dead_letter_subject(&topic),
+            ack_wait: Duration::from_secs(self.params.ack_wait),
+        };
+        let (cancel_tx, mut cancel_rx) = watch::channel(false);
+        let (msg_tx, msg_rx) = mpsc::channel::<Box<dyn Message + Send>>(1);
+        tokio::spawn(async move {
+            loop {
+                let message = select! {
+                    message = messages.next() => message,
+                    _ = async { let _ = cancel_rx.wait_for(|cancelled| *cancelled).await; } => return,
+                    _ = msg_tx.closed() => return,
+                };
+                let message = match message {
+                    Some(Ok(message)) => message,
+                    // the pull consumer keeps going after errors such as missed heartbeats
+                    Some(Err(err)) => {
+                        println!("Failed to pull messages: {}", err);
+                        continue;
+                    }
+                    None => return,
+                };
+                // JetStream cannot delay a published message, so it is handed back until it is due
+                if let Some(due_in) = due_in(message.headers.as_ref()) {
+                    if let Err(err) = message.ack_with(AckKind::Nak(Some(due_in))).await {
+                        println!("Failed to postpone message: {}", err);
+                    }
+                    continue;
+                }
+                let msg = NatsMessage::new(message, subscription.clone());
+                // an undelivered message is returned to the stream when dropped
+                if msg_tx.send(Box::new(msg)).await.is_err() {
+                    return;
+                }
+            }
+        });
+        self.subscription = Some(cancel_tx);
+
+        Ok(Box::pin(ReceiverStream::new(msg_rx)))
+    }
+
+    async fn cancel(&mut self) -> Result<(), Box<dyn Error>> {
+        if let Some(cancel) = &self.subscription {
+            let _ = cancel.send(true);
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl ReplyConsumer for NatsBus {
+    async fn consume_replies(&mut self) -> Result<(String, MessageStream), Box<dyn Error>> {
+        let inbox = self.client.new_inbox();
+        let subscriber = self.client.subscribe(inbox.clone()).await?;
+        let stream =
+            subscriber.map(|message| Box::new(Reply { message }) as Box<dyn Message + Send>);
+        Ok((inbox, Box::pin(stream)))
+    }
+}
+
+#[async_trait]
+impl DeadLetters for NatsBus {
+    async fn dead_letters(
+        &mut self,
+        topic: String,
+    ) -> Result<Vec<Box<dyn Message + Send>>, Box<dyn Error>> {
+        let mut stream = self
+            .declare_stream(&dead_letter_queue(&topic), RetentionPolicy::Limits)
+            .await?;
+        let state = stream.info().await?.state.clone();
+        let mut messages: Vec<Box<dyn Message + Send>> = vec![];
+        if state.messages == 0 {
+            return Ok(messages);
+        }
+        for sequence in state.first_sequence..=state.last_sequence {
+            // sequences of deleted messages are skipped
+            if let Ok(message) = stream.get_raw_message(sequence).await {
+                messages.push(Box::new(DeadLetter {
+                    stream: stream.clone(),
+                    message,
+                }));
+            }
+        }
+        Ok(messages)
+    }
+
+    async fn purge_dead_letters(&mut self, topic: String) -> Result<u32, Box<dyn Error>> {
+        let stream = self
+            .declare_stream(&dead_letter_queue(&topic), RetentionPolicy::Limits)
+            .await?;
+        let purged = stream.purge().await?;
+        Ok(purged.purged as u32)
+    }
+}
+
+#[async_trait]
+impl Closer for NatsBus {
+    async fn close(&mut self) -> Result<(), Box<dyn Error>> {
+        self.cancel().await?;
+        self.client.flush().await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,314 @@
+use crate::shared::config::{BusParams, Connection, Credentials, NatsParams, TopicConfig};
+use crate::shared::msgbus::bus::*;
+use crate::shared::msgbus::nats_jetstream::*;
+use async_nats::HeaderMap;
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::time::{sleep, timeout};
+use tokio_stream::StreamExt;
+use uuid::Uuid;
+
+// NATS server with JetStream enabled the integration tests run against, e.g. `nats://localhost:4222`.
+// They are skipped if it is not set, each one uses streams of its own topic.
+const SERVER_VAR: &str = "MQDISH_TEST_NATS";
+// Long enough for a message to be delivered if it is available.
+const NO_DELIVERY: Duration = Duration::from_millis(1000);
+
+struct TestServer {
+    url: String,
+    topic: String,
+}
+
+impl TestServer {
+    fn new() -> Option<Self> {
+        let Ok(url) = std::env::var(SERVER_VAR) else {
+            println!("{} is not set, skipping", SERVER_VAR);
+            return None;
+        };
+        Some(TestServer {
+            url,
+            topic: format!("mqdish-test-{}", Uuid::new_v4()),
+        })
+    }
+
+    async fn bus(&self, prefetch: u16, ack_wait: u64) -> NatsBus {
+        let params = NatsParams {
+            prefetch,
+            ack_wait,
+            ..NatsParams::default()
+        };
+        NatsBus::new(
+            Connection::DSN(self.url.clone()),
+            Credentials::None,
+            BusParams::Nats(params),
+        )
+        .await
+        .unwrap()
+    }
+
+    async fn publish(&self, bus: &mut NatsBus, body: &str) {
+        bus.publish(
+            self.topic.clone(),
+            body.to_string(),
+            MessageProps::default(),
+        )
+        .await
+        .unwrap();
+    }
+
+    // Removes the streams of the topic along with their consumers.
+    async fn clean_up(&self) {
+        let client = async_nats::connect(&self.url).await.unwrap();
+        let context = async_nats::jetstream::new(client);
+        for subject in [self.topic.clone(), dead_letter_queue(&self.topic)] {
+            let _ = context.delete_stream(stream_name(&subject)).await;
+        }
+    }
+}
+
+async fn next(stream: &mut MessageStream) -> Option<Box<dyn Message + Send>> {
+    timeout(NO_DELIVERY, stream.next()).await.ok().flatten()
+}
+
+#[test]
+fn test_stream_name() {
+    assert_eq!(stream_name("tasks"), "mqdish-tasks");
+    assert_eq!(stream_name("tasks.gpu.dlq"), "mqdish-tasks_gpu_dlq");
+}
+
+#[test]
+fn test_headers() {
+    let props = MessageProps {
+        correlation_id: Some("id".to_string()),
+        reply_to: Some("_INBOX.1".to_string()),
+        priority: None,
+        requires: vec![],
+    };
+    let mut headers = props_headers(&props);
+    assert_eq!(
+        header(Some(&headers), CORRELATION_ID_HEADER),
+        props.correlation_id
+    );
+    assert_eq!(header(Some(&headers), REPLY_TO_HEADER), props.reply_to);
+    assert_eq!(failure(Some(&headers)), None);
+
+    headers.insert(FAILURE_REASON_HEADER, "failed");
+    headers.insert(EXIT_CODE_HEADER, "3");
+    assert_eq!(
+        failure(Some(&headers)),
+        Some(Failure {
+            reason: "failed".to_string(),
+            exit_code: Some(3),
+        })
+    );
+    assert_eq!(attempt(Some(&headers)), 1);
+    headers.insert(ATTEMPT_HEADER, "3");
+    assert_eq!(attempt(Some(&headers)), 3);
+    assert!(header(None, REPLY_TO_HEADER).is_none());
+    assert!(props_headers(&MessageProps::default()).is_empty());
+}
+
+#[test]
+fn test_due_in() {
+    let in_ms = |delay: Duration| {
+        let at = SystemTime::now() + delay;
+        at.duration_since(UNIX_EPOCH).unwrap().as_millis()
+    };
+    let mut headers = HeaderMap::new();
+    assert_eq!(due_in(Some(&headers)), None);
+    headers.insert(
+        NOT_BEFORE_HEADER,
+        in_ms(Duration::from_secs(60)).to_string().as_str(),
+    );
+    let due = due_in(Some(&headers)).unwrap();
+    assert!(due > Duration::from_secs(59) && due <= Duration::from_secs(60));
+
+    let mut headers = HeaderMap::new();
+    let past = SystemTime::now().duration_since(UNIX_EPOCH).unwrap() - Duration::from_secs(1);
+    headers.insert(NOT_BEFORE_HEADER, past.as_millis().to_string().as_str());
+    assert_eq!(due_in(Some(&headers)), None);
+    assert_eq!(due_in(None), None);
+}
+
+#[tokio::test]
+async fn test_work_queue_delivers_once() {
+    let Some(server) = TestServer::new() else {
+        return;
+    };
+    let mut first = server.bus(1, 30).await;
+    let mut second = server.bus(1, 30).await;
+    server.publish(&mut first, "first").await;
+    server.publish(&mut first, "second").await;
+
+    let mut first_stream = first.consume(server.topic.clone()).await.unwrap();
+    let mut second_stream = second.consume(server.topic.clone()).await.unwrap();
+    let mut bodies = vec![];
+    for stream in [&mut first_stream, &mut second_stream] {
+        let msg = next(stream).await.unwrap();
+        bodies.push(msg.body());
+        msg.ack().await.unwrap();
+        assert!(msg.ack().await.is_err());
+    }
+    bodies.sort();
+    assert_eq!(bodies, ["first", "second"]);
+    // acknowledged messages are removed from the work queue stream
+    assert!(next(&mut first_stream).await.is_none());
+    assert!(next(&mut second_stream).await.is_none());
+    first.close().await.unwrap();
+    second.close().await.unwrap();
+    server.clean_up().await;
+}
+
+#[tokio::test]
+async fn test_progress_keeps_message() {
+    let Some(server) = TestServer::new() else {
+        return;
+    };
+    let mut running = server.bus(1, 2).await;
+    let mut other = server.bus(1, 2).await;
+    server.publish(&mut running, "task").await;
+
+    let mut running_stream = running.consume(server.topic.clone()).await.unwrap();
+    let msg = next(&mut running_stream).await.unwrap();
+    let mut other_stream = other.consume(server.topic.clone()).await.unwrap();
+    // held for longer than the ack wait, which is extended meanwhile
+    assert!(timeout(Duration::from_secs(5), other_stream.next())
+        .await
+        .is_err());
+
+    assert_eq!(msg.attempt(), 1);
+    msg.ack().await.unwrap();
+    running.close().await.unwrap();
+    other.close().await.unwrap();
+    server.clean_up().await;
+}
+
+#[tokio::test]
+async fn test_retry_is_delayed() {
+    let Some(server) = TestServer::new() else {
+        return;
+    };
+    let mut bus = server.bus(1, 30).await;
+    server.publish(&mut bus, "task").await;
+    let mut stream = bus.consume(server.topic.clone()).await.unwrap();
+
+    // redeliveries of requeued and postponed messages are not counted as attempts
+    let msg = next(&mut stream).await.unwrap();
+    msg.requeue().await.unwrap();
+    let requeued = next(&mut stream).await.unwrap();
+    requeued.postpone(Duration::from_millis(100)).await.unwrap();
+    let msg = next(&mut stream).await.unwrap();
+    assert_eq!(msg.attempt(), 1);
+    msg.retry(Duration::from_secs(2)).await.unwrap();
+    assert!(next(&mut stream).await.is_none());
+
+    sleep(Duration::from_secs(1)).await;
+    let retried = next(&mut stream).await.unwrap();
+    assert_eq!(retried.body(), "task");
+    assert_eq!(retried.attempt(), 2);
+    retried.ack().await.unwrap();
+    bus.close().await.unwrap();
+    server.clean_up().await;
+}
+
+#[tokio::test]
+async fn test_delayed_message_is_not_delivered_early() {
+    let Some(server) = TestServer::new() else {
+        return;
+    };
+    let mut bus = server.bus(1, 30).await;
+    let at = SystemTime::now() + Duration::from_secs(2);
+    bus.publish_at(
+        server.topic.clone(),
+        "delayed".to_string(),
+        MessageProps::default(),
+        at,
+    )
+    .await
+    .unwrap();
+    server.publish(&mut bus, "task").await;
+    let mut stream = bus.consume(server.topic.clone()).await.unwrap();
+
+    // the early message is handed back and takes no prefetch slot
+    let msg = next(&mut stream).await.unwrap();
+    assert_eq!(msg.body(), "task");
+    msg.ack().await.unwrap();
+    assert!(next(&mut stream).await.is_none());
+
+    sleep(Duration::from_secs(1)).await;
+    let delayed = next(&mut stream).await.unwrap();
+    assert!(SystemTime::now() >= at);
+    assert_eq!(delayed.body(), "delayed");
+    delayed.ack().await.unwrap();
+    bus.close().await.unwrap();
+    server.clean_up().await;
+}
+
+#[tokio::test]
+async fn test_rejected_message_is_terminated() {
+    let Some(server) = TestServer::new() else {
+        return;
+    };
+    let mut bus = server.bus(1, 30).await;
+    server.publish(&mut bus, "task").await;
+    let mut stream = bus.consume(server.topic.clone()).await.unwrap();
+
+    let failure = Failure {
+        reason: "failed".to_string(),
+        exit_code: Some(2),
+    };
+    next(&mut stream)
+        .await
+        .unwrap()
+        .nack(failure)
+        .await
+        .unwrap();
+    assert!(next(&mut stream).await.is_none());
+    assert!(bus
+        .dead_letters(server.topic.clone())
+        .await
+        .unwrap()
+        .is_empty());
+    bus.close().await.unwrap();
+    server.clean_up().await;
+}
+
+#[tokio::test]
+async fn test_dead_letter() {
+    let Some(server) = TestServer::new() else {
+        return;
+    };
+    let topics = HashMap::from([(
+        server.topic.clone(),
+        TopicConfig {
+            dead_letter: true,
+            ..TopicConfig::default()
+        },
+    )]);
+    let mut bus = server.bus(1, 30).await.with_topics(topics);
+    server.publish(&mut bus, "task").await;
+    let mut stream = bus.consume(server.topic.clone()).await.unwrap();
+
+    let failure = Failure {
+        reason: "failed".to_string(),
+        exit_code: Some(2),
+    };
+    next(&mut stream)
+        .await
+        .unwrap()
+        .nack(failure.clone())
+        .await
+        .unwrap();
+    assert!(next(&mut stream).await.is_none());
+
+    let dead_letters = bus.dead_letters(server.topic.clone()).await.unwrap();
+    assert_eq!(dead_letters.len(), 1);
+    assert_eq!(dead_letters[0].body(), "task");
+    assert_eq!(dead_letters[0].failure(), Some(failure));
+    assert_eq!(
+        bus.purge_dead_letters(server.topic.clone()).await.unwrap(),
+        1
+    );
+    bus.close().await.unwrap();
+    server.clean_up().await;
+}
@@ -0,0 +1,954 @@
+use crate::shared::backoff::Backoff;
+use crate::shared::config;
+use crate::shared::config::{BusParams, Credentials, PostgresParams, ReconnectParams, TopicConfig};
+use crate::shared::msgbus::bus::{
+    dead_letter_queue, Closer, Consumer, DeadLetters, Failure, Message, MessageProps,
+    MessageStream, Publisher, ReplyConsumer,
+};
+use async_trait::async_trait;
+use openssl::ssl::{SslConnector, SslFiletype, SslMethod};
+use postgres_openssl::MakeTlsConnector;
+use std::collections::HashMap;
+use std::error::Error;
+use std::future::poll_fn;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use thiserror::Error;
+use tokio::select;
+use tokio::sync::{mpsc, oneshot, watch, Notify, OwnedSemaphorePermit, Semaphore};
+use tokio::time::sleep;
+use tokio_postgres::config::SslMode;
+use tokio_postgres::types::ToSql;
+use tokio_postgres::{AsyncMessage, Client, Row};
+use tokio_stream::wrappers::ReceiverStream;
+use uuid::Uuid;
+
+const COLUMNS: &str = "id, body, correlation_id, reply_to, attempt, failure_reason, exit_code";
+// Finished tasks are removed at most this often by each worker.
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Message bus on a table of a PostgreSQL database. Workers claim tasks with
+/// `SELECT ... FOR UPDATE SKIP LOCKED` and are woken up by notifications of new tasks,
+/// claims of workers which stopped responding expire after the lease timeout.
+/// Finished tasks are kept along with their status and result for the retention period.
+pub struct PostgresBus {
+    database: Arc<Database>,
+    params: PostgresParams,
+    topics: HashMap<String, TopicConfig>,
+    // Stops the subscription created by `consume`.
+    consumer: Option<watch::Sender<bool>>,
+    // Reply queues with their subscriptions, removed on close.
+    reply_queues: Vec<(String, watch::Sender<bool>)>,
+}
+
+#[derive(Error, Debug)]
+pub enum PostgresBusError {
+    #[error("Not implemented for PostgreSQL driver: {0}")]
+    NotImplemented(String),
+    #[error("Invalid argument: {0}")]
+    InvalidArgument(String),
+    #[error("Connection to PostgreSQL server failed: {0}")]
+    ConnectionFailure(String),
+}
+
+/// Quotes the name to be used as an SQL identifier.
+pub fn quote_identifier(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+/// Connection settings of the server, TLS client certificates are set up separately.
+pub fn connection_config(
+    connection_cfg: config::Connection,
+    credentials: &Credentials,
+    dbname: &str,
+) -> Result<tokio_postgres::Config, PostgresBusError> {
+    let mut pg_config = match connection_cfg {
+        config::Connection::DSN(dsn) => dsn.parse::<tokio_postgres::Config>().map_err(|err| {
+            PostgresBusError::InvalidArgument(format!("connection string: {}", err))
+        })?,
+        config::Connection::Params(params) => {
+            let mut pg_config = tokio_postgres::Config::new();
+            pg_config
+                .host(&params.host)
+                .port(params.port)
+                .dbname(dbname)
+                .ssl_mode(if params.ssl {
+                    SslMode::Require
+                } else {
+                    SslMode::Disable
+                });
+            pg_config
+        }
+    };
+    match credentials {
+        Credentials::LoginPassword(creds) => {
+            pg_config.user(&creds.login).password(&creds.password);
+        }
+        Credentials::TLSClientAuth(_) => {
+            pg_config.ssl_mode(SslMode::Require);
+        }
+        Credentials::None => {}
+    }
+    pg_config.application_name("mqdish");
+    Ok(pg_config)
+}
+
+fn tls_connector(credentials: &Credentials) -> Result<MakeTlsConnector, PostgresBusError> {
+    let tls_error =
+        |err: openssl::error::ErrorStack| PostgresBusError::InvalidArgument(err.to_string());
+    let mut builder = SslConnector::builder(SslMethod::tls()).map_err(tls_error)?;
+    if let Credentials::TLSClientAuth(auth) = credentials {
+        builder.set_ca_file(&auth.ca_file).map_err(tls_error)?;
+        builder
+            .set_certificate_chain_file(&auth.cert_file)
+            .map_err(tls_error)?;
+        builder
+            .set_private_key_file(&auth.key_file, SslFiletype::PEM)
+            .map_err(tls_error)?;
+    }
+    Ok(MakeTlsConnector::new(builder.build()))
+}
+
+// Connection shared by the bus and its messages, re-established once lost.
+struct Database {
+    pg_config: tokio_postgres::Config,
+    tls: MakeTlsConnector,
+    reconnect: ReconnectParams,
+    // Quoted name of the table.
+    table: String,
+    // Quoted name of the table of open reply queues, replies to other queues are dropped.
+    reply_queues: String,
+    // Channel notified of new tasks with the queue name as the payload, named after the table.
+    channel: String,
+    client: tokio::sync::Mutex<Arc<Client>>,
+}
+
+impl Database {
+    async fn connect(
+        pg_config: &tokio_postgres::Config,
+        tls: &MakeTlsConnector,
+    ) -> Result<Client, PostgresBusError> {
+        let (client, connection) = pg_config
+            .connect(tls.clone())
+            .await
+            .map_err(|err| PostgresBusError::ConnectionFailure(err.to_string()))?;
+        tokio::spawn(async move {
+            if let Err(err) = connection.await {
+                println!("Connection to PostgreSQL server lost: {}", err);
+            }
+        });
+        Ok(client)
+    }
+
+    // Returns the client, connecting again with backoff if the connection has been lost.
+    async fn client(&self) -> Result<Arc<Client>, PostgresBusError> {
+        let mut client = self.client.lock().await;
+        if !client.is_closed() {
+            return Ok(Arc::clone(&client));
+        }
+        let mut backoff = Backoff::new(self.reconnect.clone());
+        loop {
+            match Database::connect(&self.pg_config, &self.tls).await {
+                Ok(connected) => {
+                    println!("Reconnected to PostgreSQL server");
+                    *client = Arc::new(connected);
+                    return Ok(Arc::clone(&client));
+                }
+                Err(err) => match backoff.next_delay() {
+                    Some(delay) => {
+                        println!(
+                            "Reconnection failed, next attempt in {} ms: {}",
+                            delay.as_millis(),
+                            err
+                        );
+                        sleep(delay).await;
+                    }
+                    None => return Err(err),
+                },
+            }
+        }
+    }
+
+    async fn execute(
+        &self,
+        statement: &str,
+        params: &[&(dyn ToSql + Sync)],
+    ) -> Result<u64, Box<dyn Error>> {
+        let client = self.client().await?;
+        Ok(client.execute(statement, params).await?)
+    }
+
+    async fn query(
+        &self,
+        statement: &str,
+        params: &[&(dyn ToSql + Sync)],
+    ) -> Result<Vec<Row>, Box<dyn Error>> {
+        let client = self.client().await?;
+        Ok(client.query(statement, params).await?)
+    }
+
+    async fn create_table(&self) -> Result<(), Box<dyn Error>> {
+        let client = self.client().await?;
+        // workers starting at once would fail creating the same table concurrently
+        client
+            .batch_execute(&format!(
+                "BEGIN;
+                 SELECT pg_advisory_xact_lock(hashtext('mqdish'));
+                 CREATE TABLE IF NOT EXISTS {table} (
+                     id BIGSERIAL PRIMARY KEY,
+                     queue TEXT NOT NULL,
+                     body TEXT NOT NULL,
+                     correlation_id TEXT,
+                     reply_to TEXT,
+                     -- ready until the task is finished, done or failed afterwards
+                     status TEXT NOT NULL DEFAULT 'ready',
+                     attempt INTEGER NOT NULL DEFAULT 1,
+                     failure_reason TEXT,
+                     exit_code INTEGER,
+                     -- result reported by the worker which executed the task
+                     result TEXT,
+                     visible_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+                     -- token of the worker which claimed the task and expiration of the claim
+                     lease TEXT,
+                     leased_until TIMESTAMPTZ,
+                     created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+                     finished_at TIMESTAMPTZ
+                 );
+                 CREATE INDEX IF NOT EXISTS {ready} ON {table} (queue, visible_at, id)
+                     WHERE status = 'ready';
+                 CREATE INDEX IF NOT EXISTS {finished} ON {table} (finished_at)
+                     WHERE status <> 'ready';
+                 CREATE TABLE IF NOT EXISTS {reply_queues} (queue TEXT PRIMARY KEY);
+                 COMMIT;",
+                table = self.table,
+                reply_queues = self.reply_queues,
+                ready = quote_identifier(&format!("{}_ready", self.channel)),
+                finished = quote_identifier(&format!("{}_finished", self.channel)),
+            ))
+            .await?;
+        Ok(())
+    }
+
+    // Inserts the task to be visible from `visible_at`, right away if not set.
+    async fn insert(
+        &self,
+        queue: &str,
+        envelope: &Envelope,
+        visible_at: Option<SystemTime>,
+    ) -> Result<(), Box<dyn Error>> {
+        self.insert_from(
+            "VALUES ($1, $2, $3, $4, COALESCE($6::timestamptz, now()))",
+            queue,
+            envelope,
+            visible_at,
+        )
+        .await
+    }
+
+    // Inserts the reply unless the reply queue has been closed. The reply queue is locked
+    // until the reply is committed, so that closing it removes the reply as well.
+    async fn insert_reply(&self, queue: &str, envelope: &Envelope) -> Result<(), Box<dyn Error>> {
+        let source = format!(
+            "SELECT $1, $2, $3, $4, COALESCE($6::timestamptz, now())
+             WHERE EXISTS (SELECT 1 FROM {} WHERE queue = $1 FOR SHARE)",
+            self.reply_queues
+        );
+        self.insert_from(&source, queue, envelope, None).await
+    }
+
+    // Inserts the rows of the source, which takes the queue, body, correlation id and reply queue
+    // of the task as the first four parameters and the visibility time as the sixth one.
+    async fn insert_from(
+        &self,
+        source: &str,
+        queue: &str,
+        envelope: &Envelope,
+        visible_at: Option<SystemTime>,
+    ) -> Result<(), Box<dyn Error>> {
+        self.execute(
+            &format!(
+                "WITH inserted AS (
+                     INSERT INTO {} (queue, body, correlation_id, reply_to, visible_at)
+                     {}
+                     RETURNING queue
+                 )
+                 SELECT pg_notify($5, queue) FROM inserted",
+                self.table, source
+            ),
+            &[
+                &queue,
+                &envelope.body,
+                &envelope.props.correlation_id,
+                &envelope.props.reply_to,
+                &self.channel,
+                &visible_at,
+            ],
+        )
+        .await?;
+        Ok(())
+    }
+
+    // Claims the oldest visible task of the queue which is not claimed by another worker.
+    async fn claim(
+        &self,
+        queue: &str,
+        lease: &str,
+        timeout: Duration,
+    ) -> Result<Option<(i64, Envelope)>, Box<dyn Error>> {
+        let rows = self
+            .query(
+                &format!(
+                    "UPDATE {table} SET lease = $1, leased_until = now() + $2 * interval '1 second'
+                     WHERE id = (SELECT id FROM {table}
+                                 WHERE queue = $3 AND status = 'ready' AND visible_at <= now()
+                                     AND (leased_until IS NULL OR leased_until < now())
+                                 ORDER BY visible_at, id LIMIT 1
+                                 FOR UPDATE SKIP LOCKED)
+                     RETURNING {columns}",
+                    table = self.table,
+                    columns = COLUMNS
+                ),
+                &[&lease, &timeout.as_secs_f64(), &queue],
+            )
+            .await?;
+        Ok(rows.first().map(Envelope::from_row))
+    }
+
+    // Claims all visible tasks of the queue which are not claimed by another worker.
+    async fn claim_all(
+        &self,
+        queue: &str,
+        lease: &str,
+        timeout: Duration,
+    ) -> Result<Vec<(i64, Envelope)>, Box<dyn Error>> {
+        let rows = self
+            .query(
+                &format!(
+                    "UPDATE {table} SET lease = $1, leased_until = now() + $2 * interval '1 second'
+                     WHERE id IN (SELECT id FROM {table}
+                                  WHERE queue = $3 AND status = 'ready'
+                                      AND (leased_until IS NULL OR leased_until < now())
+                                  FOR UPDATE SKIP LOCKED)
+                     RETURNING {columns}",
+                    table = self.table,
+                    columns = COLUMNS
+                ),
+                &[&lease, &timeout.as_secs_f64(), &queue],
+            )
+            .await?;
+        Ok(rows.iter().map(Envelope::from_row).collect())
+    }
+
+    async fn remove_finished(&self, retention: Duration) -> Result<(), Box<dyn Error>> {
+        self.execute(
+            &format!(
+                "DELETE FROM {} WHERE status <> 'ready' AND finished_at < now() - $1 * interval '1 second'",
+                self.table
+            ),
+            &[&retention.as_secs_f64()],
+        )
+        .await?;
+        Ok(())
+    }
+
+    // Opens a separate connection receiving notifications of new tasks of the queue.
+    // The connection is closed once the returned client is dropped.
+    async fn listen(
+        &self,
+        queue: String,
+        published: Arc<Notify>,
+    ) -> Result<Client, Box<dyn Error>> {
+        let (client, mut connection) = self.pg_config.connect(self.tls.clone()).await?;
+        tokio::spawn(async move {
+            while let Some(Ok(message)) = poll_fn(|cx| connection.poll_message(cx)).await {
+                if let AsyncMessage::Notification(notification) = message {
+                    if notification.payload() == queue {
+                        published.notify_one();
+                    }
+                }
+            }
+        });
+        client
+            .batch_execute(&format!("LISTEN {}", quote_identifier(&self.channel)))
+            .await?;
+        Ok(client)
+    }
+}
+
+// Contents of a row of the tasks table.
+struct Envelope {
+    body: String,
+    props: MessageProps,
+    attempt: u32,
+    failure: Option<Failure>,
+}
+
+impl Envelope {
+    fn from_row(row: &Row) -> (i64, Envelope) {
+        let attempt: i32 = row.get("attempt");
+        let envelope = Envelope {
+            body: row.get("body"),
+            props: MessageProps {
+                correlation_id: row.get("correlation_id"),
+                reply_to: row.get("reply_to"),
+                priority: None,
+                requires: vec![],
+            },
+            attempt: attempt as u32,
+            failure: row
+                .get::<_, Option<String>>("failure_reason")
+                .map(|reason| Failure {
+                    reason,
+                    exit_code: row.get("exit_code"),
+                }),
+        };
+        (row.get("id"), envelope)
+    }
+}
+
+impl PostgresBus {
+    pub async fn new(
+        connection_cfg: config::Connection,
+        credentials: Credentials,
+        bus_params: BusParams,
+    ) -> Result<Self, PostgresBusError> {
+        let BusParams::Postgres(params) = bus_params else {
+            return Err(PostgresBusError::InvalidArgument(
+                "bus params of another driver".to_string(),
+            ));
+        };
+        let pg_config = connection_config(connection_cfg, &credentials, &params.dbname)?;
+        let tls = tls_connector(&credentials)?;
+        let client = Database::connect(&pg_config, &tls).await?;
+        let database = Database {
+            pg_config,
+            tls,
+            reconnect: params.reconnect.clone(),
+            table: quote_identifier(&params.table),
+            reply_queues: quote_identifier(&format!("{}_reply_queues", params.table)),
+            channel: params.table.clone(),
+            client: tokio::sync::Mutex::new(Arc::new(client)),
+        };
+        database
+            .create_table()
+            .await
+            .map_err(|err| PostgresBusError::ConnectionFailure(format!("table setup: {}", err)))?;
+
+        Ok(PostgresBus {
+            database: Arc::new(database),
+            params,
+            topics: HashMap::new(),
+            consumer: None,
+            reply_queues: vec![],
+        })
+    }
+
+    /// Sets the settings of topics, tasks of topics with `dead_letter` enabled
+    /// are moved to the dead-letter queue when they fail for good.
+    pub fn with_topics(mut self, topics: HashMap<String, TopicConfig>) -> Self {
+        self.topics = topics;
+        self
+    }
+
+    fn dead_letter_queue(&self, topic: &str) -> Option<String> {
+        match self.topics.get(topic) {
+            Some(topic_config) if topic_config.dead_letter => Some(dead_letter_queue(topic)),
+            _ => None,
+        }
+    }
+
+    // Inserts a new task, reconnecting if the connection has been lost.
+    async fn insert(
+        &mut self,
+        topic: String,
+        msg: String,
+        props: MessageProps,
+        visible_at: Option<SystemTime>,
+    ) -> Result<(), Box<dyn Error>> {
+        let envelope = Envelope {
+            body: msg,
+            props,
+            attempt: 1,
+            failure: None,
+        };
+        loop {
+            let inserted = self
+                .database
+                .insert(&topic, &envelope, visible_at)
+                .await
+                .map_err(|err| err.to_string());
+            match inserted {
+                Ok(_) => return Ok(()),
+                // the task is inserted again once the connection is re-established
+                Err(_) if self.database.client.lock().await.is_closed() => {
+                    self.database.client().await?;
+                }
+                Err(err) => return Err(err.into()),
+            }
+        }
+    }
+
+    fn lease_timeout(&self) -> Duration {
+        Duration::from_secs(self.params.lease_timeout)
+    }
+
+    // Delivers tasks of the queue to the returned stream until the sender is set or dropped.
+    // No more than `prefetch` tasks are delivered without being settled, unlimited if zero.
+    fn subscribe(&self, queue: String, requeue: bool) -> (MessageStream, watch::Sender<bool>) {
+        let (cancel_tx, mut cancel_rx) = watch::channel(false);
+        let (msg_tx, msg_rx) = mpsc::channel::<Box<dyn Message + Send>>(1);
+        let prefetch = match self.params.prefetch {
+            0 => Semaphore::MAX_PERMITS,
+            prefetch => prefetch as usize,
+        };
+        let prefetch = Arc::new(Semaphore::new(prefetch));
+        let database = Arc::clone(&self.database);
+        let timeout = self.lease_timeout();
+        let poll_interval = Duration::from_millis(self.params.poll_interval);
+        let retention = Duration::from_secs(self.params.retention);
+        let dead_letter_queue = self.dead_letter_queue(&queue);
+
+        tokio::spawn(async move {
+            let published = Arc::new(Notify::new());
+            let mut listener: Option<Client> = None;
+            let mut cleaned_up: Option<Instant> = None;
+            loop {
+                let permit = select! {
+                    permit = Arc::clone(&prefetch).acquire_owned() => {
+                        permit.expect("semaphore is never closed")
+                    }
+                    _ = cancel_rx.wait_for(|cancelled| *cancelled) => return,
+                    _ = msg_tx.closed() => return,
+                };
+                // the claim is not interrupted, a task claimed by a dropped query
+                // would be hidden from other workers until the lease expires
+                let (id, envelope, lease) = loop {
+                    // without notifications tasks are still found by polling
+                    if listener.as_ref().is_none_or(Client::is_closed) {
+                        listener =
+                            match database.listen(queue.clone(), Arc::clone(&published)).await {
+                                Ok(client) => Some(client),
+                                Err(err) => {
+                                    println!("Failed to listen for tasks of {}: {}", queue, err);
+                                    None
+                                }
+                            };
+                    }
+                    if cleaned_up.is_none_or(|at| at.elapsed() >= CLEANUP_INTERVAL) {
+                        cleaned_up = Some(Instant::now());
+                        if let Err(err) = database.remove_finished(retention).await {
+                            println!("Failed to remove finished tasks: {}", err);
+                        }
+                    }
+                    let lease = Uuid::new_v4().to_string();
+                    match database.claim(&queue, &lease, timeout).await {
+                        Ok(Some((id, envelope))) => break (id, envelope, lease),
+                        Ok(None) => {}
+                        Err(err) => println!("Failed to claim task of {}: {}", queue, err),
+                    }
+                    select! {
+                        _ = published.notified() => {}
+                        _ = sleep(poll_interval) => {}
+                        _ = cancel_rx.wait_for(|cancelled| *cancelled) => return,
+                        _ = msg_tx.closed() => return,
+                    }
+                };
+                let msg = PostgresMessage::new(
+                    Arc::clone(&database),
+                    id,
+                    envelope,
+                    lease,
+                    timeout,
+                    Some(permit),
+                    requeue,
+                    dead_letter_queue.clone(),
+                );
+                // a task which could not be delivered is released when dropped
+                if msg_tx.send(Box::new(msg)).await.is_err() {
+                    return;
+                }
+            }
+        });
+
+        (Box::pin(ReceiverStream::new(msg_rx)), cancel_tx)
+    }
+}
+
+// Held while the task is claimed, dropping it frees the prefetch slot
+// and stops the extension of the lease.
+struct Lease {
+    _permit: Option<OwnedSemaphorePermit>,
+    _in_progress: oneshot::Sender<()>,
+}
+
+struct PostgresMessage {
+    database: Arc<Database>,
+    id: i64,
+    envelope: Envelope,
+    token: String,
+    requeue: bool,
+    dead_letter_queue: Option<String>,
+    lease: Mutex<Option<Lease>>,
+}
+
+impl PostgresMessage {
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        database: Arc<Database>,
+        id: i64,
+        envelope: Envelope,
+        token: String,
+        timeout: Duration,
+        permit: Option<OwnedSemaphorePermit>,
+        requeue: bool,
+        dead_letter_queue: Option<String>,
+    ) -> Self {
+        let (in_progress_tx, mut in_progress_rx) = oneshot::channel::<()>();
+        // long running tasks would be claimed by other workers once the lease expires
+        let extension = Arc::clone(&database);
+        let extended_token = token.clone();
+        tokio::spawn(async move {
+            let statement = format!(
+                "UPDATE {} SET leased_until = now() + $1 * interval '1 second' WHERE id = $2 AND lease = $3",
+                extension.table
+            );
+            loop {
+                select! {
+                    _ = sleep(timeout / 3) => {}
+                    _ = &mut in_progress_rx => return,
+                }
+                let extended = extension
+                    .execute(&statement, &[&timeout.as_secs_f64(), &id, &extended_token])
+                    .await
+                    .map_err(|err| err.to_string());
+                if let Err(err) = extended {
+                    println!("Failed to extend lease of task: {}", err);
+                }
+            }
+        });
+        PostgresMessage {
+            database,
+            id,
+            envelope,
+            token,
+            requeue,
+            dead_letter_queue,
+            lease: Mutex::new(Some(Lease {
+                _permit: permit,
+                _in_progress: in_progress_tx,
+            })),
+        }
+    }
+
+    // Runs the statement settling the task, which fails if the lease has been taken over.
+    // The statement takes the task id and the lease token as the last two parameters.
+    async fn settle(
+        &self,
+        statement: &str,
+        params: &[&(dyn ToSql + Sync)],
+    ) -> Result<(), Box<dyn Error>> {
+        if self.lease.lock().unwrap().take().is_none() {
+            return Err("Message is already settled".into());
+        }
+        let statement = format!(
+            "UPDATE {} SET {}, lease = NULL, leased_until = NULL WHERE id = ${} AND lease = ${}",
+            self.database.table,
+            statement,
+            params.len() + 1,
+            params.len() + 2
+        );
+        let mut params = params.to_vec();
+        params.push(&self.id);
+        params.push(&self.token);
+        let settled = self
+            .database
+            .execute(&statement, &params)
+            .await
+            .map_err(|err| err.to_string())?;
+        if settled == 0 {
+            return Err("Lease of the task has expired".into());
+        }
+        Ok(())
+    }
+
+    async fn release(&self) -> Result<(), Box<dyn Error>> {
+        self.settle("visible_at = now()", &[]).await?;
+        self.notify().await
+    }
+
+    // Wakes up workers waiting for tasks of the queue of this one.
+    async fn notify(&self) -> Result<(), Box<dyn Error>> {
+        self.database
+            .execute(
+                &format!(
+                    "SELECT pg_notify($1, queue) FROM {} WHERE id = $2",
+                    self.database.table
+                ),
+                &[&self.database.channel, &self.id],
+            )
+            .await?;
+        Ok(())
+    }
+}
+
+impl Drop for PostgresMessage {
+    // like unacknowledged messages of a closed channel, unsettled tasks are redelivered
+    fn drop(&mut self) {
+        if self.lease.lock().unwrap().take().is_none() {
+            return;
+        }
+        let (id, token) = (self.id, self.token.clone());
+        let database = Arc::clone(&self.database);
+        if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            runtime.spawn(async move {
+                let statement = format!(
+                    "UPDATE {} SET lease = NULL, leased_until = NULL, visible_at = now()
+                     WHERE id = $1 AND lease = $2
+                     RETURNING pg_notify($3, queue)",
+                    database.table
+                );
+                let _ = database
+                    .execute(&statement, &[&id, &token, &database.channel])
+                    .await
+                    .map_err(|err| err.to_string());
+            });
+        }
+    }
+}
+
+#[async_trait]
+impl Message for PostgresMessage {
+    async fn ack(&self) -> Result<(), Box<dyn Error>> {
+        self.settle("status = 'done', finished_at = now()", &[])
+            .await
+    }
+
+    async fn nack(&self, failure: Failure) -> Result<(), Box<dyn Error>> {
+        if self.requeue {
+            return self.release().await;
+        }
+        self.reject(failure).await
+    }
+
+    async fn reject(&self, failure: Failure) -> Result<(), Box<dyn Error>> {
+        match &self.dead_letter_queue {
+            Some(queue) => {
+                self.settle(
+                    "queue = $1, failure_reason = $2, exit_code = $3, visible_at = now()",
+                    &[queue, &failure.reason, &failure.exit_code],
+                )
+                .await
+            }
+            None => {
+                self.settle(
+                    "status = 'failed', failure_reason = $1, exit_code = $2, finished_at = now()",
+                    &[&failure.reason, &failure.exit_code],
+                )
+                .await
+            }
+        }
+    }
+
+    async fn requeue(&self) -> Result<(), Box<dyn Error>> {
+        self.release().await
+    }
+
+    async fn retry(&self, delay: Duration) -> Result<(), Box<dyn Error>> {
+        self.settle(
+            "attempt = attempt + 1, visible_at = now() + $1 * interval '1 second'",
+            &[&delay.as_secs_f64()],
+        )
+        .await
+    }
+
+    async fn postpone(&self, delay: Duration) -> Result<(), Box<dyn Error>> {
+        self.settle(
+            "visible_at = now() + $1 * interval '1 second'",
+            &[&delay.as_secs_f64()],
+        )
+        .await
+    }
+
+    // The result is stored along with the task, besides being sent to the reply queue.
+    async fn reply(&self, msg: String) -> Result<(), Box<dyn Error>> {
+        let stored = self
+            .database
+            .execute(
+                &format!(
+                    "UPDATE {} SET result = $1 WHERE id = $2 AND lease = $3",
+                    self.database.table
+                ),
+                &[&msg, &self.id, &self.token],
+            )
+            .await?;
+        // the worker which has taken over the task reports its own result
+        if stored == 0 {
+            return Err("Lease of the task has expired".into());
+        }
+        let Some(reply_to) = &self.envelope.props.reply_to else {
+            return Ok(());
+        };
+        let envelope = Envelope {
+            body: msg,
+            props: MessageProps {
+                correlation_id: self.envelope.props.correlation_id.clone(),
+                reply_to: None,
+                priority: None,
+                requires: vec![],
+            },
+            attempt: 1,
+            failure: None,
+        };
+        // the producer may have stopped waiting for it, nobody would consume it then
+        self.database.insert_reply(reply_to, &envelope).await
+    }
+
+    fn body(&self) -> String {
+        self.envelope.body.clone()
+    }
+
+    fn delivery_info(&self) -> String {
+        format!("message {}, attempt {}", self.id, self.attempt())
+    }
+
+    fn attempt(&self) -> u32 {
+        self.envelope.attempt
+    }
+
+    fn failure(&self) -> Option<Failure> {
+        self.envelope.failure.clone()
+    }
+}
+
+#[async_trait]
+impl Publisher for PostgresBus {
+    async fn publish(
+        &mut self,
+        topic: String,
+        msg: String,
+        props: MessageProps,
+    ) -> Result<(), Box<dyn Error>> {
+        self.insert(topic, msg, props, None).await
+    }
+
+    async fn publish_at(
+        &mut self,
+        topic: String,
+        msg: String,
+        props: MessageProps,
+        at: SystemTime,
+    ) -> Result<(), Box<dyn Error>> {
+        self.insert(topic, msg, props, Some(at)).await
+    }
+}
+
+#[async_trait]
+impl Consumer for PostgresBus {
+    async fn consume(&mut self, topic: String) -> Result<MessageStream, Box<dyn Error>> {
+        let (stream, cancel) = self.subscribe(topic, self.params.requeue);
+        self.consumer = Some(cancel);
+        Ok(stream)
+    }
+
+    async fn cancel(&mut self) -> Result<(), Box<dyn Error>> {
+        if let Some(cancel) = &self.consumer {
+            let _ = cancel.send(true);
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl ReplyConsumer for PostgresBus {
+    async fn consume_replies(&mut self) -> Result<(String, MessageStream), Box<dyn Error>> {
+        let queue = format!("mqdish.reply.{}", Uuid::new_v4());
+        self.database
+            .execute(
+                &format!(
+                    "INSERT INTO {} (queue) VALUES ($1)",
+                    self.database.reply_queues
+                ),
+                &[&queue],
+            )
+            .await?;
+        let (stream, cancel) = self.subscribe(queue.clone(), false);
+        self.reply_queues.push((queue.clone(), cancel));
+        Ok((queue, stream))
+    }
+}
+
+#[async_trait]
+impl DeadLetters for PostgresBus {
+    async fn dead_letters(
+        &mut self,
+        topic: String,
+    ) -> Result<Vec<Box<dyn Message + Send>>, Box<dyn Error>> {
+        let token = Uuid::new_v4().to_string();
+        let timeout = self.lease_timeout();
+        let claimed = self
+            .database
+            .claim_all(&dead_letter_queue(&topic), &token, timeout)
+            .await?;
+        let messages = claimed
+            .into_iter()
+            .map(|(id, envelope)| {
+                Box::new(PostgresMessage::new(
+                    Arc::clone(&self.database),
+                    id,
+                    envelope,
+                    token.clone(),
+                    timeout,
+                    None,
+                    true,
+                    None,
+                )) as Box<dyn Message + Send>
+            })
+            .collect();
+        Ok(messages)
+    }
+
+    async fn purge_dead_letters(&mut self, topic: String) -> Result<u32, Box<dyn Error>> {
+        let purged = self
+            .database
+            .execute(
+                &format!(
+                    "DELETE FROM {} WHERE queue = $1 AND status = 'ready'",
+                    self.database.table
+                ),
+                &[&dead_letter_queue(&topic)],
+            )
+            .await?;
+        Ok(purged as u32)
+    }
+}
+
+#[async_trait]
+impl Closer for PostgresBus {
+    async fn close(&mut self) -> Result<(), Box<dyn Error>> {
+        self.cancel().await?;
+        let mut queues = vec![];
+        for (queue, cancel) in self.reply_queues.drain(..) {
+            let _ = cancel.send(true);
+            queues.push(queue);
+        }
+        if !queues.is_empty() {
+            // waits for replies being inserted, no more are inserted afterwards
+            self.database
+                .execute(
+                    &format!(
+                        "DELETE FROM {} WHERE queue = ANY($1)",
+                        self.database.reply_queues
+                    ),
+                    &[&queues],
+                )
+                .await?;
+            self.database
+                .execute(
+                    &format!("DELETE FROM {} WHERE queue = ANY($1)", self.database.table),
+                    &[&queues],
+                )
+                .await?;
+        }
+        Ok(())
+    }
+}
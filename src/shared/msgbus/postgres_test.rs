@@ -0,0 +1,417 @@
+use crate::shared::config::{
+    BusParams, Connection, ConnectionParams, Credentials, LoginPassword, PostgresParams,
+    TopicConfig,
+};
+use crate::shared::msgbus::bus::*;
+use crate::shared::msgbus::postgres::*;
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime};
+use tokio::time::timeout;
+use tokio_postgres::config::{Host, SslMode};
+use tokio_postgres::{Client, NoTls};
+use tokio_stream::StreamExt;
+use uuid::Uuid;
+
+// Connection string of the database the integration tests run against,
+// e.g. `postgres://postgres@localhost/mqdish`. They are skipped if it is not set,
+// each one uses a table of its own.
+const DATABASE_VAR: &str = "MQDISH_TEST_POSTGRES";
+const TOPIC: &str = "test";
+// Long enough for a message to be delivered if it is available.
+const NO_DELIVERY: Duration = Duration::from_millis(500);
+// Long enough for a delayed message to become due.
+const DUE: Duration = Duration::from_secs(3);
+
+struct TestDatabase {
+    dsn: String,
+    table: String,
+}
+
+impl TestDatabase {
+    fn new() -> Option<Self> {
+        let Ok(dsn) = std::env::var(DATABASE_VAR) else {
+            println!("{} is not set, skipping", DATABASE_VAR);
+            return None;
+        };
+        Some(TestDatabase {
+            dsn,
+            table: format!("mqdish_test_{}", Uuid::new_v4().simple()),
+        })
+    }
+
+    async fn bus(&self, prefetch: u16, poll_interval: u64) -> PostgresBus {
+        let params = PostgresParams {
+            table: self.table.clone(),
+            prefetch,
+            poll_interval,
+            ..PostgresParams::default()
+        };
+        PostgresBus::new(
+            Connection::DSN(self.dsn.clone()),
+            Credentials::None,
+            BusParams::Postgres(params),
+        )
+        .await
+        .unwrap()
+    }
+
+    async fn client(&self) -> Client {
+        let (client, connection) = tokio_postgres::connect(&self.dsn, NoTls).await.unwrap();
+        tokio::spawn(connection);
+        client
+    }
+
+    // Runs the query on the table of the test, `{table}` is replaced with its name.
+    async fn query(&self, query: &str) -> Vec<tokio_postgres::Row> {
+        let query = query.replace("{table}", &quote_identifier(&self.table));
+        self.client().await.query(&query, &[]).await.unwrap()
+    }
+
+    async fn drop_table(&self) {
+        self.query("DROP TABLE {table}").await;
+        let reply_queues = format!("{}_reply_queues", self.table);
+        let query = format!("DROP TABLE {}", quote_identifier(&reply_queues));
+        self.client().await.execute(&query, &[]).await.unwrap();
+    }
+}
+
+async fn publish(bus: &mut PostgresBus, body: &str) {
+    bus.publish(TOPIC.to_string(), body.to_string(), MessageProps::default())
+        .await
+        .unwrap();
+}
+
+async fn next(stream: &mut MessageStream) -> Option<Box<dyn Message + Send>> {
+    timeout(NO_DELIVERY, stream.next()).await.ok().flatten()
+}
+
+#[test]
+fn test_quote_identifier() {
+    assert_eq!(quote_identifier("mqdish_tasks"), "\"mqdish_tasks\"");
+    assert_eq!(quote_identifier("a\"b"), "\"a\"\"b\"");
+}
+
+#[test]
+fn test_connection_config() {
+    let connection = Connection::Params(ConnectionParams {
+        host: "db".to_string(),
+        port: 5433,
+        ssl: true,
+    });
+    let credentials = Credentials::LoginPassword(LoginPassword {
+        login: "user".to_string(),
+        password: "pass".to_string(),
+    });
+    let pg_config = connection_config(connection, &credentials, "mqdish").unwrap();
+    assert_eq!(pg_config.get_hosts(), &[Host::Tcp("db".to_string())]);
+    assert_eq!(pg_config.get_ports(), &[5433]);
+    assert_eq!(pg_config.get_dbname(), Some("mqdish"));
+    assert_eq!(pg_config.get_user(), Some("user"));
+    assert_eq!(pg_config.get_ssl_mode(), SslMode::Require);
+
+    // the database given in the connection string takes precedence
+    let connection = Connection::DSN("postgres://user@db/tasks".to_string());
+    let pg_config = connection_config(connection, &Credentials::None, "mqdish").unwrap();
+    assert_eq!(pg_config.get_dbname(), Some("tasks"));
+}
+
+#[tokio::test]
+async fn test_workers_share_queue() {
+    let Some(database) = TestDatabase::new() else {
+        return;
+    };
+    let mut first = database.bus(1, 50).await;
+    let mut second = database.bus(1, 50).await;
+    publish(&mut first, "first").await;
+    publish(&mut first, "second").await;
+    publish(&mut first, "third").await;
+
+    let mut first_stream = first.consume(TOPIC.to_string()).await.unwrap();
+    let first_msg = next(&mut first_stream).await.unwrap();
+    assert_eq!(first_msg.body(), "first");
+    // the task claimed by the first worker is skipped
+    let mut second_stream = second.consume(TOPIC.to_string()).await.unwrap();
+    let second_msg = next(&mut second_stream).await.unwrap();
+    assert_eq!(second_msg.body(), "second");
+    assert!(next(&mut first_stream).await.is_none());
+
+    first_msg.ack().await.unwrap();
+    assert!(first_msg.ack().await.is_err());
+    let third_msg = next(&mut first_stream).await.unwrap();
+    assert_eq!(third_msg.body(), "third");
+    // unsettled messages are returned to the queue
+    drop(second_msg);
+    assert_eq!(next(&mut second_stream).await.unwrap().body(), "second");
+    database.drop_table().await;
+}
+
+#[tokio::test]
+async fn test_notification_wakes_worker() {
+    let Some(database) = TestDatabase::new() else {
+        return;
+    };
+    // polling alone would not find the task in time
+    let mut bus = database.bus(1, 60000).await;
+    let mut producer = database.bus(1, 60000).await;
+    let mut stream = bus.consume(TOPIC.to_string()).await.unwrap();
+    assert!(next(&mut stream).await.is_none());
+
+    publish(&mut producer, "task").await;
+    let msg = next(&mut stream).await.unwrap();
+    assert_eq!(msg.body(), "task");
+    // released tasks notify waiting workers as well
+    msg.requeue().await.unwrap();
+    assert_eq!(next(&mut stream).await.unwrap().body(), "task");
+    database.drop_table().await;
+}
+
+#[tokio::test]
+async fn test_expired_lease_is_claimed() {
+    let Some(database) = TestDatabase::new() else {
+        return;
+    };
+    let mut bus = database.bus(0, 50).await;
+    publish(&mut bus, "stale").await;
+    publish(&mut bus, "running").await;
+    // claimed by workers which are gone and which are still running the task
+    database
+        .query(
+            "UPDATE {table} SET lease = 'dead', leased_until = now() - interval '1 second'
+             WHERE body = 'stale'",
+        )
+        .await;
+    database
+        .query(
+            "UPDATE {table} SET lease = 'alive', leased_until = now() + interval '1 hour'
+             WHERE body = 'running'",
+        )
+        .await;
+
+    let mut stream = bus.consume(TOPIC.to_string()).await.unwrap();
+    let msg = next(&mut stream).await.unwrap();
+    assert_eq!(msg.body(), "stale");
+    assert!(next(&mut stream).await.is_none());
+
+    // once the lease is taken over, the result of the new owner is kept
+    database
+        .query("UPDATE {table} SET lease = 'other', result = 'theirs' WHERE body = 'stale'")
+        .await;
+    assert!(msg.reply("mine".to_string()).await.is_err());
+    assert!(msg.ack().await.is_err());
+    let rows = database
+        .query("SELECT result FROM {table} WHERE body = 'stale'")
+        .await;
+    assert_eq!(
+        rows[0].get::<_, Option<String>>(0).as_deref(),
+        Some("theirs")
+    );
+    database.drop_table().await;
+}
+
+#[tokio::test]
+async fn test_result_and_attempt_columns() {
+    let Some(database) = TestDatabase::new() else {
+        return;
+    };
+    let mut bus = database.bus(0, 50).await;
+    publish(&mut bus, "succeeding").await;
+    publish(&mut bus, "failing").await;
+    let mut stream = bus.consume(TOPIC.to_string()).await.unwrap();
+
+    let msg = next(&mut stream).await.unwrap();
+    msg.retry(Duration::from_secs(3600)).await.unwrap();
+    let failing = next(&mut stream).await.unwrap();
+    assert_eq!(failing.body(), "failing");
+    let failure = Failure {
+        reason: "failed".to_string(),
+        exit_code: Some(2),
+    };
+    failing.reply("failed".to_string()).await.unwrap();
+    failing.nack(failure).await.unwrap();
+    // the retry is not visible before its delay passes
+    assert!(next(&mut stream).await.is_none());
+    database
+        .query("UPDATE {table} SET visible_at = now() WHERE body = 'succeeding'")
+        .await;
+    let retried = next(&mut stream).await.unwrap();
+    assert_eq!(retried.attempt(), 2);
+    retried.reply("ok".to_string()).await.unwrap();
+    retried.ack().await.unwrap();
+
+    let rows = database
+        .query(
+            "SELECT body, status, attempt, result, failure_reason, exit_code,
+                 finished_at IS NOT NULL AS finished, lease IS NULL AS released
+             FROM {table} ORDER BY id",
+        )
+        .await;
+    let columns = |row: &tokio_postgres::Row| {
+        (
+            row.get::<_, String>("body"),
+            row.get::<_, String>("status"),
+            row.get::<_, i32>("attempt"),
+            row.get::<_, Option<String>>("result"),
+            row.get::<_, Option<String>>("failure_reason"),
+            row.get::<_, Option<i32>>("exit_code"),
+            row.get::<_, bool>("finished"),
+            row.get::<_, bool>("released"),
+        )
+    };
+    assert_eq!(
+        columns(&rows[0]),
+        (
+            "succeeding".to_string(),
+            "done".to_string(),
+            2,
+            Some("ok".to_string()),
+            None,
+            None,
+            true,
+            true
+        )
+    );
+    assert_eq!(
+        columns(&rows[1]),
+        (
+            "failing".to_string(),
+            "failed".to_string(),
+            1,
+            Some("failed".to_string()),
+            Some("failed".to_string()),
+            Some(2),
+            true,
+            true
+        )
+    );
+    database.drop_table().await;
+}
+
+#[tokio::test]
+async fn test_dead_letter() {
+    let Some(database) = TestDatabase::new() else {
+        return;
+    };
+    let topics = HashMap::from([(
+        TOPIC.to_string(),
+        TopicConfig {
+            dead_letter: true,
+            ..TopicConfig::default()
+        },
+    )]);
+    let mut bus = database.bus(0, 50).await.with_topics(topics);
+    publish(&mut bus, "task").await;
+    let mut stream = bus.consume(TOPIC.to_string()).await.unwrap();
+
+    let failure = Failure {
+        reason: "failed".to_string(),
+        exit_code: Some(2),
+    };
+    next(&mut stream)
+        .await
+        .unwrap()
+        .nack(failure.clone())
+        .await
+        .unwrap();
+    assert!(next(&mut stream).await.is_none());
+
+    let dead_letters = bus.dead_letters(TOPIC.to_string()).await.unwrap();
+    assert_eq!(dead_letters.len(), 1);
+    assert_eq!(dead_letters[0].failure(), Some(failure));
+    dead_letters[0].requeue().await.unwrap();
+    assert_eq!(bus.purge_dead_letters(TOPIC.to_string()).await.unwrap(), 1);
+    database.drop_table().await;
+}
+
+#[tokio::test]
+async fn test_retry_and_reply() {
+    let Some(database) = TestDatabase::new() else {
+        return;
+    };
+    let mut bus = database.bus(0, 50).await;
+    let mut producer = database.bus(0, 50).await;
+    let (reply_to, mut replies) = producer.consume_replies().await.unwrap();
+    let props = MessageProps {
+        correlation_id: Some("id".to_string()),
+        reply_to: Some(reply_to),
+        priority: None,
+        requires: vec![],
+    };
+    producer
+        .publish(TOPIC.to_string(), "task".to_string(), props)
+        .await
+        .unwrap();
+    let mut stream = bus.consume(TOPIC.to_string()).await.unwrap();
+
+    let msg = next(&mut stream).await.unwrap();
+    msg.reply("result".to_string()).await.unwrap();
+    msg.retry(Duration::from_millis(10)).await.unwrap();
+    assert_eq!(next(&mut replies).await.unwrap().body(), "result");
+    assert_eq!(next(&mut stream).await.unwrap().attempt(), 2);
+    producer.close().await.unwrap();
+    database.drop_table().await;
+}
+
+#[tokio::test]
+async fn test_late_reply_is_dropped() {
+    let Some(database) = TestDatabase::new() else {
+        return;
+    };
+    let mut bus = database.bus(0, 50).await;
+    let mut producer = database.bus(0, 50).await;
+    let (reply_to, _replies) = producer.consume_replies().await.unwrap();
+    let props = MessageProps {
+        reply_to: Some(reply_to.clone()),
+        ..MessageProps::default()
+    };
+    producer
+        .publish(TOPIC.to_string(), "task".to_string(), props)
+        .await
+        .unwrap();
+    let mut stream = bus.consume(TOPIC.to_string()).await.unwrap();
+    let msg = next(&mut stream).await.unwrap();
+    producer.close().await.unwrap();
+
+    msg.reply("result".to_string()).await.unwrap();
+    msg.ack().await.unwrap();
+    let rows = database
+        .query(&format!(
+            "SELECT id FROM {{table}} WHERE queue = '{}'",
+            reply_to
+        ))
+        .await;
+    assert!(rows.is_empty());
+    database.drop_table().await;
+}
+
+#[tokio::test]
+async fn test_delayed_delivery() {
+    let Some(database) = TestDatabase::new() else {
+        return;
+    };
+    let mut bus = database.bus(0, 50).await;
+    let at = SystemTime::now() + NO_DELIVERY * 2;
+    bus.publish_at(
+        TOPIC.to_string(),
+        "delayed".to_string(),
+        MessageProps::default(),
+        at,
+    )
+    .await
+    .unwrap();
+    publish(&mut bus, "task").await;
+    let mut stream = bus.consume(TOPIC.to_string()).await.unwrap();
+
+    // the task published later is delivered first
+    let msg = next(&mut stream).await.unwrap();
+    assert_eq!(msg.body(), "task");
+    msg.ack().await.unwrap();
+    assert!(next(&mut stream).await.is_none());
+    let delayed = timeout(DUE, stream.next()).await.unwrap().unwrap();
+    assert_eq!(delayed.body(), "delayed");
+    assert!(SystemTime::now() >= at);
+
+    // postponing it is not counted as an attempt
+    delayed.postpone(Duration::from_millis(10)).await.unwrap();
+    assert_eq!(next(&mut stream).await.unwrap().attempt(), 1);
+    database.drop_table().await;
+}
@@ -0,0 +1,822 @@
+use crate::shared::backoff::Backoff;
+use crate::shared::config;
+use crate::shared::config::{BusParams, Credentials, ReconnectParams, TopicConfig};
+use crate::shared::msgbus::bus::{
+    dead_letter_queue, Closer, Consumer, DeadLetters, Failure, Message, MessageProps,
+    MessageStream, Publisher, ReplyConsumer,
+};
+use async_trait::async_trait;
+use redis::aio::ConnectionManager;
+use redis::streams::{
+    StreamAddOptions, StreamAutoClaimOptions, StreamAutoClaimReply, StreamClaimOptions, StreamId,
+    StreamRangeReply, StreamReadOptions, StreamReadReply,
+};
+use redis::{AsyncCommands, Client, RedisResult};
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+use tokio::select;
+use tokio::sync::{mpsc, watch, OwnedSemaphorePermit, Semaphore};
+use tokio::time::{interval, sleep};
+use tokio_stream::wrappers::ReceiverStream;
+use uuid::Uuid;
+
+const BODY_FIELD: &str = "body";
+const CORRELATION_ID_FIELD: &str = "correlation_id";
+const REPLY_TO_FIELD: &str = "reply_to";
+const ATTEMPT_FIELD: &str = "attempt";
+const FAILURE_REASON_FIELD: &str = "failure_reason";
+const EXIT_CODE_FIELD: &str = "exit_code";
+// How long XREADGROUP waits for new entries, bounds the delay of retries and cancellation.
+const BLOCK_MS: usize = 1000;
+// Maximum number of due retries moved back to the stream at once.
+const RETRY_BATCH: isize = 16;
+// Moves due members of the retry set (KEYS[1]) to the stream (KEYS[2]), members which cannot be
+// decoded are moved to the dead-letter stream (KEYS[3]) instead. ARGV holds the current time,
+// the batch size and the names of the body, attempt and failure reason fields.
+const PROMOTE_SCRIPT: &str = r#"
+local due = redis.call('ZRANGEBYSCORE', KEYS[1], '-inf', ARGV[1], 'LIMIT', 0, ARGV[2])
+for _, member in ipairs(due) do
+    redis.call('ZREM', KEYS[1], member)
+    local ok, decoded = pcall(cjson.decode, member)
+    local fields = {}
+    ok = ok and type(decoded) == 'table' and type(decoded[2]) == 'table'
+    if ok then
+        for _, field in ipairs(decoded[2]) do
+            if type(field) ~= 'table' or type(field[1]) ~= 'string' or type(field[2]) ~= 'string' then
+                ok = false
+                break
+            end
+            table.insert(fields, field[1])
+            table.insert(fields, field[2])
+        end
+    end
+    if ok and #fields > 0 then
+        redis.call('XADD', KEYS[2], '*', unpack(fields))
+    else
+        redis.call('XADD', KEYS[3], '*', ARGV[3], member, ARGV[4], '1', ARGV[5], 'undecodable retry')
+    end
+end
+return #due
+"#;
+
+/// Message bus on Redis Streams, each topic is a stream consumed by a consumer group.
+pub struct RedisBus {
+    client: Client,
+    connection: ConnectionManager,
+    group: String,
+    // Name of this worker in the consumer group.
+    consumer: String,
+    prefetch: u16,
+    requeue: bool,
+    claim_timeout: Duration,
+    reconnect: ReconnectParams,
+    topics: HashMap<String, TopicConfig>,
+    // Stops the subscription created by `consume`.
+    subscription: Option<(String, watch::Sender<bool>)>,
+    // Reply streams with their subscriptions, removed on close.
+    reply_streams: Vec<(String, watch::Sender<bool>)>,
+}
+
+#[derive(Error, Debug)]
+pub enum RedisBusError {
+    #[error("Not implemented for Redis driver: {0}")]
+    NotImplemented(String),
+    #[error("Invalid argument: {0}")]
+    InvalidArgument(String),
+    #[error("Connection to Redis server failed: {0}")]
+    ConnectionFailure(String),
+}
+
+// Contents of a stream entry.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct Envelope {
+    pub(crate) body: String,
+    pub(crate) props: MessageProps,
+    pub(crate) attempt: u32,
+    pub(crate) failure: Option<Failure>,
+}
+
+impl Envelope {
+    pub(crate) fn from_entry(entry: &StreamId) -> Self {
+        let failure = entry
+            .get::<String>(FAILURE_REASON_FIELD)
+            .map(|reason| Failure {
+                reason,
+                exit_code: entry.get(EXIT_CODE_FIELD),
+            });
+        Envelope {
+            body: entry.get(BODY_FIELD).unwrap_or_default(),
+            props: MessageProps {
+                correlation_id: entry.get(CORRELATION_ID_FIELD),
+                reply_to: entry.get(REPLY_TO_FIELD),
+                priority: None,
+                requires: vec![],
+            },
+            attempt: entry.get(ATTEMPT_FIELD).unwrap_or(1),
+            failure,
+        }
+    }
+
+    pub(crate) fn fields(&self) -> Vec<(&'static str, String)> {
+        let mut fields = vec![
+            (BODY_FIELD, self.body.clone()),
+            (ATTEMPT_FIELD, self.attempt.to_string()),
+        ];
+        if let Some(correlation_id) = &self.props.correlation_id {
+            fields.push((CORRELATION_ID_FIELD, correlation_id.clone()));
+        }
+        if let Some(reply_to) = &self.props.reply_to {
+            fields.push((REPLY_TO_FIELD, reply_to.clone()));
+        }
+        if let Some(failure) = &self.failure {
+            fields.push((FAILURE_REASON_FIELD, failure.reason.clone()));
+            if let Some(exit_code) = failure.exit_code {
+                fields.push((EXIT_CODE_FIELD, exit_code.to_string()));
+            }
+        }
+        fields
+    }
+}
+
+// Sorted set holding messages of the stream to be retried, scored by the due time.
+fn retry_set(stream: &str) -> String {
+    format!("{}.retry", stream)
+}
+
+// Member of the retry set holding the message.
+fn retry_member(envelope: &Envelope) -> serde_json::Result<String> {
+    let fields: Vec<(String, String)> = envelope
+        .fields()
+        .into_iter()
+        .map(|(field, value)| (field.to_string(), value))
+        .collect();
+    // the unique id keeps identical messages apart in the set
+    serde_json::to_string(&(Uuid::new_v4().to_string(), fields))
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+impl RedisBus {
+    pub async fn new(
+        connection_cfg: config::Connection,
+        credentials: Credentials,
+        bus_params: BusParams,
+    ) -> Result<Self, RedisBusError> {
+        let BusParams::Redis(redis_params) = bus_params else {
+            return Err(RedisBusError::InvalidArgument(
+                "bus params of another driver".to_string(),
+            ));
+        };
+        let auth_part = match credentials {
+            Credentials::LoginPassword(creds) => format!("{}:{}@", creds.login, creds.password),
+            Credentials::TLSClientAuth(_) => {
+                return Err(RedisBusError::NotImplemented(
+                    "TLS client authentication".to_string(),
+                ))
+            }
+            Credentials::None => "".to_string(),
+        };
+        let connection_url = match connection_cfg {
+            config::Connection::DSN(dsn) => dsn,
+            config::Connection::Params(params) => format!(
+                "{}://{}{}:{}/{}",
+                if params.ssl { "rediss" } else { "redis" },
+                auth_part,
+                params.host,
+                params.port,
+                redis_params.database,
+            ),
+        };
+
+        let client = Client::open(connection_url)
+            .map_err(|err| RedisBusError::InvalidArgument(format!("connection URL: {}", err)))?;
+        let connection = ConnectionManager::new(client.clone())
+            .await
+            .map_err(|err| RedisBusError::ConnectionFailure(err.to_string()))?;
+
+        Ok(RedisBus {
+            client,
+            connection,
+            group: redis_params.group,
+            consumer: format!("mqdish-{}", Uuid::new_v4()),
+            prefetch: redis_params.prefetch,
+            requeue: redis_params.requeue,
+            claim_timeout: Duration::from_secs(redis_params.claim_timeout),
+            reconnect: redis_params.reconnect,
+            topics: HashMap::new(),
+            subscription: None,
+            reply_streams: vec![],
+        })
+    }
+
+    /// Sets the settings of topics, tasks of topics with `dead_letter` enabled
+    /// are moved to the dead-letter stream when they fail for good.
+    pub fn with_topics(mut self, topics: HashMap<String, TopicConfig>) -> Self {
+        self.topics = topics;
+        self
+    }
+
+    fn dead_letter_stream(&self, topic: &str) -> Option<String> {
+        match self.topics.get(topic) {
+            Some(topic_config) if topic_config.dead_letter => Some(dead_letter_queue(topic)),
+            _ => None,
+        }
+    }
+
+    // Creates the consumer group reading the stream from its beginning unless it exists.
+    async fn create_group(&mut self, stream: &str) -> RedisResult<()> {
+        let created: RedisResult<()> = self
+            .connection
+            .xgroup_create_mkstream(stream, &self.group, "0")
+            .await;
+        match created {
+            Err(err) if err.code() == Some("BUSYGROUP") => Ok(()),
+            created => created,
+        }
+    }
+
+    // Delivers entries of the stream to the returned stream of messages until the sender is set
+    // or the messages are dropped. No more than `prefetch` messages are delivered without being
+    // settled, unlimited if zero.
+    async fn subscribe(
+        &mut self,
+        stream: String,
+        requeue: bool,
+    ) -> Result<(MessageStream, watch::Sender<bool>), Box<dyn Error>> {
+        self.create_group(&stream).await?;
+        // blocking reads would hold up other commands of a shared connection
+        let reads = ConnectionManager::new(self.client.clone()).await?;
+
+        let (cancel_tx, cancel_rx) = watch::channel(false);
+        let (msg_tx, msg_rx) = mpsc::channel::<Box<dyn Message + Send>>(1);
+        let prefetch = match self.prefetch {
+            0 => Semaphore::MAX_PERMITS,
+            prefetch => prefetch as usize,
+        };
+        let subscription = Subscription {
+            connection: self.connection.clone(),
+            dead_letter_stream: self.dead_letter_stream(&stream),
+            stream,
+            group: self.group.clone(),
+            consumer: self.consumer.clone(),
+            requeue,
+            in_flight: Arc::new(Mutex::new(HashSet::new())),
+        };
+
+        tokio::spawn(refresh(
+            subscription.clone(),
+            self.claim_timeout / 3,
+            msg_tx.clone(),
+        ));
+        tokio::spawn(deliver(
+            subscription,
+            reads,
+            Arc::new(Semaphore::new(prefetch)),
+            self.claim_timeout,
+            self.reconnect.clone(),
+            cancel_rx,
+            msg_tx,
+        ));
+
+        Ok((Box::pin(ReceiverStream::new(msg_rx)), cancel_tx))
+    }
+
+    // Runs the command publishing a message, retrying it while the connection is being restored.
+    async fn run(&mut self, cmd: redis::Cmd) -> Result<(), Box<dyn Error>> {
+        let mut backoff = Backoff::new(self.reconnect.clone());
+        loop {
+            let run: RedisResult<()> = cmd.query_async(&mut self.connection).await;
+            // the connection manager reconnects on the next command
+            let err = match run {
+                Err(err) if err.is_io_error() || err.is_connection_dropped() => err,
+                run => return run.map_err(|err| err.into()),
+            };
+            match backoff.next_delay() {
+                Some(delay) => {
+                    println!(
+                        "Failed to publish, next attempt in {} ms: {}",
+                        delay.as_millis(),
+                        err
+                    );
+                    sleep(delay).await;
+                }
+                None => return Err(err.into()),
+            }
+        }
+    }
+}
+
+// Settings shared by the messages consumed from the same stream.
+#[derive(Clone)]
+struct Subscription {
+    connection: ConnectionManager,
+    stream: String,
+    group: String,
+    consumer: String,
+    requeue: bool,
+    dead_letter_stream: Option<String>,
+    // Entries delivered by this subscription which are not settled yet.
+    in_flight: Arc<Mutex<HashSet<String>>>,
+}
+
+impl Subscription {
+    // Takes the next entry, either a stale one of a dead consumer or a new one.
+    async fn next(
+        &self,
+        reads: &mut ConnectionManager,
+        claim_timeout: Duration,
+    ) -> RedisResult<Option<StreamId>> {
+        self.promote_retries().await?;
+
+        let mut connection = self.connection.clone();
+        let claimed: StreamAutoClaimReply = connection
+            .xautoclaim_options(
+                &self.stream,
+                &self.group,
+                &self.consumer,
+                claim_timeout.as_millis() as u64,
+                "0-0",
+                StreamAutoClaimOptions::default().count(1),
+            )
+            .await?;
+        if let Some(entry) = claimed.claimed.into_iter().next() {
+            return Ok(Some(entry));
+        }
+
+        let options = StreamReadOptions::default()
+            .group(&self.group, &self.consumer)
+            .count(1)
+            .block(BLOCK_MS);
+        let read: Option<StreamReadReply> = reads
+            .xread_options(&[&self.stream], &[">"], &options)
+            .await?;
+        Ok(read
+            .and_then(|read| read.keys.into_iter().next())
+            .and_then(|key| key.ids.into_iter().next()))
+    }
+
+    // Moves messages whose retry delay has passed back to the stream.
+    async fn promote_retries(&self) -> RedisResult<()> {
+        // the script is atomic, so a message is either still in the set or already in the stream
+        let _: u32 = redis::cmd("EVAL")
+            .arg(PROMOTE_SCRIPT)
+            .arg(3)
+            .arg(retry_set(&self.stream))
+            .arg(&self.stream)
+            .arg(dead_letter_queue(&self.stream))
+            .arg(now_ms())
+            .arg(RETRY_BATCH)
+            .arg(BODY_FIELD)
+            .arg(ATTEMPT_FIELD)
+            .arg(FAILURE_REASON_FIELD)
+            .query_async(&mut self.connection.clone())
+            .await?;
+        Ok(())
+    }
+
+    // Acknowledges the entry and removes it from the stream along with `publish`ed entries.
+    async fn settle(&self, id: &str, publish: Option<(&str, &Envelope)>) -> RedisResult<()> {
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        if let Some((stream, envelope)) = publish {
+            pipe.xadd(stream, "*", &envelope.fields()).ignore();
+        }
+        pipe.xack(&self.stream, &self.group, &[id])
+            .ignore()
+            .xdel(&self.stream, &[id])
+            .ignore();
+        pipe.query_async(&mut self.connection.clone()).await
+    }
+}
+
+async fn deliver(
+    subscription: Subscription,
+    mut reads: ConnectionManager,
+    prefetch: Arc<Semaphore>,
+    claim_timeout: Duration,
+    reconnect: ReconnectParams,
+    mut cancel_rx: watch::Receiver<bool>,
+    msg_tx: mpsc::Sender<Box<dyn Message + Send>>,
+) {
+    loop {
+        let permit = select! {
+            permit = Arc::clone(&prefetch).acquire_owned() => permit.expect("semaphore is never closed"),
+            _ = async { let _ = cancel_rx.wait_for(|cancelled| *cancelled).await; } => return,
+            _ = msg_tx.closed() => return,
+        };
+        let mut backoff = Backoff::new(reconnect.clone());
+        let entry = loop {
+            // reads are not interrupted, so that entries taken from the group are delivered
+            if *cancel_rx.borrow() || msg_tx.is_closed() {
+                return;
+            }
+            match subscription.next(&mut reads, claim_timeout).await {
+                Ok(Some(entry)) => break entry,
+                Ok(None) => backoff = Backoff::new(reconnect.clone()),
+                Err(err) => match backoff.next_delay() {
+                    Some(delay) => {
+                        println!(
+                            "Failed to read {}, next attempt in {} ms: {}",
+                            subscription.stream,
+                            delay.as_millis(),
+                            err
+                        );
+                        sleep(delay).await;
+                    }
+                    None => {
+                        println!("Giving up reading {}: {}", subscription.stream, err);
+                        return;
+                    }
+                },
+            }
+        };
+        subscription
+            .in_flight
+            .lock()
+            .unwrap()
+            .insert(entry.id.clone());
+        let msg = RedisMessage {
+            envelope: Envelope::from_entry(&entry),
+            id: entry.id,
+            subscription: subscription.clone(),
+            permit: Mutex::new(Some(permit)),
+            settled: AtomicBool::new(false),
+        };
+        // an undelivered entry stays pending and is claimed again after the claim timeout
+        if msg_tx.send(Box::new(msg)).await.is_err() {
+            return;
+        }
+    }
+}
+
+// Keeps entries being executed from being claimed by other consumers as stale.
+async fn refresh(
+    subscription: Subscription,
+    period: Duration,
+    msg_tx: mpsc::Sender<Box<dyn Message + Send>>,
+) {
+    let mut ticks = interval(period.max(Duration::from_secs(1)));
+    loop {
+        select! {
+            _ = ticks.tick() => {}
+            _ = msg_tx.closed() => return,
+        }
+        let ids: Vec<String> = subscription
+            .in_flight
+            .lock()
+            .unwrap()
+            .iter()
+            .cloned()
+            .collect();
+        if ids.is_empty() {
+            continue;
+        }
+        let claimed: RedisResult<Vec<String>> = subscription
+            .connection
+            .clone()
+            .xclaim_options(
+                &subscription.stream,
+                &subscription.group,
+                &subscription.consumer,
+                0,
+                &ids,
+                StreamClaimOptions::default().with_justid(),
+            )
+            .await;
+        if let Err(err) = claimed {
+            println!("Failed to refresh pending entries: {}", err);
+        }
+    }
+}
+
+struct RedisMessage {
+    id: String,
+    envelope: Envelope,
+    subscription: Subscription,
+    // Frees a prefetch slot of the subscription once the message is settled.
+    permit: Mutex<Option<OwnedSemaphorePermit>>,
+    settled: AtomicBool,
+}
+
+impl RedisMessage {
+    async fn settle(&self, publish: Option<(&str, &Envelope)>) -> Result<(), Box<dyn Error>> {
+        if self.settled.swap(true, Ordering::SeqCst) {
+            return Err("Message is already settled".into());
+        }
+        self.permit.lock().unwrap().take();
+        self.subscription.in_flight.lock().unwrap().remove(&self.id);
+        self.subscription.settle(&self.id, publish).await?;
+        Ok(())
+    }
+
+    // Adds the envelope to the retry set to be moved back to the stream after `delay`.
+    async fn publish_after(
+        &self,
+        delay: Duration,
+        envelope: &Envelope,
+    ) -> Result<(), Box<dyn Error>> {
+        let due = now_ms() + delay.as_millis() as u64;
+        let _: () = self
+            .subscription
+            .connection
+            .clone()
+            .zadd(
+                retry_set(&self.subscription.stream),
+                retry_member(envelope)?,
+                due,
+            )
+            .await?;
+        self.settle(None).await
+    }
+}
+
+impl Drop for RedisMessage {
+    // unsettled entries stay pending and are claimed again after the claim timeout
+    fn drop(&mut self) {
+        self.subscription.in_flight.lock().unwrap().remove(&self.id);
+    }
+}
+
+#[async_trait]
+impl Message for RedisMessage {
+    async fn ack(&self) -> Result<(), Box<dyn Error>> {
+        self.settle(None).await
+    }
+
+    async fn nack(&self, failure: Failure) -> Result<(), Box<dyn Error>> {
+        if self.subscription.requeue {
+            return self.requeue().await;
+        }
+        self.reject(failure).await
+    }
+
+    async fn reject(&self, failure: Failure) -> Result<(), Box<dyn Error>> {
+        match &self.subscription.dead_letter_stream {
+            Some(stream) => {
+                let envelope = Envelope {
+                    failure: Some(failure),
+                    ..self.envelope.clone()
+                };
+                self.settle(Some((stream, &envelope))).await
+            }
+            None => self.settle(None).await,
+        }
+    }
+
+    async fn requeue(&self) -> Result<(), Box<dyn Error>> {
+        // re-added to the end of the stream, pending entries can only be claimed by consumers
+        self.settle(Some((&self.subscription.stream, &self.envelope)))
+            .await
+    }
+
+    async fn retry(&self, delay: Duration) -> Result<(), Box<dyn Error>> {
+        let envelope = Envelope {
+            attempt: self.envelope.attempt + 1,
+            ..self.envelope.clone()
+        };
+        self.publish_after(delay, &envelope).await
+    }
+
+    async fn postpone(&self, delay: Duration) -> Result<(), Box<dyn Error>> {
+        self.publish_after(delay, &self.envelope).await
+    }
+
+    async fn reply(&self, msg: String) -> Result<(), Box<dyn Error>> {
+        let Some(reply_to) = &self.envelope.props.reply_to else {
+            return Ok(());
+        };
+        let envelope = Envelope {
+            body: msg,
+            props: MessageProps {
+                correlation_id: self.envelope.props.correlation_id.clone(),
+                reply_to: None,
+                priority: None,
+                requires: vec![],
+            },
+            attempt: 1,
+            failure: None,
+        };
+        // the stream is removed once the producer stops waiting, a late reply must not recreate it
+        let added: Option<String> = self
+            .subscription
+            .connection
+            .clone()
+            .xadd_options(
+                reply_to,
+                "*",
+                envelope.fields(),
+                &StreamAddOptions::default().nomkstream(),
+            )
+            .await?;
+        if added.is_none() {
+            println!("Reply stream {} is gone, dropping the reply", reply_to);
+        }
+        Ok(())
+    }
+
+    fn body(&self) -> String {
+        self.envelope.body.clone()
+    }
+
+    fn delivery_info(&self) -> String {
+        format!(
+            "stream {}, entry {}, attempt {}",
+            self.subscription.stream, self.id, self.envelope.attempt
+        )
+    }
+
+    fn attempt(&self) -> u32 {
+        self.envelope.attempt
+    }
+
+    fn failure(&self) -> Option<Failure> {
+        self.envelope.failure.clone()
+    }
+}
+
+// Entry of a dead-letter stream, which is read without a consumer group.
+struct DeadLetter {
+    connection: ConnectionManager,
+    stream: String,
+    id: String,
+    envelope: Envelope,
+}
+
+#[async_trait]
+impl Message for DeadLetter {
+    async fn ack(&self) -> Result<(), Box<dyn Error>> {
+        let _: u32 = self
+            .connection
+            .clone()
+            .xdel(&self.stream, &[&self.id])
+            .await?;
+        Ok(())
+    }
+
+    // entries which are not acknowledged just stay in the stream
+    async fn nack(&self, _failure: Failure) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    async fn reject(&self, _failure: Failure) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    async fn requeue(&self) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    async fn retry(&self, _delay: Duration) -> Result<(), Box<dyn Error>> {
+        Err(RedisBusError::NotImplemented("retry of dead-lettered messages".to_string()).into())
+    }
+
+    async fn reply(&self, _msg: String) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    fn body(&self) -> String {
+        self.envelope.body.clone()
+    }
+
+    fn attempt(&self) -> u32 {
+        self.envelope.attempt
+    }
+
+    fn failure(&self) -> Option<Failure> {
+        self.envelope.failure.clone()
+    }
+}
+
+#[async_trait]
+impl Publisher for RedisBus {
+    async fn publish(
+        &mut self,
+        topic: String,
+        msg: String,
+        props: MessageProps,
+    ) -> Result<(), Box<dyn Error>> {
+        let envelope = Envelope {
+            body: msg,
+            props,
+            attempt: 1,
+            failure: None,
+        };
+        self.run(redis::Cmd::xadd(&topic, "*", &envelope.fields()))
+            .await
+    }
+
+    // Delayed messages wait in the retry set of the stream, like retried ones.
+    async fn publish_at(
+        &mut self,
+        topic: String,
+        msg: String,
+        props: MessageProps,
+        at: SystemTime,
+    ) -> Result<(), Box<dyn Error>> {
+        let envelope = Envelope {
+            body: msg,
+            props,
+            attempt: 1,
+            failure: None,
+        };
+        let due = at.duration_since(UNIX_EPOCH)?.as_millis() as u64;
+        let member = retry_member(&envelope)?;
+        self.run(redis::Cmd::zadd(retry_set(&topic), member, due))
+            .await
+    }
+}
+
+#[async_trait]
+impl Consumer for RedisBus {
+    async fn consume(&mut self, topic: String) -> Result<MessageStream, Box<dyn Error>> {
+        let (stream, cancel) = self.subscribe(topic.clone(), self.requeue).await?;
+        self.subscription = Some((topic, cancel));
+        Ok(stream)
+    }
+
+    async fn cancel(&mut self) -> Result<(), Box<dyn Error>> {
+        if let Some((_, cancel)) = &self.subscription {
+            let _ = cancel.send(true);
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl ReplyConsumer for RedisBus {
+    async fn consume_replies(&mut self) -> Result<(String, MessageStream), Box<dyn Error>> {
+        let stream_name = format!("mqdish.reply.{}", Uuid::new_v4());
+        let (stream, cancel) = self.subscribe(stream_name.clone(), false).await?;
+        self.reply_streams.push((stream_name.clone(), cancel));
+        Ok((stream_name, stream))
+    }
+}
+
+#[async_trait]
+impl DeadLetters for RedisBus {
+    async fn dead_letters(
+        &mut self,
+        topic: String,
+    ) -> Result<Vec<Box<dyn Message + Send>>, Box<dyn Error>> {
+        let stream = dead_letter_queue(&topic);
+        let range: StreamRangeReply = self.connection.xrange_all(&stream).await?;
+        let messages = range
+            .ids
+            .into_iter()
+            .map(|entry| {
+                Box::new(DeadLetter {
+                    connection: self.connection.clone(),
+                    stream: stream.clone(),
+                    envelope: Envelope::from_entry(&entry),
+                    id: entry.id,
+                }) as Box<dyn Message + Send>
+            })
+            .collect();
+        Ok(messages)
+    }
+
+    async fn purge_dead_letters(&mut self, topic: String) -> Result<u32, Box<dyn Error>> {
+        let stream = dead_letter_queue(&topic);
+        let (purged, _): (u32, ()) = redis::pipe()
+            .atomic()
+            .xlen(&stream)
+            .del(&stream)
+            .query_async(&mut self.connection)
+            .await?;
+        Ok(purged)
+    }
+}
+
+#[async_trait]
+impl Closer for RedisBus {
+    async fn close(&mut self) -> Result<(), Box<dyn Error>> {
+        self.cancel().await?;
+        if let Some((topic, _)) = self.subscription.take() {
+            // the consumer is removed from the group unless entries are left pending on it
+            let pending: redis::streams::StreamPendingCountReply = self
+                .connection
+                .xpending_consumer_count(&topic, &self.group, "-", "+", 1, &self.consumer)
+                .await?;
+            if pending.ids.is_empty() {
+                let _: u32 = self
+                    .connection
+                    .xgroup_delconsumer(&topic, &self.group, &self.consumer)
+                    .await?;
+            }
+        }
+        for (stream, cancel) in self.reply_streams.drain(..) {
+            let _ = cancel.send(true);
+            let _: () = self.connection.del(&stream).await?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,368 @@
+use crate::shared::config::{BusParams, Connection, Credentials, RedisParams, TopicConfig};
+use crate::shared::msgbus::bus::*;
+use crate::shared::msgbus::redis_streams::*;
+use redis::streams::StreamId;
+use redis::{AsyncCommands, Value};
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime};
+use tokio::time::{sleep, timeout};
+use tokio_stream::StreamExt;
+use uuid::Uuid;
+
+// Redis server the integration tests run against, e.g. `redis://localhost:6379/15`.
+// They are skipped if it is not set, each one uses streams of its own topic.
+const SERVER_VAR: &str = "MQDISH_TEST_REDIS";
+// Long enough for a message to be delivered if it is available.
+const NO_DELIVERY: Duration = Duration::from_millis(1500);
+
+struct TestServer {
+    dsn: String,
+    topic: String,
+}
+
+impl TestServer {
+    fn new() -> Option<Self> {
+        let Ok(dsn) = std::env::var(SERVER_VAR) else {
+            println!("{} is not set, skipping", SERVER_VAR);
+            return None;
+        };
+        Some(TestServer {
+            dsn,
+            topic: format!("mqdish-test-{}", Uuid::new_v4()),
+        })
+    }
+
+    async fn bus(&self, prefetch: u16, claim_timeout: u64) -> RedisBus {
+        let params = RedisParams {
+            prefetch,
+            claim_timeout,
+            ..RedisParams::default()
+        };
+        RedisBus::new(
+            Connection::DSN(self.dsn.clone()),
+            Credentials::None,
+            BusParams::Redis(params),
+        )
+        .await
+        .unwrap()
+    }
+
+    async fn connection(&self) -> redis::aio::MultiplexedConnection {
+        let client = redis::Client::open(self.dsn.as_str()).unwrap();
+        client.get_multiplexed_async_connection().await.unwrap()
+    }
+
+    async fn publish(&self, bus: &mut RedisBus, body: &str) {
+        bus.publish(
+            self.topic.clone(),
+            body.to_string(),
+            MessageProps::default(),
+        )
+        .await
+        .unwrap();
+    }
+
+    // Removes the streams and the retry set of the topic.
+    async fn clean_up(&self) {
+        let keys = [
+            self.topic.clone(),
+            format!("{}.retry", self.topic),
+            dead_letter_queue(&self.topic),
+        ];
+        let _: () = self.connection().await.del(&keys).await.unwrap();
+    }
+}
+
+async fn next(stream: &mut MessageStream) -> Option<Box<dyn Message + Send>> {
+    timeout(NO_DELIVERY, stream.next()).await.ok().flatten()
+}
+
+fn entry(envelope: &Envelope) -> StreamId {
+    let map = envelope
+        .fields()
+        .into_iter()
+        .map(|(field, value)| (field.to_string(), Value::BulkString(value.into_bytes())))
+        .collect();
+    StreamId {
+        id: "1-0".to_string(),
+        map,
+    }
+}
+
+#[test]
+fn test_envelope_fields() {
+    let envelope = Envelope {
+        body: "{}".to_string(),
+        props: MessageProps {
+            correlation_id: Some("id".to_string()),
+            reply_to: Some("mqdish.reply.1".to_string()),
+            priority: None,
+            requires: vec![],
+        },
+        attempt: 2,
+        failure: Some(Failure {
+            reason: "failed".to_string(),
+            exit_code: Some(3),
+        }),
+    };
+    assert_eq!(Envelope::from_entry(&entry(&envelope)), envelope);
+
+    let minimal = Envelope {
+        body: "{}".to_string(),
+        attempt: 1,
+        ..Envelope::default()
+    };
+    assert_eq!(Envelope::from_entry(&entry(&minimal)), minimal);
+}
+
+#[tokio::test]
+async fn test_consumer_group_shares_stream() {
+    let Some(server) = TestServer::new() else {
+        return;
+    };
+    let mut first = server.bus(1, 300).await;
+    let mut second = server.bus(1, 300).await;
+    server.publish(&mut first, "first").await;
+    server.publish(&mut first, "second").await;
+    server.publish(&mut first, "third").await;
+
+    let mut first_stream = first.consume(server.topic.clone()).await.unwrap();
+    let first_msg = next(&mut first_stream).await.unwrap();
+    assert_eq!(first_msg.body(), "first");
+    let mut second_stream = second.consume(server.topic.clone()).await.unwrap();
+    let second_msg = next(&mut second_stream).await.unwrap();
+    assert_eq!(second_msg.body(), "second");
+    // the prefetch of both consumers is taken
+    assert!(next(&mut first_stream).await.is_none());
+
+    first_msg.ack().await.unwrap();
+    assert!(first_msg.ack().await.is_err());
+    assert_eq!(next(&mut first_stream).await.unwrap().body(), "third");
+    second_msg.ack().await.unwrap();
+    first.close().await.unwrap();
+    second.close().await.unwrap();
+    server.clean_up().await;
+}
+
+#[tokio::test]
+async fn test_stale_entry_is_claimed() {
+    let Some(server) = TestServer::new() else {
+        return;
+    };
+    let mut dead = server.bus(1, 1).await;
+    let mut alive = server.bus(1, 1).await;
+    server.publish(&mut dead, "task").await;
+
+    let mut dead_stream = dead.consume(server.topic.clone()).await.unwrap();
+    let msg = next(&mut dead_stream).await.unwrap();
+    // the worker stops responding without settling the message
+    dead.cancel().await.unwrap();
+    drop(msg);
+    drop(dead_stream);
+
+    let mut alive_stream = alive.consume(server.topic.clone()).await.unwrap();
+    let claimed = timeout(Duration::from_secs(5), alive_stream.next())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(claimed.body(), "task");
+    claimed.ack().await.unwrap();
+    alive.close().await.unwrap();
+    server.clean_up().await;
+}
+
+#[tokio::test]
+async fn test_running_entry_is_not_claimed() {
+    let Some(server) = TestServer::new() else {
+        return;
+    };
+    let mut running = server.bus(1, 1).await;
+    let mut other = server.bus(1, 1).await;
+    server.publish(&mut running, "task").await;
+
+    let mut running_stream = running.consume(server.topic.clone()).await.unwrap();
+    let msg = next(&mut running_stream).await.unwrap();
+    let mut other_stream = other.consume(server.topic.clone()).await.unwrap();
+    // refreshed while it is held, so it does not go stale
+    assert!(timeout(Duration::from_secs(3), other_stream.next())
+        .await
+        .is_err());
+
+    msg.ack().await.unwrap();
+    running.close().await.unwrap();
+    other.close().await.unwrap();
+    server.clean_up().await;
+}
+
+#[tokio::test]
+async fn test_retry_is_delayed_in_sorted_set() {
+    let Some(server) = TestServer::new() else {
+        return;
+    };
+    let mut bus = server.bus(0, 300).await;
+    server.publish(&mut bus, "task").await;
+    let mut stream = bus.consume(server.topic.clone()).await.unwrap();
+
+    let msg = next(&mut stream).await.unwrap();
+    msg.retry(Duration::from_secs(2)).await.unwrap();
+    let retry_set = format!("{}.retry", server.topic);
+    let delayed: u32 = server.connection().await.zcard(&retry_set).await.unwrap();
+    assert_eq!(delayed, 1);
+    assert!(next(&mut stream).await.is_none());
+
+    sleep(Duration::from_secs(1)).await;
+    let retried = next(&mut stream).await.unwrap();
+    assert_eq!(retried.body(), "task");
+    assert_eq!(retried.attempt(), 2);
+    let delayed: u32 = server.connection().await.zcard(&retry_set).await.unwrap();
+    assert_eq!(delayed, 0);
+    retried.ack().await.unwrap();
+    bus.close().await.unwrap();
+    server.clean_up().await;
+}
+
+#[tokio::test]
+async fn test_delayed_message_waits_in_sorted_set() {
+    let Some(server) = TestServer::new() else {
+        return;
+    };
+    let mut bus = server.bus(0, 300).await;
+    let at = SystemTime::now() + Duration::from_secs(2);
+    bus.publish_at(
+        server.topic.clone(),
+        "task".to_string(),
+        MessageProps::default(),
+        at,
+    )
+    .await
+    .unwrap();
+    let retry_set = format!("{}.retry", server.topic);
+    let delayed: u32 = server.connection().await.zcard(&retry_set).await.unwrap();
+    assert_eq!(delayed, 1);
+    let mut stream = bus.consume(server.topic.clone()).await.unwrap();
+    assert!(next(&mut stream).await.is_none());
+
+    sleep(Duration::from_secs(1)).await;
+    let msg = next(&mut stream).await.unwrap();
+    assert!(SystemTime::now() >= at);
+    assert_eq!((msg.body().as_str(), msg.attempt()), ("task", 1));
+    // postponing it is not counted as an attempt
+    msg.postpone(Duration::from_millis(100)).await.unwrap();
+    let postponed = next(&mut stream).await.unwrap();
+    assert_eq!(postponed.attempt(), 1);
+    postponed.ack().await.unwrap();
+    bus.close().await.unwrap();
+    server.clean_up().await;
+}
+
+#[tokio::test]
+async fn test_undecodable_retry_is_dead_lettered() {
+    let Some(server) = TestServer::new() else {
+        return;
+    };
+    let mut bus = server.bus(0, 300).await;
+    let retry_set = format!("{}.retry", server.topic);
+    let _: () = server
+        .connection()
+        .await
+        .zadd(&retry_set, "not a message", 0)
+        .await
+        .unwrap();
+    bus.publish_at(
+        server.topic.clone(),
+        "task".to_string(),
+        MessageProps::default(),
+        SystemTime::now(),
+    )
+    .await
+    .unwrap();
+    let mut stream = bus.consume(server.topic.clone()).await.unwrap();
+
+    let msg = next(&mut stream).await.unwrap();
+    assert_eq!(msg.body(), "task");
+    msg.ack().await.unwrap();
+    assert!(next(&mut stream).await.is_none());
+    let delayed: u32 = server.connection().await.zcard(&retry_set).await.unwrap();
+    assert_eq!(delayed, 0);
+
+    let dead_letters = bus.dead_letters(server.topic.clone()).await.unwrap();
+    assert_eq!(dead_letters.len(), 1);
+    assert_eq!(dead_letters[0].body(), "not a message");
+    assert_eq!(
+        dead_letters[0].failure().map(|failure| failure.reason),
+        Some("undecodable retry".to_string())
+    );
+    bus.close().await.unwrap();
+    server.clean_up().await;
+}
+
+#[tokio::test]
+async fn test_dead_letter() {
+    let Some(server) = TestServer::new() else {
+        return;
+    };
+    let topics = HashMap::from([(
+        server.topic.clone(),
+        TopicConfig {
+            dead_letter: true,
+            ..TopicConfig::default()
+        },
+    )]);
+    let mut bus = server.bus(0, 300).await.with_topics(topics);
+    server.publish(&mut bus, "task").await;
+    let mut stream = bus.consume(server.topic.clone()).await.unwrap();
+
+    let failure = Failure {
+        reason: "failed".to_string(),
+        exit_code: Some(2),
+    };
+    next(&mut stream)
+        .await
+        .unwrap()
+        .nack(failure.clone())
+        .await
+        .unwrap();
+    assert!(next(&mut stream).await.is_none());
+
+    let dead_letters = bus.dead_letters(server.topic.clone()).await.unwrap();
+    assert_eq!(dead_letters.len(), 1);
+    assert_eq!(dead_letters[0].body(), "task");
+    assert_eq!(dead_letters[0].failure(), Some(failure));
+    assert_eq!(
+        bus.purge_dead_letters(server.topic.clone()).await.unwrap(),
+        1
+    );
+    bus.close().await.unwrap();
+    server.clean_up().await;
+}
+
+#[tokio::test]
+async fn test_late_reply_does_not_recreate_stream() {
+    let Some(server) = TestServer::new() else {
+        return;
+    };
+    let mut bus = server.bus(0, 300).await;
+    let mut producer = server.bus(0, 300).await;
+    let (reply_to, mut replies) = producer.consume_replies().await.unwrap();
+    let props = MessageProps {
+        reply_to: Some(reply_to.clone()),
+        ..MessageProps::default()
+    };
+    producer
+        .publish(server.topic.clone(), "task".to_string(), props)
+        .await
+        .unwrap();
+    let mut stream = bus.consume(server.topic.clone()).await.unwrap();
+
+    let msg = next(&mut stream).await.unwrap();
+    msg.reply("result".to_string()).await.unwrap();
+    assert_eq!(next(&mut replies).await.unwrap().body(), "result");
+    // the producer stops waiting before the task is done
+    producer.close().await.unwrap();
+    msg.reply("late".to_string()).await.unwrap();
+    let exists: bool = server.connection().await.exists(&reply_to).await.unwrap();
+    assert!(!exists);
+    msg.ack().await.unwrap();
+    bus.close().await.unwrap();
+    server.clean_up().await;
+}
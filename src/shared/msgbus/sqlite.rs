@@ -0,0 +1,706 @@
+use crate::shared::config;
+use crate::shared::config::{BusParams, Credentials, SqliteParams, TopicConfig};
+use crate::shared::msgbus::bus::{
+    dead_letter_queue, Closer, Consumer, DeadLetters, Failure, Message, MessageProps,
+    MessageStream, Publisher, ReplyConsumer,
+};
+use async_trait::async_trait;
+use rusqlite::{params, OptionalExtension, Row};
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+use tokio::select;
+use tokio::sync::{mpsc, oneshot, watch, OwnedSemaphorePermit, Semaphore};
+use tokio::time::sleep;
+use tokio_stream::wrappers::ReceiverStream;
+use uuid::Uuid;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS mqdish_messages (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    queue TEXT NOT NULL,
+    body TEXT NOT NULL,
+    correlation_id TEXT,
+    reply_to TEXT,
+    attempt INTEGER NOT NULL DEFAULT 1,
+    failure_reason TEXT,
+    exit_code INTEGER,
+    -- milliseconds since the epoch from which the message can be taken
+    visible_at INTEGER NOT NULL,
+    -- token of the worker which has taken the message
+    lease TEXT
+);
+CREATE INDEX IF NOT EXISTS mqdish_messages_visible ON mqdish_messages (queue, visible_at, id);
+-- reply queues of producers, replies to queues which were closed are dropped
+CREATE TABLE IF NOT EXISTS mqdish_reply_queues (
+    queue TEXT PRIMARY KEY
+);
+";
+
+const INSERT: &str = "INSERT INTO mqdish_messages
+    (queue, body, correlation_id, reply_to, attempt, failure_reason, exit_code, visible_at)";
+
+const COLUMNS: &str = "id, body, correlation_id, reply_to, attempt, failure_reason, exit_code";
+
+/// Message bus on a table of a local SQLite database, several processes may consume
+/// the same database file. Taken messages are leased for the visibility timeout,
+/// which is extended while they are being processed.
+pub struct SqliteBus {
+    database: Database,
+    params: SqliteParams,
+    topics: HashMap<String, TopicConfig>,
+    // Stops the subscription created by `consume`.
+    consumer: Option<watch::Sender<bool>>,
+    // Reply queues with their subscriptions, removed on close.
+    reply_queues: Vec<(String, watch::Sender<bool>)>,
+}
+
+#[derive(Error, Debug)]
+pub enum SqliteBusError {
+    #[error("Invalid argument: {0}")]
+    InvalidArgument(String),
+    #[error("Failed to open database: {0}")]
+    OpenFailure(#[from] rusqlite::Error),
+}
+
+/// Path of the database file, the connection string may be prefixed with `sqlite://`.
+pub fn database_path(connection: config::Connection) -> Result<String, SqliteBusError> {
+    match connection {
+        config::Connection::DSN(dsn) => {
+            let path = dsn
+                .strip_prefix("sqlite://")
+                .or_else(|| dsn.strip_prefix("sqlite:"))
+                .unwrap_or(&dsn);
+            Ok(path.to_string())
+        }
+        config::Connection::Params(_) => Err(SqliteBusError::InvalidArgument(
+            "connection must be a path of the database file".to_string(),
+        )),
+    }
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as i64
+}
+
+// Connection shared by the bus and its messages, statements are run on the blocking thread pool.
+#[derive(Clone)]
+struct Database(Arc<Mutex<rusqlite::Connection>>);
+
+impl Database {
+    async fn run<T, F>(&self, statement: F) -> Result<T, String>
+    where
+        T: Send + 'static,
+        F: FnOnce(&rusqlite::Connection) -> rusqlite::Result<T> + Send + 'static,
+    {
+        let connection = Arc::clone(&self.0);
+        tokio::task::spawn_blocking(move || statement(&connection.lock().unwrap()))
+            .await
+            .map_err(|err| err.to_string())?
+            .map_err(|err| err.to_string())
+    }
+
+    // Inserts the message to be visible from `visible_at` in milliseconds since the epoch.
+    async fn insert(
+        &self,
+        queue: String,
+        envelope: Envelope,
+        visible_at: i64,
+    ) -> Result<(), String> {
+        let statement = format!("{} VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)", INSERT);
+        self.insert_with(statement, queue, envelope, visible_at)
+            .await?;
+        Ok(())
+    }
+
+    // Inserts the reply unless the reply queue has been closed.
+    async fn insert_reply(&self, queue: String, envelope: Envelope) -> Result<(), String> {
+        let statement = format!(
+            "{} SELECT ?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8
+             WHERE EXISTS (SELECT 1 FROM mqdish_reply_queues WHERE queue = ?1)",
+            INSERT
+        );
+        self.insert_with(statement, queue, envelope, now()).await?;
+        Ok(())
+    }
+
+    async fn insert_with(
+        &self,
+        statement: String,
+        queue: String,
+        envelope: Envelope,
+        visible_at: i64,
+    ) -> Result<usize, String> {
+        self.run(move |connection| {
+            connection.execute(
+                &statement,
+                params![
+                    queue,
+                    envelope.body,
+                    envelope.props.correlation_id,
+                    envelope.props.reply_to,
+                    envelope.attempt,
+                    envelope.failure.as_ref().map(|failure| &failure.reason),
+                    envelope
+                        .failure
+                        .as_ref()
+                        .and_then(|failure| failure.exit_code),
+                    visible_at,
+                ],
+            )
+        })
+        .await
+    }
+
+    // Takes the oldest visible message of the queue until the lease expires.
+    async fn lease(
+        &self,
+        queue: String,
+        lease: String,
+        timeout: Duration,
+    ) -> Result<Option<(i64, Envelope)>, String> {
+        self.run(move |connection| {
+            let now = now();
+            connection
+                .query_row(
+                    &format!(
+                        "UPDATE mqdish_messages SET lease = ?1, visible_at = ?2
+                         WHERE id = (SELECT id FROM mqdish_messages
+                                     WHERE queue = ?3 AND visible_at <= ?4
+                                     ORDER BY visible_at, id LIMIT 1)
+                         RETURNING {}",
+                        COLUMNS
+                    ),
+                    params![lease, now + timeout.as_millis() as i64, queue, now],
+                    Envelope::from_row,
+                )
+                .optional()
+        })
+        .await
+    }
+
+    // Takes all visible messages of the queue until the lease expires.
+    async fn lease_all(
+        &self,
+        queue: String,
+        lease: String,
+        timeout: Duration,
+    ) -> Result<Vec<(i64, Envelope)>, String> {
+        self.run(move |connection| {
+            let now = now();
+            let mut statement = connection.prepare(&format!(
+                "UPDATE mqdish_messages SET lease = ?1, visible_at = ?2
+                 WHERE queue = ?3 AND visible_at <= ?4
+                 RETURNING {}",
+                COLUMNS
+            ))?;
+            let rows = statement.query_map(
+                params![lease, now + timeout.as_millis() as i64, queue, now],
+                Envelope::from_row,
+            )?;
+            rows.collect()
+        })
+        .await
+    }
+}
+
+// Contents of a row of the messages table.
+struct Envelope {
+    body: String,
+    props: MessageProps,
+    attempt: u32,
+    failure: Option<Failure>,
+}
+
+impl Envelope {
+    fn from_row(row: &Row) -> rusqlite::Result<(i64, Envelope)> {
+        let failure = row
+            .get::<_, Option<String>>(5)?
+            .map(|reason| -> rusqlite::Result<Failure> {
+                Ok(Failure {
+                    reason,
+                    exit_code: row.get(6)?,
+                })
+            })
+            .transpose()?;
+        let envelope = Envelope {
+            body: row.get(1)?,
+            props: MessageProps {
+                correlation_id: row.get(2)?,
+                reply_to: row.get(3)?,
+                priority: None,
+                requires: vec![],
+            },
+            attempt: row.get(4)?,
+            failure,
+        };
+        Ok((row.get(0)?, envelope))
+    }
+}
+
+impl SqliteBus {
+    pub async fn new(
+        connection_cfg: config::Connection,
+        credentials: Credentials,
+        bus_params: BusParams,
+    ) -> Result<Self, SqliteBusError> {
+        let BusParams::Sqlite(params) = bus_params else {
+            return Err(SqliteBusError::InvalidArgument(
+                "bus params of another driver".to_string(),
+            ));
+        };
+        let Credentials::None = credentials else {
+            return Err(SqliteBusError::InvalidArgument(
+                "SQLite database does not take credentials".to_string(),
+            ));
+        };
+        let path = database_path(connection_cfg)?;
+        let connection = rusqlite::Connection::open(path)?;
+        connection.busy_timeout(Duration::from_millis(params.busy_timeout))?;
+        if params.wal {
+            // returns the resulting mode, so it can not be executed as an update
+            connection.query_row("PRAGMA journal_mode = WAL", [], |_| Ok(()))?;
+        }
+        connection.execute_batch(SCHEMA)?;
+
+        Ok(SqliteBus {
+            database: Database(Arc::new(Mutex::new(connection))),
+            params,
+            topics: HashMap::new(),
+            consumer: None,
+            reply_queues: vec![],
+        })
+    }
+
+    /// Sets the settings of topics, tasks of topics with `dead_letter` enabled
+    /// are moved to the dead-letter queue when they fail for good.
+    pub fn with_topics(mut self, topics: HashMap<String, TopicConfig>) -> Self {
+        self.topics = topics;
+        self
+    }
+
+    fn dead_letter_queue(&self, topic: &str) -> Option<String> {
+        match self.topics.get(topic) {
+            Some(topic_config) if topic_config.dead_letter => Some(dead_letter_queue(topic)),
+            _ => None,
+        }
+    }
+
+    fn visibility_timeout(&self) -> Duration {
+        Duration::from_secs(self.params.visibility_timeout)
+    }
+
+    // Delivers messages of the queue to the returned stream until the sender is set or dropped.
+    // No more than `prefetch` messages are delivered without being settled, unlimited if zero.
+    fn subscribe(&self, queue: String, requeue: bool) -> (MessageStream, watch::Sender<bool>) {
+        let (cancel_tx, mut cancel_rx) = watch::channel(false);
+        let (msg_tx, msg_rx) = mpsc::channel::<Box<dyn Message + Send>>(1);
+        let prefetch = match self.params.prefetch {
+            0 => Semaphore::MAX_PERMITS,
+            prefetch => prefetch as usize,
+        };
+        let prefetch = Arc::new(Semaphore::new(prefetch));
+        let database = self.database.clone();
+        let timeout = self.visibility_timeout();
+        let poll_interval = Duration::from_millis(self.params.poll_interval);
+        let dead_letter_queue = self.dead_letter_queue(&queue);
+
+        tokio::spawn(async move {
+            loop {
+                let permit = select! {
+                    permit = Arc::clone(&prefetch).acquire_owned() => {
+                        permit.expect("semaphore is never closed")
+                    }
+                    _ = cancel_rx.wait_for(|cancelled| *cancelled) => return,
+                    _ = msg_tx.closed() => return,
+                };
+                // the lease is not interrupted, a message leased by a dropped query
+                // would be hidden from other workers until the lease expires
+                let (id, envelope, lease) = loop {
+                    let lease = Uuid::new_v4().to_string();
+                    match database.lease(queue.clone(), lease.clone(), timeout).await {
+                        Ok(Some((id, envelope))) => break (id, envelope, lease),
+                        Ok(None) => {}
+                        Err(err) => println!("Failed to take message from {}: {}", queue, err),
+                    }
+                    select! {
+                        _ = sleep(poll_interval) => {}
+                        _ = cancel_rx.wait_for(|cancelled| *cancelled) => return,
+                        _ = msg_tx.closed() => return,
+                    }
+                };
+                let msg = SqliteMessage::new(
+                    database.clone(),
+                    id,
+                    envelope,
+                    lease,
+                    timeout,
+                    Some(permit),
+                    requeue,
+                    dead_letter_queue.clone(),
+                );
+                // a message which could not be delivered is returned to the queue when dropped
+                if msg_tx.send(Box::new(msg)).await.is_err() {
+                    return;
+                }
+            }
+        });
+
+        (Box::pin(ReceiverStream::new(msg_rx)), cancel_tx)
+    }
+}
+
+// Held while the message is leased, dropping it frees the prefetch slot
+// and stops the extension of the lease.
+struct Lease {
+    _permit: Option<OwnedSemaphorePermit>,
+    _in_progress: oneshot::Sender<()>,
+}
+
+struct SqliteMessage {
+    database: Database,
+    id: i64,
+    envelope: Envelope,
+    token: String,
+    requeue: bool,
+    dead_letter_queue: Option<String>,
+    lease: Mutex<Option<Lease>>,
+}
+
+impl SqliteMessage {
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        database: Database,
+        id: i64,
+        envelope: Envelope,
+        token: String,
+        timeout: Duration,
+        permit: Option<OwnedSemaphorePermit>,
+        requeue: bool,
+        dead_letter_queue: Option<String>,
+    ) -> Self {
+        let (in_progress_tx, mut in_progress_rx) = oneshot::channel::<()>();
+        // long running tasks would be taken by other workers once the lease expires
+        let extension = database.clone();
+        let extended_token = token.clone();
+        tokio::spawn(async move {
+            loop {
+                select! {
+                    _ = sleep(timeout / 3) => {}
+                    _ = &mut in_progress_rx => return,
+                }
+                let token = extended_token.clone();
+                let extended = extension
+                    .run(move |connection| {
+                        connection.execute(
+                            "UPDATE mqdish_messages SET visible_at = ?1 WHERE id = ?2 AND lease = ?3",
+                            params![now() + timeout.as_millis() as i64, id, token],
+                        )
+                    })
+                    .await;
+                if let Err(err) = extended {
+                    println!("Failed to extend lease of message: {}", err);
+                }
+            }
+        });
+        SqliteMessage {
+            database,
+            id,
+            envelope,
+            token,
+            requeue,
+            dead_letter_queue,
+            lease: Mutex::new(Some(Lease {
+                _permit: permit,
+                _in_progress: in_progress_tx,
+            })),
+        }
+    }
+
+    // Runs the statement settling the message, which fails if the lease has been taken over.
+    async fn settle<F>(&self, statement: F) -> Result<(), Box<dyn Error>>
+    where
+        F: FnOnce(&rusqlite::Connection, i64, &str) -> rusqlite::Result<usize> + Send + 'static,
+    {
+        if self.lease.lock().unwrap().take().is_none() {
+            return Err("Message is already settled".into());
+        }
+        let (id, token) = (self.id, self.token.clone());
+        let settled = self
+            .database
+            .run(move |connection| statement(connection, id, &token))
+            .await?;
+        if settled == 0 {
+            return Err("Lease of the message has expired".into());
+        }
+        Ok(())
+    }
+
+    // Returns the message to the queue to be visible again after `delay`.
+    async fn release(&self, delay: Duration) -> Result<(), Box<dyn Error>> {
+        self.settle(move |connection, id, token| {
+            connection.execute(
+                "UPDATE mqdish_messages SET lease = NULL, visible_at = ?1 WHERE id = ?2 AND lease = ?3",
+                params![now() + delay.as_millis() as i64, id, token],
+            )
+        })
+        .await
+    }
+
+    async fn delete(&self) -> Result<(), Box<dyn Error>> {
+        self.settle(|connection, id, token| {
+            connection.execute(
+                "DELETE FROM mqdish_messages WHERE id = ?1 AND lease = ?2",
+                params![id, token],
+            )
+        })
+        .await
+    }
+}
+
+impl Drop for SqliteMessage {
+    // like unacknowledged messages of a closed channel, unsettled messages are redelivered
+    fn drop(&mut self) {
+        if self.lease.lock().unwrap().take().is_none() {
+            return;
+        }
+        let (id, token) = (self.id, self.token.clone());
+        let database = self.database.clone();
+        if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            runtime.spawn(async move {
+                let _ = database
+                    .run(move |connection| {
+                        connection.execute(
+                            "UPDATE mqdish_messages SET lease = NULL, visible_at = ?1 WHERE id = ?2 AND lease = ?3",
+                            params![now(), id, token],
+                        )
+                    })
+                    .await;
+            });
+        }
+    }
+}
+
+#[async_trait]
+impl Message for SqliteMessage {
+    async fn ack(&self) -> Result<(), Box<dyn Error>> {
+        self.delete().await
+    }
+
+    async fn nack(&self, failure: Failure) -> Result<(), Box<dyn Error>> {
+        if self.requeue {
+            return self.release(Duration::ZERO).await;
+        }
+        self.reject(failure).await
+    }
+
+    async fn reject(&self, failure: Failure) -> Result<(), Box<dyn Error>> {
+        let Some(queue) = self.dead_letter_queue.clone() else {
+            return self.delete().await;
+        };
+        self.settle(move |connection, id, token| {
+            connection.execute(
+                "UPDATE mqdish_messages
+                 SET queue = ?1, failure_reason = ?2, exit_code = ?3, lease = NULL, visible_at = ?4
+                 WHERE id = ?5 AND lease = ?6",
+                params![queue, failure.reason, failure.exit_code, now(), id, token],
+            )
+        })
+        .await
+    }
+
+    async fn requeue(&self) -> Result<(), Box<dyn Error>> {
+        self.release(Duration::ZERO).await
+    }
+
+    async fn retry(&self, delay: Duration) -> Result<(), Box<dyn Error>> {
+        self.settle(move |connection, id, token| {
+            connection.execute(
+                "UPDATE mqdish_messages SET attempt = attempt + 1, lease = NULL, visible_at = ?1
+                 WHERE id = ?2 AND lease = ?3",
+                params![now() + delay.as_millis() as i64, id, token],
+            )
+        })
+        .await
+    }
+
+    async fn postpone(&self, delay: Duration) -> Result<(), Box<dyn Error>> {
+        self.release(delay).await
+    }
+
+    async fn reply(&self, msg: String) -> Result<(), Box<dyn Error>> {
+        let Some(reply_to) = &self.envelope.props.reply_to else {
+            return Ok(());
+        };
+        let envelope = Envelope {
+            body: msg,
+            props: MessageProps {
+                correlation_id: self.envelope.props.correlation_id.clone(),
+                reply_to: None,
+                priority: None,
+                requires: vec![],
+            },
+            attempt: 1,
+            failure: None,
+        };
+        // the producer may have stopped waiting for it, nobody would consume it then
+        self.database
+            .insert_reply(reply_to.clone(), envelope)
+            .await?;
+        Ok(())
+    }
+
+    fn body(&self) -> String {
+        self.envelope.body.clone()
+    }
+
+    fn delivery_info(&self) -> String {
+        format!("message {}, attempt {}", self.id, self.attempt())
+    }
+
+    fn attempt(&self) -> u32 {
+        self.envelope.attempt
+    }
+
+    fn failure(&self) -> Option<Failure> {
+        self.envelope.failure.clone()
+    }
+}
+
+#[async_trait]
+impl Publisher for SqliteBus {
+    async fn publish(
+        &mut self,
+        topic: String,
+        msg: String,
+        props: MessageProps,
+    ) -> Result<(), Box<dyn Error>> {
+        let envelope = Envelope {
+            body: msg,
+            props,
+            attempt: 1,
+            failure: None,
+        };
+        self.database.insert(topic, envelope, now()).await?;
+        Ok(())
+    }
+
+    async fn publish_at(
+        &mut self,
+        topic: String,
+        msg: String,
+        props: MessageProps,
+        at: SystemTime,
+    ) -> Result<(), Box<dyn Error>> {
+        let envelope = Envelope {
+            body: msg,
+            props,
+            attempt: 1,
+            failure: None,
+        };
+        let visible_at = at.duration_since(UNIX_EPOCH)?.as_millis() as i64;
+        self.database.insert(topic, envelope, visible_at).await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Consumer for SqliteBus {
+    async fn consume(&mut self, topic: String) -> Result<MessageStream, Box<dyn Error>> {
+        let (stream, cancel) = self.subscribe(topic, self.params.requeue);
+        self.consumer = Some(cancel);
+        Ok(stream)
+    }
+
+    async fn cancel(&mut self) -> Result<(), Box<dyn Error>> {
+        if let Some(cancel) = &self.consumer {
+            let _ = cancel.send(true);
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl ReplyConsumer for SqliteBus {
+    async fn consume_replies(&mut self) -> Result<(String, MessageStream), Box<dyn Error>> {
+        let queue = format!("mqdish.reply.{}", Uuid::new_v4());
+        let registered = queue.clone();
+        self.database
+            .run(move |connection| {
+                connection.execute(
+                    "INSERT INTO mqdish_reply_queues (queue) VALUES (?1)",
+                    [registered],
+                )
+            })
+            .await?;
+        let (stream, cancel) = self.subscribe(queue.clone(), false);
+        self.reply_queues.push((queue.clone(), cancel));
+        Ok((queue, stream))
+    }
+}
+
+#[async_trait]
+impl DeadLetters for SqliteBus {
+    async fn dead_letters(
+        &mut self,
+        topic: String,
+    ) -> Result<Vec<Box<dyn Message + Send>>, Box<dyn Error>> {
+        let token = Uuid::new_v4().to_string();
+        let timeout = self.visibility_timeout();
+        let leased = self
+            .database
+            .lease_all(dead_letter_queue(&topic), token.clone(), timeout)
+            .await?;
+        let messages = leased
+            .into_iter()
+            .map(|(id, envelope)| {
+                Box::new(SqliteMessage::new(
+                    self.database.clone(),
+                    id,
+                    envelope,
+                    token.clone(),
+                    timeout,
+                    None,
+                    true,
+                    None,
+                )) as Box<dyn Message + Send>
+            })
+            .collect();
+        Ok(messages)
+    }
+
+    async fn purge_dead_letters(&mut self, topic: String) -> Result<u32, Box<dyn Error>> {
+        let queue = dead_letter_queue(&topic);
+        let purged = self
+            .database
+            .run(move |connection| {
+                connection.execute("DELETE FROM mqdish_messages WHERE queue = ?1", [queue])
+            })
+            .await?;
+        Ok(purged as u32)
+    }
+}
+
+#[async_trait]
+impl Closer for SqliteBus {
+    async fn close(&mut self) -> Result<(), Box<dyn Error>> {
+        self.cancel().await?;
+        for (queue, cancel) in self.reply_queues.drain(..) {
+            let _ = cancel.send(true);
+            self.database
+                .run(move |connection| {
+                    // no replies are inserted once the queue is unregistered
+                    connection
+                        .execute("DELETE FROM mqdish_reply_queues WHERE queue = ?1", [&queue])?;
+                    connection.execute("DELETE FROM mqdish_messages WHERE queue = ?1", [&queue])
+                })
+                .await?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,215 @@
+use crate::shared::config::{BusParams, Connection, Credentials, SqliteParams, TopicConfig};
+use crate::shared::msgbus::bus::*;
+use crate::shared::msgbus::sqlite::*;
+use std::collections::HashMap;
+use std::fs::remove_file;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+use tokio::time::timeout;
+use tokio_stream::StreamExt;
+use uuid::Uuid;
+
+const TOPIC: &str = "test";
+// Long enough for a message to be delivered if it is available.
+const NO_DELIVERY: Duration = Duration::from_millis(200);
+// Long enough for a delayed message to become due.
+const DUE: Duration = Duration::from_secs(2);
+
+struct TempDatabase {
+    path: PathBuf,
+}
+
+impl TempDatabase {
+    fn new() -> Self {
+        let path = std::env::temp_dir().join(format!("mqdish_{}.db", Uuid::new_v4()));
+        TempDatabase { path }
+    }
+
+    async fn bus(&self, prefetch: u16, requeue: bool) -> SqliteBus {
+        let params = SqliteParams {
+            prefetch,
+            requeue,
+            poll_interval: 10,
+            ..SqliteParams::default()
+        };
+        SqliteBus::new(
+            Connection::DSN(format!("sqlite://{}", self.path.display())),
+            Credentials::None,
+            BusParams::Sqlite(params),
+        )
+        .await
+        .unwrap()
+    }
+}
+
+impl Drop for TempDatabase {
+    fn drop(&mut self) {
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = remove_file(format!("{}{}", self.path.display(), suffix));
+        }
+    }
+}
+
+async fn publish(bus: &mut SqliteBus, body: &str) {
+    bus.publish(TOPIC.to_string(), body.to_string(), MessageProps::default())
+        .await
+        .unwrap();
+}
+
+async fn next(stream: &mut MessageStream) -> Option<Box<dyn Message + Send>> {
+    timeout(NO_DELIVERY, stream.next()).await.ok().flatten()
+}
+
+#[test]
+fn test_database_path() {
+    let path = |dsn: &str| database_path(Connection::DSN(dsn.to_string())).unwrap();
+    assert_eq!(path("sqlite:///var/lib/mqdish.db"), "/var/lib/mqdish.db");
+    assert_eq!(path("sqlite:mqdish.db"), "mqdish.db");
+    assert_eq!(path("/var/lib/mqdish.db"), "/var/lib/mqdish.db");
+}
+
+#[tokio::test]
+async fn test_workers_share_queue() {
+    let database = TempDatabase::new();
+    let mut first = database.bus(1, false).await;
+    let mut second = database.bus(1, false).await;
+    publish(&mut first, "first").await;
+    publish(&mut first, "second").await;
+    publish(&mut first, "third").await;
+
+    let mut first_stream = first.consume(TOPIC.to_string()).await.unwrap();
+    let first_msg = next(&mut first_stream).await.unwrap();
+    assert_eq!(first_msg.body(), "first");
+    let mut second_stream = second.consume(TOPIC.to_string()).await.unwrap();
+    let second_msg = next(&mut second_stream).await.unwrap();
+    assert_eq!(second_msg.body(), "second");
+    assert!(next(&mut first_stream).await.is_none());
+
+    first_msg.ack().await.unwrap();
+    assert!(first_msg.ack().await.is_err());
+    let third_msg = next(&mut first_stream).await.unwrap();
+    assert_eq!(third_msg.body(), "third");
+    // unsettled messages are returned to the queue
+    drop(second_msg);
+    assert_eq!(next(&mut second_stream).await.unwrap().body(), "second");
+}
+
+#[tokio::test]
+async fn test_dead_letter() {
+    let database = TempDatabase::new();
+    let topics = HashMap::from([(
+        TOPIC.to_string(),
+        TopicConfig {
+            dead_letter: true,
+            ..TopicConfig::default()
+        },
+    )]);
+    let mut bus = database.bus(0, false).await.with_topics(topics);
+    publish(&mut bus, "task").await;
+    let mut stream = bus.consume(TOPIC.to_string()).await.unwrap();
+
+    let failure = Failure {
+        reason: "failed".to_string(),
+        exit_code: Some(2),
+    };
+    next(&mut stream)
+        .await
+        .unwrap()
+        .nack(failure.clone())
+        .await
+        .unwrap();
+    assert!(next(&mut stream).await.is_none());
+
+    let dead_letters = bus.dead_letters(TOPIC.to_string()).await.unwrap();
+    assert_eq!(dead_letters.len(), 1);
+    assert_eq!(dead_letters[0].failure(), Some(failure));
+    dead_letters[0].requeue().await.unwrap();
+    assert_eq!(bus.purge_dead_letters(TOPIC.to_string()).await.unwrap(), 1);
+}
+
+#[tokio::test]
+async fn test_retry_and_reply() {
+    let database = TempDatabase::new();
+    let mut bus = database.bus(0, false).await;
+    let mut producer = database.bus(0, false).await;
+    let (reply_to, mut replies) = producer.consume_replies().await.unwrap();
+    let props = MessageProps {
+        correlation_id: Some("id".to_string()),
+        reply_to: Some(reply_to),
+        priority: None,
+        requires: vec![],
+    };
+    producer
+        .publish(TOPIC.to_string(), "task".to_string(), props)
+        .await
+        .unwrap();
+    let mut stream = bus.consume(TOPIC.to_string()).await.unwrap();
+
+    let msg = next(&mut stream).await.unwrap();
+    msg.reply("result".to_string()).await.unwrap();
+    msg.retry(Duration::from_millis(10)).await.unwrap();
+    assert_eq!(next(&mut replies).await.unwrap().body(), "result");
+    assert_eq!(next(&mut stream).await.unwrap().attempt(), 2);
+    producer.close().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_late_reply_is_dropped() {
+    let database = TempDatabase::new();
+    let mut bus = database.bus(0, false).await;
+    let mut producer = database.bus(0, false).await;
+    let (reply_to, _replies) = producer.consume_replies().await.unwrap();
+    let props = MessageProps {
+        reply_to: Some(reply_to.clone()),
+        ..MessageProps::default()
+    };
+    producer
+        .publish(TOPIC.to_string(), "task".to_string(), props)
+        .await
+        .unwrap();
+    let mut stream = bus.consume(TOPIC.to_string()).await.unwrap();
+    let msg = next(&mut stream).await.unwrap();
+    producer.close().await.unwrap();
+
+    msg.reply("result".to_string()).await.unwrap();
+    msg.ack().await.unwrap();
+    let connection = rusqlite::Connection::open(&database.path).unwrap();
+    let replies: u32 = connection
+        .query_row(
+            "SELECT count(*) FROM mqdish_messages WHERE queue = ?1",
+            [reply_to],
+            |row| row.get(0),
+        )
+        .unwrap();
+    assert_eq!(replies, 0);
+}
+
+#[tokio::test]
+async fn test_delayed_delivery() {
+    let database = TempDatabase::new();
+    let mut bus = database.bus(0, false).await;
+    let at = SystemTime::now() + NO_DELIVERY * 2;
+    bus.publish_at(
+        TOPIC.to_string(),
+        "delayed".to_string(),
+        MessageProps::default(),
+        at,
+    )
+    .await
+    .unwrap();
+    publish(&mut bus, "task").await;
+    let mut stream = bus.consume(TOPIC.to_string()).await.unwrap();
+
+    // the task published later is delivered first
+    let msg = next(&mut stream).await.unwrap();
+    assert_eq!(msg.body(), "task");
+    msg.ack().await.unwrap();
+    assert!(next(&mut stream).await.is_none());
+    let delayed = timeout(DUE, stream.next()).await.unwrap().unwrap();
+    assert_eq!(delayed.body(), "delayed");
+    assert!(SystemTime::now() >= at);
+
+    // postponing it is not counted as an attempt
+    delayed.postpone(Duration::from_millis(10)).await.unwrap();
+    assert_eq!(next(&mut stream).await.unwrap().attempt(), 1);
+}
@@ -0,0 +1,235 @@
+use crate::shared::config::ScheduleConfig;
+use crate::shared::dispatcher::Dispatcher;
+use crate::shared::models::Task;
+use crate::shared::msgbus::bus::{Leadership, Publisher};
+use chrono::{DateTime, Local, TimeZone};
+use cron::Schedule;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::future::{pending, Future};
+use std::str::FromStr;
+use thiserror::Error;
+use tokio::sync::watch;
+use tokio::time::sleep;
+use tokio::{pin, select};
+use uuid::Uuid;
+
+// Runs missed during downtime are counted up to this number.
+pub const MAX_MISSED_RUNS: usize = 1000;
+
+#[derive(Error, Debug)]
+pub enum SchedulerError {
+    #[error("Invalid schedule {0}: {1}")]
+    InvalidSchedule(String, String),
+}
+
+/// Parses a crontab expression of 5 fields, or one with seconds (and years) as the `cron` crate does.
+pub fn parse_cron(expression: &str) -> Result<Schedule, String> {
+    let fields: Vec<&str> = expression.split_whitespace().collect();
+    let expression = match fields.as_slice() {
+        [minutes, hours, days, months, days_of_week] => format!(
+            "0 {} {} {} {} {}",
+            minutes,
+            hours,
+            days,
+            months,
+            crontab_days_of_week(days_of_week)
+        ),
+        _ => expression.to_string(),
+    };
+    Schedule::from_str(&expression).map_err(|err| err.to_string())
+}
+
+// Crontab numbers days of week from Sunday as 0 (or 7), while the `cron` crate does from Sunday
+// as 1, so the numbers are replaced with the names of the days.
+fn crontab_days_of_week(field: &str) -> String {
+    const DAYS: [&str; 8] = ["SUN", "MON", "TUE", "WED", "THU", "FRI", "SAT", "SUN"];
+    let day = |value: &str| match value.parse::<usize>() {
+        Ok(number) if number < DAYS.len() => DAYS[number].to_string(),
+        _ => value.to_string(),
+    };
+    field
+        .split(',')
+        .map(|item| {
+            let (range, step) = match item.split_once('/') {
+                Some((range, step)) => (range, Some(step)),
+                None => (item, None),
+            };
+            let range = match range.split_once('-') {
+                // a range up to Sunday as 7 would end before it starts
+                Some((first, "7")) if first != "7" && step.is_none() => {
+                    format!("{}-SAT,SUN", day(first))
+                }
+                Some((first, last)) => format!("{}-{}", day(first), day(last)),
+                // a step from a single day goes through the rest of the week
+                None if step.is_some() && range != "*" => format!("{}-SAT", day(range)),
+                None => day(range),
+            };
+            match step {
+                Some(step) => format!("{}/{}", range, step),
+                None => range,
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Number of runs of the schedule due after `last_run` until `now`, at most `MAX_MISSED_RUNS`.
+pub fn missed_runs<Z: TimeZone>(
+    schedule: &Schedule,
+    last_run: &DateTime<Z>,
+    now: &DateTime<Z>,
+) -> usize {
+    schedule
+        .after(last_run)
+        .take_while(|run| run <= now)
+        .take(MAX_MISSED_RUNS)
+        .count()
+}
+
+// Schedule along with its parsed cron expression.
+struct Job {
+    config: ScheduleConfig,
+    schedule: Schedule,
+}
+
+/// Dispatches commands on cron schedules in local time while this instance leads the election.
+/// Times of the last runs are handed over to the next leader, which reports runs missed until it took over.
+pub struct Scheduler<'a, T: Publisher> {
+    bus: &'a mut T,
+    topic: String,
+    jobs: Vec<Job>,
+}
+
+impl<'a, T: Publisher> Scheduler<'a, T> {
+    /// Validates the schedules, commands without a topic of their own are published to `topic`.
+    pub fn new(
+        bus: &'a mut T,
+        topic: String,
+        schedules: &[ScheduleConfig],
+    ) -> Result<Self, SchedulerError> {
+        let mut names = HashSet::new();
+        let jobs = schedules
+            .iter()
+            .map(|config| {
+                let invalid = |reason: &str| {
+                    SchedulerError::InvalidSchedule(config.name.clone(), reason.to_string())
+                };
+                if config.name.is_empty() {
+                    return Err(invalid("name is empty"));
+                }
+                if !names.insert(config.name.as_str()) {
+                    return Err(invalid("name is not unique"));
+                }
+                if config.command.is_empty() {
+                    return Err(invalid("command is empty"));
+                }
+                let schedule = parse_cron(&config.cron).map_err(|err| invalid(&err))?;
+                Ok(Job {
+                    config: config.clone(),
+                    schedule,
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Scheduler { bus, topic, jobs })
+    }
+
+    /// Dispatches commands as they are due whenever this instance leads, until `shutdown` completes.
+    pub async fn run_until<S: Future<Output = ()>>(
+        &mut self,
+        leadership: &mut Leadership,
+        shutdown: S,
+    ) -> Result<(), Box<dyn Error>> {
+        pin!(shutdown);
+        loop {
+            let handed_over = select! {
+                _ = &mut shutdown => return Ok(()),
+                leading = leadership.leading.wait_for(Option::is_some) => match leading {
+                    Ok(state) => state.clone().unwrap_or_default(),
+                    Err(_) => return Err("Leader election ended".into()),
+                },
+            };
+            // the state is empty if no leader has recorded any runs yet
+            let mut runs: HashMap<String, i64> =
+                serde_json::from_str(&handed_over).unwrap_or_default();
+            // runs until the takeover are reported if missed, the later ones are dispatched
+            let now = Local::now();
+            self.report_missed(&runs, &now);
+
+            select! {
+                _ = &mut shutdown => return Ok(()),
+                _ = self.lead(&mut runs, &leadership.state, &now) => {}
+                _ = leadership.leading.wait_for(Option::is_none) => {}
+            }
+        }
+    }
+
+    // Reports runs due since the last ones recorded by any leader.
+    fn report_missed(&self, runs: &HashMap<String, i64>, now: &DateTime<Local>) {
+        for job in &self.jobs {
+            let last_run = runs
+                .get(&job.config.name)
+                .and_then(|last_run| Local.timestamp_opt(*last_run, 0).single());
+            let Some(last_run) = last_run else {
+                continue;
+            };
+            let missed = missed_runs(&job.schedule, &last_run, now);
+            if missed > 0 {
+                println!(
+                    "Missed {}{} runs of {} since {}",
+                    if missed == MAX_MISSED_RUNS {
+                        "at least "
+                    } else {
+                        ""
+                    },
+                    missed,
+                    job.config.name,
+                    last_run.to_rfc3339()
+                );
+            }
+        }
+    }
+
+    // Dispatches commands as they are due after `now`, the runs are recorded in the state handed over.
+    async fn lead(
+        &mut self,
+        runs: &mut HashMap<String, i64>,
+        state: &watch::Sender<String>,
+        now: &DateTime<Local>,
+    ) {
+        let mut next: Vec<Option<DateTime<Local>>> = self
+            .jobs
+            .iter()
+            .map(|job| job.schedule.after(now).next())
+            .collect();
+        loop {
+            let Some(due) = next.iter().flatten().min().cloned() else {
+                // none of the schedules has any runs left
+                return pending().await;
+            };
+            sleep((due - Local::now()).to_std().unwrap_or_default()).await;
+
+            for (job, next) in self.jobs.iter().zip(next.iter_mut()) {
+                if *next != Some(due) {
+                    continue;
+                }
+                let topic = job.config.topic.clone().unwrap_or(self.topic.clone());
+                let task = Task {
+                    shell: job.config.shell.clone(),
+                    command: job.config.command.clone(),
+                    exclusive: job.config.exclusive,
+                    id: Some(Uuid::new_v4().to_string()),
+                    timeout: job.config.timeout,
+                    ..Task::default()
+                };
+                match Dispatcher::new(self.bus).dispatch(topic, task).await {
+                    Ok(_) => println!("Dispatched {}", job.config.name),
+                    Err(err) => println!("Failed to dispatch {}: {}", job.config.name, err),
+                }
+                runs.insert(job.config.name.clone(), due.timestamp());
+                *next = job.schedule.after(&due).next();
+            }
+            state.send_replace(serde_json::to_string(runs).unwrap_or_default());
+        }
+    }
+}
@@ -0,0 +1,110 @@
+use crate::shared::config::{MemoryParams, ScheduleConfig};
+use crate::shared::models::Task;
+use crate::shared::msgbus::bus::{Consumer, Leadership};
+use crate::shared::msgbus::memory::MemoryBus;
+use crate::shared::scheduler::*;
+use chrono::{TimeZone, Utc};
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::time::{sleep, timeout};
+use tokio_stream::StreamExt;
+
+fn schedule(name: &str, cron: &str) -> ScheduleConfig {
+    ScheduleConfig {
+        name: name.to_string(),
+        cron: cron.to_string(),
+        command: "true".to_string(),
+        ..ScheduleConfig::default()
+    }
+}
+
+#[test]
+fn test_parse_crontab() {
+    let same = |crontab: &str, expected: &str| {
+        let parsed = parse_cron(crontab).unwrap();
+        assert!(
+            parsed.timeunitspec_eq(&parse_cron(expected).unwrap()),
+            "{} is not {}",
+            crontab,
+            expected
+        );
+    };
+    same("30 2 * * *", "0 30 2 * * *");
+    same("0 9 * * 1-5", "0 0 9 * * Mon-Fri");
+    same("0 9 * * 0", "0 0 9 * * Sun");
+    same("0 9 * * 5-7", "0 0 9 * * Fri,Sat,Sun");
+    same("0 9 * * 1,3/2", "0 0 9 * * Mon,Wed,Fri");
+    same("@daily", "0 0 0 * * *");
+    assert!(parse_cron("0 9 * *").is_err());
+    assert!(parse_cron("61 * * * *").is_err());
+}
+
+#[test]
+fn test_missed_runs() {
+    let quarterly = parse_cron("*/15 * * * *").unwrap();
+    let last_run = Utc.with_ymd_and_hms(2025, 3, 1, 10, 0, 0).unwrap();
+
+    let now = Utc.with_ymd_and_hms(2025, 3, 1, 10, 14, 59).unwrap();
+    assert_eq!(missed_runs(&quarterly, &last_run, &now), 0);
+    let now = Utc.with_ymd_and_hms(2025, 3, 1, 11, 0, 0).unwrap();
+    assert_eq!(missed_runs(&quarterly, &last_run, &now), 4);
+    let now = Utc.with_ymd_and_hms(2026, 3, 1, 10, 0, 0).unwrap();
+    assert_eq!(missed_runs(&quarterly, &last_run, &now), MAX_MISSED_RUNS);
+}
+
+#[test]
+fn test_invalid_schedules() {
+    let valid = |schedules: &[ScheduleConfig]| {
+        let mut bus = MemoryBus::new(MemoryParams::default());
+        Scheduler::new(&mut bus, "test".to_string(), schedules).is_ok()
+    };
+    assert!(valid(&[
+        schedule("a", "@hourly"),
+        schedule("b", "0 * * * * *")
+    ]));
+    assert!(!valid(&[schedule("", "* * * * *")]));
+    assert!(!valid(&[
+        schedule("a", "* * * * *"),
+        schedule("a", "@hourly")
+    ]));
+    assert!(!valid(&[schedule("a", "every minute")]));
+}
+
+#[tokio::test]
+async fn test_dispatch_while_leading() {
+    let mut bus = MemoryBus::new(MemoryParams::default());
+    let mut worker_bus = bus.connect();
+    let (leading_tx, leading_rx) = watch::channel(None);
+    let (state_tx, mut state_rx) = watch::channel(String::new());
+    let mut leadership = Leadership {
+        leading: leading_rx,
+        state: state_tx,
+    };
+    let schedules = [ScheduleConfig {
+        topic: Some("reports".to_string()),
+        ..schedule("report", "* * * * * *")
+    }];
+    let mut scheduler = Scheduler::new(&mut bus, "test".to_string(), &schedules).unwrap();
+
+    let started = Utc::now().timestamp();
+    scheduler
+        .run_until(&mut leadership, async {
+            // nothing is dispatched until this instance leads
+            sleep(Duration::from_millis(1100)).await;
+            leading_tx.send_replace(Some(String::new()));
+            state_rx.changed().await.unwrap();
+        })
+        .await
+        .unwrap();
+
+    let runs: HashMap<String, i64> = serde_json::from_str(&state_rx.borrow()).unwrap();
+    assert!(runs["report"] > started);
+    let mut tasks = worker_bus.consume("reports".to_string()).await.unwrap();
+    let msg = tasks.next().await.unwrap();
+    let task: Task = serde_json::from_str(&msg.body()).unwrap();
+    assert_eq!(task.command, "true");
+    assert!(timeout(Duration::from_millis(100), tasks.next())
+        .await
+        .is_err());
+}
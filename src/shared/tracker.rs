@@ -0,0 +1,263 @@
+use crate::shared::models::TaskResult;
+use crate::shared::msgbus::bus::MessageStream;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use tokio::time::{timeout_at, Instant};
+use tokio_stream::StreamExt;
+
+/// Collects results of dispatched tasks from the reply queue.
+#[derive(Default)]
+pub struct Tracker {
+    pending: HashSet<String>,
+    total: usize,
+    succeeded: usize,
+    failed: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Summary {
+    pub total: usize,
+    pub succeeded: usize,
+    pub failed: usize,
+    // Tasks which have not reported before the reply stream ended or the deadline passed.
+    pub missing: usize,
+}
+
+impl Summary {
+    pub fn success(&self) -> bool {
+        self.failed == 0 && self.missing == 0
+    }
+}
+
+impl Tracker {
+    pub fn new() -> Self {
+        Tracker::default()
+    }
+
+    /// Registers a dispatched task whose result should be waited for.
+    pub fn expect(&mut self, id: String) {
+        if self.pending.insert(id) {
+            self.total += 1;
+        }
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// Consumes replies until every expected task has reported, the stream ends or `deadline` passes.
+    /// `on_result` is called once per expected task with the number of reported tasks so far.
+    pub async fn wait<F>(
+        &mut self,
+        replies: &mut MessageStream,
+        deadline: Option<Instant>,
+        mut on_result: F,
+    ) -> Summary
+    where
+        F: FnMut(&TaskResult, &Summary),
+    {
+        while !self.is_done() {
+            let Some(result) = self.next(replies, deadline).await else {
+                break;
+            };
+            on_result(&result, &self.summary());
+        }
+        self.summary()
+    }
+
+    /// Consumes replies until one of the expected tasks reports its final result.
+    /// Returns None once the stream ends or `deadline` passes, waits indefinitely without one.
+    pub async fn next(
+        &mut self,
+        replies: &mut MessageStream,
+        deadline: Option<Instant>,
+    ) -> Option<TaskResult> {
+        let next = async {
+            while let Some(result) = next_result(replies).await {
+                if self.record(&result) {
+                    return Some(result);
+                }
+            }
+            None
+        };
+        match deadline {
+            Some(deadline) => timeout_at(deadline, next).await.ok().flatten(),
+            None => next.await,
+        }
+    }
+
+    /// IDs of the expected tasks which have not reported yet, sorted.
+    pub fn pending(&self) -> Vec<String> {
+        let mut pending: Vec<String> = self.pending.iter().cloned().collect();
+        pending.sort();
+        pending
+    }
+
+    // Accounts the result, returns false for results of unknown or already reported tasks
+    // and for failed attempts which are going to be retried.
+    fn record(&mut self, result: &TaskResult) -> bool {
+        if result.retrying {
+            return false;
+        }
+        let known = match &result.id {
+            Some(id) => self.pending.remove(id),
+            None => false,
+        };
+        if known {
+            if result.success() {
+                self.succeeded += 1;
+            } else {
+                self.failed += 1;
+            }
+        }
+        known
+    }
+
+    pub fn summary(&self) -> Summary {
+        Summary {
+            total: self.total,
+            succeeded: self.succeeded,
+            failed: self.failed,
+            missing: self.pending.len(),
+        }
+    }
+}
+
+/// Collects results of tasks broadcast to every worker of a topic, each worker reports every task.
+#[derive(Default)]
+pub struct BroadcastTracker {
+    // broadcast tasks in the order of dispatch with the number of workers expected to report them
+    expected: Vec<(String, usize)>,
+    // workers which reported the final result of each task
+    reported: HashMap<String, HashSet<String>>,
+    succeeded: usize,
+    failed: usize,
+}
+
+/// Broadcast task which some of the workers expected have not reported.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Unanswered {
+    pub id: String,
+    pub missing: usize,
+    // The ones known by name since they reported other tasks, sorted.
+    pub workers: Vec<String>,
+}
+
+impl BroadcastTracker {
+    pub fn new() -> Self {
+        BroadcastTracker::default()
+    }
+
+    /// Registers a broadcast task whose result should be waited for from `workers` workers.
+    pub fn expect(&mut self, id: String, workers: usize) {
+        self.reported.entry(id.clone()).or_default();
+        self.expected.push((id, workers));
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.expected
+            .iter()
+            .all(|(id, workers)| self.reported[id].len() >= *workers)
+    }
+
+    /// Consumes replies until every expected worker has reported, `deadline` passes or the stream ends.
+    /// `on_result` is called once per task and worker with the number of reported results so far.
+    pub async fn wait<F>(
+        &mut self,
+        replies: &mut MessageStream,
+        deadline: Instant,
+        mut on_result: F,
+    ) -> Summary
+    where
+        F: FnMut(&TaskResult, &Summary),
+    {
+        while !self.is_done() {
+            let Ok(Some(result)) = timeout_at(deadline, self.next(replies)).await else {
+                break;
+            };
+            on_result(&result, &self.summary());
+        }
+        self.summary()
+    }
+
+    async fn next(&mut self, replies: &mut MessageStream) -> Option<TaskResult> {
+        while let Some(result) = next_result(replies).await {
+            if self.record(&result) {
+                return Some(result);
+            }
+        }
+        None
+    }
+
+    // Accounts the result, returns false for results of unknown tasks, for ones already reported
+    // by the worker and for failed attempts which are going to be retried.
+    fn record(&mut self, result: &TaskResult) -> bool {
+        if result.retrying {
+            return false;
+        }
+        let Some(reported) = result.id.as_ref().and_then(|id| self.reported.get_mut(id)) else {
+            return false;
+        };
+        // workers which do not tell their ID are indistinguishable
+        if !reported.insert(result.worker_id.clone().unwrap_or_default()) {
+            return false;
+        }
+        if result.success() {
+            self.succeeded += 1;
+        } else {
+            self.failed += 1;
+        }
+        true
+    }
+
+    pub fn summary(&self) -> Summary {
+        let total = self
+            .expected
+            .iter()
+            .map(|(id, workers)| self.reported[id].len().max(*workers))
+            .sum();
+        Summary {
+            total,
+            succeeded: self.succeeded,
+            failed: self.failed,
+            missing: total - self.succeeded - self.failed,
+        }
+    }
+
+    /// Broadcast tasks which have not been reported by all the workers expected, in the order of dispatch.
+    pub fn unanswered(&self) -> Vec<Unanswered> {
+        let known: BTreeSet<&String> = self.reported.values().flatten().collect();
+        self.expected
+            .iter()
+            .filter_map(|(id, workers)| {
+                let reported = &self.reported[id];
+                let missing = workers.saturating_sub(reported.len());
+                if missing == 0 {
+                    return None;
+                }
+                Some(Unanswered {
+                    id: id.clone(),
+                    missing,
+                    workers: known
+                        .iter()
+                        .filter(|worker| !reported.contains(**worker))
+                        .map(|worker| worker.to_string())
+                        .collect(),
+                })
+            })
+            .collect()
+    }
+}
+
+// Takes the next task result from the replies, skipping ones which cannot be decoded.
+async fn next_result(replies: &mut MessageStream) -> Option<TaskResult> {
+    while let Some(msg) = replies.next().await {
+        if let Err(err) = msg.ack().await {
+            eprintln!("Failed to ack task result: {}", err);
+        }
+        match serde_json::from_str::<TaskResult>(&msg.body()) {
+            Ok(result) => return Some(result),
+            Err(err) => eprintln!("Failed to decode task result: {}", err),
+        }
+    }
+    None
+}
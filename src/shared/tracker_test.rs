@@ -0,0 +1,99 @@
+use crate::shared::config::MemoryParams;
+use crate::shared::models::{TaskResult, TaskStatus};
+use crate::shared::msgbus::bus::{MessageProps, Publisher, ReplyConsumer};
+use crate::shared::msgbus::memory::MemoryBus;
+use crate::shared::tracker::*;
+use std::time::Duration;
+use tokio::time::Instant;
+
+fn result(id: &str, worker_id: &str, status: TaskStatus) -> TaskResult {
+    TaskResult {
+        id: Some(id.to_string()),
+        status,
+        exit_code: Some(0),
+        duration_ms: 0,
+        stdout: String::new(),
+        stderr: String::new(),
+        truncated: false,
+        error: None,
+        attempt: 1,
+        retrying: false,
+        worker_id: Some(worker_id.to_string()),
+    }
+}
+
+#[tokio::test]
+async fn test_broadcast_results_until_deadline() {
+    let mut bus = MemoryBus::new(MemoryParams::default());
+    let (reply_to, mut replies) = bus.consume_replies().await.unwrap();
+    let retrying = TaskResult {
+        retrying: true,
+        ..result("b", "w3", TaskStatus::Failed)
+    };
+    for reply in [
+        result("a", "w1", TaskStatus::Succeeded),
+        result("a", "w2", TaskStatus::Failed),
+        // reported twice, e.g. after the worker reconnected
+        result("a", "w2", TaskStatus::Succeeded),
+        result("b", "w1", TaskStatus::Succeeded),
+        retrying,
+    ] {
+        let msg = serde_json::to_string(&reply).unwrap();
+        bus.publish(reply_to.clone(), msg, MessageProps::default())
+            .await
+            .unwrap();
+    }
+
+    let mut tracker = BroadcastTracker::new();
+    tracker.expect("a".to_string(), 2);
+    tracker.expect("b".to_string(), 3);
+    let mut reported = vec![];
+    let started = Instant::now();
+    let deadline = started + Duration::from_millis(200);
+    let summary = tracker
+        .wait(&mut replies, deadline, |result, _| {
+            reported.push(result.worker_id.clone().unwrap())
+        })
+        .await;
+
+    assert!(started.elapsed() >= Duration::from_millis(200));
+    assert_eq!(reported, vec!["w1", "w2", "w1"]);
+    assert_eq!(
+        summary,
+        Summary {
+            total: 5,
+            succeeded: 2,
+            failed: 1,
+            missing: 2,
+        }
+    );
+    assert_eq!(
+        tracker.unanswered(),
+        vec![Unanswered {
+            id: "b".to_string(),
+            missing: 2,
+            workers: vec!["w2".to_string()],
+        }]
+    );
+}
+
+#[tokio::test]
+async fn test_results_until_deadline() {
+    let mut bus = MemoryBus::new(MemoryParams::default());
+    let (reply_to, mut replies) = bus.consume_replies().await.unwrap();
+    let msg = serde_json::to_string(&result("a", "w1", TaskStatus::Succeeded)).unwrap();
+    bus.publish(reply_to, msg, MessageProps::default())
+        .await
+        .unwrap();
+
+    let mut tracker = Tracker::new();
+    tracker.expect("a".to_string());
+    tracker.expect("b".to_string());
+    // the result of b is lost, e.g. its worker died after acknowledging it
+    let deadline = Instant::now() + Duration::from_millis(100);
+    let summary = tracker.wait(&mut replies, Some(deadline), |_, _| {}).await;
+
+    assert_eq!(summary.succeeded, 1);
+    assert_eq!(summary.missing, 1);
+    assert_eq!(tracker.pending(), vec!["b".to_string()]);
+}
@@ -0,0 +1,242 @@
+use crate::shared::dispatcher::Dispatcher;
+use crate::shared::models::{Task, TaskResult};
+use crate::shared::msgbus::bus::{MessageStream, Publisher};
+use crate::shared::tracker::{Summary, Tracker};
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fs::File;
+use thiserror::Error;
+use tokio::time::Instant;
+
+/// Tasks depending on each other, read from a YAML or JSON file.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct Workflow {
+    // Topic to publish the tasks without a topic of their own to, the producer's topic if empty.
+    #[serde(default)]
+    pub topic: Option<String>,
+    pub tasks: Vec<Node>,
+}
+
+/// Task of a workflow, its ID is required and unique within the workflow.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Node {
+    #[serde(flatten)]
+    pub task: Task,
+    #[serde(default)]
+    pub topic: Option<String>,
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum WorkflowError {
+    #[error("Task #{0} of the workflow has no ID")]
+    MissingId(usize),
+    #[error("Task ID {0} is not unique")]
+    DuplicateId(String),
+    #[error("Task {0} depends on unknown task {1}")]
+    UnknownDependency(String, String),
+    #[error("Tasks depend on each other in a cycle: {}", .0.join(", "))]
+    Cycle(Vec<String>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NodeStatus {
+    // Waiting for its dependencies to succeed.
+    Waiting,
+    Running,
+    Succeeded,
+    Failed,
+    // Not dispatched since one of its dependencies did not succeed.
+    Skipped,
+    // Dispatched, but did not report before the reply stream ended or the deadline passed.
+    Missing,
+}
+
+impl Workflow {
+    pub fn load(path: &str) -> Result<Workflow, Box<dyn Error>> {
+        let file = File::open(path).map_err(|err| format!("Failed to open {}: {}", path, err))?;
+        // JSON is a subset of YAML
+        let workflow: Workflow = serde_yaml::from_reader(file)?;
+        workflow.validate()?;
+        Ok(workflow)
+    }
+
+    /// Checks that tasks have unique IDs and their dependencies exist and do not form a cycle.
+    pub fn validate(&self) -> Result<(), WorkflowError> {
+        let mut ids = HashSet::new();
+        for (index, node) in self.tasks.iter().enumerate() {
+            let Some(id) = &node.task.id else {
+                return Err(WorkflowError::MissingId(index + 1));
+            };
+            if !ids.insert(id.as_str()) {
+                return Err(WorkflowError::DuplicateId(id.clone()));
+            }
+        }
+        for node in &self.tasks {
+            if let Some(unknown) = node
+                .task
+                .depends_on
+                .iter()
+                .find(|dependency| !ids.contains(dependency.as_str()))
+            {
+                return Err(WorkflowError::UnknownDependency(
+                    node.id().to_string(),
+                    unknown.clone(),
+                ));
+            }
+        }
+
+        // tasks are resolved once all their dependencies are, the ones left are in or after a cycle
+        let mut resolved = HashSet::new();
+        loop {
+            let before = resolved.len();
+            for node in &self.tasks {
+                let ready = node
+                    .task
+                    .depends_on
+                    .iter()
+                    .all(|dependency| resolved.contains(dependency.as_str()));
+                if ready {
+                    resolved.insert(node.id());
+                }
+            }
+            if resolved.len() == before {
+                break;
+            }
+        }
+        if resolved.len() < self.tasks.len() {
+            let cycle = self
+                .tasks
+                .iter()
+                .map(Node::id)
+                .filter(|id| !resolved.contains(id))
+                .map(str::to_string)
+                .collect();
+            return Err(WorkflowError::Cycle(cycle));
+        }
+        Ok(())
+    }
+}
+
+impl Node {
+    fn id(&self) -> &str {
+        self.task.id.as_deref().unwrap_or_default()
+    }
+}
+
+/// Dispatches tasks of a workflow once their dependencies succeed and collects their results.
+pub struct Coordinator<'a, T: Publisher> {
+    bus: &'a mut T,
+    topic: String,
+    tracker: Tracker,
+    deadline: Option<Instant>,
+}
+
+impl<'a, T: Publisher> Coordinator<'a, T> {
+    /// Tasks are published to `topic` unless the workflow or the task sets its own.
+    pub fn new(bus: &'a mut T, topic: String) -> Self {
+        Coordinator {
+            bus,
+            topic,
+            tracker: Tracker::new(),
+            deadline: None,
+        }
+    }
+
+    /// Stops waiting for results once `deadline` passes, the tasks running then are reported as missing.
+    pub fn with_deadline(mut self, deadline: Option<Instant>) -> Self {
+        self.deadline = deadline;
+        self
+    }
+
+    /// Runs the validated workflow until every task has finished or is skipped, results are published
+    /// to `reply_to` and consumed from `replies`. `on_result` is called with the final result of
+    /// every dispatched task. Returns the status of every task in the order of the workflow.
+    pub async fn run<F>(
+        &mut self,
+        workflow: Workflow,
+        reply_to: String,
+        replies: &mut MessageStream,
+        mut on_result: F,
+    ) -> Result<Vec<(String, NodeStatus)>, Box<dyn Error>>
+    where
+        F: FnMut(&TaskResult, &Summary),
+    {
+        let topic = workflow.topic.unwrap_or(self.topic.clone());
+        let mut statuses: HashMap<String, NodeStatus> = workflow
+            .tasks
+            .iter()
+            .map(|node| (node.id().to_string(), NodeStatus::Waiting))
+            .collect();
+
+        loop {
+            // skipping a task may skip the ones depending on it in the next pass
+            let mut progressed = true;
+            while progressed {
+                progressed = false;
+                for node in &workflow.tasks {
+                    if statuses[node.id()] != NodeStatus::Waiting {
+                        continue;
+                    }
+                    let dependencies: Vec<NodeStatus> = node
+                        .task
+                        .depends_on
+                        .iter()
+                        .map(|dependency| statuses[dependency])
+                        .collect();
+                    let status = if dependencies.iter().all(|s| *s == NodeStatus::Succeeded) {
+                        let mut task = node.task.clone();
+                        task.reply_to = Some(reply_to.clone());
+                        let topic = node.topic.clone().unwrap_or(topic.clone());
+                        Dispatcher::new(self.bus).dispatch(topic, task).await?;
+                        self.tracker.expect(node.id().to_string());
+                        NodeStatus::Running
+                    } else if dependencies.iter().any(|s| is_unsuccessful(*s)) {
+                        NodeStatus::Skipped
+                    } else {
+                        continue;
+                    };
+                    statuses.insert(node.id().to_string(), status);
+                    progressed = true;
+                }
+            }
+            if self.tracker.is_done() {
+                break;
+            }
+
+            let Some(result) = self.tracker.next(replies, self.deadline).await else {
+                // results are not coming anymore, so the waiting tasks are not dispatched
+                for status in statuses.values_mut() {
+                    *status = match *status {
+                        NodeStatus::Running => NodeStatus::Missing,
+                        NodeStatus::Waiting => NodeStatus::Skipped,
+                        status => status,
+                    };
+                }
+                break;
+            };
+            let status = if result.success() {
+                NodeStatus::Succeeded
+            } else {
+                NodeStatus::Failed
+            };
+            if let Some(id) = &result.id {
+                statuses.insert(id.clone(), status);
+            }
+            on_result(&result, &self.tracker.summary());
+        }
+
+        Ok(workflow
+            .tasks
+            .iter()
+            .map(|node| (node.id().to_string(), statuses[node.id()]))
+            .collect())
+    }
+}
+
+fn is_unsuccessful(status: NodeStatus) -> bool {
+    matches!(
+        status,
+        NodeStatus::Failed | NodeStatus::Skipped | NodeStatus::Missing
+    )
+}
@@ -0,0 +1,122 @@
+use crate::shared::config::MemoryParams;
+use crate::shared::executor::Executor;
+use crate::shared::models::Task;
+use crate::shared::msgbus::bus::ReplyConsumer;
+use crate::shared::msgbus::memory::MemoryBus;
+use crate::shared::workflow::*;
+use std::collections::HashMap;
+use tokio::sync::oneshot;
+
+const TOPIC: &str = "test";
+
+fn workflow(yaml: &str) -> Workflow {
+    serde_yaml::from_str(yaml).unwrap()
+}
+
+#[test]
+fn test_workflow_file() {
+    let workflow = workflow(
+        r#"
+        topic: transcode
+        tasks:
+          - id: a
+            command: ffmpeg -i a.mkv a.mp4
+          - {"id": "merge", "command": "merge", "depends_on": ["a"], "topic": "merge", "exclusive": true}
+        "#,
+    );
+    assert_eq!(workflow.validate(), Ok(()));
+    assert_eq!(workflow.topic.as_deref(), Some("transcode"));
+    let merge = &workflow.tasks[1];
+    assert_eq!(merge.task.shell, "sh");
+    assert!(merge.task.exclusive);
+    assert_eq!(merge.task.depends_on, vec!["a".to_string()]);
+    assert_eq!(merge.topic.as_deref(), Some("merge"));
+}
+
+#[test]
+fn test_invalid_workflows() {
+    let validate = |yaml: &str| workflow(yaml).validate();
+    assert_eq!(
+        validate("tasks: [{id: a, command: a}, {command: b}]"),
+        Err(WorkflowError::MissingId(2))
+    );
+    assert_eq!(
+        validate("tasks: [{id: a, command: a}, {id: a, command: b}]"),
+        Err(WorkflowError::DuplicateId("a".to_string()))
+    );
+    assert_eq!(
+        validate("tasks: [{id: a, command: a, depends_on: [b]}]"),
+        Err(WorkflowError::UnknownDependency(
+            "a".to_string(),
+            "b".to_string()
+        ))
+    );
+    assert_eq!(
+        validate(
+            "tasks: [{id: a, command: a}, {id: b, command: b, depends_on: [a, c]}, \
+             {id: c, command: c, depends_on: [b]}, {id: d, command: d, depends_on: [c]}]"
+        ),
+        Err(WorkflowError::Cycle(vec![
+            "b".to_string(),
+            "c".to_string(),
+            "d".to_string()
+        ]))
+    );
+}
+
+#[tokio::test]
+async fn test_dependents_of_failed_tasks_are_skipped() {
+    let node = |id: &str, command: &str, depends_on: &[&str]| Node {
+        task: Task {
+            shell: "sh".to_string(),
+            command: command.to_string(),
+            id: Some(id.to_string()),
+            depends_on: depends_on.iter().map(|id| id.to_string()).collect(),
+            ..Task::default()
+        },
+        topic: None,
+    };
+    let workflow = Workflow {
+        topic: None,
+        tasks: vec![
+            node("merge", "true", &["a", "b"]),
+            node("a", "sleep 0.1", &[]),
+            node("b", "true", &[]),
+            node("bad", "exit 1", &[]),
+            node("report", "true", &["merge", "bad"]),
+            node("cleanup", "true", &["report"]),
+        ],
+    };
+
+    let mut bus = MemoryBus::new(MemoryParams::default());
+    let mut worker_bus = bus.connect();
+    let (reply_to, mut replies) = bus.consume_replies().await.unwrap();
+    let mut executor = Executor::new(&mut worker_bus, 2, TOPIC.to_string());
+    let (done_tx, done_rx) = oneshot::channel();
+    let mut reported = vec![];
+    let (statuses, executed) = tokio::join!(
+        async {
+            let statuses = Coordinator::new(&mut bus, TOPIC.to_string())
+                .run(workflow, reply_to, &mut replies, |result, _| {
+                    reported.push(result.id.clone().unwrap())
+                })
+                .await;
+            let _ = done_tx.send(());
+            statuses
+        },
+        executor.run_until(async {
+            let _ = done_rx.await;
+        }),
+    );
+    executed.unwrap();
+
+    let statuses: HashMap<String, NodeStatus> = statuses.unwrap().into_iter().collect();
+    assert_eq!(statuses["a"], NodeStatus::Succeeded);
+    assert_eq!(statuses["merge"], NodeStatus::Succeeded);
+    assert_eq!(statuses["bad"], NodeStatus::Failed);
+    assert_eq!(statuses["report"], NodeStatus::Skipped);
+    assert_eq!(statuses["cleanup"], NodeStatus::Skipped);
+    // the merge is dispatched only after both of its dependencies have finished
+    assert_eq!(reported.len(), 4);
+    assert_eq!(reported.last().map(String::as_str), Some("merge"));
+}